@@ -0,0 +1,90 @@
+use std::ops::Range;
+
+use anyhow::{Context, Error, Result};
+
+/**
+ * Minimal bencode scanner locating values in a buffer without decoding them.
+ * serde_bencode only gives us decoded values, this is needed wherever the exact bytes matter, like the info dictionary.
+ */
+fn parse_integer(buffer: &[u8], start: usize, terminator: u8) -> Result<(i64, usize)> {
+    let end = buffer[start..]
+        .iter()
+        .position(|&byte| byte == terminator)
+        .map(|position| start + position)
+        .context("Unterminated bencode integer")?;
+    let integer = std::str::from_utf8(&buffer[start..end])?
+        .parse::<i64>()
+        .context("Bad bencode integer")?;
+    Ok((integer, end + 1))
+}
+
+/** Byte range of the value starting at `start` */
+pub fn value_span(buffer: &[u8], start: usize) -> Result<Range<usize>> {
+    let end = value_end(buffer, start, 0)?;
+    Ok(start..end)
+}
+
+fn value_end(buffer: &[u8], start: usize, depth: usize) -> Result<usize> {
+    // Deeply nested input could otherwise overflow the stack
+    if depth > 512 {
+        return Err(Error::msg("Bencode value is nested too deeply"));
+    }
+    match buffer.get(start) {
+        Some(b'i') => Ok(parse_integer(buffer, start + 1, b'e')?.1),
+        Some(b'l') | Some(b'd') => {
+            let mut position = start + 1;
+            while buffer.get(position) != Some(&b'e') {
+                if position >= buffer.len() {
+                    return Err(Error::msg("Unterminated bencode list or dictionary"));
+                }
+                position = value_end(buffer, position, depth + 1)?;
+            }
+            Ok(position + 1)
+        }
+        Some(b'0'..=b'9') => {
+            let (length, data_start) = parse_integer(buffer, start, b':')?;
+            let end = data_start + usize::try_from(length)?;
+            if end > buffer.len() {
+                return Err(Error::msg("Bencode string goes past the end of the buffer"));
+            }
+            Ok(end)
+        }
+        Some(byte) => Err(Error::msg(format!("Invalid bencode value starting with {:?}", *byte as char))),
+        None => Err(Error::msg("Unexpected end of bencode buffer")),
+    }
+}
+
+/** Byte range of the value associated to `key` in the dictionary at the start of the buffer */
+pub fn dict_value_span(buffer: &[u8], key: &[u8]) -> Result<Option<Range<usize>>> {
+    if buffer.first() != Some(&b'd') {
+        return Err(Error::msg("Not a bencode dictionary"));
+    }
+    let mut position = 1;
+    while buffer.get(position) != Some(&b'e') {
+        let key_span = value_span(buffer, position)?;
+        let value = value_span(buffer, key_span.end)?;
+        let (_, key_start) = parse_integer(buffer, key_span.start, b':')?;
+        if &buffer[key_start..key_span.end] == key {
+            return Ok(Some(value));
+        }
+        position = value.end;
+    }
+    Ok(None)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_dict_value_span() {
+        let buffer = b"d1:ai-12e4:infod1:zi1e1:al2:xyee3:zzz0:e";
+        let span = dict_value_span(buffer, b"info").unwrap().unwrap();
+        assert_eq!(&buffer[span], b"d1:zi1e1:al2:xyee");
+        assert_eq!(dict_value_span(buffer, b"zzz").unwrap(), Some(37..39));
+        assert_eq!(dict_value_span(buffer, b"missing").unwrap(), None);
+        assert!(dict_value_span(b"d4:infod", b"info").is_err());
+        assert!(dict_value_span(b"d4:info5:ab", b"info").is_err());
+        assert!(dict_value_span(b"l1:ae", b"info").is_err());
+    }
+}
@@ -7,7 +7,7 @@ pub struct BitField {
 impl BitField {
     pub fn new(length: usize) -> Self {
         let mut bits = vec![0; length / 8];
-        if length % 8 != 0 {
+        if !length.is_multiple_of(8) {
             bits.push(0);
         }
         Self { bits, length }
@@ -35,7 +35,26 @@ impl BitField {
         self.length
     }
 
-    pub fn iter(&self) -> BitFieldIter {
+    /** Bits packed high bit first, as sent in bitfield messages */
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.bits
+    }
+
+    /** Spare bits past `length` are ignored */
+    pub fn from_bytes(bytes: &[u8], length: usize) -> Self {
+        let mut bitfield = BitField::new(length);
+        for (index, byte) in bitfield.bits.iter_mut().enumerate() {
+            *byte = bytes.get(index).copied().unwrap_or(0);
+        }
+        if !length.is_multiple_of(8) {
+            if let Some(last) = bitfield.bits.last_mut() {
+                *last &= 0xff << (8 - length % 8);
+            }
+        }
+        bitfield
+    }
+
+    pub fn iter(&self) -> BitFieldIter<'_> {
         BitFieldIter {
             bitfield: self,
             index: 0,
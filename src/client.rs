@@ -1,22 +1,3066 @@
-use std::collections::BTreeMap;
+use std::collections::{BTreeMap, HashMap};
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::time::{Duration, Instant, SystemTime};
 
+use anyhow::Context;
+use sha1::{Digest, Sha1};
+use thiserror::Error;
+use tokio::net::TcpListener;
+use tokio::sync::{broadcast, mpsc, oneshot, watch};
+use tokio::task::AbortHandle;
+
+use crate::bitfield::BitField;
+use crate::connection::{
+    accept_peers, accept_utp_peers, connect, run_connection, send_handshake, ConnectionSettings, Dialer, ListenerState, PeerCommand, PeerEvent,
+    PeerState, PeerStream,
+};
+use crate::ip_filter::IpFilter;
 use crate::meta_info::MetaInfo;
-use crate::torrent::Torrent;
+use crate::peer::Handshake;
+use crate::piece::Block;
+use crate::piece_picker::Priority;
+use crate::rate_limit::{Bandwidth, Limiters};
+use crate::resume::ResumeData;
+use crate::torrent::{
+    GoalAction, InvalidTransition, SeedingGoal, SeedingGoals, Torrent, TorrentState, TorrentStatus,
+};
+use crate::tracker::{Announce, AnnounceEvent, Peers};
+use crate::utp::UtpSocket;
+use crate::proxy::ProxySettings;
+use crate::network::{bind_tcp, bind_udp, NetworkSettings};
+use crate::web_seed::WEB_SEED_RETRY_INTERVAL;
 
-#[derive(Debug, Clone)]
+/** Port listened on for peers unless the config gives another */
+pub const DEFAULT_LISTEN_PORT: u16 = 6881;
+/** Events a subscriber can fall behind by before it starts missing them */
+pub const EVENT_CHANNEL_CAPACITY: usize = 1024;
+/** Trackers that don't answer the stopped announce within this time are given up on */
+pub const STOPPED_ANNOUNCE_TIMEOUT: Duration = Duration::from_secs(5);
+/** Trackers are asked again after this long when none of them answered */
+pub const TRACKER_RETRY_INTERVAL: Duration = Duration::from_secs(5 * 60);
+/** Trackers asking to be announced to more often than this are ignored */
+pub const MIN_ANNOUNCE_INTERVAL: Duration = Duration::from_secs(60);
+/** How often rates are measured and the queue is managed */
+pub const SESSION_TICK_INTERVAL: Duration = Duration::from_secs(1);
+
+/** Where a peer address was learned from */
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PeerSource {
+    Tracker,
+    Dht,
+    Pex,
+    Lsd,
+    Magnet,
+    /** Peers connecting to us, they already know the info hash */
+    Incoming,
+}
+
+impl PeerSource {
+    /**
+     * Private torrents (BEP 27) must only get peers from the trackers listed in their metainfo.
+     * Every other discovery mechanism must neither be fed peers for them nor learn their info hash.
+     */
+    pub fn allowed_for(&self, torrent: &Torrent) -> bool {
+        matches!(self, PeerSource::Tracker | PeerSource::Incoming) || !torrent.is_private()
+    }
+}
+
+/** A piece handed in at once, as a single block */
+fn whole_piece(index: usize, data: Vec<u8>, peer: Option<SocketAddr>) -> Vec<Block> {
+    vec![Block {
+        piece_index: index as u32,
+        begin: 0,
+        length: data.len() as u32,
+        data,
+        peer,
+    }]
+}
+
+/** Azureus style peer id: client code and version between dashes, then random characters */
+fn generate_peer_id() -> [u8; 20] {
+    let seed = format!("{:?}{}", SystemTime::now(), std::process::id());
+    let random = hex::encode(Sha1::digest(seed.as_bytes()));
+    let mut peer_id = [0u8; 20];
+    peer_id[..8].copy_from_slice(b"-RF0100-");
+    peer_id[8..].copy_from_slice(&random.as_bytes()[..12]);
+    peer_id
+}
+
+/**
+ * Limits on the torrents running at once, the others wait in the queued state in queue order.
+ * Torrents transferring less than `slow_rate_threshold` in both directions don't count against the limits,
+ * once they had `slow_grace_period` to get going.
+ */
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct QueueSettings {
+    pub active_downloads: usize,
+    pub active_seeds: usize,
+    pub active_limit: usize,
+    /** Bytes per second */
+    pub slow_rate_threshold: u64,
+    pub slow_grace_period: Duration,
+}
+
+impl Default for QueueSettings {
+    fn default() -> Self {
+        Self {
+            active_downloads: 3,
+            active_seeds: 5,
+            active_limit: 15,
+            slow_rate_threshold: 2048,
+            slow_grace_period: Duration::from_secs(60),
+        }
+    }
+}
+
+/** State changes published by the session */
+#[derive(Debug, Clone, PartialEq)]
+pub enum ClientEvent {
+    TorrentAdded { info_hash: String },
+    TorrentRemoved { info_hash: String },
+    StateChanged { info_hash: String, state: TorrentState },
+    QueuePositionChanged { info_hash: String, position: usize },
+    SeedingGoalReached { info_hash: String, goal: SeedingGoal, action: GoalAction },
+    PieceFinished { info_hash: String, index: usize },
+    HashFailed { info_hash: String, index: usize },
+    TorrentCompleted { info_hash: String },
+    TrackerError { info_hash: String, url: String, message: String },
+    TrackerWarning { info_hash: String, url: String, message: String },
+    PeerConnected { info_hash: String, addr: SocketAddr },
+    PeerDisconnected { info_hash: String, addr: SocketAddr },
+    PeerBanned { info_hash: String, addr: SocketAddr },
+    /** The kill switch tripped: an interface the session is bound to went away, all traffic is stopped */
+    NetworkDown,
+    NetworkRestored,
+    StorageError { info_hash: String, message: String },
+}
+
+/** Errors returned by session commands */
+#[derive(Debug, Clone, PartialEq, Error)]
+pub enum ClientError {
+    #[error("unknown torrent {0}")]
+    UnknownTorrent(String),
+    #[error("torrent {0} was already added")]
+    DuplicateTorrent(String),
+    #[error("torrent {info_hash} has no file at index {file_index}")]
+    UnknownFile { info_hash: String, file_index: usize },
+    #[error("torrent {info_hash} has no piece {index}")]
+    InvalidPiece { info_hash: String, index: usize },
+    #[error(transparent)]
+    InvalidTransition(#[from] InvalidTransition),
+    #[error("storage error: {0}")]
+    Storage(String),
+    #[error("invalid proxy settings: {0}")]
+    InvalidProxy(String),
+    #[error("torrent {0} is v2-only, which isn't supported yet")]
+    UnsupportedV2(String),
+    #[error("the session has shut down")]
+    SessionClosed,
+}
+
+type Reply<T> = oneshot::Sender<Result<T, ClientError>>;
+
+/** Results of the disk work the session hands off to blocking tasks, so it keeps serving commands meanwhile */
+#[derive(Debug)]
+enum SessionEvent {
+    /** `next_state` is where the torrent goes once checked, its active state when not given */
+    Checked {
+        info_hash: String,
+        pieces: anyhow::Result<BitField>,
+        next_state: Option<TorrentState>,
+    },
+    PieceWritten {
+        info_hash: String,
+        index: usize,
+        /** Counted once the piece is on disk, blocks from peers were counted as they arrived */
+        downloaded: u64,
+        written: anyhow::Result<()>,
+        reply: Option<Reply<bool>>,
+    },
+    WebSeedPiece {
+        info_hash: String,
+        seed_index: usize,
+        index: usize,
+        data: anyhow::Result<Vec<u8>>,
+    },
+    /** Only failures matter, the torrent already counts as completed */
+    Finalized {
+        info_hash: String,
+        finalized: anyhow::Result<()>,
+    },
+    /** One announce for each HTTP tracker of the torrent, `event` is what was sent */
+    Announced {
+        info_hash: String,
+        event: Option<AnnounceEvent>,
+        announces: Vec<Announce>,
+    },
+}
+
+/** Task running a peer connection, and the channel to what it sends the peer */
+#[derive(Debug)]
+struct ConnectionTask {
+    abort: AbortHandle,
+    commands: mpsc::UnboundedSender<PeerCommand>,
+}
+
+/** Requests sent by a `ClientHandle` to the session task */
+#[derive(Debug)]
+enum Command {
+    AddTorrent(Box<MetaInfo>, Reply<String>),
+    RemoveTorrent {
+        info_hash: String,
+        delete_data: bool,
+        reply: Reply<()>,
+    },
+    Pause(String, Reply<()>),
+    Resume(String, Reply<()>),
+    Recheck(String, Reply<()>),
+    AddPiece {
+        info_hash: String,
+        index: usize,
+        blocks: Vec<Block>,
+        reply: Reply<bool>,
+    },
+    SetFilePriority {
+        info_hash: String,
+        file_index: usize,
+        priority: Priority,
+        reply: Reply<()>,
+    },
+    AddPeer {
+        info_hash: String,
+        addr: SocketAddr,
+        source: PeerSource,
+        reply: Reply<bool>,
+    },
+    SetQueuePosition {
+        info_hash: String,
+        position: usize,
+        reply: Reply<()>,
+    },
+    SetQueueSettings(QueueSettings, Reply<()>),
+    SetConnectionSettings(ConnectionSettings, Reply<()>),
+    SetIpFilter(IpFilter, Reply<()>),
+    SetProxy(ProxySettings, Reply<()>),
+    SetNetworkSettings(NetworkSettings, Reply<()>),
+    SetRateLimits {
+        info_hash: Option<String>,
+        upload: Option<u64>,
+        download: Option<u64>,
+        reply: Reply<()>,
+    },
+    SetPeerRateLimits {
+        upload: Option<u64>,
+        download: Option<u64>,
+        reply: Reply<()>,
+    },
+    SetIncludeOverhead(bool, Reply<()>),
+    SetSeedingGoals {
+        info_hash: Option<String>,
+        goals: Option<SeedingGoals>,
+        reply: Reply<()>,
+    },
+    Status(String, Reply<TorrentStatus>),
+    ListStatus(Reply<Vec<TorrentStatus>>),
+    DiscoverableInfoHashes(PeerSource, Reply<Vec<String>>),
+    Shutdown {
+        announce_stopped: bool,
+        reply: Reply<()>,
+    },
+}
+
+/**
+ * The session state, owned by a single task once spawned.
+ * Everything else talks to it through a `ClientHandle`, so torrents are never shared or cloned around.
+ * Events go through a broadcast channel: a slow subscriber lags and misses events instead of blocking the session.
+ */
+#[derive(Debug)]
 pub struct TorrentClient {
     pub torrents: BTreeMap<String, Torrent>,
+    pub download_dir: PathBuf,
+    /** Resume data is only saved when set */
+    pub resume_dir: Option<PathBuf>,
+    pub queue_settings: QueueSettings,
+    /** Session wide rate limits, every torrent and peer limit sits below them */
+    pub bandwidth: Bandwidth,
+    global_limiters: Limiters,
+    /** Upload and download limits given to each new peer connection */
+    pub peer_rate_limits: (Option<u64>, Option<u64>),
+    /** Applies to the torrents without goals of their own */
+    pub seeding_goals: SeedingGoals,
+    pub connection_settings: ConnectionSettings,
+    /** Peers in these ranges are never connected to nor accepted */
+    pub ip_filter: IpFilter,
+    /** Used by peer connections and web seeds, incoming and uTP connections are refused when it is forced */
+    pub proxy: ProxySettings,
+    /** Listen interfaces only apply to `listen`, before the session runs */
+    pub network_settings: NetworkSettings,
+    /** Set while the kill switch holds all traffic */
+    network_down: bool,
+    /** Sent in handshakes and announces */
+    pub peer_id: [u8; 20],
+    /** Advertised to peers and trackers, 0 while nothing listens */
+    port: u16,
+    listeners: Vec<std::net::TcpListener>,
+    /** uTP shares the port of the TCP listener on the same address */
+    udp_sockets: Vec<std::net::UdpSocket>,
+    utp: Vec<UtpSocket>,
+    listener_tasks: Vec<AbortHandle>,
+    listener_state: watch::Sender<ListenerState>,
+    /** Aborted when the torrent stops or the peer gets banned */
+    connection_tasks: HashMap<(String, SocketAddr), ConnectionTask>,
+    peer_events: mpsc::UnboundedSender<PeerEvent>,
+    peer_events_rx: Option<mpsc::UnboundedReceiver<PeerEvent>>,
+    session_events: mpsc::UnboundedSender<SessionEvent>,
+    session_events_rx: Option<mpsc::UnboundedReceiver<SessionEvent>>,
+    /** Pieces handed to a blocking task and not on disk yet, waited for on shutdown */
+    pending_writes: usize,
+    events: broadcast::Sender<ClientEvent>,
 }
 
 impl TorrentClient {
     pub fn new() -> Self {
+        Self::with_download_dir(".")
+    }
+
+    pub fn with_download_dir(download_dir: impl Into<PathBuf>) -> Self {
+        let (events, _) = broadcast::channel(EVENT_CHANNEL_CAPACITY);
+        let global_limiters = Limiters::unlimited();
+        let (peer_events, peer_events_rx) = mpsc::unbounded_channel();
+        let (session_events, session_events_rx) = mpsc::unbounded_channel();
         Self {
             torrents: BTreeMap::new(),
+            download_dir: download_dir.into(),
+            resume_dir: None,
+            queue_settings: QueueSettings::default(),
+            bandwidth: Bandwidth::new(global_limiters.clone()),
+            global_limiters,
+            peer_rate_limits: (None, None),
+            seeding_goals: SeedingGoals::default(),
+            connection_settings: ConnectionSettings::default(),
+            ip_filter: IpFilter::new(),
+            proxy: ProxySettings::default(),
+            network_settings: NetworkSettings::default(),
+            network_down: false,
+            peer_id: generate_peer_id(),
+            port: 0,
+            listeners: vec![],
+            udp_sockets: vec![],
+            utp: vec![],
+            listener_tasks: vec![],
+            listener_state: watch::Sender::new(ListenerState::default()),
+            connection_tasks: HashMap::new(),
+            peer_events,
+            peer_events_rx: Some(peer_events_rx),
+            session_events,
+            session_events_rx: Some(session_events_rx),
+            pending_writes: 0,
+            events,
+        }
+    }
+
+    pub fn with_resume_dir(mut self, resume_dir: impl Into<PathBuf>) -> Self {
+        self.resume_dir = Some(resume_dir.into());
+        self
+    }
+
+    pub fn with_ip_filter(mut self, ip_filter: IpFilter) -> Self {
+        self.ip_filter = ip_filter;
+        self
+    }
+
+    /** Accept incoming peer connections once the session runs, returns the bound address */
+    pub fn bind(&mut self, addr: SocketAddr) -> anyhow::Result<SocketAddr> {
+        let listener = bind_tcp(addr).context(format!("Failed to listen on {}", addr))?;
+        let local_addr = listener.local_addr()?;
+        let udp_socket = bind_udp(local_addr).context(format!("Failed to listen for uTP on {}", local_addr))?;
+        self.listeners.push(listener);
+        self.udp_sockets.push(udp_socket);
+        self.port = local_addr.port();
+        Ok(local_addr)
+    }
+
+    /** Bind every address of the listen interfaces, all on the same port, the first one picks it when given 0 */
+    pub fn listen(&mut self, port: u16) -> anyhow::Result<Vec<SocketAddr>> {
+        let mut bound = vec![];
+        let mut port = port;
+        for addr in self.network_settings.listen_addrs(port)? {
+            let local_addr = self.bind(SocketAddr::new(addr.ip(), port))?;
+            port = local_addr.port();
+            bound.push(local_addr);
+        }
+        Ok(bound)
+    }
+
+    /** Port peers can reach the session on */
+    pub fn listen_port(&self) -> u16 {
+        self.port
+    }
+
+    /** Start the session task */
+    pub fn spawn(self) -> ClientHandle {
+        let (command_tx, command_rx) = mpsc::unbounded_channel();
+        let events = self.events.clone();
+        tokio::spawn(self.run(command_rx));
+        ClientHandle { command_tx, events }
+    }
+
+    pub fn subscribe(&self) -> broadcast::Receiver<ClientEvent> {
+        self.events.subscribe()
+    }
+
+    fn emit(&self, event: ClientEvent) {
+        // Sending only fails when nobody is subscribed
+        let _ = self.events.send(event);
+    }
+
+    async fn run(mut self, mut command_rx: mpsc::UnboundedReceiver<Command>) {
+        let mut peer_events = self.peer_events_rx.take().expect("the session only runs once");
+        let mut session_events = self.session_events_rx.take().expect("the session only runs once");
+        self.update_listener_state();
+        for listener in std::mem::take(&mut self.listeners) {
+            let Ok(listener) = TcpListener::from_std(listener) else {
+                continue;
+            };
+            let accepting = accept_peers(
+                listener,
+                self.peer_events.clone(),
+                self.connection_settings.connect_timeout,
+                self.listener_state.subscribe(),
+            );
+            self.listener_tasks.push(tokio::spawn(accepting).abort_handle());
+        }
+        for socket in std::mem::take(&mut self.udp_sockets) {
+            let Ok(utp) = UtpSocket::from_std(socket) else {
+                continue;
+            };
+            let accepting = accept_utp_peers(
+                utp.clone(),
+                self.peer_events.clone(),
+                self.connection_settings.connect_timeout,
+                self.listener_state.subscribe(),
+            );
+            self.listener_tasks.push(tokio::spawn(accepting).abort_handle());
+            self.utp.push(utp);
+        }
+        let mut tick = tokio::time::interval(SESSION_TICK_INTERVAL);
+        let mut last_tick = Instant::now();
+        loop {
+            tokio::select! {
+                command = command_rx.recv() => {
+                    let Some(command) = command else {
+                        break;
+                    };
+                    if !self.handle_command(command).await {
+                        break;
+                    }
+                }
+                Some(event) = peer_events.recv() => {
+                    self.handle_peer_event(event);
+                }
+                Some(event) = session_events.recv() => {
+                    self.handle_session_event(event);
+                }
+                _ = tick.tick() => {
+                    self.tick(last_tick.elapsed()).await;
+                    last_tick = Instant::now();
+                }
+            }
+            self.auto_manage();
+            self.connect_peers();
+        }
+        for task in self.listener_tasks.drain(..) {
+            task.abort();
+        }
+        for (_, task) in self.connection_tasks.drain() {
+            task.abort.abort();
+        }
+    }
+
+    /** Returns false once the session is shut down */
+    async fn handle_command(&mut self, command: Command) -> bool {
+        // A dropped reply receiver only means the caller stopped waiting
+        match command {
+            Command::AddTorrent(meta_info, reply) => {
+                let added = self.add_torrent(*meta_info);
+                if let Ok(info_hash) = &added {
+                    // Failures end up in the torrent state, the torrent was added either way
+                    let _ = self.check_resume_data(info_hash);
+                }
+                let _ = reply.send(added);
+            }
+            Command::RemoveTorrent {
+                info_hash,
+                delete_data,
+                reply,
+            } => {
+                let _ = reply.send(self.remove_torrent(&info_hash, delete_data).await);
+            }
+            Command::Pause(info_hash, reply) => {
+                let _ = reply.send(self.pause(&info_hash));
+            }
+            Command::Resume(info_hash, reply) => {
+                let _ = reply.send(self.resume(&info_hash));
+            }
+            Command::Recheck(info_hash, reply) => {
+                let _ = reply.send(self.recheck(&info_hash));
+            }
+            Command::AddPiece {
+                info_hash,
+                index,
+                blocks,
+                reply,
+            } => match self.verify_blocks(&info_hash, index, blocks) {
+                // Answered once the piece is on disk
+                Ok(Some((key, data))) => {
+                    let downloaded = data.len() as u64;
+                    self.write_piece(key, index, data, downloaded, Some(reply))
+                }
+                Ok(None) => {
+                    let _ = reply.send(Ok(false));
+                }
+                Err(error) => {
+                    let _ = reply.send(Err(error));
+                }
+            },
+            Command::SetFilePriority {
+                info_hash,
+                file_index,
+                priority,
+                reply,
+            } => {
+                let _ = reply.send(self.set_file_priority(&info_hash, file_index, priority));
+            }
+            Command::AddPeer {
+                info_hash,
+                addr,
+                source,
+                reply,
+            } => {
+                let _ = reply.send(Ok(self.add_peer(&info_hash, addr, source)));
+            }
+            Command::SetQueuePosition {
+                info_hash,
+                position,
+                reply,
+            } => {
+                let _ = reply.send(self.set_queue_position(&info_hash, position));
+            }
+            Command::SetQueueSettings(queue_settings, reply) => {
+                self.queue_settings = queue_settings;
+                let _ = reply.send(Ok(()));
+            }
+            Command::SetConnectionSettings(connection_settings, reply) => {
+                self.connection_settings = connection_settings;
+                self.update_listener_state();
+                let _ = reply.send(Ok(()));
+            }
+            Command::SetIpFilter(ip_filter, reply) => {
+                self.set_ip_filter(ip_filter);
+                let _ = reply.send(Ok(()));
+            }
+            Command::SetProxy(proxy, reply) => {
+                let _ = reply.send(self.set_proxy(proxy));
+            }
+            Command::SetNetworkSettings(network_settings, reply) => {
+                self.network_settings = network_settings;
+                self.update_web_seed_clients();
+                self.check_network();
+                let _ = reply.send(Ok(()));
+            }
+            Command::SetRateLimits {
+                info_hash,
+                upload,
+                download,
+                reply,
+            } => {
+                let _ = reply.send(self.set_rate_limits(info_hash.as_deref(), upload, download));
+            }
+            Command::SetPeerRateLimits {
+                upload,
+                download,
+                reply,
+            } => {
+                self.peer_rate_limits = (upload, download);
+                let _ = reply.send(Ok(()));
+            }
+            Command::SetIncludeOverhead(include_overhead, reply) => {
+                self.bandwidth.set_include_overhead(include_overhead);
+                let _ = reply.send(Ok(()));
+            }
+            Command::SetSeedingGoals {
+                info_hash,
+                goals,
+                reply,
+            } => {
+                let _ = reply.send(self.set_seeding_goals(info_hash.as_deref(), goals));
+            }
+            Command::Status(info_hash, reply) => {
+                let _ = reply.send(self.torrent_mut(&info_hash).map(|torrent| torrent.status()));
+            }
+            Command::ListStatus(reply) => {
+                let statuses = self.queue().into_iter().map(|key| self.torrents[&key].status());
+                let _ = reply.send(Ok(statuses.collect()));
+            }
+            Command::DiscoverableInfoHashes(source, reply) => {
+                let _ = reply.send(Ok(self.discoverable_info_hashes(source)));
+            }
+            Command::Shutdown { announce_stopped, reply } => {
+                self.wait_for_writes().await;
+                let keys = self.torrents.keys().cloned().collect::<Vec<_>>();
+                for key in keys {
+                    self.save_resume_data(&key);
+                }
+                if announce_stopped {
+                    self.announce_stopped().await;
+                }
+                let _ = reply.send(Ok(()));
+                return false;
+            }
+        }
+        true
+    }
+
+    /** Returns the info hash the torrent is known by */
+    pub fn add_torrent(&mut self, meta_info: MetaInfo) -> Result<String, ClientError> {
+        // BEP 52 pieces are aligned to each file and checked against merkle trees, only v1 pieces are handled for now
+        if !meta_info.info.is_v1() {
+            return Err(ClientError::UnsupportedV2(meta_info.to_info_hash()));
+        }
+        let torrent = Torrent::new(meta_info, &self.download_dir);
+        // Hybrid torrents are the same torrent as their v1 and v2 halves
+        if let Some(known) = torrent
+            .info_hashes()
+            .into_iter()
+            .find(|info_hash| self.find_torrent_mut(info_hash).is_some())
+        {
+            return Err(ClientError::DuplicateTorrent(known));
+        }
+        let info_hash = torrent.info_hash();
+        let mut torrent = torrent;
+        // Restored torrents keep the position of their resume data, new ones go last
+        torrent.queue_position = self
+            .torrents
+            .values()
+            .map(|torrent| torrent.queue_position + 1)
+            .max()
+            .unwrap_or(0);
+        let bandwidth = torrent.bandwidth(&self.bandwidth);
+        for web_seed in &mut torrent.web_seeds {
+            web_seed.set_bandwidth(bandwidth.clone());
+            // Settings are checked when set, building the client only fails for forced proxies without one or interfaces that are down
+            let _ = web_seed.set_proxy(&self.proxy, self.network_settings.outgoing_interface.as_ref());
+        }
+        self.torrents.insert(info_hash.clone(), torrent);
+        self.update_listener_state();
+        self.emit(ClientEvent::TorrentAdded {
+            info_hash: info_hash.clone(),
+        });
+        Ok(info_hash)
+    }
+
+    fn torrent_mut(&mut self, info_hash: &str) -> Result<&mut Torrent, ClientError> {
+        self.find_torrent_mut(info_hash)
+            .ok_or_else(|| ClientError::UnknownTorrent(info_hash.to_string()))
+    }
+
+    /** Change the limits of a torrent, or the session limits without an info hash. `None` is unlimited */
+    pub fn set_rate_limits(
+        &mut self,
+        info_hash: Option<&str>,
+        upload: Option<u64>,
+        download: Option<u64>,
+    ) -> Result<(), ClientError> {
+        let limiters = match info_hash {
+            Some(info_hash) => &self.torrent_mut(info_hash)?.limiters,
+            None => &self.global_limiters,
+        };
+        limiters.upload.set_rate(upload);
+        limiters.download.set_rate(download);
+        Ok(())
+    }
+
+    /** Limits for a new connection to a peer of the torrent */
+    pub fn peer_bandwidth(&mut self, info_hash: &str) -> Result<Bandwidth, ClientError> {
+        let (upload, download) = self.peer_rate_limits;
+        let session = self.bandwidth.clone();
+        let torrent = self.torrent_mut(info_hash)?;
+        Ok(torrent.bandwidth(&session).child(&Limiters::new(upload, download)))
+    }
+
+    /** Info hashes in queue order */
+    pub fn queue(&self) -> Vec<String> {
+        let mut queue = self.torrents.values().collect::<Vec<_>>();
+        queue.sort_by_key(|torrent| (torrent.queue_position, &torrent.info_hash));
+        queue.into_iter().map(|torrent| torrent.info_hash.clone()).collect()
+    }
+
+    /** Move a torrent in the queue, the torrents in between shift by one */
+    pub fn set_queue_position(&mut self, info_hash: &str, position: usize) -> Result<(), ClientError> {
+        let key = self.torrent_mut(info_hash)?.info_hash.clone();
+        let mut queue = self.queue();
+        queue.retain(|queued| *queued != key);
+        queue.insert(position.min(queue.len()), key);
+        self.renumber_queue(queue);
+        Ok(())
+    }
+
+    fn renumber_queue(&mut self, queue: Vec<String>) {
+        for (position, key) in queue.into_iter().enumerate() {
+            let torrent = self.torrents.get_mut(&key).unwrap();
+            if torrent.queue_position != position {
+                torrent.queue_position = position;
+                self.emit(ClientEvent::QueuePositionChanged { info_hash: key, position });
+            }
+        }
+    }
+
+    async fn tick(&mut self, elapsed: Duration) {
+        self.check_network();
+        self.announce_to_trackers();
+        self.fetch_web_seed_pieces();
+        self.request_all_blocks();
+        for torrent in self.torrents.values_mut() {
+            torrent.update_rates(elapsed);
+            if torrent.state == TorrentState::Seeding {
+                torrent.seeding_time += elapsed;
+            }
+        }
+        self.apply_seeding_goals().await;
+    }
+
+    /** Session goals without an info hash, `None` clears the goals of a torrent so it follows the session again */
+    pub fn set_seeding_goals(&mut self, info_hash: Option<&str>, goals: Option<SeedingGoals>) -> Result<(), ClientError> {
+        match info_hash {
+            Some(info_hash) => self.torrent_mut(info_hash)?.seeding_goals = goals,
+            None => self.seeding_goals = goals.unwrap_or_default(),
+        }
+        Ok(())
+    }
+
+    /** Pause or remove the seeding torrents that reached their goals */
+    pub async fn apply_seeding_goals(&mut self) {
+        let reached = self
+            .torrents
+            .values()
+            .filter_map(|torrent| {
+                let goals = torrent.seeding_goals.unwrap_or(self.seeding_goals);
+                let goal = torrent.seeding_goal_reached(&goals)?;
+                Some((torrent.info_hash.clone(), goal, goals.action))
+            })
+            .collect::<Vec<_>>();
+        for (info_hash, goal, action) in reached {
+            self.emit(ClientEvent::SeedingGoalReached {
+                info_hash: info_hash.clone(),
+                goal,
+                action,
+            });
+            // Failures are reported through events and the torrent state
+            let _ = match action {
+                GoalAction::Pause => self.pause(&info_hash),
+                GoalAction::Remove => self.remove_torrent(&info_hash, false).await,
+                GoalAction::RemoveWithData => self.remove_torrent(&info_hash, true).await,
+            };
+        }
+    }
+
+    /**
+     * Start and queue torrents so that the active ones are the first in the queue within the limits.
+     * Paused, checking and errored torrents are left alone, slow torrents keep running without taking a slot.
+     */
+    pub fn auto_manage(&mut self) {
+        let settings = self.queue_settings;
+        let (mut downloads, mut seeds, mut active) = (0, 0, 0);
+        let mut transitions = vec![];
+        for key in self.queue() {
+            let torrent = &self.torrents[&key];
+            if !matches!(
+                torrent.state,
+                TorrentState::Downloading | TorrentState::Seeding | TorrentState::Queued
+            ) {
+                continue;
+            }
+            if torrent.state.is_active() && torrent.is_slow(settings.slow_rate_threshold, settings.slow_grace_period) {
+                continue;
+            }
+            let wanted = torrent.active_state();
+            let slots = match wanted {
+                TorrentState::Seeding => &mut seeds,
+                _ => &mut downloads,
+            };
+            let limit = match wanted {
+                TorrentState::Seeding => settings.active_seeds,
+                _ => settings.active_downloads,
+            };
+            let next_state = if *slots < limit && active < settings.active_limit {
+                *slots += 1;
+                active += 1;
+                wanted
+            } else {
+                TorrentState::Queued
+            };
+            if next_state != torrent.state {
+                transitions.push((key, next_state));
+            }
+        }
+        for (key, state) in transitions {
+            let _ = self.set_state(&key, state);
+        }
+    }
+
+    /** Move a torrent to a new state, announcing it and saving its resume data */
+    fn set_state(&mut self, info_hash: &str, state: TorrentState) -> Result<(), ClientError> {
+        let torrent = self.torrent_mut(info_hash)?;
+        if torrent.state == state {
+            return Ok(());
+        }
+        // Trackers only hear that we stopped once they heard that we started
+        let announced = torrent.state.is_active() && torrent.announce_event != Some(AnnounceEvent::Started);
+        torrent.set_state(state.clone())?;
+        let key = torrent.info_hash.clone();
+        if !state.is_active() {
+            self.close_connections(&key);
+            if announced && !self.network_down {
+                self.spawn_announces(&key, Some(AnnounceEvent::Stopped));
+            }
+        }
+        self.emit(ClientEvent::StateChanged {
+            info_hash: key.clone(),
+            state,
+        });
+        self.save_resume_data(&key);
+        Ok(())
+    }
+
+    /** Put a torrent in the error state instead of giving up on the whole session */
+    fn fail(&mut self, info_hash: &str, error: anyhow::Error) -> ClientError {
+        let message = format!("{:#}", error);
+        self.emit(ClientEvent::StorageError {
+            info_hash: info_hash.to_string(),
+            message: message.clone(),
+        });
+        let _ = self.set_state(info_hash, TorrentState::Error(message.clone()));
+        ClientError::Storage(message)
+    }
+
+    fn save_resume_data(&mut self, info_hash: &str) {
+        let (Some(resume_dir), Some(torrent)) = (&self.resume_dir, self.torrents.get(info_hash)) else {
+            return;
+        };
+        if let Err(error) = torrent.resume_data().write(&ResumeData::path(resume_dir, info_hash)) {
+            self.emit(ClientEvent::StorageError {
+                info_hash: info_hash.to_string(),
+                message: format!("{:#}", error),
+            });
+        }
+    }
+
+    /**
+     * Leave the checking state of a newly added torrent.
+     * Resume data is trusted when the files it points to look intact, otherwise whatever is on disk is hashed again
+     * in the background, the torrent stays in the checking state until then.
+     */
+    pub fn check_resume_data(&mut self, info_hash: &str) -> Result<(), ClientError> {
+        let resume_data = self
+            .resume_dir
+            .as_ref()
+            .map(|resume_dir| ResumeData::path(resume_dir, info_hash))
+            .filter(|path| path.exists())
+            .map(|path| ResumeData::from_file(&path));
+        let torrent = self.torrent_mut(info_hash)?;
+        let key = torrent.info_hash.clone();
+
+        let mut next_state = None;
+        let resumed = match resume_data {
+            Some(Ok(resume_data)) if torrent.apply_resume_data(&resume_data).is_ok() => {
+                if resume_data.paused == 1 {
+                    next_state = Some(TorrentState::Paused);
+                }
+                if let Some(cause) = resume_data.error {
+                    next_state = Some(TorrentState::Error(cause));
+                }
+                true
+            }
+            // Unreadable or mismatched resume data is as good as none
+            _ => {
+                torrent.pieces_bitfield = BitField::new(torrent.meta_info.info.pieces_count());
+                false
+            }
+        };
+
+        let needs_check = if resumed {
+            !torrent.storage.has_pieces_on_disk(&torrent.pieces_bitfield)
+        } else {
+            torrent.storage.has_files()
+        };
+        if needs_check {
+            self.check_pieces(key, next_state);
+            return Ok(());
         }
+        let next_state = next_state.unwrap_or_else(|| self.torrents[&key].active_state());
+        self.set_state(&key, next_state)
     }
 
-    pub fn add_torrent(self: &mut TorrentClient, meta_info: MetaInfo) {
-        let torrent = Torrent::new(meta_info);
-        self.torrents.insert(torrent.info_hash(), torrent.clone());
+    /** Hash the data on disk in a blocking task, reported back as `SessionEvent::Checked` */
+    fn check_pieces(&mut self, key: String, next_state: Option<TorrentState>) {
+        let storage = self.torrents[&key].storage.clone();
+        let session_events = self.session_events.clone();
+        tokio::spawn(async move {
+            let pieces = tokio::task::spawn_blocking(move || storage.check_pieces())
+                .await
+                .unwrap_or_else(|error| Err(error.into()));
+            let _ = session_events.send(SessionEvent::Checked {
+                info_hash: key,
+                pieces,
+                next_state,
+            });
+        });
+    }
+
+    pub fn pause(&mut self, info_hash: &str) -> Result<(), ClientError> {
+        self.set_state(info_hash, TorrentState::Paused)
+    }
+
+    /** Errored torrents are checked again before running, their data may be what failed */
+    pub fn resume(&mut self, info_hash: &str) -> Result<(), ClientError> {
+        let torrent = self.torrent_mut(info_hash)?;
+        match torrent.state {
+            TorrentState::Paused => {
+                let active_state = torrent.active_state();
+                self.set_state(info_hash, active_state)
+            }
+            TorrentState::Error(_) => self.recheck(info_hash),
+            _ => Ok(()),
+        }
+    }
+
+    pub async fn remove_torrent(&mut self, info_hash: &str, delete_data: bool) -> Result<(), ClientError> {
+        let key = self.torrent_mut(info_hash)?.info_hash.clone();
+        self.close_connections(&key);
+        let Some(torrent) = self.torrents.remove(&key) else {
+            return Err(ClientError::UnknownTorrent(info_hash.to_string()));
+        };
+        let queue = self.queue();
+        self.renumber_queue(queue);
+        self.update_listener_state();
+        self.emit(ClientEvent::TorrentRemoved { info_hash: key.clone() });
+        if let Some(resume_dir) = &self.resume_dir {
+            let path = ResumeData::path(resume_dir, &key);
+            if path.exists() {
+                std::fs::remove_file(&path).map_err(|error| ClientError::Storage(error.to_string()))?;
+            }
+        }
+        if delete_data {
+            let storage = torrent.storage;
+            let deleted = tokio::task::spawn_blocking(move || storage.delete_files())
+                .await
+                .map_err(|error| ClientError::Storage(error.to_string()))?;
+            if let Err(error) = deleted {
+                self.emit(ClientEvent::StorageError {
+                    info_hash: key,
+                    message: format!("{:#}", error),
+                });
+                return Err(ClientError::Storage(format!("{:#}", error)));
+            }
+        }
+        Ok(())
+    }
+
+    /** Hash the data on disk again and replace the pieces we have with what was found, in the background */
+    pub fn recheck(&mut self, info_hash: &str) -> Result<(), ClientError> {
+        let torrent = self.torrent_mut(info_hash)?;
+        let key = torrent.info_hash.clone();
+        let was_paused = torrent.state == TorrentState::Paused;
+        self.set_state(&key, TorrentState::CheckingResumeData)?;
+        self.check_pieces(key, was_paused.then_some(TorrentState::Paused));
+        Ok(())
+    }
+
+    /**
+     * Check a downloaded piece against its hash and write it to disk in the background.
+     * Returns false when the hash check failed and the piece has to be downloaded again.
+     * The piece counts as downloaded once written, `PieceFinished` is emitted then.
+     */
+    pub fn add_piece(&mut self, info_hash: &str, index: usize, data: Vec<u8>) -> Result<bool, ClientError> {
+        self.add_blocks(info_hash, index, whole_piece(index, data, None))
+    }
+
+    /**
+     * Same as `add_piece` for a piece put together from blocks, which must cover the piece without overlapping.
+     * Peers sending pieces failing the hash check too often get banned.
+     * The blocks of failed pieces are remembered, once the piece passes the peers whose blocks differ get banned right away.
+     */
+    pub fn add_blocks(&mut self, info_hash: &str, index: usize, blocks: Vec<Block>) -> Result<bool, ClientError> {
+        match self.verify_blocks(info_hash, index, blocks)? {
+            Some((key, data)) => {
+                let downloaded = data.len() as u64;
+                self.write_piece(key, index, data, downloaded, None);
+                Ok(true)
+            }
+            None => Ok(false),
+        }
+    }
+
+    /** The info hash the torrent is known by and the piece data when it passed the hash check */
+    fn verify_blocks(
+        &mut self,
+        info_hash: &str,
+        index: usize,
+        mut blocks: Vec<Block>,
+    ) -> Result<Option<(String, Vec<u8>)>, ClientError> {
+        let settings = self.connection_settings;
+        let torrent = self.torrent_mut(info_hash)?;
+        let info_hash = torrent.info_hash.clone();
+        let info = &torrent.meta_info.info;
+        if index >= info.pieces_count() {
+            return Err(ClientError::InvalidPiece { info_hash, index });
+        }
+        blocks.sort_by_key(|block| block.begin);
+        let mut data = Vec::with_capacity(info.piece_size(index) as usize);
+        for block in &blocks {
+            if block.begin as usize != data.len() || block.length as usize != block.data.len() {
+                return Err(ClientError::InvalidPiece { info_hash, index });
+            }
+            data.extend_from_slice(&block.data);
+        }
+        if data.len() as u64 != info.piece_size(index) || !info.verify_piece(index, &data) {
+            torrent.smart_ban.piece_failed(index as u32, &blocks);
+            // Picked again, from whoever has it
+            torrent.picker.set_downloading(index, false);
+            let mut peers = blocks.iter().filter_map(|block| block.peer).collect::<Vec<_>>();
+            peers.sort();
+            peers.dedup();
+            peers.retain(|addr| torrent.connections.hash_failed(addr, &settings));
+            self.emit(ClientEvent::HashFailed {
+                info_hash: info_hash.clone(),
+                index,
+            });
+            for addr in peers {
+                self.ban_peer(&info_hash, &addr);
+            }
+            return Ok(None);
+        }
+        let mut culprits = torrent.smart_ban.piece_passed(index as u32, &data);
+        culprits.retain(|addr| torrent.connections.ban(addr));
+        for addr in culprits {
+            self.ban_peer(&info_hash, &addr);
+        }
+        Ok(Some((info_hash, data)))
+    }
+
+    /** Write a verified piece in a blocking task, reported back as `SessionEvent::PieceWritten` */
+    fn write_piece(&mut self, key: String, index: usize, data: Vec<u8>, downloaded: u64, reply: Option<Reply<bool>>) {
+        let storage = self.torrents[&key].storage.clone();
+        let session_events = self.session_events.clone();
+        self.pending_writes += 1;
+        tokio::spawn(async move {
+            let written = tokio::task::spawn_blocking(move || storage.write_piece(index, &data))
+                .await
+                .unwrap_or_else(|error| Err(error.into()));
+            let _ = session_events.send(SessionEvent::PieceWritten {
+                info_hash: key,
+                index,
+                downloaded,
+                written,
+                reply,
+            });
+        });
+    }
+
+    /** Give every idle web seed of the downloading torrents a piece to fetch, reported back as `SessionEvent::WebSeedPiece` */
+    fn fetch_web_seed_pieces(&mut self) {
+        if self.network_down {
+            return;
+        }
+        let now = Instant::now();
+        for torrent in self.torrents.values_mut() {
+            if torrent.state != TorrentState::Downloading {
+                continue;
+            }
+            for seed_index in 0..torrent.web_seeds.len() {
+                if !torrent.web_seeds[seed_index].is_ready(now) {
+                    continue;
+                }
+                let Some(index) = torrent.pick_web_seed_piece() else {
+                    break;
+                };
+                let web_seed = &mut torrent.web_seeds[seed_index];
+                web_seed.busy = true;
+                let web_seed = web_seed.clone();
+                let info = torrent.meta_info.info.clone();
+                let info_hash = torrent.info_hash.clone();
+                let session_events = self.session_events.clone();
+                tokio::spawn(async move {
+                    let data = web_seed.fetch_piece(&info, index).await;
+                    let _ = session_events.send(SessionEvent::WebSeedPiece {
+                        info_hash,
+                        seed_index,
+                        index,
+                        data,
+                    });
+                });
+            }
+        }
+    }
+
+    /** Pieces still being written would be missing from the resume data */
+    async fn wait_for_writes(&mut self) {
+        let Some(mut session_events) = self.session_events_rx.take() else {
+            return;
+        };
+        while self.pending_writes > 0 {
+            match session_events.recv().await {
+                Some(event) => self.handle_session_event(event),
+                None => break,
+            }
+        }
+        self.session_events_rx = Some(session_events);
+    }
+
+    fn handle_session_event(&mut self, event: SessionEvent) {
+        match event {
+            SessionEvent::Checked {
+                info_hash,
+                pieces,
+                next_state,
+            } => {
+                // Removed while checking
+                let Some(torrent) = self.torrents.get_mut(&info_hash) else {
+                    return;
+                };
+                match pieces {
+                    Ok(pieces) => torrent.pieces_bitfield = pieces,
+                    Err(error) => {
+                        self.fail(&info_hash, error);
+                        return;
+                    }
+                }
+                // Paused or checked again meanwhile
+                if torrent.state == TorrentState::CheckingResumeData {
+                    let next_state = next_state.unwrap_or_else(|| torrent.active_state());
+                    let _ = self.set_state(&info_hash, next_state);
+                }
+            }
+            SessionEvent::PieceWritten {
+                info_hash,
+                index,
+                downloaded,
+                written,
+                reply,
+            } => {
+                self.pending_writes -= 1;
+                let result = self.piece_written(&info_hash, index, downloaded, written);
+                if let Some(reply) = reply {
+                    let _ = reply.send(result.map(|_| true));
+                }
+            }
+            SessionEvent::WebSeedPiece {
+                info_hash,
+                seed_index,
+                index,
+                data,
+            } => {
+                let Some(torrent) = self.torrents.get_mut(&info_hash) else {
+                    return;
+                };
+                if let Some(web_seed) = torrent.web_seeds.get_mut(seed_index) {
+                    web_seed.busy = false;
+                    web_seed.retry_at = data.is_err().then(|| Instant::now() + WEB_SEED_RETRY_INTERVAL);
+                }
+                let stored = match data {
+                    Ok(data) => self.add_piece(&info_hash, index, data),
+                    Err(_) => Ok(false),
+                };
+                if stored != Ok(true) {
+                    if let Some(torrent) = self.torrents.get_mut(&info_hash) {
+                        torrent.picker.set_downloading(index, false);
+                    }
+                }
+                self.fetch_web_seed_pieces();
+            }
+            SessionEvent::Finalized { info_hash, finalized } => {
+                if let (Err(error), true) = (finalized, self.torrents.contains_key(&info_hash)) {
+                    self.fail(&info_hash, error);
+                }
+            }
+            SessionEvent::Announced {
+                info_hash,
+                event,
+                announces,
+            } => self.announced(&info_hash, event, announces),
+        }
+    }
+
+    /** Count a piece once it is on disk, the files are finalized in the background once it was the last one */
+    fn piece_written(&mut self, info_hash: &str, index: usize, downloaded: u64, written: anyhow::Result<()>) -> Result<(), ClientError> {
+        if let Err(error) = written {
+            if let Some(torrent) = self.torrents.get_mut(info_hash) {
+                torrent.picker.set_downloading(index, false);
+            }
+            return Err(self.fail(info_hash, error));
+        }
+        let torrent = self.torrent_mut(info_hash)?;
+        torrent.picker.set_downloading(index, false);
+        let was_complete = torrent.left() == 0;
+        torrent.pieces_bitfield.set(index);
+        torrent.record_download(downloaded);
+        let completed = !was_complete && torrent.left() == 0;
+        let storage = torrent.storage.clone();
+        self.emit(ClientEvent::PieceFinished {
+            info_hash: info_hash.to_string(),
+            index,
+        });
+        if completed {
+            let session_events = self.session_events.clone();
+            let key = info_hash.to_string();
+            tokio::spawn(async move {
+                let finalized = tokio::task::spawn_blocking(move || storage.finalize())
+                    .await
+                    .unwrap_or_else(|error| Err(error.into()));
+                let _ = session_events.send(SessionEvent::Finalized { info_hash: key, finalized });
+            });
+            self.emit(ClientEvent::TorrentCompleted {
+                info_hash: info_hash.to_string(),
+            });
+            let torrent = self.torrent_mut(info_hash)?;
+            if torrent.state.is_active() && torrent.announce_event.is_none() {
+                torrent.announce_event = Some(AnnounceEvent::Completed);
+                torrent.announce_at = None;
+            }
+            if self.torrents[info_hash].state == TorrentState::Downloading {
+                self.set_state(info_hash, TorrentState::Seeding)?;
+            }
+        }
+        Ok(())
+    }
+
+    /** Announce the active torrents whose trackers are due, the responses come back as session events */
+    fn announce_to_trackers(&mut self) {
+        if self.network_down {
+            return;
+        }
+        let now = Instant::now();
+        let due = self
+            .torrents
+            .values()
+            .filter(|torrent| torrent.state.is_active() && !torrent.announcing)
+            .filter(|torrent| torrent.announce_at.is_none_or(|at| at <= now))
+            .map(|torrent| torrent.info_hash.clone())
+            .collect::<Vec<_>>();
+        for key in due {
+            let torrent = self.torrents.get_mut(&key).unwrap();
+            torrent.announcing = true;
+            let event = torrent.announce_event.take();
+            self.spawn_announces(&key, event);
+        }
+    }
+
+    /** Announce a torrent to each of its HTTP trackers in the background */
+    fn spawn_announces(&self, info_hash: &str, event: Option<AnnounceEvent>) {
+        let torrent = &self.torrents[info_hash];
+        let urls = torrent
+            .meta_info
+            .announce_urls()
+            .into_iter()
+            .filter(|url| url.starts_with("http"))
+            .collect::<Vec<_>>();
+        let params = torrent.announce_params(self.peer_id, self.port, event);
+        let proxy = self.proxy.clone();
+        let interface = self.network_settings.outgoing_interface.clone();
+        let session_events = self.session_events.clone();
+        let info_hash = info_hash.to_string();
+        tokio::spawn(async move {
+            let announces = match &params {
+                Ok(params) => {
+                    let announces = urls
+                        .iter()
+                        .map(|url| Announce::from_params(url, params, &proxy, interface.as_ref()));
+                    futures::future::join_all(announces).await
+                }
+                Err(_) => vec![],
+            };
+            let _ = session_events.send(SessionEvent::Announced {
+                info_hash,
+                event,
+                announces,
+            });
+        });
+    }
+
+    /** Schedule the next announce, the event is sent again when no tracker answered */
+    fn announced(&mut self, info_hash: &str, event: Option<AnnounceEvent>, announces: Vec<Announce>) {
+        // Trackers have nothing more to say to stopped torrents
+        if event == Some(AnnounceEvent::Stopped) {
+            return;
+        }
+        for announce in &announces {
+            let _ = self.handle_announce(info_hash, announce);
+        }
+        // Removed meanwhile
+        let Some(torrent) = self.torrents.get_mut(info_hash) else {
+            return;
+        };
+        torrent.announcing = false;
+        // Stopped meanwhile, announced again from scratch once it becomes active
+        if !torrent.state.is_active() {
+            return;
+        }
+        let interval = announces
+            .iter()
+            .filter_map(|announce| announce.response.as_ref().ok())
+            .filter(|response| response.failure_reason.is_none())
+            .map(|response| Duration::from_secs(response.interval.max(0) as u64))
+            .min();
+        let interval = match interval {
+            Some(interval) => interval.max(MIN_ANNOUNCE_INTERVAL),
+            None if announces.is_empty() => TRACKER_RETRY_INTERVAL,
+            None => {
+                torrent.announce_event = torrent.announce_event.or(event);
+                TRACKER_RETRY_INTERVAL
+            }
+        };
+        torrent.announce_at = Some(Instant::now() + interval);
+    }
+
+    /** Add the peers of an announce response, reporting tracker failures and warnings */
+    fn handle_announce(&mut self, info_hash: &str, announce: &Announce) -> Result<(), ClientError> {
+        let key = self.torrent_mut(info_hash)?.info_hash.clone();
+        let response = match &announce.response {
+            Ok(response) => response,
+            Err(error) => {
+                self.emit(ClientEvent::TrackerError {
+                    info_hash: key,
+                    url: announce.url.clone(),
+                    message: format!("{:#}", error),
+                });
+                return Ok(());
+            }
+        };
+        if let Some(message) = &response.failure_reason {
+            self.emit(ClientEvent::TrackerError {
+                info_hash: key,
+                url: announce.url.clone(),
+                message: message.clone(),
+            });
+            return Ok(());
+        }
+        if let Some(message) = &response.warning_message {
+            self.emit(ClientEvent::TrackerWarning {
+                info_hash: key.clone(),
+                url: announce.url.clone(),
+                message: message.clone(),
+            });
+        }
+        if let Peers::PeerStruct(peers) = &response.peers {
+            for addr in peers.iter().filter_map(|peer| peer.socket_addr()) {
+                self.add_peer(&key, addr, PeerSource::Tracker);
+            }
+        }
+        Ok(())
+    }
+
+    pub fn set_file_priority(
+        &mut self,
+        info_hash: &str,
+        file_index: usize,
+        priority: Priority,
+    ) -> Result<(), ClientError> {
+        let torrent = self.torrent_mut(info_hash)?;
+        if file_index >= torrent.file_priorities.len() {
+            return Err(ClientError::UnknownFile {
+                info_hash: info_hash.to_string(),
+                file_index,
+            });
+        }
+        let key = torrent.info_hash.clone();
+        let previous_state = torrent.state.clone();
+        if let Err(error) = torrent.set_file_priority(file_index, priority) {
+            return Err(self.fail(&key, error));
+        }
+        let state = self.torrents[&key].state.clone();
+        if state != previous_state {
+            self.emit(ClientEvent::StateChanged {
+                info_hash: key.clone(),
+                state,
+            });
+        }
+        self.save_resume_data(&key);
+        Ok(())
+    }
+
+    /** Info hashes that may be handed to a discovery mechanism */
+    pub fn discoverable_info_hashes(&self, source: PeerSource) -> Vec<String> {
+        if source == PeerSource::Lsd && !self.lsd_allowed() {
+            return vec![];
+        }
+        self.torrents
+            .values()
+            .filter(|torrent| source.allowed_for(torrent))
+            .flat_map(|torrent| torrent.info_hashes())
+            .collect()
+    }
+
+    /** Find a torrent from the info hash of any of its swarms */
+    pub fn find_torrent_mut(&mut self, info_hash: &str) -> Option<&mut Torrent> {
+        if self.torrents.contains_key(info_hash) {
+            return self.torrents.get_mut(info_hash);
+        }
+        self.torrents
+            .values_mut()
+            .find(|torrent| torrent.info_hashes().iter().any(|hash| hash == info_hash))
+    }
+
+    /** Add a discovered peer to the matching torrent, if the torrent accepts peers from that source and the address isn't filtered */
+    pub fn add_peer(&mut self, info_hash: &str, addr: SocketAddr, source: PeerSource) -> bool {
+        let blocked = self.ip_filter.is_blocked(addr.ip());
+        let refused = source == PeerSource::Lsd && !self.lsd_allowed();
+        match self.find_torrent_mut(info_hash) {
+            Some(torrent) if blocked => {
+                torrent.filtered_peers += 1;
+                false
+            }
+            Some(_) if refused => false,
+            Some(torrent) if source.allowed_for(torrent) => torrent.add_peer(addr, source),
+            _ => false,
+        }
+    }
+
+    /** LAN peers can only be reached directly, and announcing them where we listen would leak our address past the proxy */
+    fn lsd_allowed(&self) -> bool {
+        self.proxy.allows_direct() && !self.network_down
+    }
+
+    /** Replace the IP filter, dropping the known peers it blocks */
+    pub fn set_ip_filter(&mut self, ip_filter: IpFilter) {
+        self.ip_filter = ip_filter;
+        for key in self.torrents.keys().cloned().collect::<Vec<_>>() {
+            let blocked = self.torrents[&key]
+                .connections
+                .addrs()
+                .into_iter()
+                .filter(|addr| self.ip_filter.is_blocked(addr.ip()))
+                .collect::<Vec<_>>();
+            for addr in blocked {
+                self.close_connection(&key, &addr);
+                let torrent = self.torrents.get_mut(&key).unwrap();
+                torrent.connections.remove(&addr);
+                torrent.filtered_peers += 1;
+            }
+        }
+    }
+
+    /** Web seeds switch right away, peers already connected stay connected */
+    pub fn set_proxy(&mut self, proxy: ProxySettings) -> Result<(), ClientError> {
+        proxy
+            .http_client(None)
+            .map_err(|error| ClientError::InvalidProxy(format!("{:#}", error)))?;
+        self.proxy = proxy;
+        self.update_web_seed_clients();
+        self.update_listener_state();
+        Ok(())
+    }
+
+    /** Rebuild the HTTP clients of web seeds after the proxy, the outgoing interface or its addresses changed */
+    fn update_web_seed_clients(&mut self) {
+        let interface = self.network_settings.outgoing_interface.as_ref();
+        for web_seed in self.torrents.values_mut().flat_map(|torrent| &mut torrent.web_seeds) {
+            let _ = web_seed.set_proxy(&self.proxy, interface);
+        }
+    }
+
+    /** Connections open or being opened across the session */
+    fn open_connections(&self) -> usize {
+        self.torrents
+            .values()
+            .map(|torrent| torrent.connections.open_count())
+            .sum()
+    }
+
+    /**
+     * Connect to the candidates of the active torrents, in queue order, within the connection limits.
+     * Every connection gets its own task, which reports back through peer events.
+     */
+    fn connect_peers(&mut self) {
+        if self.network_down {
+            return;
+        }
+        let settings = self.connection_settings;
+        let now = Instant::now();
+        let mut open = self.open_connections();
+        let mut half_open = self
+            .torrents
+            .values()
+            .map(|torrent| torrent.connections.count(PeerState::HalfOpen))
+            .sum::<usize>();
+        for key in self.queue() {
+            loop {
+                if open >= settings.max_connections || half_open >= settings.max_half_open {
+                    return;
+                }
+                let torrent = &self.torrents[&key];
+                if !torrent.state.is_active() || torrent.connections.open_count() >= settings.max_connections_per_torrent {
+                    break;
+                }
+                let Some(addr) = torrent.connections.next_candidate(now) else {
+                    break;
+                };
+                if self.ip_filter.is_blocked(addr.ip()) {
+                    let torrent = self.torrents.get_mut(&key).unwrap();
+                    torrent.connections.remove(&addr);
+                    torrent.filtered_peers += 1;
+                    continue;
+                }
+                let Ok(handshake) = torrent.handshake(self.peer_id) else {
+                    break;
+                };
+                let Ok(bandwidth) = self.peer_bandwidth(&key) else {
+                    break;
+                };
+                self.torrents.get_mut(&key).unwrap().connections.set_half_open(&addr);
+                open += 1;
+                half_open += 1;
+
+                let (info_hash, events) = (key.clone(), self.peer_events.clone());
+                let (commands_tx, commands) = mpsc::unbounded_channel();
+                let dialer = Dialer {
+                    utp: self.utp_socket_for(&addr).filter(|_| settings.utp),
+                    proxy: self.proxy.clone(),
+                    interface: self.network_settings.outgoing_interface.clone(),
+                };
+                let task = tokio::spawn(async move {
+                    let encryption = settings.encryption.outgoing;
+                    let connecting = connect(
+                        addr,
+                        &handshake,
+                        settings.connect_timeout,
+                        encryption,
+                        &dialer,
+                        &bandwidth,
+                    );
+                    match connecting.await {
+                        Ok((stream, handshake)) => {
+                            let _ = events.send(PeerEvent::Connected {
+                                info_hash: info_hash.clone(),
+                                addr,
+                                handshake,
+                            });
+                            run_connection(stream, info_hash, addr, bandwidth, events, commands).await;
+                        }
+                        Err(error) => {
+                            let _ = events.send(PeerEvent::Failed {
+                                info_hash,
+                                addr,
+                                error: format!("{:#}", error),
+                            });
+                        }
+                    }
+                });
+                let task = ConnectionTask {
+                    abort: task.abort_handle(),
+                    commands: commands_tx,
+                };
+                self.connection_tasks.insert((key.clone(), addr), task);
+            }
+        }
+    }
+
+    /**
+     * uTP socket of the same family as the peer.
+     * With an outgoing interface, only a socket bound to one of its addresses will do: others may leave from anywhere.
+     */
+    fn utp_socket_for(&self, addr: &SocketAddr) -> Option<UtpSocket> {
+        let interface_ips = match &self.network_settings.outgoing_interface {
+            Some(interface) => Some(interface.addrs().ok()?),
+            None => None,
+        };
+        self.utp
+            .iter()
+            .find(|socket| {
+                socket.local_addr().is_ok_and(|local| {
+                    local.is_ipv4() == addr.is_ipv4()
+                        && interface_ips.as_ref().is_none_or(|ips| ips.contains(&local.ip()))
+                })
+            })
+            .cloned()
+    }
+
+    /** Tell the HTTP trackers of running torrents that we're leaving, slow ones are given up on */
+    async fn announce_stopped(&self) {
+        if self.network_down {
+            return;
+        }
+        let announces = self
+            .torrents
+            .values()
+            .filter(|torrent| torrent.state.is_active())
+            .filter_map(|torrent| {
+                let params = torrent
+                    .announce_params(self.peer_id, self.port, Some(AnnounceEvent::Stopped))
+                    .ok()?;
+                Some((torrent.meta_info.announce_urls(), params))
+            })
+            .flat_map(|(urls, params)| urls.into_iter().map(move |url| (url, params.clone())))
+            .filter(|(url, _)| url.starts_with("http"))
+            .map(|(url, params)| {
+                let proxy = self.proxy.clone();
+                let interface = self.network_settings.outgoing_interface.clone();
+                async move { Announce::from_params(&url, &params, &proxy, interface.as_ref()).await }
+            })
+            .collect::<Vec<_>>();
+        let _ = tokio::time::timeout(STOPPED_ANNOUNCE_TIMEOUT, futures::future::join_all(announces)).await;
+    }
+
+    /** Kill switch: drop every connection, refuse incoming ones and stop LAN discovery while an interface the session is bound to is gone */
+    fn check_network(&mut self) {
+        let down = self.network_settings.kill_switch && !self.network_settings.interfaces_up();
+        if down == self.network_down {
+            return;
+        }
+        self.network_down = down;
+        self.update_listener_state();
+        if down {
+            for key in self.torrents.keys().cloned().collect::<Vec<_>>() {
+                self.close_connections(&key);
+            }
+            self.emit(ClientEvent::NetworkDown);
+        } else {
+            // The interface may have come back with other addresses
+            self.update_web_seed_clients();
+            self.emit(ClientEvent::NetworkRestored);
+        }
+    }
+
+    /** Tell the listener which torrents encrypted peers may ask for and how to treat encryption */
+    fn update_listener_state(&self) {
+        let info_hashes = self
+            .torrents
+            .values()
+            .flat_map(|torrent| torrent.swarm_hashes())
+            .filter_map(|swarm_hash| swarm_hash.get(..20)?.try_into().ok())
+            .collect();
+        self.listener_state.send_replace(ListenerState {
+            accepting: self.proxy.allows_direct() && !self.network_down,
+            info_hashes,
+            encryption: self.connection_settings.encryption.incoming,
+        });
+    }
+
+    /** Answer the handshake of an incoming connection for one of our running torrents */
+    fn accept_peer(&mut self, mut stream: PeerStream, addr: SocketAddr, handshake: Handshake) {
+        let settings = self.connection_settings;
+        let open = self.open_connections();
+        let peer_id = self.peer_id;
+        let blocked = self.ip_filter.is_blocked(addr.ip());
+        if !self.proxy.allows_direct() || self.network_down {
+            return;
+        }
+        let Some(torrent) = self.find_torrent_mut(&hex::encode(handshake.info_hash())) else {
+            return;
+        };
+        if blocked {
+            torrent.filtered_peers += 1;
+            return;
+        }
+        let already_open = torrent
+            .connections
+            .get(&addr)
+            .is_some_and(|entry| entry.state != PeerState::Candidate);
+        if !torrent.state.is_active()
+            || already_open
+            || open >= settings.max_connections
+            || torrent.connections.open_count() >= settings.max_connections_per_torrent
+        {
+            return;
+        }
+        // Answer on the swarm the peer came from, hybrid torrents are in two
+        let mut answer = Handshake::new(handshake.info_hash(), peer_id);
+        if torrent.meta_info.info.is_v2() {
+            answer = answer.with_v2();
+        }
+        let key = torrent.info_hash.clone();
+        // Incoming peers are only learned about once connected
+        torrent.connections.add_candidate(addr, PeerSource::Incoming);
+        torrent.connections.set_connected(&addr);
+        torrent.peer_connected(addr, &handshake);
+        let Ok(bandwidth) = self.peer_bandwidth(&key) else {
+            return;
+        };
+
+        let (info_hash, events) = (key.clone(), self.peer_events.clone());
+        let (commands_tx, commands) = mpsc::unbounded_channel();
+        let task = tokio::spawn(async move {
+            if send_handshake(&mut stream, &answer, &bandwidth).await.is_ok() {
+                run_connection(stream, info_hash, addr, bandwidth, events, commands).await;
+            } else {
+                let _ = events.send(PeerEvent::Disconnected { info_hash, addr });
+            }
+        });
+        let task = ConnectionTask {
+            abort: task.abort_handle(),
+            commands: commands_tx,
+        };
+        self.connection_tasks.insert((key.clone(), addr), task);
+        self.emit(ClientEvent::PeerConnected { info_hash: key, addr });
+    }
+
+    /** Events from connections closed by the session since they were sent are ignored */
+    fn handle_peer_event(&mut self, event: PeerEvent) {
+        let settings = self.connection_settings;
+        match event {
+            PeerEvent::Incoming {
+                stream,
+                addr,
+                handshake,
+            } => self.accept_peer(*stream, addr, handshake),
+            PeerEvent::Connected {
+                info_hash,
+                addr,
+                handshake,
+            } => {
+                let Some(torrent) = self.torrents.get_mut(&info_hash) else {
+                    return;
+                };
+                if torrent.connections.get(&addr).map(|entry| entry.state) != Some(PeerState::HalfOpen) {
+                    return;
+                }
+                torrent.connections.set_connected(&addr);
+                torrent.peer_connected(addr, &handshake);
+                self.emit(ClientEvent::PeerConnected { info_hash, addr });
+            }
+            PeerEvent::Failed { info_hash, addr, .. } => {
+                let Some(torrent) = self.torrents.get_mut(&info_hash) else {
+                    return;
+                };
+                if torrent.connections.get(&addr).map(|entry| entry.state) == Some(PeerState::HalfOpen) {
+                    torrent.connections.connection_failed(&addr, Instant::now(), &settings);
+                    self.connection_tasks.remove(&(info_hash, addr));
+                }
+            }
+            PeerEvent::Bitfield {
+                info_hash,
+                addr,
+                bitfield,
+            } => {
+                if let Some(torrent) = self.torrents.get_mut(&info_hash) {
+                    torrent.set_peer_bitfield(&addr, &bitfield);
+                    self.request_blocks(&info_hash, &addr);
+                }
+            }
+            PeerEvent::Have { info_hash, addr, index } => {
+                if let Some(torrent) = self.torrents.get_mut(&info_hash) {
+                    torrent.peer_has(&addr, index);
+                    self.request_blocks(&info_hash, &addr);
+                }
+            }
+            PeerEvent::Choked {
+                info_hash,
+                addr,
+                choked,
+            } => {
+                if let Some(torrent) = self.torrents.get_mut(&info_hash) {
+                    torrent.peer_choked(&addr, choked);
+                    self.request_blocks(&info_hash, &addr);
+                }
+            }
+            PeerEvent::Block { info_hash, addr, block } => {
+                let Some(torrent) = self.torrents.get_mut(&info_hash) else {
+                    return;
+                };
+                // Counted as it arrives, whether the piece passes or not
+                torrent.record_download(block.data.len() as u64);
+                let Some((index, blocks)) = torrent.block_received(&addr, block) else {
+                    return;
+                };
+                // Failed pieces are picked again, the peers that sent them may be banned meanwhile
+                if let Ok(Some((key, data))) = self.verify_blocks(&info_hash, index, blocks) {
+                    self.write_piece(key, index, data, 0, None);
+                }
+                self.request_blocks(&info_hash, &addr);
+            }
+            PeerEvent::Disconnected { info_hash, addr } => {
+                let Some(torrent) = self.torrents.get_mut(&info_hash) else {
+                    return;
+                };
+                if torrent.connections.get(&addr).map(|entry| entry.state) != Some(PeerState::Connected) {
+                    return;
+                }
+                torrent.connections.disconnected(&addr, Instant::now(), &settings);
+                torrent.peer_disconnected(&addr);
+                self.connection_tasks.remove(&(info_hash.clone(), addr));
+                self.emit(ClientEvent::PeerDisconnected { info_hash, addr });
+            }
+        }
+    }
+
+    /** Send a peer of a downloading torrent what it should get next, see `Torrent::next_requests` */
+    fn request_blocks(&mut self, info_hash: &str, addr: &SocketAddr) {
+        let Some(task) = self.connection_tasks.get(&(info_hash.to_string(), *addr)) else {
+            return;
+        };
+        let Some(torrent) = self.torrents.get_mut(info_hash) else {
+            return;
+        };
+        if torrent.state != TorrentState::Downloading {
+            return;
+        }
+        for command in torrent.next_requests(addr) {
+            let _ = task.commands.send(command);
+        }
+    }
+
+    /** Give idle peers of the downloading torrents a piece, including the ones freed by failed or rejected pieces */
+    fn request_all_blocks(&mut self) {
+        let peers = self.connection_tasks.keys().cloned().collect::<Vec<_>>();
+        for (info_hash, addr) in peers {
+            self.request_blocks(&info_hash, &addr);
+        }
+    }
+
+    /** Announce a peer the connection manager just banned and drop the connection to it */
+    fn ban_peer(&mut self, info_hash: &str, addr: &SocketAddr) {
+        self.emit(ClientEvent::PeerBanned {
+            info_hash: info_hash.to_string(),
+            addr: *addr,
+        });
+        self.close_connection(info_hash, addr);
+    }
+
+    /** Drop the connection to one peer, its state in the connection manager is left to the caller */
+    fn close_connection(&mut self, info_hash: &str, addr: &SocketAddr) {
+        if let Some(task) = self.connection_tasks.remove(&(info_hash.to_string(), *addr)) {
+            task.abort.abort();
+        }
+        let disconnected = self
+            .torrents
+            .get_mut(info_hash)
+            .is_some_and(|torrent| torrent.peer_disconnected(addr));
+        if disconnected {
+            self.emit(ClientEvent::PeerDisconnected {
+                info_hash: info_hash.to_string(),
+                addr: *addr,
+            });
+        }
+    }
+
+    /** Drop every connection of a torrent when it stops */
+    fn close_connections(&mut self, info_hash: &str) {
+        let addrs = self
+            .connection_tasks
+            .keys()
+            .filter(|(key, _)| key == info_hash)
+            .map(|(_, addr)| *addr)
+            .collect::<Vec<_>>();
+        for addr in addrs {
+            self.close_connection(info_hash, &addr);
+        }
+        if let Some(torrent) = self.torrents.get_mut(info_hash) {
+            torrent.connections.close_all();
+        }
+    }
+}
+
+/** Cheap to clone handle to a running session */
+#[derive(Debug, Clone)]
+pub struct ClientHandle {
+    command_tx: mpsc::UnboundedSender<Command>,
+    events: broadcast::Sender<ClientEvent>,
+}
+
+impl ClientHandle {
+    /** Events published after this call, a receiver that falls behind gets `RecvError::Lagged` */
+    pub fn subscribe(&self) -> broadcast::Receiver<ClientEvent> {
+        self.events.subscribe()
+    }
+
+    async fn request<T>(&self, command: impl FnOnce(Reply<T>) -> Command) -> Result<T, ClientError> {
+        let (reply, response) = oneshot::channel();
+        self.command_tx
+            .send(command(reply))
+            .map_err(|_| ClientError::SessionClosed)?;
+        response.await.map_err(|_| ClientError::SessionClosed)?
+    }
+
+    /** Returns once added, data already on disk is checked in the background and announced with `StateChanged` */
+    pub async fn add_torrent(&self, meta_info: MetaInfo) -> Result<String, ClientError> {
+        self.request(|reply| Command::AddTorrent(Box::new(meta_info), reply)).await
+    }
+
+    pub async fn remove_torrent(&self, info_hash: &str, delete_data: bool) -> Result<(), ClientError> {
+        self.request(|reply| Command::RemoveTorrent {
+            info_hash: info_hash.to_string(),
+            delete_data,
+            reply,
+        })
+        .await
+    }
+
+    pub async fn pause(&self, info_hash: &str) -> Result<(), ClientError> {
+        self.request(|reply| Command::Pause(info_hash.to_string(), reply)).await
+    }
+
+    pub async fn resume(&self, info_hash: &str) -> Result<(), ClientError> {
+        self.request(|reply| Command::Resume(info_hash.to_string(), reply)).await
+    }
+
+    /** Returns once the check started, the torrent leaves the checking state when it is done */
+    pub async fn recheck(&self, info_hash: &str) -> Result<(), ClientError> {
+        self.request(|reply| Command::Recheck(info_hash.to_string(), reply)).await
+    }
+
+    /** Hand a downloaded piece to the session, returns false if it failed the hash check and true once it is on disk */
+    pub async fn add_piece(&self, info_hash: &str, index: usize, data: Vec<u8>) -> Result<bool, ClientError> {
+        self.add_blocks(info_hash, index, whole_piece(index, data, None)).await
+    }
+
+    /** A piece put together from blocks of several peers, see `TorrentClient::add_blocks` */
+    pub async fn add_blocks(&self, info_hash: &str, index: usize, blocks: Vec<Block>) -> Result<bool, ClientError> {
+        self.request(|reply| Command::AddPiece {
+            info_hash: info_hash.to_string(),
+            index,
+            blocks,
+            reply,
+        })
+        .await
+    }
+
+    pub async fn set_file_priority(
+        &self,
+        info_hash: &str,
+        file_index: usize,
+        priority: Priority,
+    ) -> Result<(), ClientError> {
+        self.request(|reply| Command::SetFilePriority {
+            info_hash: info_hash.to_string(),
+            file_index,
+            priority,
+            reply,
+        })
+        .await
+    }
+
+    pub async fn add_peer(&self, info_hash: &str, addr: SocketAddr, source: PeerSource) -> Result<bool, ClientError> {
+        self.request(|reply| Command::AddPeer {
+            info_hash: info_hash.to_string(),
+            addr,
+            source,
+            reply,
+        })
+        .await
+    }
+
+    /** 0 is the front of the queue, positions past the end move the torrent to the back */
+    pub async fn set_queue_position(&self, info_hash: &str, position: usize) -> Result<(), ClientError> {
+        self.request(|reply| Command::SetQueuePosition {
+            info_hash: info_hash.to_string(),
+            position,
+            reply,
+        })
+        .await
+    }
+
+    pub async fn set_queue_settings(&self, queue_settings: QueueSettings) -> Result<(), ClientError> {
+        self.request(|reply| Command::SetQueueSettings(queue_settings, reply)).await
+    }
+
+    pub async fn set_connection_settings(&self, connection_settings: ConnectionSettings) -> Result<(), ClientError> {
+        self.request(|reply| Command::SetConnectionSettings(connection_settings, reply))
+            .await
+    }
+
+    /** Listen interfaces are left as they are, they only apply when binding */
+    pub async fn set_network_settings(&self, network_settings: NetworkSettings) -> Result<(), ClientError> {
+        self.request(|reply| Command::SetNetworkSettings(network_settings, reply))
+            .await
+    }
+
+    /** Only new connections go through the new proxy */
+    pub async fn set_proxy(&self, proxy: ProxySettings) -> Result<(), ClientError> {
+        self.request(|reply| Command::SetProxy(proxy, reply)).await
+    }
+
+    /** Connections to peers the new filter blocks are closed */
+    pub async fn set_ip_filter(&self, ip_filter: IpFilter) -> Result<(), ClientError> {
+        self.request(|reply| Command::SetIpFilter(ip_filter, reply)).await
+    }
+
+    pub async fn set_global_rate_limits(&self, upload: Option<u64>, download: Option<u64>) -> Result<(), ClientError> {
+        self.request(|reply| Command::SetRateLimits {
+            info_hash: None,
+            upload,
+            download,
+            reply,
+        })
+        .await
+    }
+
+    pub async fn set_torrent_rate_limits(
+        &self,
+        info_hash: &str,
+        upload: Option<u64>,
+        download: Option<u64>,
+    ) -> Result<(), ClientError> {
+        self.request(|reply| Command::SetRateLimits {
+            info_hash: Some(info_hash.to_string()),
+            upload,
+            download,
+            reply,
+        })
+        .await
+    }
+
+    /** Applies to connections opened afterwards */
+    pub async fn set_peer_rate_limits(&self, upload: Option<u64>, download: Option<u64>) -> Result<(), ClientError> {
+        self.request(|reply| Command::SetPeerRateLimits {
+            upload,
+            download,
+            reply,
+        })
+        .await
+    }
+
+    /** Whether protocol overhead counts against the rate limits */
+    pub async fn set_include_overhead(&self, include_overhead: bool) -> Result<(), ClientError> {
+        self.request(|reply| Command::SetIncludeOverhead(include_overhead, reply)).await
+    }
+
+    pub async fn set_seeding_goals(&self, goals: SeedingGoals) -> Result<(), ClientError> {
+        self.request(|reply| Command::SetSeedingGoals {
+            info_hash: None,
+            goals: Some(goals),
+            reply,
+        })
+        .await
+    }
+
+    /** `None` makes the torrent follow the session goals */
+    pub async fn set_torrent_seeding_goals(&self, info_hash: &str, goals: Option<SeedingGoals>) -> Result<(), ClientError> {
+        self.request(|reply| Command::SetSeedingGoals {
+            info_hash: Some(info_hash.to_string()),
+            goals,
+            reply,
+        })
+        .await
+    }
+
+    pub async fn status(&self, info_hash: &str) -> Result<TorrentStatus, ClientError> {
+        self.request(|reply| Command::Status(info_hash.to_string(), reply)).await
+    }
+
+    pub async fn list(&self) -> Result<Vec<TorrentStatus>, ClientError> {
+        self.request(Command::ListStatus).await
+    }
+
+    pub async fn discoverable_info_hashes(&self, source: PeerSource) -> Result<Vec<String>, ClientError> {
+        self.request(|reply| Command::DiscoverableInfoHashes(source, reply)).await
+    }
+
+    /** Stop the session, pending commands sent afterwards fail with `SessionClosed` */
+    pub async fn shutdown(&self) -> Result<(), ClientError> {
+        self.request(|reply| Command::Shutdown {
+            announce_stopped: false,
+            reply,
+        })
+        .await
+    }
+
+    /** Stop the session once trackers of the running torrents know we're leaving */
+    pub async fn shutdown_gracefully(&self) -> Result<(), ClientError> {
+        self.request(|reply| Command::Shutdown {
+            announce_stopped: true,
+            reply,
+        })
+        .await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use sha1::{Digest, Sha1};
+    use tokio::net::TcpStream;
+
+    use super::*;
+    use crate::mse::{EncryptionPolicy, EncryptionSettings};
+    use crate::proxy::tests::socks5_stand_in;
+    use crate::network::Interface;
+    use crate::proxy::Proxy;
+    use crate::web_seed::tests::serve;
+
+    fn meta_info(name: &str, private: bool) -> MetaInfo {
+        let mut info = format!("4:infod6:lengthi10e4:name{}:{}12:piece lengthi16384e6:pieces20:", name.len(), name).into_bytes();
+        info.extend_from_slice(&[0u8; 20]);
+        if private {
+            info.extend_from_slice(b"7:privatei1e");
+        }
+        let mut buffer = b"d8:announce23:http://tracker/announce".to_vec();
+        buffer.extend(info);
+        buffer.extend_from_slice(b"ee");
+        MetaInfo::from_buffer(&buffer).unwrap()
+    }
+
+    #[test]
+    fn test_private_torrent_discovery() {
+        let mut client = TorrentClient::new();
+        let public = meta_info("public", false);
+        let private = meta_info("private", true);
+        let public_hash = public.to_info_hash();
+        let private_hash = private.to_info_hash();
+        client.add_torrent(public).unwrap();
+        client.add_torrent(private).unwrap();
+
+        for source in [PeerSource::Dht, PeerSource::Pex, PeerSource::Lsd, PeerSource::Magnet] {
+            assert_eq!(client.discoverable_info_hashes(source), vec![public_hash.clone()]);
+        }
+        let mut tracker_hashes = client.discoverable_info_hashes(PeerSource::Tracker);
+        tracker_hashes.sort();
+        let mut all_hashes = vec![public_hash.clone(), private_hash.clone()];
+        all_hashes.sort();
+        assert_eq!(tracker_hashes, all_hashes);
+
+        let addr: SocketAddr = "192.168.1.2:6881".parse().unwrap();
+        for source in [PeerSource::Dht, PeerSource::Pex, PeerSource::Lsd, PeerSource::Magnet] {
+            assert!(!client.add_peer(&private_hash, addr, source));
+        }
+        assert!(client.torrents[&private_hash].connections.is_empty());
+        assert!(client.add_peer(&private_hash, addr, PeerSource::Tracker));
+        assert_eq!(client.torrents[&private_hash].connections.len(), 1);
+
+        assert!(client.add_peer(&public_hash, addr, PeerSource::Lsd));
+        assert!(!client.add_peer(&public_hash, addr, PeerSource::Dht));
+        assert!(!client.add_peer("unknown", addr, PeerSource::Tracker));
+
+        let mut v2_only = meta_info("v2", false);
+        v2_only.info.meta_version = Some(2);
+        v2_only.info.pieces = Default::default();
+        assert!(matches!(client.add_torrent(v2_only), Err(ClientError::UnsupportedV2(_))));
+        assert_eq!(client.torrents.len(), 2);
+    }
+
+    #[tokio::test]
+    async fn test_session_commands() {
+        let download_dir = std::env::temp_dir().join(format!("riffle-session-{}", std::process::id()));
+        let handle = TorrentClient::with_download_dir(&download_dir).spawn();
+        let public = meta_info("public", false);
+        let info_hash = public.to_info_hash();
+
+        assert_eq!(handle.add_torrent(public.clone()).await, Ok(info_hash.clone()));
+        assert_eq!(
+            handle.add_torrent(public).await,
+            Err(ClientError::DuplicateTorrent(info_hash.clone()))
+        );
+        assert_eq!(
+            handle.pause("unknown").await,
+            Err(ClientError::UnknownTorrent("unknown".to_string()))
+        );
+
+        handle.pause(&info_hash).await.unwrap();
+        assert_eq!(handle.status(&info_hash).await.unwrap().state, TorrentState::Paused);
+        handle.resume(&info_hash).await.unwrap();
+        assert_eq!(handle.status(&info_hash).await.unwrap().state, TorrentState::Downloading);
+
+        handle.set_file_priority(&info_hash, 0, Priority::Skip).await.unwrap();
+        assert_eq!(handle.status(&info_hash).await.unwrap().left, 0);
+        assert!(matches!(
+            handle.set_file_priority(&info_hash, 1, Priority::High).await,
+            Err(ClientError::UnknownFile { file_index: 1, .. })
+        ));
+
+        handle.recheck(&info_hash).await.unwrap();
+        assert_eq!(handle.status(&info_hash).await.unwrap().downloaded_pieces(), 0);
+
+        handle.set_torrent_rate_limits(&info_hash, Some(1024), None).await.unwrap();
+        let status = handle.status(&info_hash).await.unwrap();
+        assert_eq!((status.upload_limit, status.download_limit), (Some(1024), None));
+        assert!(handle.set_global_rate_limits(Some(4096), Some(4096)).await.is_ok());
+        assert!(handle.set_include_overhead(true).await.is_ok());
+
+        let addr: SocketAddr = "192.168.1.2:6881".parse().unwrap();
+        assert_eq!(handle.add_peer(&info_hash, addr, PeerSource::Lsd).await, Ok(true));
+        assert_eq!(handle.list().await.unwrap().len(), 1);
+
+        handle.remove_torrent(&info_hash, true).await.unwrap();
+        assert!(handle.list().await.unwrap().is_empty());
+        assert_eq!(
+            handle.remove_torrent(&info_hash, false).await,
+            Err(ClientError::UnknownTorrent(info_hash.clone()))
+        );
+
+        handle.shutdown().await.unwrap();
+        assert!(matches!(handle.list().await, Err(ClientError::SessionClosed)));
+    }
+
+    #[tokio::test]
+    async fn test_session_events() {
+        let download_dir = std::env::temp_dir().join(format!("riffle-events-{}", std::process::id()));
+        let handle = TorrentClient::with_download_dir(&download_dir).spawn();
+        let mut events = handle.subscribe();
+        // Never read, it must not hold the session back
+        let _slow = handle.subscribe();
+
+        let data = b"0123456789".to_vec();
+        let mut buffer = b"d4:infod6:lengthi10e4:name6:events12:piece lengthi16384e6:pieces20:".to_vec();
+        buffer.extend(Sha1::digest(&data));
+        buffer.extend_from_slice(b"ee");
+        let info_hash = handle.add_torrent(MetaInfo::from_buffer(&buffer).unwrap()).await.unwrap();
+        assert_eq!(
+            events.recv().await.unwrap(),
+            ClientEvent::TorrentAdded {
+                info_hash: info_hash.clone()
+            }
+        );
+        assert_eq!(
+            events.recv().await.unwrap(),
+            ClientEvent::StateChanged {
+                info_hash: info_hash.clone(),
+                state: TorrentState::Downloading
+            }
+        );
+
+        assert_eq!(handle.add_piece(&info_hash, 0, b"corrupted!".to_vec()).await, Ok(false));
+        assert_eq!(
+            events.recv().await.unwrap(),
+            ClientEvent::HashFailed {
+                info_hash: info_hash.clone(),
+                index: 0
+            }
+        );
+        assert!(matches!(
+            handle.add_piece(&info_hash, 1, data.clone()).await,
+            Err(ClientError::InvalidPiece { index: 1, .. })
+        ));
+
+        assert_eq!(handle.add_piece(&info_hash, 0, data.clone()).await, Ok(true));
+        assert_eq!(
+            events.recv().await.unwrap(),
+            ClientEvent::PieceFinished {
+                info_hash: info_hash.clone(),
+                index: 0
+            }
+        );
+        assert_eq!(
+            events.recv().await.unwrap(),
+            ClientEvent::TorrentCompleted {
+                info_hash: info_hash.clone()
+            }
+        );
+        assert_eq!(
+            events.recv().await.unwrap(),
+            ClientEvent::StateChanged {
+                info_hash: info_hash.clone(),
+                state: TorrentState::Seeding
+            }
+        );
+        assert_eq!(fs::read(download_dir.join("events")).unwrap(), data);
+
+        for _ in 0..EVENT_CHANNEL_CAPACITY {
+            handle.add_piece(&info_hash, 0, vec![0; 10]).await.unwrap();
+        }
+
+        handle.remove_torrent(&info_hash, true).await.unwrap();
+        assert!(!download_dir.join("events").exists());
+        handle.shutdown().await.unwrap();
+        fs::remove_dir(download_dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_queueing() {
+        let download_dir = std::env::temp_dir().join(format!("riffle-queue-{}", std::process::id()));
+        let handle = TorrentClient::with_download_dir(&download_dir).spawn();
+        handle
+            .set_queue_settings(QueueSettings {
+                active_downloads: 2,
+                active_seeds: 1,
+                active_limit: 2,
+                slow_rate_threshold: 1,
+                slow_grace_period: Duration::from_secs(3600),
+            })
+            .await
+            .unwrap();
+        let mut info_hashes = vec![];
+        for name in ["first", "second", "third"] {
+            info_hashes.push(handle.add_torrent(meta_info(name, false)).await.unwrap());
+        }
+        let states = |statuses: Vec<TorrentStatus>| {
+            statuses
+                .into_iter()
+                .map(|status| (status.info_hash, status.state))
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(
+            states(handle.list().await.unwrap()),
+            vec![
+                (info_hashes[0].clone(), TorrentState::Downloading),
+                (info_hashes[1].clone(), TorrentState::Downloading),
+                (info_hashes[2].clone(), TorrentState::Queued),
+            ]
+        );
+
+        handle.set_queue_position(&info_hashes[2], 0).await.unwrap();
+        assert_eq!(
+            states(handle.list().await.unwrap()),
+            vec![
+                (info_hashes[2].clone(), TorrentState::Downloading),
+                (info_hashes[0].clone(), TorrentState::Downloading),
+                (info_hashes[1].clone(), TorrentState::Queued),
+            ]
+        );
+        assert_eq!(handle.status(&info_hashes[1]).await.unwrap().queue_position, 2);
+
+        // Paused torrents free their slot
+        handle.pause(&info_hashes[2]).await.unwrap();
+        assert_eq!(handle.status(&info_hashes[1]).await.unwrap().state, TorrentState::Downloading);
+        handle.resume(&info_hashes[2]).await.unwrap();
+        assert_eq!(handle.status(&info_hashes[1]).await.unwrap().state, TorrentState::Queued);
+
+        // Stalled torrents keep running but make room for the next ones
+        handle
+            .set_queue_settings(QueueSettings {
+                active_downloads: 1,
+                active_seeds: 1,
+                active_limit: 1,
+                slow_rate_threshold: 1,
+                slow_grace_period: Duration::ZERO,
+            })
+            .await
+            .unwrap();
+        let statuses = handle.list().await.unwrap();
+        assert!(statuses.iter().all(|status| status.state == TorrentState::Downloading));
+
+        handle.remove_torrent(&info_hashes[2], false).await.unwrap();
+        assert_eq!(handle.status(&info_hashes[1]).await.unwrap().queue_position, 1);
+        handle.shutdown().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_seeding_goals() {
+        let download_dir = std::env::temp_dir().join(format!("riffle-goals-{}", std::process::id()));
+        let handle = TorrentClient::with_download_dir(&download_dir).spawn();
+        let mut events = handle.subscribe();
+        handle
+            .set_seeding_goals(SeedingGoals {
+                seeding_time: Some(Duration::from_secs(3600)),
+                ..Default::default()
+            })
+            .await
+            .unwrap();
+
+        let data = b"0123456789".to_vec();
+        let mut buffer = b"d4:infod6:lengthi10e4:name5:goals12:piece lengthi16384e6:pieces20:".to_vec();
+        buffer.extend(Sha1::digest(&data));
+        buffer.extend_from_slice(b"ee");
+        let info_hash = handle.add_torrent(MetaInfo::from_buffer(&buffer).unwrap()).await.unwrap();
+        handle
+            .set_torrent_seeding_goals(
+                &info_hash,
+                Some(SeedingGoals {
+                    seeding_time: Some(Duration::ZERO),
+                    action: GoalAction::RemoveWithData,
+                    ..Default::default()
+                }),
+            )
+            .await
+            .unwrap();
+        handle.add_piece(&info_hash, 0, data).await.unwrap();
+
+        let reached = tokio::time::timeout(Duration::from_secs(5), async {
+            loop {
+                if let ClientEvent::SeedingGoalReached { goal, action, .. } = events.recv().await.unwrap() {
+                    return (goal, action);
+                }
+            }
+        })
+        .await
+        .unwrap();
+        assert_eq!(reached, (SeedingGoal::SeedingTime, GoalAction::RemoveWithData));
+        assert!(handle.list().await.unwrap().is_empty());
+        assert!(!download_dir.join("goals").exists());
+        handle.shutdown().await.unwrap();
+        let _ = fs::remove_dir_all(download_dir);
+    }
+
+    #[tokio::test]
+    async fn test_peer_connections() {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+        let download_dir = std::env::temp_dir().join(format!("riffle-connections-{}", std::process::id()));
+        let mut client = TorrentClient::with_download_dir(&download_dir);
+        let listen_addr = client.bind("127.0.0.1:0".parse().unwrap()).unwrap();
+        let handle = client.spawn();
+        handle
+            .set_connection_settings(ConnectionSettings {
+                max_hash_failures: 1,
+                // The remote peer below only speaks plaintext over TCP
+                encryption: EncryptionSettings {
+                    outgoing: EncryptionPolicy::Disabled,
+                    ..Default::default()
+                },
+                utp: false,
+                ..Default::default()
+            })
+            .await
+            .unwrap();
+        let mut events = handle.subscribe();
+        let meta_info = meta_info("peers", false);
+        let info_hash = handle.add_torrent(meta_info.clone()).await.unwrap();
+        let hash_buffer: [u8; 20] = meta_info.info.to_hash_buffer().unwrap().try_into().unwrap();
+        let mut next_peer_event = async || loop {
+            match tokio::time::timeout(Duration::from_secs(5), events.recv()).await.unwrap().unwrap() {
+                event @ (ClientEvent::PeerConnected { .. }
+                | ClientEvent::PeerDisconnected { .. }
+                | ClientEvent::PeerBanned { .. }) => return event,
+                _ => continue,
+            }
+        };
+
+        // Outgoing: the remote peer answers the handshake and announces its pieces
+        let remote = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let remote_addr = remote.local_addr().unwrap();
+        assert_eq!(handle.add_peer(&info_hash, remote_addr, PeerSource::Dht).await, Ok(true));
+        let (mut stream, _) = remote.accept().await.unwrap();
+        let mut buffer = [0u8; crate::peer::HANDSHAKE_LENGTH];
+        stream.read_exact(&mut buffer).await.unwrap();
+        assert_eq!(Handshake::from_buffer(&buffer).unwrap().info_hash(), hash_buffer);
+        stream.write_all(&Handshake::new(hash_buffer, [1; 20]).to_buffer()).await.unwrap();
+        stream.write_all(&[0, 0, 0, 2, 5, 0x80]).await.unwrap();
+        assert_eq!(
+            next_peer_event().await,
+            ClientEvent::PeerConnected {
+                info_hash: info_hash.clone(),
+                addr: remote_addr
+            }
+        );
+        assert_eq!(handle.status(&info_hash).await.unwrap().peers_count, 1);
+        // Blocks are counted as they arrive, before any piece is checked
+        stream.write_all(&[0, 0, 0, 13, 7, 0, 0, 0, 0, 0, 0, 0, 0, 1, 2, 3, 4]).await.unwrap();
+        tokio::time::timeout(Duration::from_secs(5), async {
+            while handle.status(&info_hash).await.unwrap().downloaded != 4 {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .unwrap();
+        drop(stream);
+        assert_eq!(
+            next_peer_event().await,
+            ClientEvent::PeerDisconnected {
+                info_hash: info_hash.clone(),
+                addr: remote_addr
+            }
+        );
+        assert_eq!(handle.status(&info_hash).await.unwrap().peers_count, 0);
+
+        // Incoming: unknown torrents are dropped, ours get an answer
+        let mut stranger = TcpStream::connect(listen_addr).await.unwrap();
+        stranger.write_all(&Handshake::new([9; 20], [2; 20]).to_buffer()).await.unwrap();
+        assert_eq!(stranger.read(&mut buffer).await.unwrap(), 0);
+        let mut incoming = TcpStream::connect(listen_addr).await.unwrap();
+        let incoming_addr = incoming.local_addr().unwrap();
+        incoming.write_all(&Handshake::new(hash_buffer, [3; 20]).to_buffer()).await.unwrap();
+        incoming.read_exact(&mut buffer).await.unwrap();
+        assert_eq!(Handshake::from_buffer(&buffer).unwrap().info_hash(), hash_buffer);
+        assert_eq!(
+            next_peer_event().await,
+            ClientEvent::PeerConnected {
+                info_hash: info_hash.clone(),
+                addr: incoming_addr
+            }
+        );
+
+        // Bad data gets the peer banned and disconnected
+        serve_piece(&mut incoming, &[0; 10]).await;
+        assert!(matches!(next_peer_event().await, ClientEvent::PeerBanned { addr, .. } if addr == incoming_addr));
+        assert!(matches!(next_peer_event().await, ClientEvent::PeerDisconnected { addr, .. } if addr == incoming_addr));
+        assert_eq!(incoming.read(&mut buffer).await.unwrap(), 0);
+
+        handle.remove_torrent(&info_hash, true).await.unwrap();
+        handle.shutdown().await.unwrap();
+        let _ = fs::remove_dir_all(download_dir);
+    }
+
+    #[tokio::test]
+    async fn test_ip_filter() {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+        let download_dir = std::env::temp_dir().join(format!("riffle-ip-filter-{}", std::process::id()));
+        let mut ip_filter = IpFilter::new();
+        ip_filter.add_rules("Test network:192.168.0.0-192.168.255.255");
+        let mut client = TorrentClient::with_download_dir(&download_dir).with_ip_filter(ip_filter.clone());
+        let listen_addr = client.bind("127.0.0.1:0".parse().unwrap()).unwrap();
+        let handle = client.spawn();
+        let meta_info = meta_info("filtered", false);
+        let info_hash = handle.add_torrent(meta_info.clone()).await.unwrap();
+        let hash_buffer: [u8; 20] = meta_info.info.to_hash_buffer().unwrap().try_into().unwrap();
+
+        let blocked: SocketAddr = "192.168.1.2:6881".parse().unwrap();
+        assert_eq!(handle.add_peer(&info_hash, blocked, PeerSource::Dht).await, Ok(false));
+        assert_eq!(handle.status(&info_hash).await.unwrap().filtered_peers, 1);
+
+        // Blocking loopback closes the connection right after the handshake
+        ip_filter.add_rules("127.0.0.0/8");
+        handle.set_ip_filter(ip_filter).await.unwrap();
+        let mut incoming = TcpStream::connect(listen_addr).await.unwrap();
+        incoming.write_all(&Handshake::new(hash_buffer, [3; 20]).to_buffer()).await.unwrap();
+        let mut buffer = [0u8; crate::peer::HANDSHAKE_LENGTH];
+        assert_eq!(incoming.read(&mut buffer).await.unwrap(), 0);
+        let status = handle.status(&info_hash).await.unwrap();
+        assert_eq!((status.filtered_peers, status.peers_count), (2, 0));
+
+        handle.shutdown().await.unwrap();
+        let _ = fs::remove_dir_all(download_dir);
+    }
+
+    #[tokio::test]
+    async fn test_smart_ban() {
+        let download_dir = std::env::temp_dir().join(format!("riffle-smart-ban-{}", std::process::id()));
+        let mut client = TorrentClient::with_download_dir(&download_dir);
+        let mut events = client.subscribe();
+        let data = b"0123456789".to_vec();
+        let mut buffer = b"d4:infod6:lengthi10e4:name5:smart12:piece lengthi16384e6:pieces20:".to_vec();
+        buffer.extend(Sha1::digest(&data));
+        buffer.extend_from_slice(b"ee");
+        let info_hash = client.add_torrent(MetaInfo::from_buffer(&buffer).unwrap()).unwrap();
+        let honest: SocketAddr = "10.0.0.1:6881".parse().unwrap();
+        let liar: SocketAddr = "10.0.0.2:6881".parse().unwrap();
+        client.add_peer(&info_hash, honest, PeerSource::Dht);
+        client.add_peer(&info_hash, liar, PeerSource::Dht);
+        let blocks = |second: &[u8], peer: SocketAddr| {
+            vec![
+                Block {
+                    piece_index: 0,
+                    begin: 5,
+                    length: 5,
+                    data: second.to_vec(),
+                    peer: Some(peer),
+                },
+                Block {
+                    piece_index: 0,
+                    begin: 0,
+                    length: 5,
+                    data: data[..5].to_vec(),
+                    peer: Some(honest),
+                },
+            ]
+        };
+
+        // A single failure isn't enough to tell who sent the bad block
+        client.torrents.get_mut(&info_hash).unwrap().picker.set_downloading(0, true);
+        assert_eq!(client.add_blocks(&info_hash, 0, blocks(b"5X789", liar)), Ok(false));
+        // The piece is up for download again
+        assert_eq!(client.torrents.get_mut(&info_hash).unwrap().pick_web_seed_piece(), Some(0));
+        assert!(client.torrents[&info_hash].status().banned_peers.is_empty());
+        assert!(matches!(
+            client.add_blocks(&info_hash, 0, blocks(b"567", honest)),
+            Err(ClientError::InvalidPiece { index: 0, .. })
+        ));
+
+        // Once the piece passes, the block the liar sent doesn't match
+        assert_eq!(client.add_blocks(&info_hash, 0, blocks(b"56789", honest)), Ok(true));
+        assert_eq!(client.torrents[&info_hash].status().banned_peers, vec![liar]);
+        let banned = std::iter::from_fn(|| events.try_recv().ok())
+            .find_map(|event| match event {
+                ClientEvent::PeerBanned { addr, .. } => Some(addr),
+                _ => None,
+            });
+        assert_eq!(banned, Some(liar));
+
+        client.remove_torrent(&info_hash, true).await.unwrap();
+        let _ = fs::remove_dir_all(download_dir);
+    }
+
+    /** Play a peer having the single piece of a torrent: answer the first request for it with `data`, then choke */
+    async fn serve_piece(stream: &mut TcpStream, data: &[u8]) {
+        use tokio::io::AsyncWriteExt;
+
+        stream.write_all(&[0, 0, 0, 2, 5, 0x80, 0, 0, 0, 1, 1]).await.unwrap();
+        let bandwidth = Bandwidth::unlimited();
+        loop {
+            let message = crate::peer::read_message(stream, &bandwidth).await.unwrap();
+            if message.first() == Some(&(crate::peer::MessageId::Request as u8)) {
+                let mut piece = ((9 + data.len()) as u32).to_be_bytes().to_vec();
+                piece.push(crate::peer::MessageId::Piece as u8);
+                piece.extend_from_slice(&message[1..9]);
+                piece.extend_from_slice(data);
+                piece.extend_from_slice(&[0, 0, 0, 1, 0]);
+                stream.write_all(&piece).await.unwrap();
+                return;
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn test_smart_ban_peers() {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+        let download_dir = std::env::temp_dir().join(format!("riffle-smart-ban-peers-{}", std::process::id()));
+        let handle = TorrentClient::with_download_dir(&download_dir).spawn();
+        handle
+            .set_connection_settings(ConnectionSettings {
+                // Only the smart ban gets the liar banned
+                max_hash_failures: 10,
+                encryption: EncryptionSettings {
+                    outgoing: EncryptionPolicy::Disabled,
+                    ..Default::default()
+                },
+                utp: false,
+                ..Default::default()
+            })
+            .await
+            .unwrap();
+        let mut events = handle.subscribe();
+        let data = b"0123456789".to_vec();
+        let mut buffer = b"d4:infod6:lengthi10e4:name11:smart-peers12:piece lengthi16384e6:pieces20:".to_vec();
+        buffer.extend(Sha1::digest(&data));
+        buffer.extend_from_slice(b"ee");
+        let meta_info = MetaInfo::from_buffer(&buffer).unwrap();
+        let hash_buffer: [u8; 20] = meta_info.info.to_hash_buffer().unwrap().try_into().unwrap();
+        let info_hash = handle.add_torrent(meta_info).await.unwrap();
+        let mut next_event = async |matches: &dyn Fn(&ClientEvent) -> bool| loop {
+            let event = tokio::time::timeout(Duration::from_secs(5), events.recv()).await.unwrap().unwrap();
+            if matches(&event) {
+                return event;
+            }
+        };
+        let connect = async |data: &[u8]| {
+            let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let addr = listener.local_addr().unwrap();
+            assert_eq!(handle.add_peer(&info_hash, addr, PeerSource::Tracker).await, Ok(true));
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let mut handshake = [0u8; crate::peer::HANDSHAKE_LENGTH];
+            stream.read_exact(&mut handshake).await.unwrap();
+            stream.write_all(&Handshake::new(hash_buffer, [1; 20]).to_buffer()).await.unwrap();
+            serve_piece(&mut stream, data).await;
+            (addr, stream)
+        };
+
+        // The piece is requested from the liar, who sends a corrupt block
+        let (liar, _liar_stream) = connect(b"01234X6789").await;
+        next_event(&|event| matches!(event, ClientEvent::HashFailed { index: 0, .. })).await;
+        assert!(handle.status(&info_hash).await.unwrap().banned_peers.is_empty());
+
+        // Once the honest peer sent the piece, the block of the liar is known to be bad
+        let (_, _honest_stream) = connect(&data).await;
+        let banned = next_event(&|event| matches!(event, ClientEvent::PeerBanned { .. })).await;
+        assert_eq!(banned, ClientEvent::PeerBanned { info_hash: info_hash.clone(), addr: liar });
+        next_event(&|event| matches!(event, ClientEvent::TorrentCompleted { .. })).await;
+        let status = handle.status(&info_hash).await.unwrap();
+        assert_eq!(status.banned_peers, vec![liar]);
+        // Both pieces went over the wire, the one written isn't counted again
+        assert_eq!(status.downloaded, 20);
+
+        handle.remove_torrent(&info_hash, true).await.unwrap();
+        handle.shutdown().await.unwrap();
+        let _ = fs::remove_dir_all(download_dir);
+    }
+
+    #[tokio::test]
+    async fn test_encrypted_connections() {
+        let root = std::env::temp_dir().join(format!("riffle-encryption-{}", std::process::id()));
+        let meta_info = meta_info("encrypted", false);
+        let session = async |name: &str, incoming: EncryptionPolicy, outgoing: EncryptionPolicy| {
+            let mut client = TorrentClient::with_download_dir(root.join(name));
+            client.connection_settings.encryption = EncryptionSettings { incoming, outgoing };
+            let listen_addr = client.bind("127.0.0.1:0".parse().unwrap()).unwrap();
+            let handle = client.spawn();
+            let events = handle.subscribe();
+            let info_hash = handle.add_torrent(meta_info.clone()).await.unwrap();
+            (handle, events, info_hash, listen_addr)
+        };
+        let connected = async |events: &mut broadcast::Receiver<ClientEvent>| loop {
+            match tokio::time::timeout(Duration::from_secs(5), events.recv()).await.unwrap().unwrap() {
+                ClientEvent::PeerConnected { addr, .. } => return addr,
+                _ => continue,
+            }
+        };
+
+        // Both sessions refuse plaintext
+        let (seed, mut seed_events, info_hash, seed_addr) =
+            session("seed", EncryptionPolicy::Forced, EncryptionPolicy::Forced).await;
+        let (leech, mut leech_events, _, _) = session("leech", EncryptionPolicy::Forced, EncryptionPolicy::Forced).await;
+        assert_eq!(leech.add_peer(&info_hash, seed_addr, PeerSource::Dht).await, Ok(true));
+        assert_eq!(connected(&mut leech_events).await, seed_addr);
+        assert_eq!(connected(&mut seed_events).await.ip(), seed_addr.ip());
+
+        // Falling back to plaintext for a session that doesn't encrypt
+        let (plain, mut plain_events, _, plain_addr) =
+            session("plain", EncryptionPolicy::Disabled, EncryptionPolicy::Disabled).await;
+        let (fallback, mut fallback_events, _, _) =
+            session("fallback", EncryptionPolicy::Enabled, EncryptionPolicy::Enabled).await;
+        assert_eq!(fallback.add_peer(&info_hash, plain_addr, PeerSource::Dht).await, Ok(true));
+        assert_eq!(connected(&mut fallback_events).await, plain_addr);
+        assert_eq!(connected(&mut plain_events).await.ip(), plain_addr.ip());
+
+        for handle in [seed, leech, plain, fallback] {
+            handle.shutdown().await.unwrap();
+        }
+        let _ = fs::remove_dir_all(root);
+    }
+
+    #[tokio::test]
+    async fn test_utp_connections() {
+        let root = std::env::temp_dir().join(format!("riffle-utp-{}", std::process::id()));
+        let meta_info = meta_info("utp", false);
+        let session = async |name: &str, utp: bool| {
+            let mut client = TorrentClient::with_download_dir(root.join(name));
+            client.connection_settings.utp = utp;
+            let listen_addr = client.bind("127.0.0.1:0".parse().unwrap()).unwrap();
+            let handle = client.spawn();
+            let events = handle.subscribe();
+            let info_hash = handle.add_torrent(meta_info.clone()).await.unwrap();
+            (handle, events, info_hash, listen_addr)
+        };
+        let connected = async |events: &mut broadcast::Receiver<ClientEvent>| loop {
+            match tokio::time::timeout(Duration::from_secs(5), events.recv()).await.unwrap().unwrap() {
+                ClientEvent::PeerConnected { addr, .. } => return addr,
+                _ => continue,
+            }
+        };
+
+        // uTP connections come from the shared listen port, TCP ones from an ephemeral port
+        let (seed, mut seed_events, info_hash, seed_addr) = session("seed", true).await;
+        let (utp_leech, mut utp_events, _, utp_addr) = session("utp", true).await;
+        let (tcp_leech, mut tcp_events, _, tcp_addr) = session("tcp", false).await;
+        assert_eq!(utp_leech.add_peer(&info_hash, seed_addr, PeerSource::Dht).await, Ok(true));
+        assert_eq!(connected(&mut utp_events).await, seed_addr);
+        assert_eq!(connected(&mut seed_events).await, utp_addr);
+        assert_eq!(tcp_leech.add_peer(&info_hash, seed_addr, PeerSource::Dht).await, Ok(true));
+        assert_eq!(connected(&mut tcp_events).await, seed_addr);
+        assert_ne!(connected(&mut seed_events).await, tcp_addr);
+
+        // Closing the uTP connection from one side is seen by the other
+        utp_leech.shutdown().await.unwrap();
+        let disconnected = loop {
+            match tokio::time::timeout(Duration::from_secs(5), seed_events.recv()).await.unwrap().unwrap() {
+                ClientEvent::PeerDisconnected { addr, .. } => break addr,
+                _ => continue,
+            }
+        };
+        assert_eq!(disconnected, utp_addr);
+
+        for handle in [seed, tcp_leech] {
+            handle.shutdown().await.unwrap();
+        }
+        let _ = fs::remove_dir_all(root);
+    }
+
+    #[tokio::test]
+    async fn test_proxied_connections() {
+        use tokio::io::AsyncReadExt;
+
+        let root = std::env::temp_dir().join(format!("riffle-proxy-{}", std::process::id()));
+        let meta_info = meta_info("proxy", false);
+        let (proxy_addr, targets) = socks5_stand_in(Some(("user", "secret"))).await;
+        let proxy = Proxy::parse(&format!("socks5://user:secret@{}", proxy_addr)).unwrap();
+        let session = async |name: &str, proxy: ProxySettings| {
+            let mut client = TorrentClient::with_download_dir(root.join(name));
+            client.proxy = proxy;
+            let listen_addr = client.bind("127.0.0.1:0".parse().unwrap()).unwrap();
+            let handle = client.spawn();
+            let events = handle.subscribe();
+            let info_hash = handle.add_torrent(meta_info.clone()).await.unwrap();
+            (handle, events, info_hash, listen_addr)
+        };
+        let connected = async |events: &mut broadcast::Receiver<ClientEvent>| loop {
+            match tokio::time::timeout(Duration::from_secs(5), events.recv()).await.unwrap().unwrap() {
+                ClientEvent::PeerConnected { addr, .. } => return addr,
+                _ => continue,
+            }
+        };
+
+        // Outgoing connections go through the proxy over TCP even with uTP enabled
+        let forced = ProxySettings {
+            proxy: Some(proxy),
+            force: true,
+        };
+        let (seed, mut seed_events, info_hash, seed_addr) = session("seed", ProxySettings::default()).await;
+        let (leech, mut leech_events, _, leech_addr) = session("leech", forced).await;
+        assert_eq!(leech.add_peer(&info_hash, seed_addr, PeerSource::Dht).await, Ok(true));
+        assert_eq!(connected(&mut leech_events).await, seed_addr);
+        assert_ne!(connected(&mut seed_events).await, leech_addr);
+        assert_eq!(*targets.lock().unwrap(), vec![seed_addr]);
+
+        // Direct connections to a session forcing its proxy are refused
+        assert_eq!(seed.add_peer(&info_hash, leech_addr, PeerSource::Dht).await, Ok(true));
+        let incoming = tokio::time::timeout(Duration::from_secs(1), connected(&mut leech_events)).await;
+        assert!(incoming.is_err());
+        // Listeners close connections without a handshake and LAN discovery is off, both would reveal our address
+        let mut stranger = TcpStream::connect(leech_addr).await.unwrap();
+        let mut buffer = [0u8; 1];
+        let closed = tokio::time::timeout(Duration::from_secs(1), stranger.read(&mut buffer)).await.unwrap();
+        assert!(matches!(closed, Ok(0) | Err(_)));
+        assert_eq!(leech.discoverable_info_hashes(PeerSource::Lsd).await, Ok(vec![]));
+        let lan_peer = "192.168.1.9:6881".parse().unwrap();
+        assert_eq!(leech.add_peer(&info_hash, lan_peer, PeerSource::Lsd).await, Ok(false));
+        assert_eq!(seed.add_peer(&info_hash, lan_peer, PeerSource::Lsd).await, Ok(true));
+
+        let no_proxy = ProxySettings {
+            proxy: None,
+            force: true,
+        };
+        assert!(matches!(leech.set_proxy(no_proxy).await, Err(ClientError::InvalidProxy(_))));
+
+        for handle in [seed, leech] {
+            handle.shutdown().await.unwrap();
+        }
+        let _ = fs::remove_dir_all(root);
+    }
+
+    #[tokio::test]
+    async fn test_graceful_shutdown() {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+        // Tracker stand-in passing on the request lines it gets
+        let tracker = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let tracker_url = format!("http://{}/announce", tracker.local_addr().unwrap());
+        let (requests_tx, mut requests) = mpsc::unbounded_channel();
+        tokio::spawn(async move {
+            loop {
+                let (mut stream, _) = tracker.accept().await.unwrap();
+                let mut buffer = vec![0u8; 4096];
+                let length = stream.read(&mut buffer).await.unwrap();
+                let request = String::from_utf8_lossy(&buffer[..length]).to_string();
+                let _ = requests_tx.send(request.lines().next().unwrap_or_default().to_string());
+                let body = "d5:peers0:e";
+                let response = format!("HTTP/1.1 200 OK\r\nContent-Length: {}\r\n\r\n{}", body.len(), body);
+                let _ = stream.write_all(response.as_bytes()).await;
+            }
+        });
+
+        let root = std::env::temp_dir().join(format!("riffle-graceful-{}", std::process::id()));
+        let (download_dir, resume_dir) = (root.join("downloads"), root.join("resume"));
+        let mut buffer = format!("d8:announce{}:{}", tracker_url.len(), tracker_url).into_bytes();
+        buffer.extend_from_slice(b"4:infod6:lengthi10e4:name8:graceful12:piece lengthi16384e6:pieces20:");
+        buffer.extend(Sha1::digest(b"0123456789"));
+        buffer.extend_from_slice(b"ee");
+        let meta_info = MetaInfo::from_buffer(&buffer).unwrap();
+        assert_eq!(meta_info.announce_urls(), vec![tracker_url.clone()]);
+
+        let handle = TorrentClient::with_download_dir(&download_dir)
+            .with_resume_dir(&resume_dir)
+            .spawn();
+        let mut events = handle.subscribe();
+        let info_hash = handle.add_torrent(meta_info).await.unwrap();
+        loop {
+            if let ClientEvent::StateChanged { state, .. } = events.recv().await.unwrap() {
+                if state == TorrentState::Downloading {
+                    break;
+                }
+            }
+        }
+        let request = requests.recv().await.unwrap();
+        assert!(request.contains("&event=started"), "{}", request);
+        handle.shutdown_gracefully().await.unwrap();
+
+        let request = requests.recv().await.unwrap();
+        assert!(request.starts_with("GET /announce?"), "{}", request);
+        assert!(request.contains("&event=stopped"), "{}", request);
+        assert!(ResumeData::path(&resume_dir, &info_hash).exists());
+        assert_eq!(handle.shutdown().await, Err(ClientError::SessionClosed));
+        let _ = fs::remove_dir_all(root);
+    }
+
+    #[tokio::test]
+    async fn test_tracker_announces() {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+        // The only peer the tracker knows about
+        let peer = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let mut compact_peer = vec![127, 0, 0, 1];
+        compact_peer.extend(peer.local_addr().unwrap().port().to_be_bytes());
+
+        let tracker = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let tracker_url = format!("http://{}/announce", tracker.local_addr().unwrap());
+        let (requests_tx, mut requests) = mpsc::unbounded_channel();
+        tokio::spawn(async move {
+            loop {
+                let (mut stream, _) = tracker.accept().await.unwrap();
+                let mut buffer = vec![0u8; 4096];
+                let length = stream.read(&mut buffer).await.unwrap();
+                let request = String::from_utf8_lossy(&buffer[..length]).to_string();
+                let _ = requests_tx.send(request.lines().next().unwrap_or_default().to_string());
+                let mut body = b"d8:completei0e10:incompletei1e8:intervali1800e5:peers6:".to_vec();
+                body.extend(&compact_peer);
+                body.push(b'e');
+                let mut response = format!("HTTP/1.1 200 OK\r\nContent-Length: {}\r\n\r\n", body.len()).into_bytes();
+                response.extend(body);
+                let _ = stream.write_all(&response).await;
+            }
+        });
+
+        let download_dir = std::env::temp_dir().join(format!("riffle-announces-{}", std::process::id()));
+        let data = b"0123456789".to_vec();
+        let mut buffer = format!("d8:announce{}:{}", tracker_url.len(), tracker_url).into_bytes();
+        buffer.extend_from_slice(b"4:infod6:lengthi10e4:name9:announces12:piece lengthi16384e6:pieces20:");
+        buffer.extend(Sha1::digest(&data));
+        buffer.extend_from_slice(b"ee");
+        let handle = TorrentClient::with_download_dir(&download_dir).spawn();
+        let info_hash = handle.add_torrent(MetaInfo::from_buffer(&buffer).unwrap()).await.unwrap();
+
+        let request = requests.recv().await.unwrap();
+        assert!(request.contains("&left=10&compact=1&event=started"), "{}", request);
+        let connected = tokio::time::timeout(Duration::from_secs(5), peer.accept()).await;
+        assert!(connected.unwrap().is_ok());
+
+        assert_eq!(handle.add_piece(&info_hash, 0, data).await, Ok(true));
+        let request = requests.recv().await.unwrap();
+        assert!(request.contains("&left=0&compact=1&event=completed"), "{}", request);
+
+        handle.pause(&info_hash).await.unwrap();
+        let request = requests.recv().await.unwrap();
+        assert!(request.contains("&event=stopped"), "{}", request);
+
+        handle.remove_torrent(&info_hash, true).await.unwrap();
+        handle.shutdown().await.unwrap();
+        let _ = fs::remove_dir_all(download_dir);
+    }
+
+    #[tokio::test]
+    async fn test_web_seeds() {
+        let download_dir = std::env::temp_dir().join(format!("riffle-web-seeds-{}", std::process::id()));
+        let data = (0..40_000u32).map(|x| (x * 7) as u8).collect::<Vec<_>>();
+        let url = serve(HashMap::from([("/seeded".to_string(), data.clone())]), false).await;
+        let mut buffer = b"d4:infod6:lengthi40000e4:name6:seeded12:piece lengthi16384e6:pieces60:".to_vec();
+        for chunk in data.chunks(16384) {
+            buffer.extend(Sha1::digest(chunk));
+        }
+        buffer.extend(format!("e8:url-list{}:{}e", url.len(), url).into_bytes());
+
+        let handle = TorrentClient::with_download_dir(&download_dir).spawn();
+        let mut events = handle.subscribe();
+        let info_hash = handle.add_torrent(MetaInfo::from_buffer(&buffer).unwrap()).await.unwrap();
+        let completed = tokio::time::timeout(Duration::from_secs(10), async {
+            while !matches!(events.recv().await.unwrap(), ClientEvent::TorrentCompleted { .. }) {}
+        });
+        completed.await.unwrap();
+        let status = handle.status(&info_hash).await.unwrap();
+        assert_eq!(status.downloaded_pieces(), 3);
+        assert_eq!(status.downloaded, 40_000);
+        assert_eq!(fs::read(download_dir.join("seeded")).unwrap(), data);
+
+        handle.remove_torrent(&info_hash, true).await.unwrap();
+        handle.shutdown().await.unwrap();
+        let _ = fs::remove_dir_all(download_dir);
+    }
+
+    #[tokio::test]
+    async fn test_kill_switch() {
+        use tokio::io::AsyncReadExt;
+
+        let root = std::env::temp_dir().join(format!("riffle-kill-switch-{}", std::process::id()));
+        let meta_info = meta_info("kill-switch", false);
+        let loopback: Interface = "127.0.0.1".parse().unwrap();
+        let network_settings = NetworkSettings {
+            listen_interfaces: vec![loopback.clone()],
+            outgoing_interface: Some(loopback),
+            kill_switch: true,
+        };
+        let session = async |name: &str| {
+            let mut client = TorrentClient::with_download_dir(root.join(name));
+            client.network_settings = network_settings.clone();
+            let listen_addrs = client.listen(0).unwrap();
+            let handle = client.spawn();
+            let events = handle.subscribe();
+            let info_hash = handle.add_torrent(meta_info.clone()).await.unwrap();
+            (handle, events, info_hash, listen_addrs)
+        };
+        let next_event = async |events: &mut broadcast::Receiver<ClientEvent>, wanted: fn(&ClientEvent) -> bool| loop {
+            let event = tokio::time::timeout(Duration::from_secs(5), events.recv()).await.unwrap().unwrap();
+            if wanted(&event) {
+                return event;
+            }
+        };
+
+        let (seed, _, info_hash, seed_addrs) = session("seed").await;
+        let (leech, mut leech_events, _, leech_addrs) = session("leech").await;
+        assert_eq!(seed_addrs.len(), 1);
+        assert!(seed_addrs[0].ip().is_loopback());
+        assert_eq!(leech.add_peer(&info_hash, seed_addrs[0], PeerSource::Dht).await, Ok(true));
+        next_event(&mut leech_events, |event| matches!(event, ClientEvent::PeerConnected { .. })).await;
+
+        // The outgoing interface going away stops all traffic until it is back
+        let gone = NetworkSettings {
+            outgoing_interface: Some(Interface::Name("riffle-missing0".to_string())),
+            ..network_settings.clone()
+        };
+        leech.set_network_settings(gone).await.unwrap();
+        next_event(&mut leech_events, |event| matches!(event, ClientEvent::PeerDisconnected { .. })).await;
+        next_event(&mut leech_events, |event| *event == ClientEvent::NetworkDown).await;
+        let status = leech.status(&info_hash).await.unwrap();
+        assert_eq!(status.peers_count, 0);
+        // Nothing comes in either
+        let mut stranger = TcpStream::connect(leech_addrs[0]).await.unwrap();
+        let closed = tokio::time::timeout(Duration::from_secs(1), stranger.read(&mut [0u8; 1])).await.unwrap();
+        assert!(matches!(closed, Ok(0) | Err(_)));
+        assert_eq!(leech.discoverable_info_hashes(PeerSource::Lsd).await, Ok(vec![]));
+
+        leech.set_network_settings(network_settings.clone()).await.unwrap();
+        next_event(&mut leech_events, |event| *event == ClientEvent::NetworkRestored).await;
+        next_event(&mut leech_events, |event| matches!(event, ClientEvent::PeerConnected { .. })).await;
+        assert_eq!(leech.discoverable_info_hashes(PeerSource::Lsd).await, Ok(vec![info_hash.clone()]));
+
+        for handle in [seed, leech] {
+            handle.shutdown().await.unwrap();
+        }
+        let _ = fs::remove_dir_all(root);
+    }
+
+    #[tokio::test]
+    async fn test_resume_data() {
+        let root = std::env::temp_dir().join(format!("riffle-resume-{}", std::process::id()));
+        let (download_dir, resume_dir) = (root.join("downloads"), root.join("resume"));
+        let data = b"0123456789".to_vec();
+        let mut buffer = b"d4:infod6:lengthi10e4:name6:resume12:piece lengthi16384e6:pieces20:".to_vec();
+        buffer.extend(Sha1::digest(&data));
+        buffer.extend_from_slice(b"ee");
+        let meta_info = MetaInfo::from_buffer(&buffer).unwrap();
+
+        let handle = TorrentClient::with_download_dir(&download_dir)
+            .with_resume_dir(&resume_dir)
+            .spawn();
+        let info_hash = handle.add_torrent(meta_info.clone()).await.unwrap();
+        handle.add_piece(&info_hash, 0, data.clone()).await.unwrap();
+        handle.pause(&info_hash).await.unwrap();
+        let other = handle.add_torrent(self::meta_info("other", false)).await.unwrap();
+        handle.set_queue_position(&other, 0).await.unwrap();
+        handle.shutdown().await.unwrap();
+        assert!(ResumeData::path(&resume_dir, &info_hash).exists());
+
+        // Trusted as long as the file is there
+        let handle = TorrentClient::with_download_dir(&download_dir)
+            .with_resume_dir(&resume_dir)
+            .spawn();
+        handle.add_torrent(meta_info.clone()).await.unwrap();
+        let status = handle.status(&info_hash).await.unwrap();
+        assert_eq!(status.state, TorrentState::Paused);
+        assert_eq!(status.downloaded_pieces(), 1);
+        assert_eq!(status.downloaded, 10);
+        assert_eq!(status.queue_position, 1);
+        handle.add_torrent(self::meta_info("other", false)).await.unwrap();
+        assert_eq!(handle.status(&other).await.unwrap().queue_position, 0);
+        handle.resume(&info_hash).await.unwrap();
+        assert_eq!(handle.status(&info_hash).await.unwrap().state, TorrentState::Seeding);
+        handle.shutdown().await.unwrap();
+
+        fs::write(download_dir.join("resume"), b"0123").unwrap();
+        let handle = TorrentClient::with_download_dir(&download_dir)
+            .with_resume_dir(&resume_dir)
+            .spawn();
+        let mut events = handle.subscribe();
+        handle.add_torrent(meta_info).await.unwrap();
+        // Hashed in the background, the session keeps answering meanwhile
+        assert!(handle.status(&info_hash).await.is_ok());
+        loop {
+            if let ClientEvent::StateChanged { state, .. } = events.recv().await.unwrap() {
+                assert_eq!(state, TorrentState::Downloading);
+                break;
+            }
+        }
+        let status = handle.status(&info_hash).await.unwrap();
+        assert_eq!(status.downloaded_pieces(), 0);
+        handle.remove_torrent(&info_hash, true).await.unwrap();
+        assert!(!ResumeData::path(&resume_dir, &info_hash).exists());
+
+        fs::remove_dir_all(root).unwrap();
     }
 }
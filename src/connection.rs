@@ -0,0 +1,681 @@
+use std::collections::BTreeMap;
+use std::io;
+use std::net::SocketAddr;
+use std::pin::Pin;
+use std::task::{Context as TaskContext, Poll};
+use std::time::{Duration, Instant};
+
+use anyhow::{Error, Result};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, ReadBuf};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::{mpsc, watch};
+use tokio::time::timeout;
+
+use crate::client::PeerSource;
+use crate::mse::{self, CipherStream, EncryptionPolicy, EncryptionSettings};
+use crate::network::{connect_tcp, Interface};
+use crate::proxy::ProxySettings;
+use crate::peer::{
+    read_message, write_message, Handshake, Interested, MessageId, Piece, Request, HANDSHAKE_LENGTH,
+};
+use crate::piece::Block;
+use crate::rate_limit::{Bandwidth, Direction};
+use crate::utp::{UtpSocket, UtpStream};
+
+/** Peers may drop connections they received nothing on for two minutes */
+pub const KEEP_ALIVE_INTERVAL: Duration = Duration::from_secs(120);
+
+/** Time given to a peer to answer over uTP before falling back to TCP, many don't support it */
+const UTP_CONNECT_TIMEOUT: Duration = Duration::from_secs(3);
+
+/** Peer connections, encrypted or not, once the encryption handshake is over */
+pub type PeerStream = CipherStream<Transport>;
+
+/** Either transport peers are reached over, the peer wire protocol is the same on both */
+#[derive(Debug)]
+pub enum Transport {
+    Tcp(TcpStream),
+    Utp(UtpStream),
+}
+
+impl AsyncRead for Transport {
+    fn poll_read(self: Pin<&mut Self>, cx: &mut TaskContext<'_>, buf: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            Transport::Tcp(stream) => Pin::new(stream).poll_read(cx, buf),
+            Transport::Utp(stream) => Pin::new(stream).poll_read(cx, buf),
+        }
+    }
+}
+
+impl AsyncWrite for Transport {
+    fn poll_write(self: Pin<&mut Self>, cx: &mut TaskContext<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+        match self.get_mut() {
+            Transport::Tcp(stream) => Pin::new(stream).poll_write(cx, buf),
+            Transport::Utp(stream) => Pin::new(stream).poll_write(cx, buf),
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut TaskContext<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            Transport::Tcp(stream) => Pin::new(stream).poll_flush(cx),
+            Transport::Utp(stream) => Pin::new(stream).poll_flush(cx),
+        }
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut TaskContext<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            Transport::Tcp(stream) => Pin::new(stream).poll_shutdown(cx),
+            Transport::Utp(stream) => Pin::new(stream).poll_shutdown(cx),
+        }
+    }
+}
+
+/** Limits on peer connections, the connection counts are across the whole session unless noted */
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ConnectionSettings {
+    /** Open connections, half-open ones included */
+    pub max_connections: usize,
+    pub max_connections_per_torrent: usize,
+    /** Outgoing connections still waiting for their handshake */
+    pub max_half_open: usize,
+    /** Time allowed to connect and exchange handshakes */
+    pub connect_timeout: Duration,
+    /** Delay before retrying a failed peer, doubled after every failure in a row */
+    pub retry_delay: Duration,
+    pub max_retry_delay: Duration,
+    /** Failures in a row before a peer is forgotten */
+    pub max_failures: u32,
+    /** Pieces failing the hash check before the peer that sent them is banned */
+    pub max_hash_failures: u32,
+    pub encryption: EncryptionSettings,
+    /** Try uTP before TCP when connecting to peers */
+    pub utp: bool,
+}
+
+impl Default for ConnectionSettings {
+    fn default() -> Self {
+        Self {
+            max_connections: 200,
+            max_connections_per_torrent: 50,
+            max_half_open: 20,
+            connect_timeout: Duration::from_secs(10),
+            retry_delay: Duration::from_secs(30),
+            max_retry_delay: Duration::from_secs(30 * 60),
+            max_failures: 5,
+            max_hash_failures: 3,
+            encryption: EncryptionSettings::default(),
+            utp: true,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum PeerState {
+    Candidate,
+    HalfOpen,
+    Connected,
+    Banned,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct PeerEntry {
+    /** Where the address was first learned from */
+    pub source: PeerSource,
+    pub state: PeerState,
+    /** Failed connection attempts in a row */
+    pub failures: u32,
+    /** Candidates aren't connected to before this */
+    pub retry_at: Option<Instant>,
+    pub hash_failures: u32,
+}
+
+/**
+ * Peers known to a torrent and the state of our connection to each of them.
+ * Addresses are deduplicated whatever source they come from, so a peer is never connected to twice.
+ */
+#[derive(Debug, Clone, Default)]
+pub struct ConnectionManager {
+    peers: BTreeMap<SocketAddr, PeerEntry>,
+}
+
+impl ConnectionManager {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /** Returns false if the address was already known */
+    pub fn add_candidate(&mut self, addr: SocketAddr, source: PeerSource) -> bool {
+        if self.peers.contains_key(&addr) {
+            return false;
+        }
+        self.peers.insert(
+            addr,
+            PeerEntry {
+                source,
+                state: PeerState::Candidate,
+                failures: 0,
+                retry_at: None,
+                hash_failures: 0,
+            },
+        );
+        true
+    }
+
+    pub fn get(&self, addr: &SocketAddr) -> Option<&PeerEntry> {
+        self.peers.get(addr)
+    }
+
+    pub fn remove(&mut self, addr: &SocketAddr) -> Option<PeerEntry> {
+        self.peers.remove(addr)
+    }
+
+    pub fn addrs(&self) -> Vec<SocketAddr> {
+        self.peers.keys().copied().collect()
+    }
+
+    pub fn len(&self) -> usize {
+        self.peers.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.peers.is_empty()
+    }
+
+    pub fn count(&self, state: PeerState) -> usize {
+        self.peers.values().filter(|entry| entry.state == state).count()
+    }
+
+    /** Connections established or being established */
+    pub fn open_count(&self) -> usize {
+        self.count(PeerState::HalfOpen) + self.count(PeerState::Connected)
+    }
+
+    pub fn connected(&self) -> Vec<SocketAddr> {
+        self.peers
+            .iter()
+            .filter(|(_, entry)| entry.state == PeerState::Connected)
+            .map(|(addr, _)| *addr)
+            .collect()
+    }
+
+    pub fn banned(&self) -> Vec<SocketAddr> {
+        self.peers
+            .iter()
+            .filter(|(_, entry)| entry.state == PeerState::Banned)
+            .map(|(addr, _)| *addr)
+            .collect()
+    }
+
+    /** Returns true if the known peer wasn't banned yet */
+    pub fn ban(&mut self, addr: &SocketAddr) -> bool {
+        match self.peers.get_mut(addr) {
+            Some(entry) if entry.state != PeerState::Banned => {
+                entry.state = PeerState::Banned;
+                true
+            }
+            _ => false,
+        }
+    }
+
+    pub fn is_banned(&self, addr: &SocketAddr) -> bool {
+        self.peers
+            .get(addr)
+            .is_some_and(|entry| entry.state == PeerState::Banned)
+    }
+
+    /** Next candidate due for a connection attempt, the ones that failed the least first */
+    pub fn next_candidate(&self, now: Instant) -> Option<SocketAddr> {
+        self.peers
+            .iter()
+            .filter(|(_, entry)| entry.state == PeerState::Candidate && entry.retry_at.is_none_or(|at| at <= now))
+            .min_by_key(|(_, entry)| entry.failures)
+            .map(|(addr, _)| *addr)
+    }
+
+    pub fn set_half_open(&mut self, addr: &SocketAddr) {
+        if let Some(entry) = self.peers.get_mut(addr) {
+            entry.state = PeerState::HalfOpen;
+        }
+    }
+
+    pub fn set_connected(&mut self, addr: &SocketAddr) {
+        if let Some(entry) = self.peers.get_mut(addr) {
+            entry.state = PeerState::Connected;
+            entry.failures = 0;
+            entry.retry_at = None;
+        }
+    }
+
+    /** Schedule a retry with exponential backoff, returns true if the peer failed too often and was forgotten */
+    pub fn connection_failed(&mut self, addr: &SocketAddr, now: Instant, settings: &ConnectionSettings) -> bool {
+        let Some(entry) = self.peers.get_mut(addr) else {
+            return false;
+        };
+        if entry.state == PeerState::Banned {
+            return false;
+        }
+        entry.failures += 1;
+        if entry.failures >= settings.max_failures {
+            self.peers.remove(addr);
+            return true;
+        }
+        entry.state = PeerState::Candidate;
+        entry.retry_at = Some(now + retry_backoff(settings, entry.failures));
+        false
+    }
+
+    /**
+     * A peer we managed to talk to may be connected to again after the base retry delay.
+     * Incoming peers are forgotten instead, they're known by the port they connected from, which nothing listens on.
+     */
+    pub fn disconnected(&mut self, addr: &SocketAddr, now: Instant, settings: &ConnectionSettings) {
+        match self.peers.get_mut(addr) {
+            Some(entry) if entry.state == PeerState::Banned => {}
+            Some(entry) if entry.source == PeerSource::Incoming => {
+                self.peers.remove(addr);
+            }
+            Some(entry) => {
+                entry.state = PeerState::Candidate;
+                entry.retry_at = Some(now + settings.retry_delay);
+            }
+            None => {}
+        }
+    }
+
+    /** Count a piece failing the hash check against a peer, returns true when this got it banned */
+    pub fn hash_failed(&mut self, addr: &SocketAddr, settings: &ConnectionSettings) -> bool {
+        let Some(entry) = self.peers.get_mut(addr) else {
+            return false;
+        };
+        if entry.state == PeerState::Banned {
+            return false;
+        }
+        entry.hash_failures += 1;
+        if entry.hash_failures >= settings.max_hash_failures {
+            entry.state = PeerState::Banned;
+            return true;
+        }
+        false
+    }
+
+    /** Forget every open connection when the torrent stops, the peers stay known except incoming ones */
+    pub fn close_all(&mut self) {
+        self.peers.retain(|_, entry| {
+            entry.source != PeerSource::Incoming || !matches!(entry.state, PeerState::HalfOpen | PeerState::Connected)
+        });
+        for entry in self.peers.values_mut() {
+            if matches!(entry.state, PeerState::HalfOpen | PeerState::Connected) {
+                entry.state = PeerState::Candidate;
+                entry.retry_at = None;
+            }
+        }
+    }
+}
+
+fn retry_backoff(settings: &ConnectionSettings, failures: u32) -> Duration {
+    settings
+        .retry_delay
+        .saturating_mul(1 << failures.saturating_sub(1).min(16))
+        .min(settings.max_retry_delay)
+}
+
+/** Reported by connection tasks to the session, which owns the connection state */
+#[derive(Debug)]
+pub enum PeerEvent {
+    /** An incoming connection whose handshake was read, the torrent it is for isn't known yet */
+    Incoming {
+        stream: Box<PeerStream>,
+        addr: SocketAddr,
+        handshake: Handshake,
+    },
+    Connected {
+        info_hash: String,
+        addr: SocketAddr,
+        handshake: Handshake,
+    },
+    Failed {
+        info_hash: String,
+        addr: SocketAddr,
+        error: String,
+    },
+    Bitfield {
+        info_hash: String,
+        addr: SocketAddr,
+        bitfield: Vec<u8>,
+    },
+    Have {
+        info_hash: String,
+        addr: SocketAddr,
+        index: usize,
+    },
+    /** Requests sent before a choke are discarded by the peer */
+    Choked {
+        info_hash: String,
+        addr: SocketAddr,
+        choked: bool,
+    },
+    /** The data of a piece message, the only bytes counted as downloaded from peers */
+    Block {
+        info_hash: String,
+        addr: SocketAddr,
+        block: Block,
+    },
+    Disconnected {
+        info_hash: String,
+        addr: SocketAddr,
+    },
+}
+
+/** Sent by the session to a connection task, which writes it to the peer */
+#[derive(Debug, Clone, PartialEq)]
+pub enum PeerCommand {
+    Interested,
+    Request { index: u32, begin: u32, length: u32 },
+}
+
+impl PeerCommand {
+    fn to_buffer(&self) -> Vec<u8> {
+        match *self {
+            PeerCommand::Interested => Interested::default().to_buffer(),
+            PeerCommand::Request { index, begin, length } => Request::new(index, begin, length).to_buffer(),
+        }
+    }
+}
+
+/** Handshakes are protocol overhead */
+pub async fn send_handshake<S: AsyncWrite + Unpin>(stream: &mut S, handshake: &Handshake, bandwidth: &Bandwidth) -> Result<()> {
+    bandwidth
+        .acquire(Direction::Upload, 0, HANDSHAKE_LENGTH as u64)
+        .await;
+    stream.write_all(&handshake.to_buffer()).await?;
+    stream.flush().await?;
+    Ok(())
+}
+
+pub async fn read_handshake<S: AsyncRead + Unpin>(stream: &mut S) -> Result<Handshake> {
+    let mut buffer = [0u8; HANDSHAKE_LENGTH];
+    stream.read_exact(&mut buffer).await?;
+    Handshake::from_buffer(&buffer)
+}
+
+/** How outgoing peer connections are opened */
+#[derive(Debug, Clone, Default)]
+pub struct Dialer {
+    /** Tried before TCP when set */
+    pub utp: Option<UtpSocket>,
+    pub proxy: ProxySettings,
+    /** Interface TCP connections leave from */
+    pub interface: Option<Interface>,
+}
+
+impl Dialer {
+    /** uTP can't go through the proxy, TCP connections go through it whenever there is one */
+    fn is_proxied(&self) -> bool {
+        self.proxy.proxy.is_some() || !self.proxy.allows_direct()
+    }
+
+    /** uTP when given a socket and the peer answers on it, TCP otherwise */
+    async fn open(&self, addr: SocketAddr) -> Result<Transport> {
+        if self.is_proxied() {
+            return Ok(Transport::Tcp(self.proxy.connect(addr, self.interface.as_ref()).await?));
+        }
+        if let Some(utp) = &self.utp {
+            if let Ok(Ok(stream)) = timeout(UTP_CONNECT_TIMEOUT, utp.connect(addr)).await {
+                return Ok(Transport::Utp(stream));
+            }
+        }
+        Ok(Transport::Tcp(connect_tcp(addr, self.interface.as_ref()).await?))
+    }
+
+    /** Open a connection and go through the encryption handshake the policy asks for */
+    async fn open_stream(&self, addr: SocketAddr, info_hash: &[u8; 20], policy: EncryptionPolicy) -> Result<PeerStream> {
+        let transport = self.open(addr).await?;
+        match policy {
+            EncryptionPolicy::Disabled => Ok(CipherStream::plaintext(transport)),
+            EncryptionPolicy::Forced => mse::initiate(transport, info_hash, policy).await,
+            EncryptionPolicy::Enabled => {
+                let used_utp = matches!(transport, Transport::Utp(_));
+                match mse::initiate(transport, info_hash, policy).await {
+                    Ok(stream) => Ok(stream),
+                    // Peers without encryption support drop the connection on our key, try again in plaintext
+                    Err(_) => {
+                        let dialer = Dialer {
+                            utp: self.utp.clone().filter(|_| used_utp),
+                            proxy: self.proxy.clone(),
+                            interface: self.interface.clone(),
+                        };
+                        Ok(CipherStream::plaintext(dialer.open(addr).await?))
+                    }
+                }
+            }
+        }
+    }
+}
+
+/** Open a connection and exchange handshakes, the peer must answer for the same torrent */
+pub async fn connect(
+    addr: SocketAddr,
+    handshake: &Handshake,
+    connect_timeout: Duration,
+    encryption: EncryptionPolicy,
+    dialer: &Dialer,
+    bandwidth: &Bandwidth,
+) -> Result<(PeerStream, Handshake)> {
+    let connecting = async {
+        let mut stream = dialer.open_stream(addr, &handshake.info_hash(), encryption).await?;
+        send_handshake(&mut stream, handshake, bandwidth).await?;
+        let answer = read_handshake(&mut stream).await?;
+        bandwidth
+            .acquire(Direction::Download, 0, HANDSHAKE_LENGTH as u64)
+            .await;
+        if answer.info_hash() != handshake.info_hash() {
+            return Err(Error::msg(format!("{} answered for another torrent", addr)));
+        }
+        Ok((stream, answer))
+    };
+    timeout(connect_timeout, connecting)
+        .await
+        .map_err(|_| Error::msg(format!("Connection to {} timed out", addr)))?
+}
+
+/** What the listener needs from the session to go through encryption handshakes on its own */
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct ListenerState {
+    /** Incoming connections are closed right away otherwise, without going through any handshake */
+    pub accepting: bool,
+    /** Swarm hashes of every torrent, encrypted peers name theirs through a hash of it */
+    pub info_hashes: Vec<[u8; 20]>,
+    pub encryption: EncryptionPolicy,
+}
+
+/** Read the handshake of an incoming connection in its own task, the session gets it once done */
+fn handshake_incoming(
+    transport: Transport,
+    addr: SocketAddr,
+    events: &mpsc::UnboundedSender<PeerEvent>,
+    handshake_timeout: Duration,
+    state: &watch::Receiver<ListenerState>,
+) {
+    let events = events.clone();
+    let ListenerState {
+        accepting,
+        info_hashes,
+        encryption,
+    } = state.borrow().clone();
+    if !accepting {
+        return;
+    }
+    tokio::spawn(async move {
+        let accepting = async {
+            let mut stream = mse::accept(transport, &info_hashes, encryption).await?;
+            let handshake = read_handshake(&mut stream).await?;
+            Ok::<_, Error>((stream, handshake))
+        };
+        if let Ok(Ok((stream, handshake))) = timeout(handshake_timeout, accepting).await {
+            let _ = events.send(PeerEvent::Incoming {
+                stream: Box::new(stream),
+                addr,
+                handshake,
+            });
+        }
+    });
+}
+
+/** Hand incoming connections to the session once their handshake is read */
+pub async fn accept_peers(
+    listener: TcpListener,
+    events: mpsc::UnboundedSender<PeerEvent>,
+    handshake_timeout: Duration,
+    state: watch::Receiver<ListenerState>,
+) {
+    loop {
+        if let Ok((stream, addr)) = listener.accept().await {
+            handshake_incoming(Transport::Tcp(stream), addr, &events, handshake_timeout, &state);
+        }
+    }
+}
+
+pub async fn accept_utp_peers(
+    socket: UtpSocket,
+    events: mpsc::UnboundedSender<PeerEvent>,
+    handshake_timeout: Duration,
+    state: watch::Receiver<ListenerState>,
+) {
+    while let Ok((stream, addr)) = socket.accept().await {
+        handshake_incoming(Transport::Utp(stream), addr, &events, handshake_timeout, &state);
+    }
+}
+
+/**
+ * Run an established connection until either side closes it.
+ * Availability, choking and blocks are reported to the session, which decides what to send through `commands`.
+ * Nothing is uploaded: our bitfield isn't sent, the peer is never unchoked and its requests are ignored.
+ */
+pub async fn run_connection(
+    stream: PeerStream,
+    info_hash: String,
+    addr: SocketAddr,
+    bandwidth: Bandwidth,
+    events: mpsc::UnboundedSender<PeerEvent>,
+    mut commands: mpsc::UnboundedReceiver<PeerCommand>,
+) {
+    let (mut reader, mut writer) = tokio::io::split(stream);
+    let send = async {
+        let start = tokio::time::Instant::now() + KEEP_ALIVE_INTERVAL;
+        let mut interval = tokio::time::interval_at(start, KEEP_ALIVE_INTERVAL);
+        loop {
+            let message = tokio::select! {
+                _ = interval.tick() => vec![0; 4],
+                Some(command) = commands.recv() => command.to_buffer(),
+            };
+            if write_message(&mut writer, &message, &bandwidth).await.is_err() {
+                return;
+            }
+        }
+    };
+    let receive = async {
+        while let Ok(message) = read_message(&mut reader, &bandwidth).await {
+            let event = match message.first() {
+                Some(&id) if id == MessageId::Bitfield as u8 => PeerEvent::Bitfield {
+                    info_hash: info_hash.clone(),
+                    addr,
+                    bitfield: message[1..].to_vec(),
+                },
+                Some(&id) if id == MessageId::Have as u8 && message.len() == 5 => PeerEvent::Have {
+                    info_hash: info_hash.clone(),
+                    addr,
+                    index: u32::from_be_bytes(message[1..5].try_into().unwrap()) as usize,
+                },
+                Some(&id) if id == MessageId::Choke as u8 || id == MessageId::Unchoke as u8 => PeerEvent::Choked {
+                    info_hash: info_hash.clone(),
+                    addr,
+                    choked: id == MessageId::Choke as u8,
+                },
+                Some(&id) if id == MessageId::Piece as u8 => match Piece::from_payload(&message[1..]) {
+                    Ok(piece) => PeerEvent::Block {
+                        info_hash: info_hash.clone(),
+                        addr,
+                        block: piece.into_block(addr),
+                    },
+                    Err(_) => return,
+                },
+                _ => continue,
+            };
+            if events.send(event).is_err() {
+                return;
+            }
+        }
+    };
+    tokio::select! {
+        _ = send => {}
+        _ = receive => {}
+    }
+    let _ = events.send(PeerEvent::Disconnected { info_hash, addr });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_connection_manager() {
+        let settings = ConnectionSettings {
+            max_failures: 3,
+            max_hash_failures: 2,
+            ..Default::default()
+        };
+        let mut manager = ConnectionManager::new();
+        let a: SocketAddr = "10.0.0.1:6881".parse().unwrap();
+        let b: SocketAddr = "10.0.0.2:6881".parse().unwrap();
+        assert!(manager.add_candidate(a, PeerSource::Tracker));
+        assert!(!manager.add_candidate(a, PeerSource::Dht));
+        assert!(manager.add_candidate(b, PeerSource::Pex));
+        assert_eq!(manager.get(&a).unwrap().source, PeerSource::Tracker);
+
+        // Failed peers wait longer after every failure, and are forgotten in the end
+        let now = Instant::now();
+        manager.set_half_open(&a);
+        assert_eq!(manager.open_count(), 1);
+        assert!(!manager.connection_failed(&a, now, &settings));
+        assert_eq!(manager.next_candidate(now), Some(b));
+        manager.set_half_open(&b);
+        assert_eq!(manager.next_candidate(now), None);
+        assert_eq!(manager.next_candidate(now + settings.retry_delay), Some(a));
+        manager.set_half_open(&a);
+        assert!(!manager.connection_failed(&a, now, &settings));
+        assert_eq!(manager.next_candidate(now + settings.retry_delay), None);
+        assert_eq!(manager.next_candidate(now + settings.retry_delay * 2), Some(a));
+        assert!(manager.connection_failed(&a, now, &settings));
+        assert!(manager.get(&a).is_none());
+
+        manager.set_connected(&b);
+        assert_eq!(manager.connected(), vec![b]);
+        assert!(!manager.hash_failed(&b, &settings));
+        assert!(manager.hash_failed(&b, &settings));
+        assert!(manager.is_banned(&b));
+        assert!(!manager.ban(&b));
+        assert_eq!(manager.banned(), vec![b]);
+        manager.disconnected(&b, now, &settings);
+        manager.close_all();
+
+        // Incoming peers can't be dialed back on the port they came from
+        let c: SocketAddr = "10.0.0.3:51413".parse().unwrap();
+        let d: SocketAddr = "10.0.0.4:51413".parse().unwrap();
+        for addr in [c, d] {
+            assert!(manager.add_candidate(addr, PeerSource::Incoming));
+            manager.set_connected(&addr);
+        }
+        manager.disconnected(&c, now, &settings);
+        assert!(manager.get(&c).is_none());
+        manager.close_all();
+        assert!(manager.get(&d).is_none());
+        assert!(manager.is_banned(&b));
+        assert!(!manager.add_candidate(b, PeerSource::Tracker));
+        assert_eq!(manager.next_candidate(now + settings.max_retry_delay), None);
+
+        let settings = ConnectionSettings::default();
+        assert_eq!(retry_backoff(&settings, 1), settings.retry_delay);
+        assert_eq!(retry_backoff(&settings, 3), settings.retry_delay * 4);
+        assert_eq!(retry_backoff(&settings, 30), settings.max_retry_delay);
+    }
+}
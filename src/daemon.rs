@@ -0,0 +1,578 @@
+use std::fs::{File, OpenOptions};
+use std::io::Write;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use anyhow::{Context, Result};
+use sha2::{Digest, Sha256};
+use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::signal::unix::{signal, SignalKind};
+use tokio::sync::{broadcast, mpsc};
+
+use crate::client::ClientHandle;
+use crate::config::{Cli, Config, TorrentSource};
+use crate::lsd::run_lsd;
+use crate::piece_picker::Priority;
+use crate::torrent::{GoalAction, SeedingGoals, TorrentStatus};
+
+/** Control connections sending a longer line are closed */
+pub const MAX_CONTROL_LINE_LENGTH: usize = 64 * 1024;
+
+/** Timestamped lines on stdout or appended to a file */
+#[derive(Debug, Clone)]
+pub struct Logger {
+    file: Option<Arc<Mutex<File>>>,
+}
+
+impl Logger {
+    pub fn new(path: Option<&Path>) -> Result<Logger> {
+        let file = match path {
+            Some(path) => {
+                let file = OpenOptions::new()
+                    .create(true)
+                    .append(true)
+                    .open(path)
+                    .context(format!("Failed to open log file {}", path.display()))?;
+                Some(Arc::new(Mutex::new(file)))
+            }
+            None => None,
+        };
+        Ok(Logger { file })
+    }
+
+    pub fn log(&self, message: impl AsRef<str>) {
+        let line = format!("{} {}\n", format_timestamp(SystemTime::now()), message.as_ref());
+        // Losing a log line is better than stopping the session over it
+        let _ = match &self.file {
+            Some(file) => file.lock().unwrap().write_all(line.as_bytes()),
+            None => std::io::stdout().lock().write_all(line.as_bytes()),
+        };
+    }
+}
+
+/** RFC 3339 in UTC, days are converted to a civil date following Howard Hinnant's algorithm */
+fn format_timestamp(time: SystemTime) -> String {
+    let seconds = time.duration_since(UNIX_EPOCH).map_or(0, |elapsed| elapsed.as_secs() as i64);
+    let (days, day_seconds) = (seconds.div_euclid(86400), seconds.rem_euclid(86400));
+    let z = days + 719468;
+    let era = z.div_euclid(146097);
+    let day_of_era = z.rem_euclid(146097);
+    let year_of_era = (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let month_index = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * month_index + 2) / 5 + 1;
+    let month = if month_index < 10 { month_index + 3 } else { month_index - 9 };
+    let year = year_of_era + era * 400 + i64::from(month <= 2);
+    format!(
+        "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}Z",
+        year,
+        month,
+        day,
+        day_seconds / 3600,
+        day_seconds % 3600 / 60,
+        day_seconds % 60
+    )
+}
+
+/**
+ * Control socket protocol, one JSON object per line each way:
+ *
+ * {"token": "secret", "command": "list"}
+ * {"command": "add", "torrent": "/data/sintel.torrent"}
+ * {"command": "remove", "info_hash": "08ada5...", "delete_data": true}
+ * {"command": "pause", "info_hash": "08ada5..."}
+ * {"command": "status", "info_hash": "08ada5..."}
+ * {"command": "set_file_priority", "info_hash": "08ada5...", "file_index": 0, "priority": "skip"}
+ * {"command": "set_rate_limits", "info_hash": null, "upload": 1048576, "download": null}
+ * {"command": "set_seeding_goals", "info_hash": null, "ratio": 2.0, "seeding_time": 86400, "action": "remove"}
+ * {"command": "shutdown"}
+ *
+ * The token is only checked when the daemon is configured with one.
+ */
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+pub struct ControlMessage {
+    #[serde(default)]
+    pub token: Option<String>,
+    #[serde(flatten)]
+    pub request: ControlRequest,
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+#[serde(tag = "command", rename_all = "snake_case")]
+pub enum ControlRequest {
+    List,
+    /** Torrent file path on the daemon host or magnet link */
+    Add {
+        torrent: String,
+    },
+    Remove {
+        info_hash: String,
+        #[serde(default)]
+        delete_data: bool,
+    },
+    Pause {
+        info_hash: String,
+    },
+    Resume {
+        info_hash: String,
+    },
+    Recheck {
+        info_hash: String,
+    },
+    Status {
+        info_hash: String,
+    },
+    SetFilePriority {
+        info_hash: String,
+        file_index: usize,
+        priority: Priority,
+    },
+    /** 0 is the front of the queue, positions past the end move the torrent to the back */
+    SetQueuePosition {
+        info_hash: String,
+        position: usize,
+    },
+    /** Session limits without an info hash, in bytes per second, unlimited when null */
+    SetRateLimits {
+        #[serde(default)]
+        info_hash: Option<String>,
+        #[serde(default)]
+        upload: Option<u64>,
+        #[serde(default)]
+        download: Option<u64>,
+    },
+    /** Limits of each peer connection opened afterwards, in bytes per second */
+    SetPeerRateLimits {
+        #[serde(default)]
+        upload: Option<u64>,
+        #[serde(default)]
+        download: Option<u64>,
+    },
+    /** Whether protocol overhead counts against the rate limits */
+    SetIncludeOverhead {
+        include_overhead: bool,
+    },
+    /** Session goals without an info hash, a torrent given neither goal follows the session goals again */
+    SetSeedingGoals {
+        #[serde(default)]
+        info_hash: Option<String>,
+        #[serde(default)]
+        ratio: Option<f64>,
+        /** Seconds */
+        #[serde(default)]
+        seeding_time: Option<u64>,
+        #[serde(default)]
+        action: GoalAction,
+    },
+    Shutdown,
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+#[serde(tag = "result", rename_all = "snake_case")]
+pub enum ControlResponse {
+    Ok,
+    Added { info_hash: String, warnings: Vec<String> },
+    Torrents { torrents: Vec<TorrentSummary> },
+    Torrent { torrent: TorrentSummary },
+    Error { message: String },
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+pub struct TorrentSummary {
+    pub info_hash: String,
+    pub name: String,
+    pub state: String,
+    pub queue_position: usize,
+    pub pieces: usize,
+    pub downloaded_pieces: usize,
+    pub left: u64,
+    pub uploaded: u64,
+    pub downloaded: u64,
+    pub download_rate: u64,
+    pub upload_rate: u64,
+    pub peers: usize,
+    /** Indexed like the files of the torrent */
+    pub file_priorities: Vec<Priority>,
+    pub filtered_peers: u64,
+    pub upload_limit: Option<u64>,
+    pub download_limit: Option<u64>,
+    pub ratio: f64,
+    /** Seconds */
+    pub seeding_time: u64,
+}
+
+impl From<&TorrentStatus> for TorrentSummary {
+    fn from(status: &TorrentStatus) -> Self {
+        TorrentSummary {
+            info_hash: status.info_hash.clone(),
+            name: status.name.clone(),
+            state: status.state.to_string(),
+            queue_position: status.queue_position,
+            pieces: status.pieces_count(),
+            downloaded_pieces: status.downloaded_pieces(),
+            left: status.left,
+            uploaded: status.uploaded,
+            downloaded: status.downloaded,
+            download_rate: status.download_rate,
+            upload_rate: status.upload_rate,
+            peers: status.peers_count,
+            file_priorities: status.file_priorities.clone(),
+            filtered_peers: status.filtered_peers,
+            upload_limit: status.upload_limit,
+            download_limit: status.download_limit,
+            ratio: status.ratio,
+            seeding_time: status.seeding_time.as_secs(),
+        }
+    }
+}
+
+/** Digests are compared in constant time, so neither the token nor its length can be guessed a byte at a time */
+fn token_matches(expected: &str, given: &str) -> bool {
+    let (expected, given) = (Sha256::digest(expected), Sha256::digest(given));
+    expected
+        .iter()
+        .zip(given.iter())
+        .fold(0, |difference, (a, b)| difference | (a ^ b))
+        == 0
+}
+
+async fn handle_request(client: &ClientHandle, request: ControlRequest) -> ControlResponse {
+    let result = match request {
+        ControlRequest::List => {
+            return match client.list().await {
+                Ok(statuses) => ControlResponse::Torrents {
+                    torrents: statuses.iter().map(TorrentSummary::from).collect(),
+                },
+                Err(error) => ControlResponse::Error {
+                    message: error.to_string(),
+                },
+            }
+        }
+        ControlRequest::Add { torrent } => {
+            let added = match TorrentSource::parse(&torrent) {
+                Ok(source) => source.add(client).await,
+                Err(error) => Err(error),
+            };
+            return match added {
+                Ok((info_hash, warnings)) => ControlResponse::Added {
+                    info_hash,
+                    warnings: warnings.iter().map(ToString::to_string).collect(),
+                },
+                Err(error) => ControlResponse::Error {
+                    message: format!("{:#}", error),
+                },
+            };
+        }
+        ControlRequest::Remove { info_hash, delete_data } => client.remove_torrent(&info_hash, delete_data).await,
+        ControlRequest::Pause { info_hash } => client.pause(&info_hash).await,
+        ControlRequest::Resume { info_hash } => client.resume(&info_hash).await,
+        ControlRequest::Recheck { info_hash } => client.recheck(&info_hash).await,
+        ControlRequest::Status { info_hash } => {
+            return match client.status(&info_hash).await {
+                Ok(status) => ControlResponse::Torrent {
+                    torrent: TorrentSummary::from(&status),
+                },
+                Err(error) => ControlResponse::Error {
+                    message: error.to_string(),
+                },
+            }
+        }
+        ControlRequest::SetFilePriority {
+            info_hash,
+            file_index,
+            priority,
+        } => client.set_file_priority(&info_hash, file_index, priority).await,
+        ControlRequest::SetQueuePosition { info_hash, position } => client.set_queue_position(&info_hash, position).await,
+        ControlRequest::SetRateLimits {
+            info_hash: Some(info_hash),
+            upload,
+            download,
+        } => client.set_torrent_rate_limits(&info_hash, upload, download).await,
+        ControlRequest::SetRateLimits {
+            info_hash: None,
+            upload,
+            download,
+        } => client.set_global_rate_limits(upload, download).await,
+        ControlRequest::SetPeerRateLimits { upload, download } => client.set_peer_rate_limits(upload, download).await,
+        ControlRequest::SetIncludeOverhead { include_overhead } => client.set_include_overhead(include_overhead).await,
+        ControlRequest::SetSeedingGoals {
+            info_hash,
+            ratio,
+            seeding_time,
+            action,
+        } => {
+            let goals = SeedingGoals {
+                ratio,
+                seeding_time: seeding_time.map(Duration::from_secs),
+                action,
+            };
+            match info_hash {
+                Some(info_hash) => {
+                    let goals = (ratio.is_some() || seeding_time.is_some()).then_some(goals);
+                    client.set_torrent_seeding_goals(&info_hash, goals).await
+                }
+                None => client.set_seeding_goals(goals).await,
+            }
+        }
+        // Answered by the connection once the daemon is told to stop
+        ControlRequest::Shutdown => Ok(()),
+    };
+    match result {
+        Ok(()) => ControlResponse::Ok,
+        Err(error) => ControlResponse::Error {
+            message: error.to_string(),
+        },
+    }
+}
+
+async fn handle_control_connection(
+    stream: TcpStream,
+    client: ClientHandle,
+    token: Option<String>,
+    shutdown: mpsc::UnboundedSender<()>,
+) -> Result<()> {
+    let (reader, mut writer) = stream.into_split();
+    let mut reader = BufReader::new(reader);
+    let mut line = Vec::new();
+    loop {
+        line.clear();
+        let limit = MAX_CONTROL_LINE_LENGTH as u64 + 1;
+        if (&mut reader).take(limit).read_until(b'\n', &mut line).await? == 0 {
+            break;
+        }
+        if line.strip_suffix(b"\n").unwrap_or(&line).len() > MAX_CONTROL_LINE_LENGTH {
+            let mut buffer = serde_json::to_vec(&ControlResponse::Error {
+                message: "Request too long".to_string(),
+            })?;
+            buffer.push(b'\n');
+            writer.write_all(&buffer).await?;
+            break;
+        }
+        let line = String::from_utf8_lossy(&line);
+        if line.trim().is_empty() {
+            continue;
+        }
+        let (response, stop) = match serde_json::from_str::<ControlMessage>(&line) {
+            Ok(message) => match (&token, &message.token) {
+                (Some(expected), Some(given)) if !token_matches(expected, given) => (None, false),
+                (Some(_), None) => (None, false),
+                _ => {
+                    let stop = message.request == ControlRequest::Shutdown;
+                    (Some(handle_request(&client, message.request).await), stop)
+                }
+            },
+            Err(error) => (
+                Some(ControlResponse::Error {
+                    message: format!("Bad request: {}", error),
+                }),
+                false,
+            ),
+        };
+        let response = response.unwrap_or(ControlResponse::Error {
+            message: "Bad token".to_string(),
+        });
+        let mut buffer = serde_json::to_vec(&response)?;
+        buffer.push(b'\n');
+        writer.write_all(&buffer).await?;
+        if stop {
+            let _ = shutdown.send(());
+            break;
+        }
+    }
+    Ok(())
+}
+
+/** Accept control connections, each served in its own task */
+pub async fn serve_control(
+    listener: TcpListener,
+    client: ClientHandle,
+    token: Option<String>,
+    shutdown: mpsc::UnboundedSender<()>,
+) {
+    while let Ok((stream, _)) = listener.accept().await {
+        let (client, token, shutdown) = (client.clone(), token.clone(), shutdown.clone());
+        tokio::spawn(handle_control_connection(stream, client, token, shutdown));
+    }
+}
+
+async fn log_events(client: ClientHandle, logger: Logger) {
+    let mut events = client.subscribe();
+    loop {
+        match events.recv().await {
+            Ok(event) => logger.log(format!("{:?}", event)),
+            Err(broadcast::error::RecvError::Lagged(missed)) => logger.log(format!("Missed {} events", missed)),
+            Err(broadcast::error::RecvError::Closed) => break,
+        }
+    }
+}
+
+/** Load the config again and apply it to the session, see `Config::apply` for what can change */
+async fn reload(cli: &Cli, client: &ClientHandle) -> Result<()> {
+    Config::load(cli)?.apply(client).await
+}
+
+/**
+ * Run the session without a terminal until SIGINT, SIGTERM or a shutdown request on the control socket.
+ * SIGHUP reloads the config. Trackers are told we're leaving and resume data is saved before returning.
+ */
+pub async fn run(cli: &Cli, config: Config) -> Result<()> {
+    let logger = Logger::new(config.log_file.as_deref())?;
+    let client = config.client()?;
+    let listen_port = client.listen_port();
+    let client = client.spawn();
+    if listen_port == 0 {
+        logger.log("Not listening for peers, direct connections are refused");
+    } else {
+        logger.log(format!("Listening for peers on port {}", listen_port));
+    }
+
+    let mut tasks = vec![tokio::spawn(log_events(client.clone(), logger.clone()))];
+    for message in config.add_torrents(&client).await {
+        logger.log(message);
+    }
+    let (lsd_client, lsd_logger) = (client.clone(), logger.clone());
+    tasks.push(tokio::spawn(async move {
+        // LAN discovery is best effort, the multicast group might not be reachable
+        if let Err(error) = run_lsd(lsd_client, listen_port).await {
+            lsd_logger.log(format!("Local service discovery is unavailable: {:#}", error));
+        }
+    }));
+    let (shutdown_tx, mut shutdown_rx) = mpsc::unbounded_channel();
+    if let Some(control_addr) = config.control_addr {
+        let listener = TcpListener::bind(control_addr)
+            .await
+            .context(format!("Failed to listen for control connections on {}", control_addr))?;
+        logger.log(format!("Accepting control connections on {}", listener.local_addr()?));
+        let serving = serve_control(listener, client.clone(), config.control_token.clone(), shutdown_tx);
+        tasks.push(tokio::spawn(serving));
+    }
+
+    let mut sigterm = signal(SignalKind::terminate())?;
+    let mut sigint = signal(SignalKind::interrupt())?;
+    let mut sighup = signal(SignalKind::hangup())?;
+    loop {
+        tokio::select! {
+            _ = sigterm.recv() => logger.log("Received SIGTERM, shutting down"),
+            _ = sigint.recv() => logger.log("Received SIGINT, shutting down"),
+            _ = shutdown_rx.recv() => logger.log("Shutdown requested on the control socket"),
+            _ = sighup.recv() => {
+                match reload(cli, &client).await {
+                    Ok(()) => logger.log("Received SIGHUP, reloaded the config"),
+                    Err(error) => logger.log(format!("Received SIGHUP, keeping the current config: {:#}", error)),
+                }
+                continue;
+            }
+        }
+        break;
+    }
+    client.shutdown_gracefully().await?;
+    for task in tasks {
+        task.abort();
+    }
+    logger.log("Stopped");
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::client::TorrentClient;
+
+    #[test]
+    fn test_format_timestamp() {
+        assert_eq!(format_timestamp(UNIX_EPOCH), "1970-01-01T00:00:00Z");
+        let time = UNIX_EPOCH + Duration::from_secs(1_709_210_096);
+        assert_eq!(format_timestamp(time), "2024-02-29T12:34:56Z");
+    }
+
+    #[tokio::test]
+    async fn test_control_socket() {
+        let root = std::env::temp_dir().join(format!("riffle-control-{}", std::process::id()));
+        std::fs::create_dir_all(&root).unwrap();
+        let torrent_path = root.join("control.torrent");
+        let mut buffer = b"d4:infod6:lengthi10e4:name7:control12:piece lengthi16384e6:pieces20:".to_vec();
+        buffer.extend_from_slice(&[0u8; 20]);
+        buffer.extend_from_slice(b"ee");
+        std::fs::write(&torrent_path, &buffer).unwrap();
+
+        let client = TorrentClient::with_download_dir(root.join("downloads")).spawn();
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let control_addr = listener.local_addr().unwrap();
+        let (shutdown_tx, mut shutdown_rx) = mpsc::unbounded_channel();
+        tokio::spawn(serve_control(listener, client.clone(), Some("secret".to_string()), shutdown_tx));
+
+        let stream = TcpStream::connect(control_addr).await.unwrap();
+        let (reader, mut writer) = stream.into_split();
+        let mut lines = BufReader::new(reader).lines();
+        let mut request = async |line: String| {
+            writer.write_all(format!("{}\n", line).as_bytes()).await.unwrap();
+            let response = lines.next_line().await.unwrap().unwrap();
+            serde_json::from_str::<ControlResponse>(&response).unwrap()
+        };
+
+        let denied = request(r#"{"token": "guess", "command": "list"}"#.to_string()).await;
+        assert!(matches!(denied, ControlResponse::Error { .. }));
+        let added = request(format!(
+            r#"{{"token": "secret", "command": "add", "torrent": {:?}}}"#,
+            torrent_path.display().to_string()
+        ))
+        .await;
+        let ControlResponse::Added { info_hash, .. } = added else {
+            panic!("{:?}", added);
+        };
+        let ControlResponse::Torrents { torrents } = request(r#"{"token": "secret", "command": "list"}"#.to_string()).await else {
+            panic!("expected torrents");
+        };
+        assert_eq!(torrents.len(), 1);
+        assert_eq!((torrents[0].info_hash.as_str(), torrents[0].name.as_str()), (info_hash.as_str(), "control"));
+
+        let paused = request(format!(r#"{{"token": "secret", "command": "pause", "info_hash": "{}"}}"#, info_hash)).await;
+        assert_eq!(paused, ControlResponse::Ok);
+        let skipped = request(format!(
+            r#"{{"token": "secret", "command": "set_file_priority", "info_hash": "{}", "file_index": 0, "priority": "skip"}}"#,
+            info_hash
+        ))
+        .await;
+        assert_eq!(skipped, ControlResponse::Ok);
+        let moved = request(format!(
+            r#"{{"token": "secret", "command": "set_queue_position", "info_hash": "{}", "position": 3}}"#,
+            info_hash
+        ))
+        .await;
+        assert_eq!(moved, ControlResponse::Ok);
+        let goals = request(format!(
+            r#"{{"token": "secret", "command": "set_seeding_goals", "info_hash": "{}", "ratio": 2.0, "action": "remove"}}"#,
+            info_hash
+        ))
+        .await;
+        assert_eq!(goals, ControlResponse::Ok);
+        let status = request(format!(r#"{{"token": "secret", "command": "status", "info_hash": "{}"}}"#, info_hash)).await;
+        let ControlResponse::Torrent { torrent } = status else {
+            panic!("{:?}", status);
+        };
+        assert_eq!((torrent.state.as_str(), torrent.file_priorities), ("Paused", vec![Priority::Skip]));
+        assert_eq!(torrent.queue_position, 0);
+        let unknown = request(r#"{"token": "secret", "command": "resume", "info_hash": "00"}"#.to_string()).await;
+        assert!(matches!(unknown, ControlResponse::Error { .. }));
+        let bad = request(r#"{"token": "secret", "command": "explode"}"#.to_string()).await;
+        assert!(matches!(bad, ControlResponse::Error { .. }));
+
+        // Overlong lines get an error and the connection closed, before the whole line is read
+        let mut flood = TcpStream::connect(control_addr).await.unwrap();
+        flood.write_all(&vec![b'x'; MAX_CONTROL_LINE_LENGTH + 1]).await.unwrap();
+        let mut flood_lines = BufReader::new(flood).lines();
+        let response = flood_lines.next_line().await.unwrap().unwrap();
+        assert!(matches!(serde_json::from_str(&response).unwrap(), ControlResponse::Error { .. }));
+        assert_eq!(flood_lines.next_line().await.unwrap(), None);
+        assert!(!token_matches("secret", "secret!") && token_matches("secret", "secret"));
+
+        let stopped = request(r#"{"token": "secret", "command": "shutdown"}"#.to_string()).await;
+        assert_eq!(stopped, ControlResponse::Ok);
+        assert_eq!(shutdown_rx.recv().await, Some(()));
+
+        client.shutdown().await.unwrap();
+        let _ = std::fs::remove_dir_all(root);
+    }
+}
@@ -0,0 +1,237 @@
+use std::fs;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use std::path::Path;
+
+use anyhow::{Context, Error, Result};
+
+/** eMule access levels below this block the range */
+const EMULE_BLOCK_LEVEL: u32 = 128;
+
+/**
+ * Blocked address ranges, kept sorted and merged so a lookup is a binary search.
+ * Rules are read from eMule `ipfilter.dat`, PeerGuardian P2P text and CIDR lists, any of which may be mixed in a file:
+ *   001.002.003.000 - 001.002.003.255 , 000 , Some organization
+ *   Some organization:1.2.3.0-1.2.3.255
+ *   1.2.3.0/24
+ *   2001:db8::/32
+ */
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct RulesRead {
+    pub ranges: usize,
+    /** Lines that couldn't be parsed, lists in the wild often have a few */
+    pub skipped: usize,
+}
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct IpFilter {
+    v4: Vec<(u32, u32)>,
+    v6: Vec<(u128, u128)>,
+}
+
+impl IpFilter {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn from_file(path: impl AsRef<Path>) -> Result<IpFilter> {
+        let path = path.as_ref();
+        let buffer = fs::read(path).context(format!("Failed to read IP filter {}", path.display()))?;
+        let mut filter = IpFilter::new();
+        // Descriptions aren't always UTF-8, only the addresses matter
+        let read = filter.add_rules(&String::from_utf8_lossy(&buffer));
+        if read.ranges == 0 && read.skipped > 0 {
+            return Err(Error::msg(format!("Bad IP filter {}, no line could be parsed", path.display())));
+        }
+        Ok(filter)
+    }
+
+    /** Add every rule of a list, lines that aren't rules are skipped */
+    pub fn add_rules(&mut self, text: &str) -> RulesRead {
+        let mut read = RulesRead::default();
+        for line in text.lines() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') || line.starts_with("//") {
+                continue;
+            }
+            match parse_rule(line).and_then(|rule| rule.map(|(first, last)| self.add_range(first, last)).transpose()) {
+                Ok(Some(())) => read.ranges += 1,
+                Ok(None) => {}
+                Err(_) => read.skipped += 1,
+            }
+        }
+        read
+    }
+
+    pub fn add_range(&mut self, first: IpAddr, last: IpAddr) -> Result<()> {
+        match (first.to_canonical(), last.to_canonical()) {
+            (IpAddr::V4(first), IpAddr::V4(last)) => insert_range(&mut self.v4, (first.into(), last.into())),
+            (IpAddr::V6(first), IpAddr::V6(last)) => insert_range(&mut self.v6, (first.into(), last.into())),
+            _ => return Err(Error::msg("Range mixes IPv4 and IPv6 addresses")),
+        }
+        Ok(())
+    }
+
+    pub fn is_blocked(&self, ip: IpAddr) -> bool {
+        // Dual stack sockets see IPv4 peers as mapped IPv6 addresses
+        match ip.to_canonical() {
+            IpAddr::V4(ip) => contains(&self.v4, u32::from(ip)),
+            IpAddr::V6(ip) => contains(&self.v6, u128::from(ip)),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.v4.is_empty() && self.v6.is_empty()
+    }
+
+    /** Number of disjoint ranges left once overlapping rules are merged */
+    pub fn len(&self) -> usize {
+        self.v4.len() + self.v6.len()
+    }
+}
+
+/** Integer form of an address, so IPv4 and IPv6 ranges are handled alike */
+trait Address: Copy + Ord {
+    /** The next address, saturating at the last one */
+    fn next(self) -> Self;
+}
+
+impl Address for u32 {
+    fn next(self) -> Self {
+        self.saturating_add(1)
+    }
+}
+
+impl Address for u128 {
+    fn next(self) -> Self {
+        self.saturating_add(1)
+    }
+}
+
+/** Keep the ranges sorted and disjoint, merging the new one with those it overlaps or touches */
+fn insert_range<T: Address>(ranges: &mut Vec<(T, T)>, (first, last): (T, T)) {
+    let (mut first, mut last) = (first.min(last), first.max(last));
+    let start = ranges.partition_point(|&(_, end)| end.next() < first);
+    let mut end = start;
+    while end < ranges.len() && ranges[end].0 <= last.next() {
+        first = first.min(ranges[end].0);
+        last = last.max(ranges[end].1);
+        end += 1;
+    }
+    ranges.splice(start..end, [(first, last)]);
+}
+
+fn contains<T: Address>(ranges: &[(T, T)], ip: T) -> bool {
+    let index = ranges.partition_point(|&(_, last)| last < ip);
+    ranges.get(index).is_some_and(|&(first, _)| first <= ip)
+}
+
+/** eMule lists pad IPv4 addresses with zeros, which the standard parser rejects */
+fn parse_ip(text: &str) -> Result<IpAddr> {
+    let text = text.trim();
+    if let Ok(ip) = text.parse::<IpAddr>() {
+        return Ok(ip);
+    }
+    let octets = text
+        .split('.')
+        .map(|octet| octet.parse::<u8>())
+        .collect::<std::result::Result<Vec<_>, _>>()
+        .context(format!("Bad address {:?}", text))?;
+    let octets: [u8; 4] = octets
+        .try_into()
+        .map_err(|_| Error::msg(format!("Bad address {:?}", text)))?;
+    Ok(IpAddr::V4(Ipv4Addr::from(octets)))
+}
+
+fn parse_range(text: &str) -> Result<(IpAddr, IpAddr)> {
+    let (first, last) = text.split_once('-').context("Missing range separator")?;
+    Ok((parse_ip(first)?, parse_ip(last)?))
+}
+
+fn parse_cidr(text: &str) -> Result<(IpAddr, IpAddr)> {
+    let (ip, prefix) = text.split_once('/').context("Missing prefix length")?;
+    let prefix = prefix.trim().parse::<u32>().context("Bad prefix length")?;
+    match parse_ip(ip)? {
+        IpAddr::V4(ip) if prefix <= 32 => {
+            let mask = u32::MAX.checked_shl(32 - prefix).unwrap_or(0);
+            let first = u32::from(ip) & mask;
+            Ok((Ipv4Addr::from(first).into(), Ipv4Addr::from(first | !mask).into()))
+        }
+        IpAddr::V6(ip) if prefix <= 128 => {
+            let mask = u128::MAX.checked_shl(128 - prefix).unwrap_or(0);
+            let first = u128::from(ip) & mask;
+            Ok((Ipv6Addr::from(first).into(), Ipv6Addr::from(first | !mask).into()))
+        }
+        _ => Err(Error::msg(format!("Prefix length {} is too long", prefix))),
+    }
+}
+
+/** A blocked range, `None` for eMule rules allowing the range */
+fn parse_rule(line: &str) -> Result<Option<(IpAddr, IpAddr)>> {
+    // eMule: first - last , access level , description
+    if let Some((range, rest)) = line.split_once(',') {
+        if let Ok(range) = parse_range(range) {
+            let level = rest.split(',').next().unwrap_or_default().trim();
+            let level = level.parse::<u32>().context(format!("Bad access level {:?}", level))?;
+            return Ok((level < EMULE_BLOCK_LEVEL).then_some(range));
+        }
+    }
+    if let Ok(range) = parse_range(line) {
+        return Ok(Some(range));
+    }
+    // PeerGuardian: description:first-last, the description may contain anything but IPv6 ranges contain colons too
+    let ranges = line.match_indices(':').map(|(index, _)| &line[index + 1..]);
+    if let Some(range) = ranges.filter_map(|range| parse_range(range).ok()).next() {
+        return Ok(Some(range));
+    }
+    if line.contains('/') {
+        return parse_cidr(line).map(Some);
+    }
+    let ip = parse_ip(line)?;
+    Ok(Some((ip, ip)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_ip_filter() {
+        let mut filter = IpFilter::new();
+        let rules = "\
+# eMule
+001.002.003.000 - 001.002.003.255 , 000 , Blocked organization
+005.000.000.000 - 005.255.255.255 , 200 , Allowed organization
+// PeerGuardian
+Some: organization, Inc/Ltd:10.0.0.0-10.0.0.9
+IPv6 organization:2001:dba::-2001:dba::ff
+10.0.0.10-10.0.0.20
+192.168.0.0/16
+8.8.8.8
+2001:db8::/32
+not an address
+1.2.3.0/33
+";
+        assert_eq!(filter.add_rules(rules), RulesRead { ranges: 7, skipped: 2 });
+        let blocked = |ip: &str| filter.is_blocked(ip.parse().unwrap());
+
+        assert!(blocked("1.2.3.0") && blocked("1.2.3.255"));
+        assert!(!blocked("1.2.4.0") && !blocked("1.2.2.255"));
+        assert!(!blocked("5.1.1.1"));
+        assert!(blocked("10.0.0.0") && blocked("10.0.0.15") && !blocked("10.0.0.21"));
+        assert!(blocked("192.168.42.1") && !blocked("192.169.0.0"));
+        assert!(blocked("8.8.8.8") && !blocked("8.8.8.9"));
+        assert!(blocked("2001:db8::1") && !blocked("2001:db9::1"));
+        assert!(blocked("2001:dba::ff") && !blocked("2001:dba::100"));
+        // Mapped addresses are looked up as IPv4
+        assert!(blocked("::ffff:1.2.3.4") && !blocked("::ffff:1.2.4.4"));
+
+        // The two adjacent 10.0.0.x rules are merged
+        assert_eq!(filter.len(), 6);
+        filter.add_range("0.0.0.0".parse().unwrap(), "255.255.255.255".parse().unwrap()).unwrap();
+        assert_eq!(filter.len(), 3);
+        assert!(filter.is_blocked("5.1.1.1".parse().unwrap()));
+
+        assert_eq!(IpFilter::new().add_rules("1.2.3.4 - 1.2.3.5 , high , bad level").skipped, 1);
+        assert_eq!(IpFilter::new().add_rules("1.2.3.4-2001:db8::1").skipped, 1);
+    }
+}
@@ -0,0 +1,248 @@
+use std::net::{Ipv4Addr, SocketAddr, SocketAddrV4};
+use std::time::Duration;
+
+use anyhow::{Context, Error, Result};
+use socket2::{Domain, Protocol, Socket, Type};
+use tokio::net::UdpSocket;
+
+use crate::client::{ClientHandle, PeerSource};
+
+/**
+ * Local Service Discovery (BEP 14)
+ * LSD uses the following multicast group: 239.192.152.143:6771 (org-local)
+ * An LSD announce is formatted as follows:
+ *
+ * BT-SEARCH * HTTP/1.1\r\n
+ * Host: <host>\r\n
+ * Port: <port>\r\n
+ * Infohash: <ihash>\r\n
+ * cookie: <cookie (optional)>\r\n
+ * \r\n
+ * \r\n
+ *
+ * host: RFC 2616 section 14.23 and RFC 2732 compliant Host header specifying the multicast group to which the announce is sent. In other words, strings A) or B), as appropriate.
+ * port: port on which the bittorrent client is listening in base-10, ascii
+ * ihash: hex-encoded (40 character) infohash, or 64 character v2 infohash. An announce may contain multiple, consecutive Infohash headers to announce the participation in more than one torrent. This may not be supported by older implementations. When sending multiple infohashes the packet length should not exceed 1400 bytes to avoid MTU/fragmentation problems.
+ * cookie: opaque value, allowing the sending client to filter out its own announces if it receives them via multicast loopback
+ */
+pub const LSD_MULTICAST_ADDR: Ipv4Addr = Ipv4Addr::new(239, 192, 152, 143);
+pub const LSD_PORT: u16 = 6771;
+
+/** Announces should be sent at most once every 5 minutes per torrent */
+pub const LSD_ANNOUNCE_INTERVAL: Duration = Duration::from_secs(5 * 60);
+
+const LSD_MAX_PACKET_LENGTH: usize = 1400;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct LsdAnnounce {
+    pub port: u16,
+    pub info_hashes: Vec<String>,
+    pub cookie: Option<String>,
+}
+
+impl LsdAnnounce {
+    pub fn new(port: u16, info_hashes: Vec<String>, cookie: Option<String>) -> Self {
+        Self {
+            port,
+            info_hashes,
+            cookie,
+        }
+    }
+
+    pub fn to_buffer(&self) -> Vec<u8> {
+        let mut message = format!(
+            "BT-SEARCH * HTTP/1.1\r\nHost: {}:{}\r\nPort: {}\r\n",
+            LSD_MULTICAST_ADDR, LSD_PORT, self.port
+        );
+        for info_hash in &self.info_hashes {
+            message.push_str(&format!("Infohash: {}\r\n", info_hash));
+        }
+        if let Some(cookie) = &self.cookie {
+            message.push_str(&format!("cookie: {}\r\n", cookie));
+        }
+        message.push_str("\r\n\r\n");
+        message.into_bytes()
+    }
+
+    pub fn from_buffer(buffer: &[u8]) -> Result<LsdAnnounce> {
+        let message = std::str::from_utf8(buffer).context("LSD announce is not valid UTF-8")?;
+        let mut lines = message.split("\r\n");
+
+        if lines.next() != Some("BT-SEARCH * HTTP/1.1") {
+            return Err(Error::msg("Not a BT-SEARCH message"));
+        }
+
+        let mut port = None;
+        let mut info_hashes = Vec::new();
+        let mut cookie = None;
+        for line in lines {
+            let Some((name, value)) = line.split_once(':') else {
+                continue;
+            };
+            let value = value.trim();
+            match name.trim().to_ascii_lowercase().as_str() {
+                "port" => port = Some(value.parse::<u16>().context("Bad LSD announce port")?),
+                // Other torrents of the announce are still worth joining when one hash is bad
+                "infohash" if value.chars().all(|c| c.is_ascii_hexdigit()) => match value.len() {
+                    40 => info_hashes.push(value.to_ascii_lowercase()),
+                    // v2 swarms are known by the truncated v2 info hash, like in handshakes and trackers
+                    64 => info_hashes.push(value[..40].to_ascii_lowercase()),
+                    _ => {}
+                },
+                "cookie" => cookie = Some(value.to_string()),
+                _ => {}
+            }
+        }
+
+        Ok(LsdAnnounce {
+            port: port.context("LSD announce is missing a port")?,
+            info_hashes,
+            cookie,
+        })
+    }
+}
+
+#[derive(Debug)]
+pub struct LocalServiceDiscovery {
+    socket: UdpSocket,
+    cookie: String,
+}
+
+impl LocalServiceDiscovery {
+    pub async fn bind() -> Result<Self> {
+        let socket = Socket::new(Domain::IPV4, Type::DGRAM, Some(Protocol::UDP))?;
+        // Other clients on this host are likely listening on the same multicast port
+        socket.set_reuse_address(true)?;
+        socket
+            .bind(&SocketAddrV4::new(Ipv4Addr::UNSPECIFIED, LSD_PORT).into())
+            .context("Failed to bind LSD socket")?;
+        socket
+            .join_multicast_v4(&LSD_MULTICAST_ADDR, &Ipv4Addr::UNSPECIFIED)
+            .context("Failed to join LSD multicast group")?;
+        socket.set_nonblocking(true)?;
+        let socket = UdpSocket::from_std(socket.into())?;
+
+        // Only needs to be unique enough to recognize our own looped back announces
+        let cookie = format!("riffle-{:x}", std::process::id() ^ LSD_PORT as u32);
+
+        Ok(Self { socket, cookie })
+    }
+
+    /** Send an announce for the given info hashes, split across several packets if needed */
+    pub async fn announce(&self, port: u16, info_hashes: &[String]) -> Result<()> {
+        let destination = SocketAddrV4::new(LSD_MULTICAST_ADDR, LSD_PORT);
+        let empty_length = LsdAnnounce::new(port, vec![], Some(self.cookie.clone()))
+            .to_buffer()
+            .len();
+        let info_hash_line_length = "Infohash: \r\n".len() + 40;
+        let info_hashes_per_packet =
+            ((LSD_MAX_PACKET_LENGTH - empty_length) / info_hash_line_length).max(1);
+
+        for chunk in info_hashes.chunks(info_hashes_per_packet) {
+            let announce = LsdAnnounce::new(port, chunk.to_vec(), Some(self.cookie.clone()));
+            self.socket
+                .send_to(&announce.to_buffer(), destination)
+                .await
+                .context("Failed to send LSD announce")?;
+        }
+        Ok(())
+    }
+
+    /** Wait for the next announce coming from another client */
+    pub async fn recv(&self) -> Result<(LsdAnnounce, SocketAddr)> {
+        let mut buffer = [0u8; LSD_MAX_PACKET_LENGTH];
+        loop {
+            let (length, from) = self.socket.recv_from(&mut buffer).await?;
+            let Ok(announce) = LsdAnnounce::from_buffer(&buffer[..length]) else {
+                continue;
+            };
+            if announce.cookie.as_deref() == Some(self.cookie.as_str()) {
+                continue;
+            }
+            let peer_addr = SocketAddr::new(from.ip(), announce.port);
+            return Ok((announce, peer_addr));
+        }
+    }
+}
+
+/** Next announce from another client, never when LSD isn't running */
+pub async fn recv_lsd(lsd: Option<&LocalServiceDiscovery>) -> Option<Result<(LsdAnnounce, SocketAddr)>> {
+    match lsd {
+        Some(lsd) => Some(lsd.recv().await),
+        None => std::future::pending().await,
+    }
+}
+
+/**
+ * Announce the torrents of the session on the LAN and hand it the peers found, until the session shuts down.
+ * The multicast socket is only open while the session has torrents to announce, it has none when LSD isn't allowed.
+ */
+pub async fn run_lsd(client: ClientHandle, listen_port: u16) -> Result<()> {
+    let mut lsd = None;
+    let mut announce_interval = tokio::time::interval(LSD_ANNOUNCE_INTERVAL);
+    loop {
+        tokio::select! {
+            _ = announce_interval.tick() => {
+                let Ok(info_hashes) = client.discoverable_info_hashes(PeerSource::Lsd).await else {
+                    return Ok(());
+                };
+                if info_hashes.is_empty() {
+                    lsd = None;
+                    continue;
+                }
+                if lsd.is_none() {
+                    lsd = Some(LocalServiceDiscovery::bind().await?);
+                }
+                if let Some(lsd) = &lsd {
+                    let _ = lsd.announce(listen_port, &info_hashes).await;
+                }
+            }
+            Some(Ok((announce, addr))) = recv_lsd(lsd.as_ref()) => {
+                for info_hash in announce.info_hashes {
+                    if client.add_peer(&info_hash, addr, PeerSource::Lsd).await.is_err() {
+                        return Ok(());
+                    }
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_lsd_announce() {
+        let announce = LsdAnnounce::new(
+            6881,
+            vec![
+                "a88fda5954e89178c372716a6a78b8180ed4dad3".to_string(),
+                "0123456789abcdef0123456789abcdef01234567".to_string(),
+            ],
+            Some("cookie".to_string()),
+        );
+        let buffer = announce.to_buffer();
+        assert!(buffer.starts_with(b"BT-SEARCH * HTTP/1.1\r\nHost: 239.192.152.143:6771\r\n"));
+        assert!(buffer.ends_with(b"\r\n\r\n\r\n"));
+        assert_eq!(LsdAnnounce::from_buffer(&buffer).unwrap(), announce);
+
+        let foreign = b"BT-SEARCH * HTTP/1.1\r\nHost: 239.192.152.143:6771\r\nPort: 51413\r\nInfohash: A88FDA5954E89178C372716A6A78B8180ED4DAD3\r\n\r\n\r\n";
+        let parsed = LsdAnnounce::from_buffer(foreign).unwrap();
+        assert_eq!(parsed.port, 51413);
+        assert_eq!(parsed.info_hashes, vec!["a88fda5954e89178c372716a6a78b8180ed4dad3"]);
+        assert_eq!(parsed.cookie, None);
+
+        assert!(LsdAnnounce::from_buffer(b"M-SEARCH * HTTP/1.1\r\nPort: 1\r\n\r\n").is_err());
+        assert!(LsdAnnounce::from_buffer(b"BT-SEARCH * HTTP/1.1\r\nInfohash: 00\r\n\r\n").is_err());
+
+        let mixed = format!(
+            "BT-SEARCH * HTTP/1.1\r\nPort: 6881\r\nInfohash: 00\r\nInfohash: {}\r\nInfohash: {}\r\nInfohash: {}\r\n\r\n\r\n",
+            "z".repeat(40),
+            "A".repeat(64),
+            "a88fda5954e89178c372716a6a78b8180ed4dad3"
+        );
+        let parsed = LsdAnnounce::from_buffer(mixed.as_bytes()).unwrap();
+        assert_eq!(parsed.info_hashes, vec!["a".repeat(40), "a88fda5954e89178c372716a6a78b8180ed4dad3".to_string()]);
+    }
+}
@@ -0,0 +1,104 @@
+use anyhow::{Context, Error, Result};
+use urlencoding::decode;
+
+/**
+ * Magnet link (BEP 9)
+ * magnet:?xt=urn:btih:<info-hash>&dn=<name>&tr=<tracker-url>&x.pe=<peer-address>
+ *
+ * The info hash is 40 hex characters or 32 base32 characters.
+ * The torrent itself has to be downloaded from peers before anything else can happen.
+ */
+#[derive(Debug, Clone, PartialEq)]
+pub struct MagnetLink {
+    /** Hex encoded */
+    pub info_hash: String,
+    pub name: Option<String>,
+    pub trackers: Vec<String>,
+    pub peers: Vec<String>,
+}
+
+impl MagnetLink {
+    pub fn parse(uri: &str) -> Result<MagnetLink> {
+        let query = uri
+            .strip_prefix("magnet:?")
+            .context(format!("{:?} is not a magnet link", uri))?;
+        let mut info_hash = None;
+        let mut name = None;
+        let mut trackers = vec![];
+        let mut peers = vec![];
+        for parameter in query.split('&').filter(|parameter| !parameter.is_empty()) {
+            let (key, value) = parameter.split_once('=').unwrap_or((parameter, ""));
+            let value = decode(value)
+                .context(format!("Bad magnet parameter {:?}", parameter))?
+                .into_owned();
+            match key {
+                "xt" => {
+                    if let Some(hash) = value.strip_prefix("urn:btih:") {
+                        info_hash = Some(parse_info_hash(hash)?);
+                    }
+                }
+                "dn" => name = Some(value),
+                "tr" => trackers.push(value),
+                "x.pe" => peers.push(value),
+                _ => {}
+            }
+        }
+        Ok(MagnetLink {
+            info_hash: info_hash.context("Magnet link has no BitTorrent info hash")?,
+            name,
+            trackers,
+            peers,
+        })
+    }
+}
+
+fn parse_info_hash(hash: &str) -> Result<String> {
+    match hash.len() {
+        40 if hash.chars().all(|c| c.is_ascii_hexdigit()) => Ok(hash.to_ascii_lowercase()),
+        32 => Ok(hex::encode(decode_base32(hash)?)),
+        _ => Err(Error::msg(format!("Bad magnet info hash {:?}", hash))),
+    }
+}
+
+/** RFC 4648 base32, without padding */
+fn decode_base32(text: &str) -> Result<Vec<u8>> {
+    let mut bytes = vec![];
+    let (mut buffer, mut bits) = (0u64, 0);
+    for c in text.chars() {
+        let value = match c.to_ascii_uppercase() {
+            c @ 'A'..='Z' => c as u64 - 'A' as u64,
+            c @ '2'..='7' => c as u64 - '2' as u64 + 26,
+            _ => return Err(Error::msg(format!("Bad base32 character {:?}", c))),
+        };
+        buffer = (buffer << 5) | value;
+        bits += 5;
+        if bits >= 8 {
+            bits -= 8;
+            bytes.push((buffer >> bits) as u8);
+        }
+    }
+    Ok(bytes)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_magnet_link() {
+        let magnet = MagnetLink::parse(
+            "magnet:?xt=urn:btih:08ADA5A7A6183AAE1E09D831DF6748D566095A10&dn=Sintel&tr=udp%3A%2F%2Fexplodie.org%3A6969&tr=wss%3A%2F%2Ftracker.btorrent.xyz",
+        )
+        .unwrap();
+        assert_eq!(magnet.info_hash, "08ada5a7a6183aae1e09d831df6748d566095a10");
+        assert_eq!(magnet.name.as_deref(), Some("Sintel"));
+        assert_eq!(magnet.trackers, vec!["udp://explodie.org:6969", "wss://tracker.btorrent.xyz"]);
+
+        let base32 = MagnetLink::parse("magnet:?xt=urn:btih:BCW2LJ5GDA5K4HQJ3AY56Z2I2VTASWQQ").unwrap();
+        assert_eq!(base32.info_hash, magnet.info_hash);
+
+        assert!(MagnetLink::parse("magnet:?dn=nothing").is_err());
+        assert!(MagnetLink::parse("magnet:?xt=urn:btih:1234").is_err());
+        assert!(MagnetLink::parse("http://example.com").is_err());
+    }
+}
@@ -1,25 +1,65 @@
+#![allow(dead_code)]
+
 #[macro_use]
 extern crate serde_derive;
 
 use anyhow::Result;
+use clap::Parser;
 
+mod bencode;
 mod bitfield;
 mod client;
+mod connection;
+mod lsd;
+mod merkle;
 mod meta_info;
+mod meta_info_builder;
 mod peer;
+mod piece;
 mod torrent;
 mod tracker;
 mod tui;
 mod utils;
+mod web_seed;
 mod piece_picker;
+mod rate_limit;
+mod resume;
+mod storage;
+mod ip_filter;
+mod smart_ban;
+mod mse;
+mod utp;
+mod proxy;
+mod network;
+mod magnet;
+mod config;
+mod daemon;
 
+use config::{Cli, Config};
 use tui::{initialize_panic_handler, run, shutdown, startup};
 
 #[tokio::main]
 async fn main() -> Result<()> {
+    // Configuration errors are reported before the terminal is taken over
+    let cli = Cli::parse();
+    if let Some((builder, output)) = cli.torrent_to_create() {
+        let meta_info = builder.write(&output)?;
+        println!("Created {} with info hash {}", output.display(), meta_info.to_info_hash());
+        return Ok(());
+    }
+    let config = match Config::load(&cli) {
+        Ok(config) => config,
+        Err(error) => {
+            eprintln!("riffle: {:#}", error);
+            std::process::exit(2);
+        }
+    };
+    if config.headless {
+        return daemon::run(&cli, config).await;
+    }
     initialize_panic_handler();
     startup()?;
-    run().await?;
+    let result = run(config).await;
     shutdown()?;
-    Ok(())
+    result
 }
@@ -0,0 +1,145 @@
+use sha2::{Digest, Sha256};
+
+/**
+ * BitTorrent v2 (BEP 52) hashes each file separately with a merkle tree of SHA-256 hashes.
+ * The leaves are the hashes of the 16 KiB blocks of the file, the last block may be shorter.
+ * The remaining leaf hashes beyond the end of the file required to construct upper layers of the merkle tree are set to zero.
+ * The "piece layer" is the layer of the tree where each node covers exactly one piece.
+ */
+pub const MERKLE_BLOCK_SIZE: usize = 16 * 1024;
+
+pub type Hash = [u8; 32];
+
+pub fn hash_pair(left: &Hash, right: &Hash) -> Hash {
+    let mut hasher = Sha256::new();
+    hasher.update(left);
+    hasher.update(right);
+    hasher.finalize().into()
+}
+
+pub fn block_hash(data: &[u8]) -> Hash {
+    Sha256::digest(data).into()
+}
+
+pub fn block_hashes(data: &[u8]) -> Vec<Hash> {
+    data.chunks(MERKLE_BLOCK_SIZE).map(block_hash).collect()
+}
+
+/** Root of a subtree of the given height where every leaf is zero */
+pub fn pad_hash(height: u32) -> Hash {
+    (0..height).fold([0u8; 32], |hash, _| hash_pair(&hash, &hash))
+}
+
+/** Root of the tree built on top of `layer`, padded to `width` nodes with `pad` */
+pub fn root(layer: &[Hash], width: usize, pad: Hash) -> Hash {
+    let mut layer = layer.to_vec();
+    layer.resize(width.max(layer.len()).max(1).next_power_of_two(), pad);
+    while layer.len() > 1 {
+        layer = layer
+            .chunks(2)
+            .map(|pair| hash_pair(&pair[0], &pair[1]))
+            .collect();
+    }
+    layer[0]
+}
+
+/** The `pieces root` of a whole file */
+pub fn file_root(data: &[u8]) -> Hash {
+    let leaves = block_hashes(data);
+    root(&leaves, leaves.len(), [0u8; 32])
+}
+
+fn blocks_per_piece(piece_length: usize) -> usize {
+    (piece_length / MERKLE_BLOCK_SIZE).max(1)
+}
+
+/** Hash of the subtree covering one piece, the last piece of a file is padded with zero leaves */
+pub fn piece_hash(data: &[u8], piece_length: usize) -> Hash {
+    root(&block_hashes(data), blocks_per_piece(piece_length), [0u8; 32])
+}
+
+/** Hashes of each piece of a file, as found in the `piece layers` dictionary */
+pub fn piece_layer(data: &[u8], piece_length: usize) -> Vec<Hash> {
+    data.chunks(piece_length)
+        .map(|piece| piece_hash(piece, piece_length))
+        .collect()
+}
+
+/** Root of a file computed from its piece layer */
+pub fn root_from_piece_layer(piece_layer: &[Hash], piece_length: usize) -> Hash {
+    let height = blocks_per_piece(piece_length).trailing_zeros();
+    root(piece_layer, piece_layer.len(), pad_hash(height))
+}
+
+/**
+ * Check a node against a root given its index in its layer and the uncle hashes going up, closest first.
+ * This is how the hashes carried by the hashes message are verified.
+ */
+pub fn verify_proof(hash: Hash, index: usize, proof: &[Hash], expected_root: &Hash) -> bool {
+    let (computed, _) = proof.iter().fold((hash, index), |(hash, index), uncle| {
+        if index % 2 == 0 {
+            (hash_pair(&hash, uncle), index / 2)
+        } else {
+            (hash_pair(uncle, &hash), index / 2)
+        }
+    });
+    &computed == expected_root
+}
+
+/** Verify one block of a piece using the hashes of the other blocks of the piece */
+pub fn verify_block(
+    data: &[u8],
+    block_index: usize,
+    proof: &[Hash],
+    piece_hash: &Hash,
+) -> bool {
+    data.len() <= MERKLE_BLOCK_SIZE && verify_proof(block_hash(data), block_index, proof, piece_hash)
+}
+
+/** Uncle hashes of a leaf, closest first, used to build hashes messages */
+pub fn proof(leaves: &[Hash], index: usize, pad: Hash) -> Vec<Hash> {
+    let mut layer = leaves.to_vec();
+    layer.resize(layer.len().max(1).next_power_of_two(), pad);
+    let mut index = index;
+    let mut proof = Vec::new();
+    while layer.len() > 1 {
+        proof.push(layer[index ^ 1]);
+        layer = layer
+            .chunks(2)
+            .map(|pair| hash_pair(&pair[0], &pair[1]))
+            .collect();
+        index /= 2;
+    }
+    proof
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_merkle() {
+        let piece_length = 2 * MERKLE_BLOCK_SIZE;
+        let data = (0..5 * MERKLE_BLOCK_SIZE + 100)
+            .map(|x| (x % 251) as u8)
+            .collect::<Vec<_>>();
+
+        let layer = piece_layer(&data, piece_length);
+        assert_eq!(layer.len(), 3);
+        assert_eq!(root_from_piece_layer(&layer, piece_length), file_root(&data));
+
+        let leaves = block_hashes(&data);
+        assert_eq!(leaves.len(), 6);
+        let root_hash = file_root(&data);
+        for (index, leaf) in leaves.iter().enumerate() {
+            assert!(verify_proof(*leaf, index, &proof(&leaves, index, [0; 32]), &root_hash));
+        }
+
+        let piece = &data[piece_length..2 * piece_length];
+        let piece_leaves = block_hashes(piece);
+        let block = &piece[MERKLE_BLOCK_SIZE..];
+        assert!(verify_block(block, 1, &proof(&piece_leaves, 1, [0; 32]), &layer[1]));
+        assert!(!verify_block(block, 0, &proof(&piece_leaves, 1, [0; 32]), &layer[1]));
+        assert!(!verify_block(&block[1..], 1, &proof(&piece_leaves, 1, [0; 32]), &layer[1]));
+    }
+}
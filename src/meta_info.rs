@@ -1,28 +1,190 @@
 use anyhow::{Context, Result};
+use encoding_rs::Encoding;
+use thiserror::Error;
 use sha1::{Digest, Sha1};
+use sha2::Sha256;
 
 use serde_bencode::de;
 use serde_bencode::ser;
+use serde_bencode::value::Value;
 use serde_bytes::ByteBuf;
+use std::collections::BTreeMap;
+use std::ffi::OsString;
 use std::io::Read;
+use std::path::PathBuf;
 use urlencoding::encode_binary;
 
+use crate::bencode;
+use crate::merkle::{self, Hash};
+
 #[derive(Debug, Clone, Deserialize, Serialize)]
 pub struct Node(String, i64);
 
-#[derive(Debug, Clone, Deserialize, Serialize)]
+#[derive(Debug, Clone, Default, Deserialize, Serialize)]
 pub struct File {
-    pub path: Vec<String>,
+    // Raw bytes, names aren't always valid UTF-8
+    pub path: Vec<ByteBuf>,
     pub length: i64,
     #[serde(default)]
     pub md5sum: Option<String>,
+    #[serde(default)]
+    #[serde(rename = "path.utf-8")]
+    pub path_utf8: Option<Vec<ByteBuf>>,
+    // BEP 47
+    #[serde(default)]
+    pub attr: Option<ByteBuf>,
+    #[serde(default)]
+    #[serde(rename = "symlink path")]
+    pub symlink_path: Option<Vec<ByteBuf>>,
+    #[serde(default)]
+    pub sha1: Option<ByteBuf>,
+    // Keys we don't know about, dropping them would change the info hash once the info is re-serialized
+    #[serde(flatten)]
+    pub extra: BTreeMap<String, Value>,
+}
+
+/**
+ * BEP 47 file attributes, each one a character of the `attr` string:
+ * p: padding file, its content is zeros and it is never written to disk
+ * x: executable bit set
+ * h: hidden file
+ * l: symlink, the target is in `symlink path`
+ */
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct FileAttributes {
+    pub padding: bool,
+    pub executable: bool,
+    pub hidden: bool,
+    pub symlink: bool,
+}
+
+impl FileAttributes {
+    pub fn parse(attr: &[u8]) -> Self {
+        // Unknown attributes must be ignored
+        FileAttributes {
+            padding: attr.contains(&b'p'),
+            executable: attr.contains(&b'x'),
+            hidden: attr.contains(&b'h'),
+            symlink: attr.contains(&b'l'),
+        }
+    }
+}
+
+impl File {
+    pub fn attributes(&self) -> FileAttributes {
+        FileAttributes::parse(self.attr.as_deref().map_or(&[], |attr| attr.as_slice()))
+    }
+
+    /** `path.utf-8` is preferred when it is present and valid */
+    pub fn path_components(&self) -> &[ByteBuf] {
+        match &self.path_utf8 {
+            Some(path) if path.iter().all(|component| std::str::from_utf8(component).is_ok()) => path,
+            _ => &self.path,
+        }
+    }
+}
+
+/** Decode a name from the torrent, using the declared `encoding` when it isn't valid UTF-8 */
+pub fn decode_name(bytes: &[u8], encoding: Option<&str>) -> String {
+    if let Ok(name) = std::str::from_utf8(bytes) {
+        return name.to_string();
+    }
+    if let Some(encoding) = encoding.and_then(|label| Encoding::for_label(label.as_bytes())) {
+        let (decoded, _, had_errors) = encoding.decode(bytes);
+        if !had_errors {
+            return decoded.into_owned();
+        }
+    }
+    String::from_utf8_lossy(bytes).into_owned()
+}
+
+/**
+ * Name of a file or directory on disk.
+ * Names that can't be decoded are kept as raw bytes on unix so nothing is lost.
+ */
+pub fn decode_path_component(bytes: &[u8], encoding: Option<&str>) -> OsString {
+    if let Ok(name) = std::str::from_utf8(bytes) {
+        return OsString::from(name);
+    }
+    if let Some(encoding) = encoding.and_then(|label| Encoding::for_label(label.as_bytes())) {
+        let (decoded, _, had_errors) = encoding.decode(bytes);
+        if !had_errors {
+            return OsString::from(decoded.into_owned());
+        }
+    }
+    #[cfg(unix)]
+    {
+        <std::ffi::OsStr as std::os::unix::ffi::OsStrExt>::from_bytes(bytes).to_os_string()
+    }
+    #[cfg(not(unix))]
+    {
+        OsString::from(String::from_utf8_lossy(bytes).into_owned())
+    }
+}
+
+/** A file of a v2 `file tree`, stored under an empty key in the dictionary named after the file */
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct FileTreeFile {
+    pub length: i64,
+    // Absent for empty files
+    #[serde(default)]
+    #[serde(rename = "pieces root")]
+    pub pieces_root: Option<ByteBuf>,
 }
 
-#[allow(dead_code)]
 #[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(untagged)]
+pub enum FileTreeNode {
+    File(FileTreeFile),
+    Directory(BTreeMap<String, FileTreeNode>),
+}
+
+/** A v2 file flattened out of the `file tree` */
+#[derive(Debug, Clone, PartialEq)]
+pub struct V2File {
+    pub path: Vec<String>,
+    pub length: u64,
+    pub pieces_root: Option<Hash>,
+}
+
+fn flatten_file_tree(tree: &BTreeMap<String, FileTreeNode>, path: &mut Vec<String>, files: &mut Vec<V2File>) {
+    for (name, node) in tree {
+        match node {
+            FileTreeNode::File(file) if name.is_empty() => files.push(V2File {
+                path: path.clone(),
+                length: file.length as u64,
+                pieces_root: file
+                    .pieces_root
+                    .as_ref()
+                    .and_then(|root| <Hash>::try_from(root.as_slice()).ok()),
+            }),
+            FileTreeNode::File(_) => {}
+            FileTreeNode::Directory(tree) => {
+                path.push(name.clone());
+                flatten_file_tree(tree, path, files);
+                path.pop();
+            }
+        }
+    }
+}
+
+/** A contiguous byte range of a single file, as covered by a piece */
+#[derive(Debug, Clone, PartialEq)]
+pub struct FileSlice {
+    pub file_index: usize,
+    pub offset: u64,
+    pub length: u64,
+}
+
+#[allow(dead_code)]
+#[derive(Debug, Clone, Default, Deserialize, Serialize)]
 pub struct Info {
-    pub name: String,
-    // 20 bytes per SHA1 hash
+    pub name: ByteBuf,
+    #[serde(default)]
+    #[serde(rename = "name.utf-8")]
+    pub name_utf8: Option<ByteBuf>,
+    // 20 bytes per SHA1 hash, absent from v2 only torrents
+    #[serde(default)]
     pub pieces: ByteBuf,
     #[serde(rename = "piece length")]
     pub piece_length: i64,
@@ -39,11 +201,107 @@ pub struct Info {
     #[serde(default)]
     #[serde(rename = "root hash")]
     pub root_hash: Option<String>,
+    #[serde(default)]
+    #[serde(rename = "meta version")]
+    pub meta_version: Option<i64>,
+    #[serde(default)]
+    #[serde(rename = "file tree")]
+    pub file_tree: Option<BTreeMap<String, FileTreeNode>>,
+    // BEP 47 attributes of single file torrents
+    #[serde(default)]
+    pub attr: Option<ByteBuf>,
+    #[serde(default)]
+    #[serde(rename = "symlink path")]
+    pub symlink_path: Option<Vec<ByteBuf>>,
+    #[serde(default)]
+    pub sha1: Option<ByteBuf>,
+    // Keys we don't know about (source, ...), kept so re-serializing doesn't change the info hash
+    #[serde(flatten)]
+    pub extra: BTreeMap<String, Value>,
+    /**
+     * Exact bytes of the info dictionary as found in the metainfo file, hashed instead of re-serializing the struct.
+     * Paired with the digest of the struct as it was parsed, so it's ignored once any field is modified.
+     */
+    #[serde(skip)]
+    buffer: Option<(ByteBuf, [u8; 20])>,
 }
 
 impl Info {
     pub fn to_buffer(&self) -> Result<Vec<u8>> {
-        ser::to_bytes(self).context("Failed to serialize info")
+        let serialized = ser::to_bytes(self).context("Failed to serialize info")?;
+        match &self.buffer {
+            Some((buffer, digest)) if Sha1::digest(&serialized).as_slice() == digest => Ok(buffer.to_vec()),
+            _ => Ok(serialized),
+        }
+    }
+
+    /** Raw bytes are only kept while the struct still serializes the way it did when they were parsed */
+    fn set_buffer(&mut self, buffer: &[u8]) -> Result<()> {
+        let serialized = ser::to_bytes(&*self).context("Failed to serialize info")?;
+        self.buffer = Some((ByteBuf::from(buffer), Sha1::digest(serialized).into()));
+        Ok(())
+    }
+
+    /** `name.utf-8` is preferred when it is present and valid */
+    pub fn name_bytes(&self) -> &[u8] {
+        match &self.name_utf8 {
+            Some(name) if std::str::from_utf8(name).is_ok() => name,
+            _ => &self.name,
+        }
+    }
+
+    pub fn name(&self, encoding: Option<&str>) -> String {
+        decode_name(self.name_bytes(), encoding)
+    }
+
+    /** Path of a file relative to the download directory, the torrent name is the directory of multi-file torrents */
+    pub fn file_path(&self, file_index: usize, encoding: Option<&str>) -> PathBuf {
+        let mut path = PathBuf::from(decode_path_component(self.name_bytes(), encoding));
+        if let Some(files) = &self.files {
+            for component in files[file_index].path_components() {
+                path.push(decode_path_component(component, encoding));
+            }
+        }
+        path
+    }
+
+    pub fn file_attributes(&self, file_index: usize) -> FileAttributes {
+        match &self.files {
+            Some(files) => files[file_index].attributes(),
+            None => FileAttributes::parse(self.attr.as_deref().map_or(&[], |attr| attr.as_slice())),
+        }
+    }
+
+    /** Symlink target relative to the torrent root, the same way paths are */
+    pub fn symlink_target(&self, file_index: usize, encoding: Option<&str>) -> Option<PathBuf> {
+        if !self.file_attributes(file_index).symlink {
+            return None;
+        }
+        // The root of single file torrents is the download directory itself
+        let (mut path, symlink_path) = match &self.files {
+            Some(files) => (
+                PathBuf::from(decode_path_component(self.name_bytes(), encoding)),
+                files.get(file_index)?.symlink_path.as_ref()?,
+            ),
+            None => (PathBuf::new(), self.symlink_path.as_ref()?),
+        };
+        for component in symlink_path {
+            path.push(decode_path_component(component, encoding));
+        }
+        Some(path)
+    }
+
+    /** BEP 47 SHA-1 of the whole content of a file, when the torrent has one */
+    pub fn file_sha1(&self, file_index: usize) -> Option<&[u8]> {
+        let sha1 = match &self.files {
+            Some(files) => files.get(file_index)?.sha1.as_ref()?,
+            None => self.sha1.as_ref()?,
+        };
+        Some(sha1.as_slice()).filter(|sha1| sha1.len() == 20)
+    }
+
+    pub fn clear_buffer(&mut self) {
+        self.buffer = None;
     }
 
     pub fn to_hash_buffer(&self) -> Result<Vec<u8>> {
@@ -58,19 +316,212 @@ impl Info {
         hex::encode(buffer)
     }
 
+    /** v2 info hash, SHA-256 of the info dictionary */
+    pub fn to_v2_hash_buffer(&self) -> Result<Vec<u8>> {
+        let buffer = self.to_buffer()?;
+        Ok(Sha256::digest(buffer).to_vec())
+    }
+
+    /**
+     * 20 byte info hashes of the swarms this torrent can join, v1 first.
+     * v2 swarms are identified by the v2 info hash truncated to 20 bytes in trackers, DHT and handshakes.
+     */
+    pub fn swarm_hashes(&self) -> Result<Vec<Vec<u8>>> {
+        let mut hashes = Vec::new();
+        if self.is_v1() {
+            hashes.push(self.to_hash_buffer()?);
+        }
+        if self.is_v2() {
+            hashes.push(self.to_v2_hash_buffer()?[..20].to_vec());
+        }
+        Ok(hashes)
+    }
+
+    /** Hex info hash identifying the torrent, the v1 one for hybrid torrents */
     pub fn to_hash(&self) -> String {
-        self.to_hash_hex()
+        match self.swarm_hashes().unwrap().first() {
+            Some(hash) => hex::encode(hash),
+            None => self.to_hash_hex(),
+        }
+    }
+
+    pub fn is_v1(&self) -> bool {
+        self.meta_version.is_none() || !self.pieces.is_empty()
+    }
+
+    pub fn is_v2(&self) -> bool {
+        self.meta_version == Some(2) && self.file_tree.is_some()
+    }
+
+    pub fn is_hybrid(&self) -> bool {
+        self.is_v1() && self.is_v2()
+    }
+
+    pub fn v2_files(&self) -> Vec<V2File> {
+        let mut files = Vec::new();
+        if let Some(tree) = &self.file_tree {
+            flatten_file_tree(tree, &mut vec![], &mut files);
+        }
+        files
     }
 
     pub fn to_url_encoded(&self) -> Result<String> {
-        let info_hash_buffer = self.to_buffer().context("Failed to get info hash")?;
+        let info_hash_buffer = self.to_hash_buffer().context("Failed to get info hash")?;
         Ok(encode_binary(&info_hash_buffer).to_string())
     }
 
+    pub fn is_private(&self) -> bool {
+        self.private == Some(1)
+    }
+
     pub fn pieces_count(&self) -> usize {
+        if !self.is_v1() {
+            // v2 pieces never span several files
+            return self
+                .v2_files()
+                .iter()
+                .map(|file| file.length.div_ceil(self.piece_length as u64) as usize)
+                .sum();
+        }
         // 20 bytes per SHA1 hash
         self.pieces.len() / 20
     }
+
+    pub fn piece_hash(&self, index: usize) -> &[u8] {
+        &self.pieces[index * 20..(index + 1) * 20]
+    }
+
+    pub fn verify_piece(&self, index: usize, data: &[u8]) -> bool {
+        let mut hasher = Sha1::new();
+        hasher.update(data);
+        hasher.finalize().as_slice() == self.piece_hash(index)
+    }
+
+    /** Lengths of the files in the order they are laid out in the torrent, single file torrents have one file */
+    pub fn file_lengths(&self) -> Vec<u64> {
+        match (&self.files, self.length) {
+            (Some(files), _) => files.iter().map(|file| file.length as u64).collect(),
+            (None, Some(length)) => vec![length as u64],
+            (None, None) => self.v2_files().iter().map(|file| file.length).collect(),
+        }
+    }
+
+    pub fn total_length(&self) -> u64 {
+        self.file_lengths().iter().sum()
+    }
+
+    /** The last piece is usually shorter than the others */
+    pub fn piece_size(&self, index: usize) -> u64 {
+        let piece_length = self.piece_length as u64;
+        let offset = index as u64 * piece_length;
+        piece_length.min(self.total_length().saturating_sub(offset))
+    }
+
+    /** Map a byte range of the torrent to the files it spans */
+    pub fn file_slices(&self, offset: u64, length: u64) -> Vec<FileSlice> {
+        let end = offset + length;
+        let mut slices = Vec::new();
+        let mut file_start = 0;
+        for (file_index, file_length) in self.file_lengths().into_iter().enumerate() {
+            let file_end = file_start + file_length;
+            if file_end > offset && file_start < end && file_length > 0 {
+                let slice_start = offset.max(file_start);
+                let slice_end = end.min(file_end);
+                slices.push(FileSlice {
+                    file_index,
+                    offset: slice_start - file_start,
+                    length: slice_end - slice_start,
+                });
+            }
+            if file_end >= end {
+                break;
+            }
+            file_start = file_end;
+        }
+        slices
+    }
+
+    pub fn piece_file_slices(&self, index: usize) -> Vec<FileSlice> {
+        self.file_slices(index as u64 * self.piece_length as u64, self.piece_size(index))
+    }
+}
+
+/** The `url-list` key may either be a single URL or a list of them */
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(untagged)]
+pub enum UrlList {
+    Single(String),
+    List(Vec<String>),
+}
+
+/** Everything that makes a metainfo file invalid, surfaced to users when a torrent is rejected */
+#[derive(Debug, Clone, PartialEq, Error)]
+pub enum MetaInfoError {
+    #[error("failed to parse meta info: {0}")]
+    Parse(String),
+    #[error("meta info has no info dictionary")]
+    MissingInfo,
+    #[error("piece length must be positive, got {0}")]
+    InvalidPieceLength(i64),
+    #[error("v2 piece length must be a power of two of at least 16 KiB, got {0}")]
+    InvalidV2PieceLength(i64),
+    #[error("pieces is {0} bytes long, which is not a multiple of 20")]
+    PiecesNotMultipleOf20(usize),
+    #[error("expected {expected} piece hashes, found {found}")]
+    PiecesCountMismatch { expected: usize, found: usize },
+    #[error("info has both `length` and `files`")]
+    LengthAndFiles,
+    #[error("info has neither `length` nor `files`")]
+    MissingLength,
+    #[error("info has an empty `files` list")]
+    NoFiles,
+    #[error("negative length {length} for file {file_index}")]
+    NegativeLength { file_index: usize, length: i64 },
+    #[error("torrent name is empty")]
+    EmptyName,
+    #[error("file {0} has an empty path")]
+    EmptyPath(usize),
+    #[error("file {0} has an empty path component")]
+    EmptyPathComponent(usize),
+    #[error("file {0} is a symlink without a `symlink path`")]
+    MissingSymlinkPath(usize),
+    #[error("path component {0:?} could escape the download directory")]
+    UnsafePathComponent(String),
+    #[error("unsupported meta version {0}")]
+    UnsupportedMetaVersion(i64),
+    #[error("piece layers don't match the files pieces root")]
+    InvalidPieceLayers,
+}
+
+impl MetaInfoError {
+    /** Violations that even lenient mode can't work around */
+    pub fn is_fatal(&self) -> bool {
+        matches!(
+            self,
+            MetaInfoError::Parse(_)
+                | MetaInfoError::MissingInfo
+                | MetaInfoError::InvalidPieceLength(_)
+                | MetaInfoError::NegativeLength { .. }
+                | MetaInfoError::PiecesNotMultipleOf20(_)
+                | MetaInfoError::PiecesCountMismatch { .. }
+                | MetaInfoError::LengthAndFiles
+                | MetaInfoError::MissingLength
+                | MetaInfoError::UnsafePathComponent(_)
+                | MetaInfoError::UnsupportedMetaVersion(_)
+        )
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ValidationMode {
+    /** Any violation rejects the torrent */
+    Strict,
+    /** Only fatal violations reject the torrent, the others are returned as warnings */
+    Lenient,
+}
+
+fn is_unsafe_path_component(component: &[u8]) -> bool {
+    component == b"." || component == b".." || component.iter().any(|byte| matches!(byte, b'/' | b'\\' | b'\0'))
 }
 
 #[derive(Debug, Clone, Deserialize, Serialize)]
@@ -85,6 +536,9 @@ pub struct MetaInfo {
     #[serde(default)]
     pub httpseeds: Option<Vec<String>>,
     #[serde(default)]
+    #[serde(rename = "url-list")]
+    pub url_list: Option<UrlList>,
+    #[serde(default)]
     #[serde(rename = "announce-list")]
     pub announce_list: Option<Vec<Vec<String>>>,
     #[serde(default)]
@@ -95,13 +549,38 @@ pub struct MetaInfo {
     #[serde(default)]
     #[serde(rename = "created by")]
     pub created_by: Option<String>,
+    // Keyed by `pieces root`, concatenated SHA-256 hashes of the pieces of each file larger than a piece
+    #[serde(default)]
+    #[serde(rename = "piece layers")]
+    pub piece_layers: Option<BTreeMap<ByteBuf, ByteBuf>>,
 }
 
 impl MetaInfo {
+    /** The info dictionary is always the exact bytes that are hashed, written back byte for byte when it was parsed from a file */
+    pub fn to_buffer(&self) -> Result<Vec<u8>> {
+        let mut buffer = ser::to_bytes(self).context("Failed to serialize meta info")?;
+        let span = bencode::dict_value_span(&buffer, b"info")?
+            .context("Serialized meta info has no info dictionary")?;
+        buffer.splice(span, self.info.to_buffer()?);
+        Ok(buffer)
+    }
+
     pub fn to_info_hash(&self) -> String {
         self.info.to_hash()
     }
 
+    /** Announce urls of every tier, the main one first */
+    pub fn announce_urls(&self) -> Vec<String> {
+        let mut urls = Vec::new();
+        let tiers = self.announce_list.iter().flatten().flatten();
+        for url in self.announce.iter().chain(tiers) {
+            if !urls.contains(url) {
+                urls.push(url.clone());
+            }
+        }
+        urls
+    }
+
     pub fn tracker_urls(&self) -> Vec<String> {
         let mut urls = Vec::new();
         if let Some(announce) = &self.announce {
@@ -115,28 +594,488 @@ impl MetaInfo {
         urls
     }
 
-    pub fn from_buffer(buffer: &Vec<u8>) -> Result<MetaInfo> {
-        de::from_bytes::<MetaInfo>(&buffer).context("Failed to parse meta info buffer")
+    /** GetRight style web seeds (BEP 19) */
+    pub fn url_list(&self) -> Vec<String> {
+        match &self.url_list {
+            Some(UrlList::Single(url)) => vec![url.clone()],
+            Some(UrlList::List(urls)) => urls.clone(),
+            None => vec![],
+        }
+        .into_iter()
+        .filter(|url| !url.is_empty())
+        .collect()
     }
 
-    pub fn from_file(str: &str) -> Result<MetaInfo> {
-        let mut file = std::fs::File::open(str)?;
+    /** Hoffman style web seeds (BEP 17) */
+    pub fn http_seeds(&self) -> Vec<String> {
+        self.httpseeds.clone().unwrap_or_default()
+    }
+
+    pub fn piece_layer(&self, pieces_root: &Hash) -> Option<Vec<Hash>> {
+        let layer = self
+            .piece_layers
+            .as_ref()?
+            .get(&ByteBuf::from(pieces_root.to_vec()))?;
+        layer
+            .chunks(32)
+            .map(|hash| <Hash>::try_from(hash).ok())
+            .collect()
+    }
+
+    /** Check that every v2 file larger than a piece has a piece layer matching its `pieces root` */
+    pub fn verify_piece_layers(&self) -> bool {
+        let piece_length = self.info.piece_length as usize;
+        self.info.v2_files().iter().all(|file| {
+            match file.pieces_root {
+                Some(pieces_root) if file.length > piece_length as u64 => self
+                    .piece_layer(&pieces_root)
+                    .is_some_and(|layer| merkle::root_from_piece_layer(&layer, piece_length) == pieces_root),
+                _ => true,
+            }
+        })
+    }
+
+    /** Every violation of the metainfo format, in the order they are checked */
+    pub fn violations(&self) -> Vec<MetaInfoError> {
+        let info = &self.info;
+        let mut violations = Vec::new();
+
+        if info.piece_length <= 0 {
+            violations.push(MetaInfoError::InvalidPieceLength(info.piece_length));
+        }
+        if let Some(meta_version) = info.meta_version {
+            if meta_version != 2 {
+                violations.push(MetaInfoError::UnsupportedMetaVersion(meta_version));
+            } else if info.piece_length < merkle::MERKLE_BLOCK_SIZE as i64
+                || !(info.piece_length as u64).is_power_of_two()
+            {
+                violations.push(MetaInfoError::InvalidV2PieceLength(info.piece_length));
+            }
+        }
+
+        if info.name.is_empty() {
+            violations.push(MetaInfoError::EmptyName);
+        }
+        for name in std::iter::once(&info.name).chain(&info.name_utf8) {
+            if is_unsafe_path_component(name) {
+                violations.push(MetaInfoError::UnsafePathComponent(
+                    String::from_utf8_lossy(name).into_owned(),
+                ));
+            }
+        }
+
+        if info.is_v1() {
+            if !info.pieces.len().is_multiple_of(20) {
+                violations.push(MetaInfoError::PiecesNotMultipleOf20(info.pieces.len()));
+            }
+            match (&info.length, &info.files) {
+                (Some(_), Some(_)) => violations.push(MetaInfoError::LengthAndFiles),
+                (None, None) => violations.push(MetaInfoError::MissingLength),
+                (Some(length), None) if *length < 0 => {
+                    violations.push(MetaInfoError::NegativeLength {
+                        file_index: 0,
+                        length: *length,
+                    })
+                }
+                _ => {}
+            }
+            if info.files.is_none() {
+                if info.file_attributes(0).symlink && info.symlink_path.is_none() {
+                    violations.push(MetaInfoError::MissingSymlinkPath(0));
+                }
+                for component in info.symlink_path.iter().flatten() {
+                    if component.is_empty() {
+                        violations.push(MetaInfoError::EmptyPathComponent(0));
+                    } else if is_unsafe_path_component(component) {
+                        violations.push(MetaInfoError::UnsafePathComponent(
+                            String::from_utf8_lossy(component).into_owned(),
+                        ));
+                    }
+                }
+            }
+            for (file_index, file) in info.files.iter().flatten().enumerate() {
+                if file.length < 0 {
+                    violations.push(MetaInfoError::NegativeLength {
+                        file_index,
+                        length: file.length,
+                    });
+                }
+                if file.path.is_empty() {
+                    violations.push(MetaInfoError::EmptyPath(file_index));
+                }
+                if file.attributes().symlink && file.symlink_path.is_none() {
+                    violations.push(MetaInfoError::MissingSymlinkPath(file_index));
+                }
+                let components = file
+                    .path
+                    .iter()
+                    .chain(file.path_utf8.iter().flatten())
+                    .chain(file.symlink_path.iter().flatten());
+                for component in components {
+                    if component.is_empty() {
+                        violations.push(MetaInfoError::EmptyPathComponent(file_index));
+                    } else if is_unsafe_path_component(component) {
+                        violations.push(MetaInfoError::UnsafePathComponent(
+                            String::from_utf8_lossy(component).into_owned(),
+                        ));
+                    }
+                }
+            }
+            if info.files.as_ref().is_some_and(|files| files.is_empty()) {
+                violations.push(MetaInfoError::NoFiles);
+            }
+
+            let lengths_valid = !violations
+                .iter()
+                .any(|violation| matches!(violation, MetaInfoError::NegativeLength { .. }));
+            if info.piece_length > 0 && lengths_valid {
+                let expected = info.total_length().div_ceil(info.piece_length as u64) as usize;
+                let found = info.pieces.len() / 20;
+                if expected != found {
+                    violations.push(MetaInfoError::PiecesCountMismatch { expected, found });
+                }
+            }
+        }
+
+        if info.is_v2() {
+            for file in info.v2_files() {
+                for component in &file.path {
+                    if is_unsafe_path_component(component.as_bytes()) {
+                        violations.push(MetaInfoError::UnsafePathComponent(component.clone()));
+                    }
+                }
+            }
+            if info.piece_length > 0 && !self.verify_piece_layers() {
+                violations.push(MetaInfoError::InvalidPieceLayers);
+            }
+        }
+
+        violations
+    }
+
+    pub fn validate(&self) -> Result<(), MetaInfoError> {
+        match self.violations().into_iter().next() {
+            Some(violation) => Err(violation),
+            None => Ok(()),
+        }
+    }
+
+    fn parse_buffer(buffer: &[u8]) -> Result<MetaInfo, MetaInfoError> {
+        let mut meta_info = de::from_bytes::<MetaInfo>(buffer)
+            .map_err(|error| MetaInfoError::Parse(error.to_string()))?;
+        let info_span = bencode::dict_value_span(buffer, b"info")
+            .map_err(|error| MetaInfoError::Parse(error.to_string()))?
+            .ok_or(MetaInfoError::MissingInfo)?;
+        meta_info
+            .info
+            .set_buffer(&buffer[info_span])
+            .map_err(|error| MetaInfoError::Parse(error.to_string()))?;
+        Ok(meta_info)
+    }
+
+    /** Parse and validate, returns the warnings tolerated in lenient mode */
+    pub fn from_buffer_with_mode(
+        buffer: &[u8],
+        mode: ValidationMode,
+    ) -> Result<(MetaInfo, Vec<MetaInfoError>), MetaInfoError> {
+        let meta_info = MetaInfo::parse_buffer(buffer)?;
+        let violations = meta_info.violations();
+        let rejection = match mode {
+            ValidationMode::Strict => violations.first(),
+            ValidationMode::Lenient => violations.iter().find(|violation| violation.is_fatal()),
+        };
+        if let Some(rejection) = rejection {
+            return Err(rejection.clone());
+        }
+        Ok((meta_info, violations))
+    }
+
+    pub fn name(&self) -> String {
+        self.info.name(self.encoding.as_deref())
+    }
+
+    pub fn file_path(&self, file_index: usize) -> PathBuf {
+        self.info.file_path(file_index, self.encoding.as_deref())
+    }
+
+    pub fn from_buffer(buffer: &[u8]) -> Result<MetaInfo> {
+        let (meta_info, _) = MetaInfo::from_buffer_with_mode(buffer, ValidationMode::Strict)?;
+        Ok(meta_info)
+    }
+
+    pub fn from_file_with_mode(
+        str: &str,
+        mode: ValidationMode,
+    ) -> Result<(MetaInfo, Vec<MetaInfoError>)> {
+        let mut file = std::fs::File::open(str).context(format!("Failed to open {}", str))?;
         let mut buffer = Vec::new();
         file.read_to_end(&mut buffer)?;
-        MetaInfo::from_buffer(&buffer)
+        MetaInfo::from_buffer_with_mode(&buffer, mode).context(format!("Invalid torrent file {}", str))
     }
 
+    pub fn from_file(str: &str) -> Result<MetaInfo> {
+        let (meta_info, _) = MetaInfo::from_file_with_mode(str, ValidationMode::Strict)?;
+        Ok(meta_info)
+    }
+
+    /** One scrape url per tracker and swarm, hybrid torrents are scraped on both their v1 and v2 swarms */
     pub fn scrape_urls(&self) -> Result<Vec<String>> {
-        let url_encoded_info_hash = self.info.to_url_encoded()?;
+        let url_encoded_info_hashes = self
+            .info
+            .swarm_hashes()?
+            .iter()
+            .map(|hash| encode_binary(hash).to_string())
+            .collect::<Vec<_>>();
         let scrape_urls = MetaInfo::tracker_urls(self)
             .iter()
-            .map(|announce| {
-                let mut url: String = announce.clone();
-                url.push_str("scrape?info_hash=");
-                url.push_str(&url_encoded_info_hash);
-                url.replacen("udp", "http", 1)
+            .flat_map(|announce| {
+                url_encoded_info_hashes.iter().map(move |url_encoded_info_hash| {
+                    let mut url: String = announce.clone();
+                    url.push_str("scrape?info_hash=");
+                    url.push_str(url_encoded_info_hash);
+                    url.replacen("udp", "http", 1)
+                })
             })
             .collect::<Vec<_>>();
         Ok(scrape_urls)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn hybrid_meta_info(data: &[u8], piece_length: usize) -> MetaInfo {
+        let pieces = data
+            .chunks(piece_length)
+            .flat_map(|chunk| Sha1::digest(chunk).to_vec())
+            .collect::<Vec<_>>();
+        let pieces_root = merkle::file_root(data);
+        let piece_layer = merkle::piece_layer(data, piece_length).concat();
+        let file = FileTreeNode::Directory(BTreeMap::from([(
+            String::new(),
+            FileTreeNode::File(FileTreeFile {
+                length: data.len() as i64,
+                pieces_root: Some(ByteBuf::from(pieces_root.to_vec())),
+            }),
+        )]));
+        let info = Info {
+            name: ByteBuf::from("hybrid.bin"),
+            pieces: ByteBuf::from(pieces),
+            piece_length: piece_length as i64,
+            length: Some(data.len() as i64),
+            meta_version: Some(2),
+            file_tree: Some(BTreeMap::from([("hybrid.bin".to_string(), file)])),
+            ..Default::default()
+        };
+        let buffer = ser::to_bytes(&MetaInfo {
+            info,
+            announce: None,
+            nodes: None,
+            encoding: None,
+            httpseeds: None,
+            url_list: None,
+            announce_list: None,
+            creation_date: None,
+            comment: None,
+            created_by: None,
+            piece_layers: Some(BTreeMap::from([(
+                ByteBuf::from(pieces_root.to_vec()),
+                ByteBuf::from(piece_layer),
+            )])),
+        })
+        .unwrap();
+        MetaInfo::from_buffer(&buffer).unwrap()
+    }
+
+    #[test]
+    fn test_hybrid_meta_info() {
+        let piece_length = 2 * merkle::MERKLE_BLOCK_SIZE;
+        let data = (0..3 * piece_length - 10).map(|x| (x % 13) as u8).collect::<Vec<_>>();
+        let meta_info = hybrid_meta_info(&data, piece_length);
+
+        assert!(meta_info.info.is_hybrid());
+        let files = meta_info.info.v2_files();
+        assert_eq!(files.len(), 1);
+        assert_eq!(files[0].path, vec!["hybrid.bin"]);
+        assert_eq!(files[0].length, data.len() as u64);
+        assert_eq!(files[0].pieces_root, Some(merkle::file_root(&data)));
+        assert!(meta_info.verify_piece_layers());
+
+        let swarm_hashes = meta_info.info.swarm_hashes().unwrap();
+        assert_eq!(swarm_hashes.len(), 2);
+        assert_eq!(swarm_hashes[0], meta_info.info.to_hash_buffer().unwrap());
+        assert_eq!(swarm_hashes[1], meta_info.info.to_v2_hash_buffer().unwrap()[..20]);
+        assert_eq!(meta_info.to_info_hash(), hex::encode(&swarm_hashes[0]));
+
+        let mut v2_only = meta_info.clone();
+        v2_only.info.pieces = ByteBuf::new();
+        v2_only.info.length = None;
+        v2_only.info.clear_buffer();
+        assert!(!v2_only.info.is_v1());
+        assert_eq!(v2_only.info.swarm_hashes().unwrap().len(), 1);
+        assert_eq!(v2_only.info.total_length(), data.len() as u64);
+        assert_eq!(v2_only.info.pieces_count(), 3);
+
+        let mut corrupted = meta_info.clone();
+        let layers = corrupted.piece_layers.as_mut().unwrap();
+        layers.values_mut().next().unwrap()[0] ^= 0xff;
+        assert!(!corrupted.verify_piece_layers());
+    }
+
+    #[test]
+    fn test_sintel_round_trip() {
+        let buffer = std::fs::read("sintel.torrent").unwrap();
+        let meta_info = MetaInfo::from_buffer(&buffer).unwrap();
+        assert_eq!(meta_info.to_info_hash(), "08ada5a7a6183aae1e09d831df6748d566095a10");
+        assert_eq!(meta_info.to_buffer().unwrap(), buffer);
+        assert_eq!(meta_info.url_list(), vec!["https://webtorrent.io/torrents/"]);
+
+        let mut reserialized = meta_info.clone();
+        reserialized.info.clear_buffer();
+        assert_eq!(reserialized.to_info_hash(), meta_info.to_info_hash());
+    }
+
+    #[test]
+    fn test_raw_info_hash() {
+        // Unknown `source` and file `mtime` keys, and keys out of order
+        let mut info = b"d6:source3:abc4:name4:test5:filesld4:pathl1:ae6:lengthi10e5:mtimei7eee12:piece lengthi16384e6:pieces20:".to_vec();
+        info.extend_from_slice(&[1u8; 20]);
+        info.push(b'e');
+        let mut buffer = b"d4:info".to_vec();
+        buffer.extend_from_slice(&info);
+        buffer.push(b'e');
+
+        let meta_info = MetaInfo::from_buffer(&buffer).unwrap();
+        assert_eq!(meta_info.info.to_hash_buffer().unwrap(), Sha1::digest(&info).to_vec());
+        assert_eq!(meta_info.to_buffer().unwrap(), buffer);
+        assert!(matches!(meta_info.info.extra.get("source"), Some(Value::Bytes(source)) if source == b"abc"));
+        let files = meta_info.info.files.as_ref().unwrap();
+        assert!(matches!(files[0].extra.get("mtime"), Some(Value::Int(7))));
+
+        let mut reserialized = meta_info.info.clone();
+        reserialized.clear_buffer();
+        let reserialized = reserialized.to_buffer().unwrap();
+        assert_ne!(reserialized, info);
+        assert!(reserialized.windows(13).any(|window| window == b"6:source3:abc"));
+        assert!(reserialized.windows(10).any(|window| window == b"5:mtimei7e"));
+        assert_eq!(reserialized.len(), info.len());
+
+        // Modifying a field drops the raw bytes without an explicit `clear_buffer`
+        let mut modified = meta_info.clone();
+        modified.info.private = Some(1);
+        assert!(modified.info.to_buffer().unwrap().windows(10).any(|window| window == b"7:privatei"));
+        assert_ne!(modified.info.to_hash(), meta_info.info.to_hash());
+        assert_ne!(modified.to_buffer().unwrap(), buffer);
+    }
+
+    fn with_info(info: &str) -> Vec<u8> {
+        format!("d4:info{}e", info).into_bytes()
+    }
+
+    #[test]
+    fn test_validation() {
+        let pieces = format!("6:pieces20:{}", "a".repeat(20));
+        let valid = with_info(&format!("d6:lengthi10e4:name4:test12:piece lengthi16384e{}e", pieces));
+        assert!(MetaInfo::from_buffer(&valid).is_ok());
+
+        let reject = |info: String| MetaInfo::from_buffer_with_mode(&with_info(&info), ValidationMode::Strict).unwrap_err();
+        assert_eq!(
+            reject(format!("d6:lengthi10e4:name4:test12:piece lengthi16384e6:pieces19:{}e", "a".repeat(19))),
+            MetaInfoError::PiecesNotMultipleOf20(19)
+        );
+        assert_eq!(
+            reject(format!("d6:lengthi10e4:name4:test12:piece lengthi-1e{}e", pieces)),
+            MetaInfoError::InvalidPieceLength(-1)
+        );
+        assert_eq!(
+            reject(format!("d5:filesld6:lengthi10e4:pathl1:aeee6:lengthi10e4:name4:test12:piece lengthi16384e{}e", pieces)),
+            MetaInfoError::LengthAndFiles
+        );
+        assert_eq!(
+            reject(format!("d5:filesld6:lengthi10e4:pathl1:a0:eee4:name4:test12:piece lengthi16384e{}e", pieces)),
+            MetaInfoError::EmptyPathComponent(0)
+        );
+        assert_eq!(
+            reject(format!("d5:filesld6:lengthi10e4:pathl2:..1:aeee4:name4:test12:piece lengthi16384e{}e", pieces)),
+            MetaInfoError::UnsafePathComponent("..".to_string())
+        );
+        assert_eq!(
+            reject(format!("d6:lengthi40000e4:name4:test12:piece lengthi16384e{}e", pieces)),
+            MetaInfoError::PiecesCountMismatch { expected: 3, found: 1 }
+        );
+        assert!(matches!(reject("d4:name4:teste".to_string()), MetaInfoError::Parse(_)));
+        assert!(matches!(
+            MetaInfo::from_buffer_with_mode(b"d8:announce3:abce", ValidationMode::Lenient),
+            Err(MetaInfoError::Parse(_))
+        ));
+
+        let (_, warnings) = MetaInfo::from_buffer_with_mode(
+            &with_info(&format!("d6:lengthi10e4:name0:12:piece lengthi16384e{}e", pieces)),
+            ValidationMode::Lenient,
+        )
+        .unwrap();
+        assert_eq!(warnings, vec![MetaInfoError::EmptyName]);
+        assert_eq!(
+            MetaInfo::from_buffer_with_mode(
+                &with_info(&format!("d6:lengthi10e4:name2:..12:piece lengthi16384e{}e", pieces)),
+                ValidationMode::Lenient,
+            )
+            .unwrap_err(),
+            MetaInfoError::UnsafePathComponent("..".to_string())
+        );
+        let lenient = |info: String| MetaInfo::from_buffer_with_mode(&with_info(&info), ValidationMode::Lenient).unwrap_err();
+        assert_eq!(
+            lenient(format!("d6:lengthi-1e4:name4:test12:piece lengthi16384e{}e", pieces)),
+            MetaInfoError::NegativeLength { file_index: 0, length: -1 }
+        );
+        assert_eq!(
+            lenient(format!("d6:lengthi10e4:name4:test12:piece lengthi16384e6:pieces19:{}e", "a".repeat(19))),
+            MetaInfoError::PiecesNotMultipleOf20(19)
+        );
+        // The piece count and the file lengths decide how the data is laid out on disk, there's nothing to guess
+        assert_eq!(
+            lenient(format!("d6:lengthi40000e4:name4:test12:piece lengthi16384e{}e", pieces)),
+            MetaInfoError::PiecesCountMismatch { expected: 3, found: 1 }
+        );
+        assert_eq!(
+            lenient(format!("d5:filesld6:lengthi10e4:pathl1:aeee6:lengthi10e4:name4:test12:piece lengthi16384e{}e", pieces)),
+            MetaInfoError::LengthAndFiles
+        );
+        assert_eq!(
+            lenient(format!("d4:name4:test12:piece lengthi16384e{}e", pieces)),
+            MetaInfoError::MissingLength
+        );
+    }
+
+    #[test]
+    fn test_internationalized_names() {
+        let pieces = format!("6:pieces20:{}", "a".repeat(20));
+        // "日本" in Shift_JIS, "Привет" in windows-1251
+        let mut buffer = b"d8:encoding9:Shift_JIS4:infod5:filesld6:lengthi5e4:pathl4:".to_vec();
+        buffer.extend_from_slice(&[0x93, 0xfa, 0x96, 0x7b]);
+        buffer.extend_from_slice(b"eed6:lengthi5e4:pathl3:");
+        buffer.extend_from_slice(&[0xff, 0xfe, 0x80]);
+        buffer.extend_from_slice(b"eed6:lengthi5e4:pathl6:");
+        buffer.extend_from_slice(&[0xcf, 0xf0, 0xe8, 0xe2, 0xe5, 0xf2]);
+        buffer.extend_from_slice("e10:path.utf-8l12:Привет".as_bytes());
+        buffer.extend_from_slice(b"eee4:name4:");
+        buffer.extend_from_slice(&[0x93, 0xfa, 0x96, 0x7b]);
+        buffer.extend_from_slice("10:name.utf-86:日本".as_bytes());
+        buffer.extend_from_slice(format!("12:piece lengthi16384e{}ee", pieces).as_bytes());
+
+        let meta_info = MetaInfo::from_buffer(&buffer).unwrap();
+        assert_eq!(meta_info.name(), "日本");
+        assert_eq!(meta_info.file_path(0), PathBuf::from("日本/日本"));
+        assert_eq!(meta_info.file_path(2), PathBuf::from("日本/Привет"));
+        assert_eq!(decode_name(&[0xcf, 0xf0, 0xe8, 0xe2, 0xe5, 0xf2], Some("windows-1251")), "Привет");
+
+        // Shift_JIS can't decode these bytes, they are kept as is
+        #[cfg(unix)]
+        {
+            use std::os::unix::ffi::OsStrExt;
+            let path = meta_info.file_path(1);
+            assert_eq!(path.file_name().unwrap().as_bytes(), &[0xff, 0xfe, 0x80]);
+        }
+    }
+}
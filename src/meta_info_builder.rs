@@ -0,0 +1,308 @@
+use std::ffi::OsStr;
+use std::fs;
+use std::io::{Read, Seek, SeekFrom};
+use std::path::{Path, PathBuf};
+use std::thread;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use anyhow::{Context, Error, Result};
+use serde_bytes::ByteBuf;
+use sha1::{Digest, Sha1};
+
+use crate::meta_info::{File, Info, MetaInfo, UrlList};
+
+const MIN_PIECE_LENGTH: u64 = 16 * 1024;
+const MAX_PIECE_LENGTH: u64 = 16 * 1024 * 1024;
+/** Aim for about this many pieces when no piece length is given */
+const TARGET_PIECES_COUNT: u64 = 1500;
+
+/**
+ * Create a `MetaInfo` from a file or a directory on disk.
+ * Directories are walked recursively with their entries sorted by name so the same content always gives the same info hash.
+ * Symlinks to files are followed, symlinks to directories are skipped so the walk can't loop.
+ */
+#[derive(Debug, Clone)]
+pub struct MetaInfoBuilder {
+    path: PathBuf,
+    piece_length: Option<u64>,
+    private: bool,
+    announce: Option<String>,
+    announce_list: Vec<Vec<String>>,
+    comment: Option<String>,
+    url_list: Vec<String>,
+}
+
+impl MetaInfoBuilder {
+    pub fn new(path: impl Into<PathBuf>) -> Self {
+        Self {
+            path: path.into(),
+            piece_length: None,
+            private: false,
+            announce: None,
+            announce_list: vec![],
+            comment: None,
+            url_list: vec![],
+        }
+    }
+
+    /** Must be a power of two of at least 16 KiB, chosen from the total size when not set */
+    pub fn piece_length(mut self, piece_length: u64) -> Self {
+        self.piece_length = Some(piece_length);
+        self
+    }
+
+    pub fn private(mut self, private: bool) -> Self {
+        self.private = private;
+        self
+    }
+
+    pub fn announce(mut self, announce: impl Into<String>) -> Self {
+        self.announce = Some(announce.into());
+        self
+    }
+
+    pub fn announce_tier(mut self, tier: Vec<String>) -> Self {
+        self.announce_list.push(tier);
+        self
+    }
+
+    pub fn comment(mut self, comment: impl Into<String>) -> Self {
+        self.comment = Some(comment.into());
+        self
+    }
+
+    pub fn web_seed(mut self, url: impl Into<String>) -> Self {
+        self.url_list.push(url.into());
+        self
+    }
+
+    pub fn build(&self) -> Result<MetaInfo> {
+        let name = os_str_bytes(
+            self.path
+                .file_name()
+                .context(format!("{} has no file name", self.path.display()))?,
+        );
+
+        let metadata = fs::metadata(&self.path)
+            .context(format!("Failed to read {}", self.path.display()))?;
+        let disk_files = if metadata.is_dir() {
+            let mut disk_files = Vec::new();
+            walk_directory(&self.path, &mut disk_files)?;
+            if disk_files.is_empty() {
+                return Err(Error::msg(format!("{} contains no files", self.path.display())));
+            }
+            disk_files
+        } else {
+            vec![self.path.clone()]
+        };
+
+        let mut info = Info::default();
+        info.name = name;
+        info.private = self.private.then_some(1);
+        if metadata.is_dir() {
+            let files = disk_files
+                .iter()
+                .map(|disk_file| {
+                    let path = disk_file
+                        .strip_prefix(&self.path)?
+                        .components()
+                        .map(|component| os_str_bytes(component.as_os_str()))
+                        .collect();
+                    Ok(File {
+                        path,
+                        length: fs::metadata(disk_file)?.len() as i64,
+                        ..Default::default()
+                    })
+                })
+                .collect::<Result<Vec<_>>>()?;
+            info.files = Some(files);
+        } else {
+            info.length = Some(metadata.len() as i64);
+        }
+
+        let piece_length = match self.piece_length {
+            Some(piece_length) if piece_length < MIN_PIECE_LENGTH || !piece_length.is_power_of_two() => {
+                return Err(Error::msg(format!("Invalid piece length {}", piece_length)));
+            }
+            Some(piece_length) => piece_length,
+            None => default_piece_length(info.total_length()),
+        };
+        info.piece_length = piece_length as i64;
+        let threads = thread::available_parallelism().map_or(1, |threads| threads.get());
+        info.pieces = ByteBuf::from(hash_pieces(&info, &disk_files, threads)?);
+        let creation_date = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs() as i64;
+
+        Ok(MetaInfo {
+            info,
+            announce: self.announce.clone(),
+            nodes: None,
+            encoding: None,
+            httpseeds: None,
+            url_list: match self.url_list.len() {
+                0 => None,
+                1 => Some(UrlList::Single(self.url_list[0].clone())),
+                _ => Some(UrlList::List(self.url_list.clone())),
+            },
+            announce_list: (!self.announce_list.is_empty()).then(|| self.announce_list.clone()),
+            creation_date: Some(creation_date),
+            comment: self.comment.clone(),
+            created_by: Some(format!("riffle/{}", env!("CARGO_PKG_VERSION"))),
+            piece_layers: None,
+        })
+    }
+
+    /** Build and write the `.torrent` file */
+    pub fn write(&self, torrent_path: impl AsRef<Path>) -> Result<MetaInfo> {
+        let meta_info = self.build()?;
+        fs::write(torrent_path.as_ref(), meta_info.to_buffer()?).context(format!(
+            "Failed to write {}",
+            torrent_path.as_ref().display()
+        ))?;
+        Ok(meta_info)
+    }
+}
+
+/** File names are stored as is on unix, even when they aren't valid UTF-8 */
+fn os_str_bytes(name: &OsStr) -> ByteBuf {
+    #[cfg(unix)]
+    {
+        ByteBuf::from(std::os::unix::ffi::OsStrExt::as_bytes(name))
+    }
+    #[cfg(not(unix))]
+    {
+        ByteBuf::from(name.to_string_lossy().as_bytes())
+    }
+}
+
+fn walk_directory(directory: &Path, disk_files: &mut Vec<PathBuf>) -> Result<()> {
+    let mut entries = fs::read_dir(directory)
+        .context(format!("Failed to read directory {}", directory.display()))?
+        .collect::<std::io::Result<Vec<_>>>()?;
+    entries.sort_by_key(|entry| entry.file_name());
+    for entry in entries {
+        let path = entry.path();
+        let file_type = entry.file_type()?;
+        if file_type.is_dir() {
+            walk_directory(&path, disk_files)?;
+        } else if !file_type.is_symlink() || fs::metadata(&path).is_ok_and(|metadata| metadata.is_file()) {
+            disk_files.push(path);
+        }
+    }
+    Ok(())
+}
+
+fn default_piece_length(total_length: u64) -> u64 {
+    (total_length / TARGET_PIECES_COUNT)
+        .next_power_of_two()
+        .clamp(MIN_PIECE_LENGTH, MAX_PIECE_LENGTH)
+}
+
+fn read_piece(info: &Info, disk_files: &[PathBuf], index: usize) -> Result<Vec<u8>> {
+    let mut data = vec![0u8; info.piece_size(index) as usize];
+    let mut position = 0;
+    for slice in info.piece_file_slices(index) {
+        let disk_file = &disk_files[slice.file_index];
+        let mut file = fs::File::open(disk_file).context(format!("Failed to open {}", disk_file.display()))?;
+        file.seek(SeekFrom::Start(slice.offset))?;
+        let length = slice.length as usize;
+        file.read_exact(&mut data[position..position + length])
+            .context(format!("{} changed while hashing", disk_file.display()))?;
+        position += length;
+    }
+    Ok(data)
+}
+
+/** Pieces are spread across threads, each thread hashing every n-th piece */
+fn hash_pieces(info: &Info, disk_files: &[PathBuf], threads: usize) -> Result<Vec<u8>> {
+    let pieces_count = info.total_length().div_ceil(info.piece_length as u64) as usize;
+    let threads = threads.clamp(1, pieces_count.max(1));
+
+    let hashed = thread::scope(|scope| {
+        let handles = (0..threads)
+            .map(|thread_index| {
+                scope.spawn(move || {
+                    (thread_index..pieces_count)
+                        .step_by(threads)
+                        .map(|index| Ok((index, Sha1::digest(read_piece(info, disk_files, index)?).to_vec())))
+                        .collect::<Result<Vec<_>>>()
+                })
+            })
+            .collect::<Vec<_>>();
+        handles
+            .into_iter()
+            .map(|handle| handle.join().map_err(|_| Error::msg("Hashing thread panicked"))?)
+            .collect::<Result<Vec<_>>>()
+    })?;
+
+    let mut pieces = vec![0u8; pieces_count * 20];
+    for (index, hash) in hashed.into_iter().flatten() {
+        pieces[index * 20..(index + 1) * 20].copy_from_slice(&hash);
+    }
+    Ok(pieces)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_build_meta_info() {
+        let root = std::env::temp_dir().join(format!("riffle-builder-{}", std::process::id()));
+        let directory = root.join("data set");
+        fs::create_dir_all(directory.join("sub")).unwrap();
+        let a = (0..50_000u32).map(|x| x as u8).collect::<Vec<_>>();
+        let b = (0..30_000u32).map(|x| (x * 7) as u8).collect::<Vec<_>>();
+        fs::write(directory.join("sub").join("b.bin"), &b).unwrap();
+        fs::write(directory.join("a.bin"), &a).unwrap();
+
+        let meta_info = MetaInfoBuilder::new(&directory)
+            .piece_length(16 * 1024)
+            .private(true)
+            .announce("http://tracker/announce")
+            .comment("test")
+            .web_seed("http://seed/")
+            .write(root.join("data set.torrent"))
+            .unwrap();
+
+        assert_eq!(meta_info.info.files.as_ref().unwrap().len(), 2);
+        assert_eq!(meta_info.file_path(0), PathBuf::from("data set/a.bin"));
+        assert_eq!(meta_info.file_path(1), PathBuf::from("data set/sub/b.bin"));
+        assert_eq!(meta_info.info.total_length(), 80_000);
+        assert_eq!(meta_info.info.pieces_count(), 5);
+        assert!(meta_info.info.is_private());
+
+        let data = [a, b].concat();
+        for (index, chunk) in data.chunks(16 * 1024).enumerate() {
+            assert!(meta_info.info.verify_piece(index, chunk));
+        }
+        let disk_files = [directory.join("a.bin"), directory.join("sub").join("b.bin")];
+        assert_eq!(hash_pieces(&meta_info.info, &disk_files, 3).unwrap(), meta_info.info.pieces.to_vec());
+
+        let parsed = MetaInfo::from_file(root.join("data set.torrent").to_str().unwrap()).unwrap();
+        assert_eq!(parsed.to_info_hash(), meta_info.to_info_hash());
+        assert_eq!(parsed.url_list(), vec!["http://seed/"]);
+        assert_eq!(parsed.comment.as_deref(), Some("test"));
+        assert!(parsed.creation_date.is_some());
+
+        let single = MetaInfoBuilder::new(directory.join("a.bin")).build().unwrap();
+        assert_eq!(single.name(), "a.bin");
+        assert_eq!(single.info.length, Some(50_000));
+        assert_eq!(single.info.piece_length as u64, MIN_PIECE_LENGTH);
+        assert!(MetaInfoBuilder::new(&directory).piece_length(1000).build().is_err());
+
+        let empty = root.join("empty");
+        fs::create_dir_all(empty.join("sub")).unwrap();
+        assert!(MetaInfoBuilder::new(&empty).build().is_err());
+
+        #[cfg(unix)]
+        {
+            std::os::unix::fs::symlink(directory.join("a.bin"), empty.join("link.bin")).unwrap();
+            std::os::unix::fs::symlink(&empty, empty.join("sub").join("loop")).unwrap();
+            let linked = MetaInfoBuilder::new(&empty).build().unwrap();
+            assert_eq!(linked.info.files.as_ref().unwrap().len(), 1);
+            assert_eq!(linked.info.total_length(), 50_000);
+        }
+
+        fs::remove_dir_all(root).unwrap();
+    }
+}
@@ -0,0 +1,481 @@
+use std::fmt;
+use std::io;
+use std::pin::Pin;
+use std::task::{ready, Context as TaskContext, Poll};
+
+use anyhow::{Context, Error, Result};
+use num_bigint::BigUint;
+use rand::{Rng, RngCore};
+use sha1::{Digest, Sha1};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, ReadBuf};
+
+use crate::peer::PROTOCOL;
+
+/*
+ * Message Stream Encryption
+ * A Diffie-Hellman key exchange followed by RC4 obfuscation of the stream, to get past ISPs throttling plaintext BitTorrent.
+ * It doesn't protect against an active attacker, the only secret is the info hash.
+ *
+ * 1 A->B: Diffie Hellman Ya, PadA
+ * 2 B->A: Diffie Hellman Yb, PadB
+ * 3 A->B: HASH('req1', S), HASH('req2', SKEY) xor HASH('req3', S), ENCRYPT(VC, crypto_provide, len(PadC), PadC, len(IA)), ENCRYPT(IA)
+ * 4 B->A: ENCRYPT(VC, crypto_select, len(padD), padD), ENCRYPT2(Payload Stream)
+ * 5 A->B: ENCRYPT2(Payload Stream)
+ *
+ * S is the shared secret and SKEY the info hash. A encrypts with RC4 keyed with HASH('keyA', S, SKEY), B with HASH('keyB', S, SKEY),
+ * both discarding the first 1024 bytes of the key stream. VC is eight zero bytes used to find the end of the random padding.
+ */
+
+/** 768 bit safe prime */
+const PRIME: &str = "FFFFFFFFFFFFFFFFC90FDAA22168C234C4C6628B80DC1CD129024E088A67CC74020BBEA63B139B22514A08798E3404DDEF9519B3CD3A431B302B0A6DF25F14374FE1356D6D51C245E485B576625E7EC6F44C42E9A63A36210000000000090563";
+const GENERATOR: u32 = 2;
+const KEY_LENGTH: usize = 96;
+/** Private keys are 160 bits, as recommended */
+const PRIVATE_KEY_LENGTH: usize = 20;
+const MAX_PAD_LENGTH: usize = 512;
+const VC: [u8; 8] = [0; 8];
+const CRYPTO_PLAINTEXT: u32 = 0x01;
+const CRYPTO_RC4: u32 = 0x02;
+
+/** Whether connections in one direction must, may or must not be encrypted */
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum EncryptionPolicy {
+    /** Plaintext connections are refused */
+    Forced,
+    /** Encryption is preferred, plaintext is accepted and tried when the peer doesn't support encryption */
+    #[default]
+    Enabled,
+    /** Only plaintext connections */
+    Disabled,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub struct EncryptionSettings {
+    pub incoming: EncryptionPolicy,
+    pub outgoing: EncryptionPolicy,
+}
+
+#[derive(Clone)]
+struct Rc4 {
+    state: [u8; 256],
+    i: u8,
+    j: u8,
+}
+
+impl Rc4 {
+    fn new(key: &[u8]) -> Self {
+        let mut state = [0u8; 256];
+        for (index, value) in state.iter_mut().enumerate() {
+            *value = index as u8;
+        }
+        let mut j = 0u8;
+        for i in 0..256 {
+            j = j.wrapping_add(state[i]).wrapping_add(key[i % key.len()]);
+            state.swap(i, j as usize);
+        }
+        let mut rc4 = Rc4 { state, i: 0, j: 0 };
+        rc4.apply(&mut [0; 1024]);
+        rc4
+    }
+
+    /** Encryption and decryption are the same operation */
+    fn apply(&mut self, data: &mut [u8]) {
+        for byte in data {
+            self.i = self.i.wrapping_add(1);
+            self.j = self.j.wrapping_add(self.state[self.i as usize]);
+            self.state.swap(self.i as usize, self.j as usize);
+            let index = self.state[self.i as usize].wrapping_add(self.state[self.j as usize]);
+            *byte ^= self.state[index as usize];
+        }
+    }
+}
+
+/** The key stream state must not end up in logs */
+impl fmt::Debug for Rc4 {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Rc4")
+    }
+}
+
+fn hash(parts: &[&[u8]]) -> [u8; 20] {
+    let mut hasher = Sha1::new();
+    for part in parts {
+        hasher.update(part);
+    }
+    hasher.finalize().into()
+}
+
+fn to_key_bytes(value: &BigUint) -> [u8; KEY_LENGTH] {
+    let bytes = value.to_bytes_be();
+    let mut key = [0u8; KEY_LENGTH];
+    key[KEY_LENGTH - bytes.len()..].copy_from_slice(&bytes);
+    key
+}
+
+struct KeyPair {
+    private: BigUint,
+    public: [u8; KEY_LENGTH],
+}
+
+impl KeyPair {
+    fn generate() -> Self {
+        let mut private = [0u8; PRIVATE_KEY_LENGTH];
+        rand::thread_rng().fill_bytes(&mut private);
+        let private = BigUint::from_bytes_be(&private);
+        let prime = BigUint::parse_bytes(PRIME.as_bytes(), 16).unwrap();
+        let public = BigUint::from(GENERATOR).modpow(&private, &prime);
+        Self {
+            private,
+            public: to_key_bytes(&public),
+        }
+    }
+
+    fn shared_secret(&self, their_public: &[u8; KEY_LENGTH]) -> [u8; KEY_LENGTH] {
+        let prime = BigUint::parse_bytes(PRIME.as_bytes(), 16).unwrap();
+        to_key_bytes(&BigUint::from_bytes_be(their_public).modpow(&self.private, &prime))
+    }
+}
+
+fn random_pad() -> Vec<u8> {
+    let mut rng = rand::thread_rng();
+    let mut pad = vec![0u8; rng.gen_range(0..=MAX_PAD_LENGTH)];
+    rng.fill_bytes(&mut pad);
+    pad
+}
+
+/** Read until `pattern` shows up, it must do so within `MAX_PAD_LENGTH` bytes of random padding */
+async fn synchronize<S: AsyncRead + Unpin>(stream: &mut S, pattern: &[u8]) -> Result<()> {
+    let mut window = vec![0u8; pattern.len()];
+    stream.read_exact(&mut window).await?;
+    for _ in 0..MAX_PAD_LENGTH {
+        if window == pattern {
+            return Ok(());
+        }
+        window.rotate_left(1);
+        *window.last_mut().unwrap() = stream.read_u8().await?;
+    }
+    if window == pattern {
+        return Ok(());
+    }
+    Err(Error::msg("Encryption handshake out of sync"))
+}
+
+async fn read_decrypted<S: AsyncRead + Unpin>(stream: &mut S, cipher: &mut Rc4, length: usize) -> Result<Vec<u8>> {
+    let mut buffer = vec![0u8; length];
+    stream.read_exact(&mut buffer).await?;
+    cipher.apply(&mut buffer);
+    Ok(buffer)
+}
+
+async fn read_pad_length<S: AsyncRead + Unpin>(stream: &mut S, cipher: &mut Rc4) -> Result<usize> {
+    let length = read_decrypted(stream, cipher, 2).await?;
+    let length = u16::from_be_bytes([length[0], length[1]]) as usize;
+    if length > MAX_PAD_LENGTH {
+        return Err(Error::msg(format!("Encryption padding of {} bytes is too long", length)));
+    }
+    Ok(length)
+}
+
+/** Outgoing side of the handshake, only RC4 is offered when encryption is forced */
+pub async fn initiate<S: AsyncRead + AsyncWrite + Unpin>(
+    mut stream: S,
+    info_hash: &[u8; 20],
+    policy: EncryptionPolicy,
+) -> Result<CipherStream<S>> {
+    let provide = match policy {
+        EncryptionPolicy::Forced => CRYPTO_RC4,
+        EncryptionPolicy::Enabled => CRYPTO_RC4 | CRYPTO_PLAINTEXT,
+        EncryptionPolicy::Disabled => return Err(Error::msg("Encryption is disabled")),
+    };
+    let keys = KeyPair::generate();
+    stream.write_all(&[keys.public.as_slice(), &random_pad()].concat()).await?;
+    let mut their_public = [0u8; KEY_LENGTH];
+    stream.read_exact(&mut their_public).await?;
+    let secret = keys.shared_secret(&their_public);
+    let mut encrypt = Rc4::new(&hash(&[b"keyA", &secret, info_hash]));
+    let mut decrypt = Rc4::new(&hash(&[b"keyB", &secret, info_hash]));
+
+    let mut message = hash(&[b"req1", &secret]).to_vec();
+    let req2 = hash(&[b"req2", info_hash]);
+    let req3 = hash(&[b"req3", &secret]);
+    message.extend(req2.iter().zip(req3).map(|(a, b)| a ^ b));
+    let pad = random_pad();
+    let mut encrypted = VC.to_vec();
+    encrypted.extend_from_slice(&provide.to_be_bytes());
+    encrypted.extend_from_slice(&(pad.len() as u16).to_be_bytes());
+    encrypted.extend(pad);
+    // No initial payload, the peer wire handshake goes through the established stream
+    encrypted.extend_from_slice(&0u16.to_be_bytes());
+    encrypt.apply(&mut encrypted);
+    message.extend(encrypted);
+    stream.write_all(&message).await?;
+
+    // The answer starts with the encrypted VC, once past the padding of B's public key
+    let mut expected = VC;
+    decrypt.clone().apply(&mut expected);
+    synchronize(&mut stream, &expected).await?;
+    decrypt.apply(&mut [0; VC.len()]);
+    let select = read_decrypted(&mut stream, &mut decrypt, 4).await?;
+    let select = u32::from_be_bytes(select.try_into().unwrap());
+    let pad_length = read_pad_length(&mut stream, &mut decrypt).await?;
+    read_decrypted(&mut stream, &mut decrypt, pad_length).await?;
+
+    match select {
+        CRYPTO_RC4 => Ok(CipherStream::encrypted(stream, decrypt, encrypt)),
+        CRYPTO_PLAINTEXT if provide & CRYPTO_PLAINTEXT != 0 => Ok(CipherStream::plaintext(stream)),
+        _ => Err(Error::msg(format!("Peer selected unsupported encryption {:#x}", select))),
+    }
+}
+
+/**
+ * Incoming side of the handshake, for any of the torrents in `info_hashes`.
+ * Plaintext connections are told apart by their first 20 bytes, which are the protocol string of the peer wire handshake.
+ */
+pub async fn accept<S: AsyncRead + AsyncWrite + Unpin>(
+    mut stream: S,
+    info_hashes: &[[u8; 20]],
+    policy: EncryptionPolicy,
+) -> Result<CipherStream<S>> {
+    let mut start = [0u8; 20];
+    stream.read_exact(&mut start).await?;
+    if start[0] as usize == PROTOCOL.len() && &start[1..] == PROTOCOL.as_bytes() {
+        if policy == EncryptionPolicy::Forced {
+            return Err(Error::msg("Plaintext connections are refused"));
+        }
+        return Ok(CipherStream::plaintext(stream).with_buffered(start.to_vec()));
+    }
+    if policy == EncryptionPolicy::Disabled {
+        return Err(Error::msg("Encrypted connections are refused"));
+    }
+
+    let mut their_public = [0u8; KEY_LENGTH];
+    their_public[..start.len()].copy_from_slice(&start);
+    stream.read_exact(&mut their_public[start.len()..]).await?;
+    let keys = KeyPair::generate();
+    stream.write_all(&[keys.public.as_slice(), &random_pad()].concat()).await?;
+    let secret = keys.shared_secret(&their_public);
+
+    synchronize(&mut stream, &hash(&[b"req1", &secret])).await?;
+    let mut skey_hash = [0u8; 20];
+    stream.read_exact(&mut skey_hash).await?;
+    for (byte, mask) in skey_hash.iter_mut().zip(hash(&[b"req3", &secret])) {
+        *byte ^= mask;
+    }
+    let info_hash = info_hashes
+        .iter()
+        .find(|info_hash| hash(&[b"req2", info_hash.as_slice()]) == skey_hash)
+        .context("Encrypted connection for an unknown torrent")?;
+    let mut decrypt = Rc4::new(&hash(&[b"keyA", &secret, info_hash]));
+    let mut encrypt = Rc4::new(&hash(&[b"keyB", &secret, info_hash]));
+
+    let header = read_decrypted(&mut stream, &mut decrypt, VC.len() + 4).await?;
+    if header[..VC.len()] != VC {
+        return Err(Error::msg("Bad encryption verification constant"));
+    }
+    let provide = u32::from_be_bytes(header[VC.len()..].try_into().unwrap());
+    let pad_length = read_pad_length(&mut stream, &mut decrypt).await?;
+    read_decrypted(&mut stream, &mut decrypt, pad_length).await?;
+    let initial_payload_length = read_decrypted(&mut stream, &mut decrypt, 2).await?;
+    let initial_payload_length = u16::from_be_bytes([initial_payload_length[0], initial_payload_length[1]]);
+    let initial_payload = read_decrypted(&mut stream, &mut decrypt, initial_payload_length as usize).await?;
+
+    let select = if provide & CRYPTO_RC4 != 0 {
+        CRYPTO_RC4
+    } else if provide & CRYPTO_PLAINTEXT != 0 && policy != EncryptionPolicy::Forced {
+        CRYPTO_PLAINTEXT
+    } else {
+        return Err(Error::msg(format!("No acceptable encryption in {:#x}", provide)));
+    };
+    let pad = random_pad();
+    let mut answer = VC.to_vec();
+    answer.extend_from_slice(&select.to_be_bytes());
+    answer.extend_from_slice(&(pad.len() as u16).to_be_bytes());
+    answer.extend(pad);
+    encrypt.apply(&mut answer);
+    stream.write_all(&answer).await?;
+
+    let stream = if select == CRYPTO_RC4 {
+        CipherStream::encrypted(stream, decrypt, encrypt)
+    } else {
+        CipherStream::plaintext(stream)
+    };
+    Ok(stream.with_buffered(initial_payload))
+}
+
+/**
+ * Stream passing data through RC4 once the handshake selected it, or as is for plaintext connections.
+ * The peer wire codec reads and writes it like any other stream.
+ */
+#[derive(Debug)]
+pub struct CipherStream<S> {
+    inner: S,
+    /** Data received during the handshake, returned before anything else */
+    buffered: Vec<u8>,
+    read_cipher: Option<Rc4>,
+    write_cipher: Option<Rc4>,
+    /** Encrypted data accepted by `poll_write` and not written yet, the key stream already moved past it */
+    pending: Vec<u8>,
+    pending_written: usize,
+}
+
+impl<S> CipherStream<S> {
+    pub fn plaintext(inner: S) -> Self {
+        Self {
+            inner,
+            buffered: vec![],
+            read_cipher: None,
+            write_cipher: None,
+            pending: vec![],
+            pending_written: 0,
+        }
+    }
+
+    fn encrypted(inner: S, read_cipher: Rc4, write_cipher: Rc4) -> Self {
+        Self {
+            read_cipher: Some(read_cipher),
+            write_cipher: Some(write_cipher),
+            ..Self::plaintext(inner)
+        }
+    }
+
+    fn with_buffered(mut self, buffered: Vec<u8>) -> Self {
+        self.buffered = buffered;
+        self
+    }
+
+    pub fn is_encrypted(&self) -> bool {
+        self.write_cipher.is_some()
+    }
+
+    pub fn get_ref(&self) -> &S {
+        &self.inner
+    }
+}
+
+impl<S: AsyncWrite + Unpin> CipherStream<S> {
+    fn poll_write_pending(&mut self, cx: &mut TaskContext<'_>) -> Poll<io::Result<()>> {
+        while self.pending_written < self.pending.len() {
+            let written = ready!(Pin::new(&mut self.inner).poll_write(cx, &self.pending[self.pending_written..]))?;
+            if written == 0 {
+                return Poll::Ready(Err(io::ErrorKind::WriteZero.into()));
+            }
+            self.pending_written += written;
+        }
+        self.pending.clear();
+        self.pending_written = 0;
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for CipherStream<S> {
+    fn poll_read(self: Pin<&mut Self>, cx: &mut TaskContext<'_>, buf: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        if !this.buffered.is_empty() {
+            let length = this.buffered.len().min(buf.remaining());
+            buf.put_slice(&this.buffered[..length]);
+            this.buffered.drain(..length);
+            return Poll::Ready(Ok(()));
+        }
+        let start = buf.filled().len();
+        ready!(Pin::new(&mut this.inner).poll_read(cx, buf))?;
+        if let Some(cipher) = &mut this.read_cipher {
+            cipher.apply(&mut buf.filled_mut()[start..]);
+        }
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for CipherStream<S> {
+    fn poll_write(self: Pin<&mut Self>, cx: &mut TaskContext<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        ready!(this.poll_write_pending(cx))?;
+        let Some(cipher) = &mut this.write_cipher else {
+            return Pin::new(&mut this.inner).poll_write(cx, buf);
+        };
+        this.pending.extend_from_slice(buf);
+        cipher.apply(&mut this.pending);
+        // Whatever doesn't fit in the socket now goes out on the next write or flush
+        if let Poll::Ready(Err(error)) = this.poll_write_pending(cx) {
+            return Poll::Ready(Err(error));
+        }
+        Poll::Ready(Ok(buf.len()))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut TaskContext<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        ready!(this.poll_write_pending(cx))?;
+        Pin::new(&mut this.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut TaskContext<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        ready!(this.poll_write_pending(cx))?;
+        Pin::new(&mut this.inner).poll_shutdown(cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_rc4() {
+        // Known answer from RFC 6229, offset 1024 for a 40 bit key
+        let mut rc4 = Rc4::new(&[1, 2, 3, 4, 5]);
+        let mut stream = [0u8; 16];
+        rc4.apply(&mut stream);
+        assert_eq!(hex::encode(stream), "30abbcc7c20b01609f23ee2d5f6bb7df");
+        assert_eq!(PRIME.len(), KEY_LENGTH * 2);
+    }
+
+    #[tokio::test]
+    async fn test_encryption_handshake() {
+        let info_hash = [7u8; 20];
+        let handshake = async |incoming: EncryptionPolicy, outgoing: EncryptionPolicy| {
+            let (a, b) = tokio::io::duplex(4096);
+            let known = [[1; 20], info_hash];
+            let (initiated, accepted) = tokio::join!(initiate(a, &info_hash, outgoing), accept(b, &known, incoming));
+            (initiated, accepted)
+        };
+
+        let (initiated, accepted) = handshake(EncryptionPolicy::Enabled, EncryptionPolicy::Enabled).await;
+        let (mut a, mut b) = (initiated.unwrap(), accepted.unwrap());
+        assert!(a.is_encrypted() && b.is_encrypted());
+        a.write_all(b"BitTorrent protocol").await.unwrap();
+        a.flush().await.unwrap();
+        let mut buffer = [0u8; 19];
+        b.read_exact(&mut buffer).await.unwrap();
+        assert_eq!(&buffer, b"BitTorrent protocol");
+        b.write_all(b"answer").await.unwrap();
+        b.flush().await.unwrap();
+        a.read_exact(&mut buffer[..6]).await.unwrap();
+        assert_eq!(&buffer[..6], b"answer");
+
+        let (initiated, accepted) = handshake(EncryptionPolicy::Disabled, EncryptionPolicy::Forced).await;
+        assert!(initiated.is_err() && accepted.is_err());
+
+        // Plaintext peer wire handshakes are still accepted unless encryption is forced
+        for (policy, accepted) in [(EncryptionPolicy::Enabled, true), (EncryptionPolicy::Forced, false)] {
+            let (mut a, b) = tokio::io::duplex(4096);
+            a.write_all(&crate::peer::Handshake::new(info_hash, [2; 20]).to_buffer()).await.unwrap();
+            let stream = accept(b, &[info_hash], policy).await;
+            assert_eq!(stream.is_ok(), accepted);
+            if let Ok(mut stream) = stream {
+                assert!(!stream.is_encrypted());
+                let mut buffer = [0u8; crate::peer::HANDSHAKE_LENGTH];
+                stream.read_exact(&mut buffer).await.unwrap();
+                assert_eq!(crate::peer::Handshake::from_buffer(&buffer).unwrap().info_hash(), info_hash);
+            }
+        }
+
+        // Unknown torrents are dropped
+        let (a, b) = tokio::io::duplex(4096);
+        let (unknown, known) = ([3; 20], [info_hash]);
+        let (initiated, accepted) = tokio::join!(
+            initiate(a, &unknown, EncryptionPolicy::Enabled),
+            accept(b, &known, EncryptionPolicy::Enabled)
+        );
+        assert!(accepted.is_err());
+        assert!(initiated.is_err());
+    }
+}
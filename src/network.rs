@@ -0,0 +1,228 @@
+use std::fmt;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::str::FromStr;
+
+use anyhow::{Context, Error, Result};
+use socket2::{Domain, Protocol, Socket, Type};
+use tokio::net::{TcpSocket, TcpStream, UdpSocket};
+
+/** A network interface by name, like `eth0` or `tun0`, or one of its addresses */
+#[derive(Debug, Clone, PartialEq)]
+pub enum Interface {
+    Name(String),
+    Addr(IpAddr),
+}
+
+impl FromStr for Interface {
+    type Err = Error;
+
+    fn from_str(value: &str) -> Result<Self> {
+        let value = value.trim();
+        if value.is_empty() {
+            return Err(Error::msg("Empty interface name"));
+        }
+        let addr = value.trim_start_matches('[').trim_end_matches(']');
+        Ok(match addr.parse::<IpAddr>() {
+            Ok(ip) => Interface::Addr(ip),
+            Err(_) => Interface::Name(value.to_string()),
+        })
+    }
+}
+
+impl fmt::Display for Interface {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Interface::Name(name) => write!(f, "{}", name),
+            Interface::Addr(ip) => write!(f, "{}", ip),
+        }
+    }
+}
+
+impl Interface {
+    /** Addresses currently assigned, unspecified addresses stand for every interface and are always there */
+    pub fn addrs(&self) -> Result<Vec<IpAddr>> {
+        if let Interface::Addr(ip) = self {
+            if ip.is_unspecified() {
+                return Ok(vec![*ip]);
+            }
+        }
+        let addrs = if_addrs::get_if_addrs()
+            .context("Failed to list network interfaces")?
+            .into_iter()
+            .filter(|interface| match self {
+                Interface::Name(name) => &interface.name == name,
+                Interface::Addr(ip) => &interface.ip() == ip,
+            })
+            .map(|interface| interface.ip())
+            .collect();
+        Ok(addrs)
+    }
+
+    pub fn is_up(&self) -> bool {
+        self.addrs().is_ok_and(|addrs| !addrs.is_empty())
+    }
+
+    /** Address to bind to for reaching `remote`, from the same family */
+    pub fn local_ip_for(&self, remote: &SocketAddr) -> Result<IpAddr> {
+        let addrs = self.addrs()?;
+        if addrs.is_empty() {
+            return Err(Error::msg(format!("Interface {} is down", self)));
+        }
+        addrs
+            .into_iter()
+            .find(|ip| ip.is_ipv4() == remote.is_ipv4())
+            .context(format!("Interface {} has no address to reach {}", self, remote))
+    }
+}
+
+/** Interfaces the session listens on and connects from */
+#[derive(Debug, Clone, PartialEq)]
+pub struct NetworkSettings {
+    /** Every address of each interface gets a TCP listener and a UDP socket, on the same port */
+    pub listen_interfaces: Vec<Interface>,
+    /** Outgoing peer connections, proxy connections included, leave from this interface */
+    pub outgoing_interface: Option<Interface>,
+    /** Stop all traffic while one of the interfaces above is gone, instead of leaking through another one */
+    pub kill_switch: bool,
+}
+
+impl Default for NetworkSettings {
+    fn default() -> Self {
+        Self {
+            listen_interfaces: vec![Interface::Addr(IpAddr::V4(Ipv4Addr::UNSPECIFIED))],
+            outgoing_interface: None,
+            kill_switch: false,
+        }
+    }
+}
+
+impl NetworkSettings {
+    /** Listen addresses, IPv4 and IPv6 ones alike */
+    pub fn listen_addrs(&self, port: u16) -> Result<Vec<SocketAddr>> {
+        let mut addrs = vec![];
+        for interface in &self.listen_interfaces {
+            let ips = interface.addrs()?;
+            if ips.is_empty() {
+                return Err(Error::msg(format!("Interface {} has no address to listen on", interface)));
+            }
+            addrs.extend(ips.into_iter().map(|ip| SocketAddr::new(ip, port)));
+        }
+        Ok(addrs)
+    }
+
+    /** Whether every interface the session is bound to is still there */
+    pub fn interfaces_up(&self) -> bool {
+        self.listen_interfaces
+            .iter()
+            .chain(&self.outgoing_interface)
+            .all(Interface::is_up)
+    }
+}
+
+/** IPv6 sockets only take IPv6, so IPv4 and IPv6 listeners can share a port */
+fn socket(addr: SocketAddr, kind: Type, protocol: Protocol) -> Result<Socket> {
+    let socket = Socket::new(Domain::for_address(addr), kind, Some(protocol))?;
+    if addr.is_ipv6() {
+        socket.set_only_v6(true)?;
+    }
+    Ok(socket)
+}
+
+pub fn bind_tcp(addr: SocketAddr) -> Result<std::net::TcpListener> {
+    let socket = socket(addr, Type::STREAM, Protocol::TCP)?;
+    socket.set_reuse_address(true)?;
+    socket.bind(&addr.into())?;
+    socket.listen(128)?;
+    socket.set_nonblocking(true)?;
+    Ok(socket.into())
+}
+
+pub fn bind_udp(addr: SocketAddr) -> Result<std::net::UdpSocket> {
+    let socket = socket(addr, Type::DGRAM, Protocol::UDP)?;
+    socket.bind(&addr.into())?;
+    socket.set_nonblocking(true)?;
+    Ok(socket.into())
+}
+
+/** Connect from the given interface, or from wherever the routing table says when there is none */
+pub async fn connect_tcp(addr: SocketAddr, interface: Option<&Interface>) -> Result<TcpStream> {
+    let Some(interface) = interface else {
+        return Ok(TcpStream::connect(addr).await?);
+    };
+    let socket = match addr {
+        SocketAddr::V4(_) => TcpSocket::new_v4()?,
+        SocketAddr::V6(_) => TcpSocket::new_v6()?,
+    };
+    socket.bind(SocketAddr::new(interface.local_ip_for(&addr)?, 0))?;
+    Ok(socket.connect(addr).await?)
+}
+
+/** UDP socket for talking to `remote`, bound to the given interface when there is one */
+pub async fn udp_socket_for(remote: SocketAddr, interface: Option<&Interface>) -> Result<UdpSocket> {
+    let ip = match interface {
+        Some(interface) => interface.local_ip_for(&remote)?,
+        None if remote.is_ipv4() => IpAddr::from(Ipv4Addr::UNSPECIFIED),
+        None => IpAddr::from(std::net::Ipv6Addr::UNSPECIFIED),
+    };
+    Ok(UdpSocket::bind(SocketAddr::new(ip, 0)).await?)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::Ipv6Addr;
+
+    use tokio::net::TcpListener;
+
+    use super::*;
+
+    #[test]
+    fn test_interfaces() {
+        assert_eq!("eth0".parse::<Interface>().unwrap(), Interface::Name("eth0".to_string()));
+        assert_eq!(
+            "[::1]".parse::<Interface>().unwrap(),
+            Interface::Addr(IpAddr::V6(Ipv6Addr::LOCALHOST))
+        );
+        assert!("".parse::<Interface>().is_err());
+
+        let loopback = Interface::Addr(IpAddr::V4(Ipv4Addr::LOCALHOST));
+        assert!(loopback.is_up());
+        assert_eq!(
+            loopback.local_ip_for(&"127.0.0.1:6881".parse().unwrap()).unwrap(),
+            IpAddr::V4(Ipv4Addr::LOCALHOST)
+        );
+        assert!(loopback.local_ip_for(&"[::1]:6881".parse().unwrap()).is_err());
+        let missing = Interface::Name("riffle-missing0".to_string());
+        assert!(!missing.is_up());
+        assert!(missing.local_ip_for(&"127.0.0.1:6881".parse().unwrap()).is_err());
+
+        let settings = NetworkSettings {
+            listen_interfaces: vec![loopback.clone()],
+            outgoing_interface: Some(missing),
+            kill_switch: true,
+        };
+        assert_eq!(settings.listen_addrs(6881).unwrap(), vec!["127.0.0.1:6881".parse().unwrap()]);
+        assert!(!settings.interfaces_up());
+        assert!(NetworkSettings::default().interfaces_up());
+    }
+
+    #[tokio::test]
+    async fn test_bound_connection() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let loopback = Interface::Addr(IpAddr::V4(Ipv4Addr::LOCALHOST));
+        let (stream, accepted) = tokio::join!(connect_tcp(addr, Some(&loopback)), listener.accept());
+        assert_eq!(stream.unwrap().local_addr().unwrap(), accepted.unwrap().1);
+
+        let missing = Interface::Addr("192.0.2.1".parse().unwrap());
+        assert!(connect_tcp(addr, Some(&missing)).await.is_err());
+
+        // IPv4 and IPv6 listeners on the same port
+        let tcp = bind_tcp("127.0.0.1:0".parse().unwrap()).unwrap();
+        let port = tcp.local_addr().unwrap().port();
+        if let Ok(tcp6) = bind_tcp(SocketAddr::new(Ipv6Addr::LOCALHOST.into(), port)) {
+            assert_eq!(tcp6.local_addr().unwrap().port(), port);
+        }
+        let udp = bind_udp(SocketAddr::new(Ipv4Addr::LOCALHOST.into(), port)).unwrap();
+        assert_eq!(udp.local_addr().unwrap().port(), port);
+    }
+}
@@ -1,6 +1,14 @@
+use std::net::SocketAddr;
+
+use anyhow::{Error, Result};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+use crate::merkle::Hash;
+use crate::piece::{Block, PieceDownload};
+use crate::rate_limit::{Bandwidth, Direction};
 use crate::{bitfield::BitField, tracker::TrackerPeer, utils::IpAddr};
 
-/**
+/*
  * Overview
  * The peer protocol facilitates the exchange of pieces as described in the 'metainfo file.
  *
@@ -27,7 +35,7 @@ use crate::{bitfield::BitField, tracker::TrackerPeer, utils::IpAddr};
  * It is important for the client to keep its peers informed as to whether or not it is interested in them. This state information should be kept up-to-date with each peer even when the client is choked. This will allow peers to know if the client will begin downloading when it is unchoked (and vice-versa).
  */
 
-/**
+/*
  * Data Types
  * Unless specified otherwise, all integers in the peer wire protocol are encoded as four byte big-endian values. This includes the length prefix on all messages that come after the handshake.
  */
@@ -51,10 +59,20 @@ pub struct PeerWire {
     peer_choking: bool,
     peer_interested: bool,
 
-    peer_bitfield: BitField
+    peer_bitfield: BitField,
+
+    /** Piece whose blocks were requested from the peer */
+    download: Option<PieceDownload>,
 }
 
 impl PeerWire {
+    pub fn from_tracker_peer(info: TrackerPeer) -> Self {
+        let peer_id = info.peer_id.clone();
+        let ip = info.ip.clone();
+        let port = info.port;
+        Self::new(info, peer_id, ip, port)
+    }
+
     pub fn new(info: TrackerPeer, peer_id: Option<String>, ip: IpAddr, port: u16) -> Self {
         Self {
             info,
@@ -65,9 +83,71 @@ impl PeerWire {
             am_interested: false,
             peer_choking: true,
             peer_interested: false,
-            peer_bitfield: BitField::new(0)
+            peer_bitfield: BitField::new(0),
+            download: None,
         }
     }
+
+    /** A peer we exchanged handshakes with */
+    pub fn from_handshake(addr: SocketAddr, handshake: &Handshake, pieces_count: usize) -> Self {
+        let mut info = TrackerPeer::from(addr);
+        info.peer_id = Some(String::from_utf8_lossy(&handshake.peer_id()).into_owned());
+        let mut peer = Self::from_tracker_peer(info);
+        peer.peer_bitfield = BitField::new(pieces_count);
+        peer
+    }
+
+    pub fn info(&self) -> &TrackerPeer {
+        &self.info
+    }
+
+    pub fn is_addr(&self, addr: &SocketAddr) -> bool {
+        self.port == addr.port() && self.ip == IpAddr::from(addr.ip())
+    }
+
+    pub fn bitfield(&self) -> &BitField {
+        &self.peer_bitfield
+    }
+
+    pub fn bitfield_mut(&mut self) -> &mut BitField {
+        &mut self.peer_bitfield
+    }
+
+    pub fn set_bitfield(&mut self, bitfield: BitField) {
+        self.peer_bitfield = bitfield;
+    }
+
+    pub fn is_choking(&self) -> bool {
+        self.peer_choking
+    }
+
+    pub fn set_choking(&mut self, choking: bool) {
+        self.peer_choking = choking;
+    }
+
+    pub fn is_interested(&self) -> bool {
+        self.am_interested
+    }
+
+    pub fn set_interested(&mut self, interested: bool) {
+        self.am_interested = interested;
+    }
+
+    pub fn download(&self) -> Option<&PieceDownload> {
+        self.download.as_ref()
+    }
+
+    pub fn download_mut(&mut self) -> Option<&mut PieceDownload> {
+        self.download.as_mut()
+    }
+
+    pub fn set_download(&mut self, download: Option<PieceDownload>) {
+        self.download = download;
+    }
+
+    pub fn take_download(&mut self) -> Option<PieceDownload> {
+        self.download.take()
+    }
 }
 
 /**
@@ -96,6 +176,71 @@ pub struct Handshake {
     peer_id: [u8; 20],
 }
 
+pub const PROTOCOL: &str = "BitTorrent protocol";
+pub const HANDSHAKE_LENGTH: usize = 49 + PROTOCOL.len();
+
+/** BEP 52: peers supporting v2 set the fourth most significant bit of the last reserved byte */
+pub const RESERVED_V2_BIT: (usize, u8) = (7, 0x10);
+
+impl Handshake {
+    pub fn new(info_hash: [u8; 20], peer_id: [u8; 20]) -> Self {
+        Self {
+            pstrlen: PROTOCOL.len() as u8,
+            pstr: PROTOCOL.to_string(),
+            reserved: [0; 8],
+            info_hash,
+            peer_id,
+        }
+    }
+
+    /**
+     * Hybrid torrents are joined on the v1 swarm with the v1 info hash and on the v2 swarm with the truncated v2 info hash.
+     * The v2 bit is set in both cases so peers can upgrade to the v2 hash messages.
+     */
+    pub fn with_v2(mut self) -> Self {
+        self.reserved[RESERVED_V2_BIT.0] |= RESERVED_V2_BIT.1;
+        self
+    }
+
+    pub fn supports_v2(&self) -> bool {
+        self.reserved[RESERVED_V2_BIT.0] & RESERVED_V2_BIT.1 != 0
+    }
+
+    pub fn info_hash(&self) -> [u8; 20] {
+        self.info_hash
+    }
+
+    pub fn peer_id(&self) -> [u8; 20] {
+        self.peer_id
+    }
+
+    pub fn to_buffer(&self) -> Vec<u8> {
+        let mut buffer = Vec::with_capacity(HANDSHAKE_LENGTH);
+        buffer.push(self.pstrlen);
+        buffer.extend_from_slice(self.pstr.as_bytes());
+        buffer.extend_from_slice(&self.reserved);
+        buffer.extend_from_slice(&self.info_hash);
+        buffer.extend_from_slice(&self.peer_id);
+        buffer
+    }
+
+    pub fn from_buffer(buffer: &[u8]) -> Result<Handshake> {
+        if buffer.len() != HANDSHAKE_LENGTH
+            || buffer[0] as usize != PROTOCOL.len()
+            || &buffer[1..20] != PROTOCOL.as_bytes()
+        {
+            return Err(Error::msg("Not a BitTorrent handshake"));
+        }
+        Ok(Handshake {
+            pstrlen: buffer[0],
+            pstr: PROTOCOL.to_string(),
+            reserved: buffer[20..28].try_into()?,
+            info_hash: buffer[28..48].try_into()?,
+            peer_id: buffer[48..68].try_into()?,
+        })
+    }
+}
+
 /**
  * All of the remaining messages in the protocol take the form of <length prefix><message ID><payload>. The length prefix is a four byte big-endian value. The message ID is a single decimal byte. The payload is message dependent.
  */
@@ -114,6 +259,48 @@ pub enum MessageId {
     Piece = 7,
     Cancel = 8,
     Port = 9,
+    /** BEP 52 */
+    HashRequest = 21,
+    Hashes = 22,
+    HashReject = 23,
+}
+
+/** Messages longer than this are treated as a protocol violation */
+pub const MAX_MESSAGE_LENGTH: usize = 4 * 1024 * 1024;
+
+/** Only the blocks of piece messages are payload, everything else on the wire is protocol overhead */
+pub fn payload_length(message: &[u8]) -> usize {
+    match message.first() {
+        Some(&id) if id == MessageId::Piece as u8 => message.len().saturating_sub(9),
+        _ => 0,
+    }
+}
+
+/** Read one length-prefixed message, without its prefix. Keep-alives are empty */
+pub async fn read_message<R: AsyncRead + Unpin>(reader: &mut R, bandwidth: &Bandwidth) -> Result<Vec<u8>> {
+    let length = reader.read_u32().await? as usize;
+    if length > MAX_MESSAGE_LENGTH {
+        return Err(Error::msg(format!("Peer sent a {} bytes long message", length)));
+    }
+    let mut message = vec![0; length];
+    reader.read_exact(&mut message).await?;
+    let payload = payload_length(&message);
+    bandwidth
+        .acquire(Direction::Download, payload as u64, (4 + length - payload) as u64)
+        .await;
+    Ok(message)
+}
+
+/** Write a message built by one of the `to_buffer` methods, length prefix included */
+pub async fn write_message<W: AsyncWrite + Unpin>(writer: &mut W, buffer: &[u8], bandwidth: &Bandwidth) -> Result<()> {
+    let payload = payload_length(buffer.get(4..).unwrap_or_default());
+    bandwidth
+        .acquire(Direction::Upload, payload as u64, (buffer.len() - payload) as u64)
+        .await;
+    writer.write_all(buffer).await?;
+    // Encrypted streams may hold on to what the socket didn't take
+    writer.flush().await?;
+    Ok(())
 }
 
 #[derive(Debug, Clone)]
@@ -263,3 +450,257 @@ pub struct Port {
     message_id: MessageId,
     listen_port: u16,
 }
+
+/**
+ * The hash request message is used to request hashes of the merkle tree of a file, along with the uncle hashes needed to validate them.
+ * hash request: <len=0049><id=21><pieces root><base layer><index><length><proof layers>
+ * pieces root: root hash of the file
+ * base layer: the lowest requested layer of the merkle tree, 0 being the leaf layer
+ * index: index of the first requested hash in the base layer
+ * length: number of hashes requested, must be a power of two and at least 2
+ * proof layers: number of ancestor layers to include uncle hashes for
+ */
+#[derive(Debug, Clone, PartialEq)]
+pub struct HashRequest {
+    pub pieces_root: Hash,
+    pub base_layer: u32,
+    pub index: u32,
+    pub length: u32,
+    pub proof_layers: u32,
+}
+
+/**
+ * The hashes message is sent in response to a hash request, with the same fields followed by the requested hashes then the uncle hashes, bottom up.
+ * hashes: <len=0049+X*32><id=22><pieces root><base layer><index><length><proof layers><hashes>
+ */
+#[derive(Debug, Clone, PartialEq)]
+pub struct Hashes {
+    pub request: HashRequest,
+    pub hashes: Vec<Hash>,
+}
+
+/**
+ * The hash reject message is sent when a hash request can't be answered, its payload is identical to the hash request.
+ * hash reject: <len=0049><id=23><pieces root><base layer><index><length><proof layers>
+ */
+#[derive(Debug, Clone, PartialEq)]
+pub struct HashReject {
+    pub request: HashRequest,
+}
+
+impl HashRequest {
+    const PAYLOAD_LENGTH: usize = 48;
+
+    fn write_payload(&self, buffer: &mut Vec<u8>) {
+        buffer.extend_from_slice(&self.pieces_root);
+        for value in [self.base_layer, self.index, self.length, self.proof_layers] {
+            buffer.extend_from_slice(&value.to_be_bytes());
+        }
+    }
+
+    fn read_payload(payload: &[u8]) -> Result<HashRequest> {
+        if payload.len() < HashRequest::PAYLOAD_LENGTH {
+            return Err(Error::msg("Hash request payload is too short"));
+        }
+        let read_u32 = |offset: usize| u32::from_be_bytes(payload[offset..offset + 4].try_into().unwrap());
+        Ok(HashRequest {
+            pieces_root: payload[..32].try_into()?,
+            base_layer: read_u32(32),
+            index: read_u32(36),
+            length: read_u32(40),
+            proof_layers: read_u32(44),
+        })
+    }
+
+    fn to_message(&self, message_id: MessageId, hashes: &[Hash]) -> Vec<u8> {
+        let length_prefix = (1 + HashRequest::PAYLOAD_LENGTH + hashes.len() * 32) as u32;
+        let mut buffer = Vec::with_capacity(4 + length_prefix as usize);
+        buffer.extend_from_slice(&length_prefix.to_be_bytes());
+        buffer.push(message_id as u8);
+        self.write_payload(&mut buffer);
+        for hash in hashes {
+            buffer.extend_from_slice(hash);
+        }
+        buffer
+    }
+
+    pub fn to_buffer(&self) -> Vec<u8> {
+        self.to_message(MessageId::HashRequest, &[])
+    }
+
+    /** Parse the payload following the message id */
+    pub fn from_payload(payload: &[u8]) -> Result<HashRequest> {
+        if payload.len() != HashRequest::PAYLOAD_LENGTH {
+            return Err(Error::msg("Bad hash request length"));
+        }
+        HashRequest::read_payload(payload)
+    }
+}
+
+impl Hashes {
+    pub fn to_buffer(&self) -> Vec<u8> {
+        self.request.to_message(MessageId::Hashes, &self.hashes)
+    }
+
+    pub fn from_payload(payload: &[u8]) -> Result<Hashes> {
+        let request = HashRequest::read_payload(payload)?;
+        let hashes = &payload[HashRequest::PAYLOAD_LENGTH..];
+        if !hashes.len().is_multiple_of(32) {
+            return Err(Error::msg("Bad hashes length"));
+        }
+        Ok(Hashes {
+            request,
+            hashes: hashes.chunks(32).map(|hash| hash.try_into().unwrap()).collect(),
+        })
+    }
+}
+
+impl HashReject {
+    pub fn to_buffer(&self) -> Vec<u8> {
+        self.request.to_message(MessageId::HashReject, &[])
+    }
+
+    pub fn from_payload(payload: &[u8]) -> Result<HashReject> {
+        Ok(HashReject {
+            request: HashRequest::from_payload(payload)?,
+        })
+    }
+}
+
+impl Default for Interested {
+    fn default() -> Self {
+        Self {
+            length_prefix: 1,
+            message_id: MessageId::Interested,
+        }
+    }
+}
+
+impl Interested {
+    pub fn to_buffer(&self) -> Vec<u8> {
+        let mut buffer = self.length_prefix.to_be_bytes().to_vec();
+        buffer.push(self.message_id.clone() as u8);
+        buffer
+    }
+}
+
+impl Request {
+    pub fn new(index: u32, begin: u32, length: u32) -> Self {
+        Self {
+            length_prefix: 13,
+            message_id: MessageId::Request,
+            index,
+            begin,
+            length,
+        }
+    }
+
+    pub fn to_buffer(&self) -> Vec<u8> {
+        let mut buffer = Vec::with_capacity(4 + self.length_prefix as usize);
+        buffer.extend_from_slice(&self.length_prefix.to_be_bytes());
+        buffer.push(self.message_id.clone() as u8);
+        for value in [self.index, self.begin, self.length] {
+            buffer.extend_from_slice(&value.to_be_bytes());
+        }
+        buffer
+    }
+}
+
+impl Piece {
+    /** Parse the payload following the message id */
+    pub fn from_payload(payload: &[u8]) -> Result<Piece> {
+        if payload.len() < 8 {
+            return Err(Error::msg("Piece payload is too short"));
+        }
+        Ok(Piece {
+            length_prefix: (1 + payload.len()) as u32,
+            message_id: MessageId::Piece,
+            index: u32::from_be_bytes(payload[..4].try_into()?),
+            begin: u32::from_be_bytes(payload[4..8].try_into()?),
+            block: payload[8..].to_vec(),
+        })
+    }
+
+    /** The block carried by the message, as received from `peer` */
+    pub fn into_block(self, peer: SocketAddr) -> Block {
+        Block {
+            piece_index: self.index,
+            begin: self.begin,
+            length: self.block.len() as u32,
+            data: self.block,
+            peer: Some(peer),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_hash_messages() {
+        let request = HashRequest {
+            pieces_root: [7; 32],
+            base_layer: 0,
+            index: 4,
+            length: 2,
+            proof_layers: 3,
+        };
+        let buffer = request.to_buffer();
+        assert_eq!(&buffer[..5], &[0, 0, 0, 49, MessageId::HashRequest as u8]);
+        assert_eq!(HashRequest::from_payload(&buffer[5..]).unwrap(), request);
+
+        let hashes = Hashes {
+            request: request.clone(),
+            hashes: vec![[1; 32], [2; 32], [3; 32]],
+        };
+        let buffer = hashes.to_buffer();
+        assert_eq!(u32::from_be_bytes(buffer[..4].try_into().unwrap()), 49 + 3 * 32);
+        assert_eq!(Hashes::from_payload(&buffer[5..]).unwrap(), hashes);
+        assert!(Hashes::from_payload(&buffer[5..buffer.len() - 1]).is_err());
+
+        let reject = HashReject { request };
+        let buffer = reject.to_buffer();
+        assert_eq!(buffer[4], MessageId::HashReject as u8);
+        assert_eq!(HashReject::from_payload(&buffer[5..]).unwrap(), reject);
+    }
+
+    #[test]
+    fn test_block_messages() {
+        assert_eq!(Interested::default().to_buffer(), [0, 0, 0, 1, 2]);
+        assert_eq!(Request::new(1, 16384, 100).to_buffer(), [0, 0, 0, 13, 6, 0, 0, 0, 1, 0, 0, 64, 0, 0, 0, 0, 100]);
+        let block = Piece::from_payload(&[0, 0, 0, 1, 0, 0, 0, 5, 0xaa, 0xbb]).unwrap().into_block("10.0.0.1:6881".parse().unwrap());
+        assert_eq!((block.piece_index, block.begin, block.length, block.data), (1, 5, 2, vec![0xaa, 0xbb]));
+        assert!(Piece::from_payload(&[0, 0, 0, 1]).is_err());
+    }
+
+    #[test]
+    fn test_handshake() {
+        let handshake = Handshake::new([1; 20], [2; 20]).with_v2();
+        let buffer = handshake.to_buffer();
+        assert_eq!(buffer.len(), HANDSHAKE_LENGTH);
+        let parsed = Handshake::from_buffer(&buffer).unwrap();
+        assert!(parsed.supports_v2());
+        assert_eq!(parsed.info_hash(), [1; 20]);
+        assert_eq!(parsed.peer_id(), [2; 20]);
+        assert!(!Handshake::new([1; 20], [2; 20]).supports_v2());
+    }
+
+    #[tokio::test]
+    async fn test_message_framing() {
+        let (mut a, mut b) = tokio::io::duplex(1024);
+        let bandwidth = Bandwidth::unlimited();
+        let mut piece = 13u32.to_be_bytes().to_vec();
+        piece.push(MessageId::Piece as u8);
+        piece.extend_from_slice(&[0, 0, 0, 1, 0, 0, 0, 0, 0xaa, 0xbb, 0xcc, 0xdd]);
+        assert_eq!(payload_length(&piece[4..]), 4);
+
+        write_message(&mut a, &piece, &bandwidth).await.unwrap();
+        write_message(&mut a, &[0, 0, 0, 0], &bandwidth).await.unwrap();
+        assert_eq!(read_message(&mut b, &bandwidth).await.unwrap(), piece[4..]);
+        assert!(read_message(&mut b, &bandwidth).await.unwrap().is_empty());
+
+        a.write_all(&u32::MAX.to_be_bytes()).await.unwrap();
+        assert!(read_message(&mut b, &bandwidth).await.is_err());
+    }
+}
@@ -1,11 +1,55 @@
+use std::net::SocketAddr;
 
+#[derive(Debug, Clone, PartialEq)]
 pub struct Block {
     pub piece_index: u32,
     pub begin: u32,
     pub length: u32,
     pub data: Vec<u8>,
+    /** Peer the block was received from, `None` for web seeds and whole pieces handed in at once */
+    pub peer: Option<SocketAddr>,
 }
 
+/** Blocks are requested this long, peers drop connections asking for more at once */
+pub const BLOCK_LENGTH: u32 = 16 * 1024;
+
+/** A piece downloaded from one peer, every block is requested at once */
+#[derive(Debug, Clone, PartialEq)]
+pub struct PieceDownload {
+    pub index: usize,
+    /** Begin and length of the blocks not received yet */
+    pub pending: Vec<(u32, u32)>,
+    pub blocks: Vec<Block>,
+}
+
+impl PieceDownload {
+    pub fn new(index: usize, length: u64) -> Self {
+        let pending = (0..length)
+            .step_by(BLOCK_LENGTH as usize)
+            .map(|begin| (begin as u32, (length - begin).min(BLOCK_LENGTH as u64) as u32))
+            .collect();
+        Self {
+            index,
+            pending,
+            blocks: vec![],
+        }
+    }
+
+    /** Keep a block that was asked for, returns true once every block of the piece is in */
+    pub fn add_block(&mut self, block: Block) -> bool {
+        let position = self
+            .pending
+            .iter()
+            .position(|&(begin, length)| block.piece_index as usize == self.index && begin == block.begin && length == block.length);
+        if let Some(position) = position {
+            self.pending.swap_remove(position);
+            self.blocks.push(block);
+        }
+        self.pending.is_empty()
+    }
+}
+
+#[derive(Debug, Clone)]
 pub struct Piece {
     pub index: u32,
     pub blocks: Vec<Block>,
@@ -27,7 +71,12 @@ impl Piece {
         }
     }
 
+    /** Blocks are copied in place, so `data` is the piece as far as it was received */
     pub fn add_block(&mut self, block: Block) {
+        let begin = block.begin as usize;
+        if let Some(target) = self.data.get_mut(begin..begin + block.data.len()) {
+            target.copy_from_slice(&block.data);
+        }
         self.blocks.push(block);
     }
 
@@ -55,3 +104,27 @@ impl Piece {
         self.hash.clone()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_piece_download() {
+        let mut download = PieceDownload::new(3, BLOCK_LENGTH as u64 + 10);
+        assert_eq!(download.pending, vec![(0, BLOCK_LENGTH), (BLOCK_LENGTH, 10)]);
+        let block = |piece_index, begin, length| Block {
+            piece_index,
+            begin,
+            length,
+            data: vec![0; length as usize],
+            peer: None,
+        };
+        // Blocks nobody asked for are ignored
+        assert!(!download.add_block(block(2, BLOCK_LENGTH, 10)));
+        assert!(!download.add_block(block(3, BLOCK_LENGTH, 9)));
+        assert!(!download.add_block(block(3, BLOCK_LENGTH, 10)));
+        assert!(download.add_block(block(3, 0, BLOCK_LENGTH)));
+        assert_eq!(download.blocks.len(), 2);
+    }
+}
@@ -1,15 +1,103 @@
-use crate::peer::PeerWire;
+use std::cmp::Reverse;
 
+use crate::bitfield::BitField;
+
+/** Download priority of a file, pieces take the highest priority of the files they span */
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Default, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Priority {
+    /** Never requested, unless the piece is shared with a wanted file */
+    Skip,
+    Low,
+    #[default]
+    Normal,
+    High,
+}
+
+impl Priority {
+    pub fn from_u8(value: u8) -> Option<Priority> {
+        match value {
+            0 => Some(Priority::Skip),
+            1 => Some(Priority::Low),
+            2 => Some(Priority::Normal),
+            3 => Some(Priority::High),
+            _ => None,
+        }
+    }
+}
 
 #[derive(Debug, Clone)]
-pub struct PiecePicker<'a> {
-    peers: &'a Vec<PeerWire>,
+pub struct PiecePicker {
+    /** Number of known peers having each piece */
+    availability: Vec<u32>,
+    priorities: Vec<Priority>,
+    /** Pieces being downloaded, never picked twice */
+    downloading: Vec<bool>,
 }
 
-impl PiecePicker<'_> {
-    pub fn new(peers: &Vec<PeerWire>) -> PiecePicker {
+impl PiecePicker {
+    pub fn new(pieces_count: usize) -> PiecePicker {
         PiecePicker {
-            peers,
+            availability: vec![0; pieces_count],
+            priorities: vec![Priority::Normal; pieces_count],
+            downloading: vec![false; pieces_count],
+        }
+    }
+
+    /** A peer having every piece, like a web seed */
+    pub fn add_seed(&mut self) {
+        for availability in self.availability.iter_mut() {
+            *availability += 1;
+        }
+    }
+
+    pub fn add_bitfield(&mut self, bitfield: &BitField) {
+        for (index, has) in bitfield.iter().enumerate().take(self.availability.len()) {
+            if has {
+                self.availability[index] += 1;
+            }
+        }
+    }
+
+    pub fn remove_bitfield(&mut self, bitfield: &BitField) {
+        for (index, has) in bitfield.iter().enumerate().take(self.availability.len()) {
+            if has {
+                self.availability[index] = self.availability[index].saturating_sub(1);
+            }
         }
     }
+
+    /** A peer announcing a piece it just got */
+    pub fn add_have(&mut self, index: usize) {
+        if let Some(availability) = self.availability.get_mut(index) {
+            *availability += 1;
+        }
+    }
+
+    pub fn availability(&self, index: usize) -> u32 {
+        self.availability[index]
+    }
+
+    pub fn priority(&self, index: usize) -> Priority {
+        self.priorities[index]
+    }
+
+    pub fn set_priority(&mut self, index: usize, priority: Priority) {
+        self.priorities[index] = priority;
+    }
+
+    /** Cleared once the piece is stored or has to be downloaded again */
+    pub fn set_downloading(&mut self, index: usize, downloading: bool) {
+        if let Some(slot) = self.downloading.get_mut(index) {
+            *slot = downloading;
+        }
+    }
+
+    /** Highest priority first, then rarest first, among the wanted pieces we miss, aren't downloading and the peer has */
+    pub fn pick(&self, have: &BitField, peer_has: impl Fn(usize) -> bool) -> Option<usize> {
+        (0..self.availability.len())
+            .filter(|&index| self.priorities[index] != Priority::Skip && !self.downloading[index])
+            .filter(|&index| !have.get(index) && peer_has(index))
+            .min_by_key(|&index| (Reverse(self.priorities[index]), self.availability[index]))
+    }
 }
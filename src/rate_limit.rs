@@ -0,0 +1,211 @@
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+/**
+ * Token bucket shared by every transfer it limits, `rate` bytes are added per second up to one second worth of burst.
+ * Transfers larger than the bucket are allowed to borrow, the bucket goes negative and the next transfers wait it out.
+ */
+#[derive(Debug)]
+struct TokenBucket {
+    /** Bytes per second, `None` is unlimited */
+    rate: Option<u64>,
+    tokens: f64,
+    last_refill: Instant,
+}
+
+impl TokenBucket {
+    fn refill(&mut self) {
+        let now = Instant::now();
+        if let Some(rate) = self.rate {
+            let elapsed = now.duration_since(self.last_refill).as_secs_f64();
+            self.tokens = (self.tokens + elapsed * rate as f64).min(rate as f64);
+        }
+        self.last_refill = now;
+    }
+
+    /** Take the tokens and return how long to wait before the transfer may go on */
+    fn take(&mut self, amount: u64) -> Duration {
+        let Some(rate) = self.rate.filter(|rate| *rate > 0) else {
+            return Duration::ZERO;
+        };
+        self.refill();
+        self.tokens -= amount as f64;
+        if self.tokens >= 0.0 {
+            Duration::ZERO
+        } else {
+            Duration::from_secs_f64(-self.tokens / rate as f64)
+        }
+    }
+}
+
+/** Cheap to clone handle to a token bucket, the rate can be changed at any time */
+#[derive(Debug, Clone)]
+pub struct RateLimiter {
+    bucket: Arc<Mutex<TokenBucket>>,
+}
+
+impl RateLimiter {
+    pub fn new(rate: Option<u64>) -> Self {
+        Self {
+            bucket: Arc::new(Mutex::new(TokenBucket {
+                rate,
+                tokens: rate.unwrap_or(0) as f64,
+                last_refill: Instant::now(),
+            })),
+        }
+    }
+
+    pub fn unlimited() -> Self {
+        Self::new(None)
+    }
+
+    pub fn rate(&self) -> Option<u64> {
+        self.bucket.lock().unwrap().rate
+    }
+
+    pub fn set_rate(&self, rate: Option<u64>) {
+        let mut bucket = self.bucket.lock().unwrap();
+        bucket.refill();
+        bucket.rate = rate;
+        // Don't keep a burst or a debt computed with the previous rate
+        bucket.tokens = bucket.tokens.clamp(0.0, rate.unwrap_or(0) as f64);
+    }
+
+    /** Wait until `amount` bytes may be transferred */
+    pub async fn acquire(&self, amount: u64) {
+        let wait = self.bucket.lock().unwrap().take(amount);
+        if !wait.is_zero() {
+            tokio::time::sleep(wait).await;
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Direction {
+    Upload,
+    Download,
+}
+
+/** Upload and download limiters of one level: the session, a torrent or a peer */
+#[derive(Debug, Clone)]
+pub struct Limiters {
+    pub upload: RateLimiter,
+    pub download: RateLimiter,
+}
+
+impl Limiters {
+    pub fn new(upload: Option<u64>, download: Option<u64>) -> Self {
+        Self {
+            upload: RateLimiter::new(upload),
+            download: RateLimiter::new(download),
+        }
+    }
+
+    pub fn unlimited() -> Self {
+        Self::new(None, None)
+    }
+
+    pub fn get(&self, direction: Direction) -> &RateLimiter {
+        match direction {
+            Direction::Upload => &self.upload,
+            Direction::Download => &self.download,
+        }
+    }
+}
+
+/**
+ * Every limiter a transfer goes through, from the session down to the peer.
+ * Protocol overhead (message headers, handshakes, HTTP headers) only counts against the limits when `include_overhead` is set.
+ */
+#[derive(Debug, Clone)]
+pub struct Bandwidth {
+    levels: Vec<Limiters>,
+    include_overhead: Arc<AtomicBool>,
+}
+
+impl Bandwidth {
+    pub fn new(global: Limiters) -> Self {
+        Self {
+            levels: vec![global],
+            include_overhead: Arc::new(AtomicBool::new(false)),
+        }
+    }
+
+    pub fn unlimited() -> Self {
+        Self::new(Limiters::unlimited())
+    }
+
+    /** The same limits with one more level below, sharing the overhead setting */
+    pub fn child(&self, limiters: &Limiters) -> Self {
+        let mut levels = self.levels.clone();
+        levels.push(limiters.clone());
+        Self {
+            levels,
+            include_overhead: self.include_overhead.clone(),
+        }
+    }
+
+    pub fn include_overhead(&self) -> bool {
+        self.include_overhead.load(Ordering::Relaxed)
+    }
+
+    pub fn set_include_overhead(&self, include_overhead: bool) {
+        self.include_overhead.store(include_overhead, Ordering::Relaxed);
+    }
+
+    /** Wait until every level lets `payload` bytes, plus `overhead` bytes if counted, through */
+    pub async fn acquire(&self, direction: Direction, payload: u64, overhead: u64) {
+        let amount = if self.include_overhead() {
+            payload + overhead
+        } else {
+            payload
+        };
+        if amount == 0 {
+            return;
+        }
+        for limiters in &self.levels {
+            limiters.get(direction).acquire(amount).await;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_rate_limits() {
+        let start = Instant::now();
+        Bandwidth::unlimited()
+            .acquire(Direction::Download, 1 << 30, 0)
+            .await;
+        assert!(start.elapsed() < Duration::from_millis(50));
+
+        let global = Limiters::new(Some(100_000), Some(100_000));
+        let torrent = Limiters::new(None, Some(10_000));
+        let bandwidth = Bandwidth::new(global).child(&torrent);
+
+        // The first second worth of data goes through at once, the rest at the torrent rate
+        let start = Instant::now();
+        bandwidth.acquire(Direction::Download, 10_000, 0).await;
+        bandwidth.acquire(Direction::Download, 2_000, 0).await;
+        let elapsed = start.elapsed();
+        assert!(elapsed >= Duration::from_millis(150) && elapsed < Duration::from_millis(600));
+
+        // Overhead is free unless included
+        let start = Instant::now();
+        bandwidth.acquire(Direction::Upload, 0, 1 << 30).await;
+        assert!(start.elapsed() < Duration::from_millis(50));
+        bandwidth.set_include_overhead(true);
+        torrent.upload.set_rate(Some(10_000));
+        let start = Instant::now();
+        bandwidth.acquire(Direction::Upload, 1_000, 1_000).await;
+        assert!(start.elapsed() >= Duration::from_millis(150));
+
+        torrent.download.set_rate(None);
+        let start = Instant::now();
+        bandwidth.acquire(Direction::Download, 50_000, 0).await;
+        assert!(start.elapsed() < Duration::from_millis(50));
+    }
+}
@@ -0,0 +1,54 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use anyhow::{Context, Result};
+use serde_bytes::ByteBuf;
+
+/**
+ * Resume data lets a torrent pick up where it left off without hashing everything again.
+ * It is stored bencoded, one file per torrent named after its info hash.
+ * Pieces claimed by resume data are only trusted if the files they land in look right on disk.
+ */
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+pub struct ResumeData {
+    #[serde(rename = "info hash")]
+    pub info_hash: String,
+    /** Bitfield of the verified pieces */
+    pub pieces: ByteBuf,
+    #[serde(rename = "file priorities")]
+    pub file_priorities: Vec<u8>,
+    pub uploaded: u64,
+    pub downloaded: u64,
+    /** Seconds */
+    #[serde(default)]
+    #[serde(rename = "seeding time")]
+    pub seeding_time: u64,
+    pub paused: u8,
+    #[serde(default)]
+    pub error: Option<String>,
+    #[serde(default)]
+    #[serde(rename = "queue position")]
+    pub queue_position: usize,
+}
+
+impl ResumeData {
+    pub fn path(resume_dir: &Path, info_hash: &str) -> PathBuf {
+        resume_dir.join(format!("{}.resume", info_hash))
+    }
+
+    pub fn from_file(path: &Path) -> Result<ResumeData> {
+        let buffer = fs::read(path).context(format!("Failed to read {}", path.display()))?;
+        serde_bencode::from_bytes(&buffer).context(format!("Failed to parse resume data {}", path.display()))
+    }
+
+    /** Written to a temporary file first so a crash never leaves truncated resume data behind */
+    pub fn write(&self, path: &Path) -> Result<()> {
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent).context(format!("Failed to create {}", parent.display()))?;
+        }
+        let temporary = path.with_extension("resume.tmp");
+        fs::write(&temporary, serde_bencode::to_bytes(self)?)
+            .context(format!("Failed to write {}", temporary.display()))?;
+        fs::rename(&temporary, path).context(format!("Failed to write {}", path.display()))
+    }
+}
@@ -0,0 +1,127 @@
+use std::collections::HashMap;
+use std::net::SocketAddr;
+
+use sha1::{Digest, Sha1};
+
+use crate::piece::Block;
+
+#[derive(Debug, Clone, PartialEq)]
+struct BlockRecord {
+    begin: u32,
+    length: u32,
+    peer: SocketAddr,
+    digest: [u8; 20],
+}
+
+/**
+ * Find out who sent the corrupt data of pieces downloaded from several peers.
+ * The blocks of a piece failing the hash check are remembered by digest, once the piece was downloaded again and passed,
+ * the peers whose blocks differ from the good data are the ones who sent bad data.
+ */
+#[derive(Debug, Clone, Default)]
+pub struct SmartBan {
+    failed: HashMap<u32, Vec<BlockRecord>>,
+}
+
+impl SmartBan {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /**
+     * Remember the blocks of a piece that failed the hash check, blocks without a peer can't be blamed on anyone.
+     * Only the latest block of each peer at each offset is kept, so a piece failing over and over doesn't grow the records.
+     */
+    pub fn piece_failed(&mut self, index: u32, blocks: &[Block]) {
+        let records = self.failed.entry(index).or_default();
+        for block in blocks {
+            if let Some(peer) = block.peer {
+                records.retain(|record| record.begin != block.begin || record.peer != peer);
+                records.push(BlockRecord {
+                    begin: block.begin,
+                    length: block.length,
+                    peer,
+                    digest: Sha1::digest(&block.data).into(),
+                });
+            }
+        }
+    }
+
+    /** Compare the remembered blocks with the piece that passed, returns the peers that sent corrupt blocks */
+    pub fn piece_passed(&mut self, index: u32, data: &[u8]) -> Vec<SocketAddr> {
+        let Some(records) = self.failed.remove(&index) else {
+            return vec![];
+        };
+        let mut culprits = records
+            .into_iter()
+            .filter(|record| {
+                let range = record.begin as usize..(record.begin + record.length) as usize;
+                data.get(range)
+                    .is_none_or(|good| <[u8; 20]>::from(Sha1::digest(good)) != record.digest)
+            })
+            .map(|record| record.peer)
+            .collect::<Vec<_>>();
+        culprits.sort();
+        culprits.dedup();
+        culprits
+    }
+
+    /** Pieces waiting to be downloaded again */
+    pub fn pending(&self) -> usize {
+        self.failed.len()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn block(begin: u32, data: &[u8], peer: Option<&str>) -> Block {
+        Block {
+            piece_index: 0,
+            begin,
+            length: data.len() as u32,
+            data: data.to_vec(),
+            peer: peer.map(|peer| peer.parse().unwrap()),
+        }
+    }
+
+    #[test]
+    fn test_smart_ban() {
+        let good = b"aaaabbbbccccdddd";
+        let mut smart_ban = SmartBan::new();
+        assert!(smart_ban.piece_passed(0, good).is_empty());
+
+        smart_ban.piece_failed(
+            0,
+            &[
+                block(0, b"aaaa", Some("10.0.0.1:1")),
+                block(4, b"bXbb", Some("10.0.0.2:2")),
+                block(8, b"cccc", Some("10.0.0.3:3")),
+                block(12, b"dddd", None),
+            ],
+        );
+        // Failed again, with the corrupt block coming from another peer this time
+        smart_ban.piece_failed(
+            0,
+            &[
+                block(0, b"aaaa", Some("10.0.0.1:1")),
+                block(4, b"bbbb", Some("10.0.0.3:3")),
+                block(8, b"cccc", Some("10.0.0.3:3")),
+                block(12, b"ddXd", Some("10.0.0.4:4")),
+            ],
+        );
+        // The same peer sending the same block again replaces its record
+        for _ in 0..10 {
+            smart_ban.piece_failed(0, &[block(0, b"aaaa", Some("10.0.0.1:1"))]);
+        }
+        assert_eq!(smart_ban.failed[&0].len(), 5);
+        assert_eq!(smart_ban.pending(), 1);
+        let culprits = smart_ban.piece_passed(0, good);
+        assert_eq!(
+            culprits,
+            vec!["10.0.0.2:2".parse().unwrap(), "10.0.0.4:4".parse().unwrap()]
+        );
+        assert_eq!(smart_ban.pending(), 0);
+    }
+}
@@ -0,0 +1,465 @@
+use std::fs;
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+
+use anyhow::{Context, Error, Result};
+use sha1::{Digest, Sha1};
+
+use crate::bitfield::BitField;
+use crate::meta_info::{FileAttributes, Info, MetaInfo};
+
+#[derive(Debug, Clone)]
+pub struct StorageFile {
+    pub path: PathBuf,
+    pub length: u64,
+    pub attributes: FileAttributes,
+    pub symlink_target: Option<PathBuf>,
+    pub sha1: Option<Vec<u8>>,
+}
+
+impl StorageFile {
+    /** Padding files and symlinks have no data of their own on disk */
+    pub fn has_data(&self) -> bool {
+        !self.attributes.padding && !self.attributes.symlink
+    }
+}
+
+/**
+ * Maps pieces to the files of a torrent in the download directory.
+ * Padding files (BEP 47) take part in the piece to file mapping but are never written, they read back as zeros.
+ * Pieces shared between wanted and skipped files still have to be downloaded whole to be verified.
+ * The bytes belonging to skipped files go to a partial-file store instead, one file per piece, so skipped files are never created.
+ */
+#[derive(Debug, Clone)]
+pub struct Storage {
+    pub download_dir: PathBuf,
+    pub files: Vec<StorageFile>,
+    parts_dir: PathBuf,
+    wanted: Vec<bool>,
+    info: Info,
+}
+
+impl Storage {
+    pub fn new(download_dir: impl Into<PathBuf>, meta_info: &MetaInfo) -> Self {
+        let download_dir = download_dir.into();
+        let info = meta_info.info.clone();
+        let encoding = meta_info.encoding.as_deref();
+        let files = info
+            .file_lengths()
+            .into_iter()
+            .enumerate()
+            .map(|(file_index, length)| StorageFile {
+                path: download_dir.join(info.file_path(file_index, encoding)),
+                length,
+                attributes: info.file_attributes(file_index),
+                symlink_target: info
+                    .symlink_target(file_index, encoding)
+                    .map(|target| download_dir.join(target)),
+                sha1: info.file_sha1(file_index).map(<[u8]>::to_vec),
+            })
+            .collect::<Vec<_>>();
+        Self {
+            parts_dir: download_dir.join(format!(".{}.parts", meta_info.to_info_hash())),
+            wanted: vec![true; files.len()],
+            download_dir,
+            files,
+            info,
+        }
+    }
+
+    fn open(path: &Path) -> Result<fs::File> {
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent).context(format!("Failed to create {}", parent.display()))?;
+        }
+        fs::OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(path)
+            .context(format!("Failed to open {}", path.display()))
+    }
+
+    fn part_path(&self, index: usize) -> PathBuf {
+        self.parts_dir.join(index.to_string())
+    }
+
+    pub fn is_wanted(&self, file_index: usize) -> bool {
+        self.wanted[file_index]
+    }
+
+    /**
+     * Skip or select a file.
+     * Data of a newly selected file already sitting in the partial-file store is moved to the file itself.
+     */
+    pub fn set_wanted(&mut self, file_index: usize, wanted: bool) -> Result<()> {
+        if self.wanted[file_index] == wanted {
+            return Ok(());
+        }
+        if !wanted {
+            self.wanted[file_index] = false;
+            return Ok(());
+        }
+        // Read the stored pieces before the file is selected, their data still comes from the store
+        let stored = (0..self.info.pieces_count())
+            .filter(|&index| self.part_path(index).exists())
+            .filter(|&index| {
+                self.info
+                    .piece_file_slices(index)
+                    .iter()
+                    .any(|slice| slice.file_index == file_index)
+            })
+            .map(|index| Ok((index, self.read_piece(index)?)))
+            .collect::<Result<Vec<_>>>()?;
+        self.wanted[file_index] = true;
+        for (index, data) in stored {
+            self.write_piece(index, &data)?;
+        }
+        Ok(())
+    }
+
+    /** Write a verified piece to the files it spans */
+    pub fn write_piece(&self, index: usize, data: &[u8]) -> Result<()> {
+        let mut position = 0;
+        let mut has_skipped = false;
+        for slice in self.info.piece_file_slices(index) {
+            let length = slice.length as usize;
+            let file = &self.files[slice.file_index];
+            if file.has_data() && self.wanted[slice.file_index] {
+                let mut handle = Storage::open(&file.path)?;
+                handle.seek(SeekFrom::Start(slice.offset))?;
+                handle
+                    .write_all(&data[position..position + length])
+                    .context(format!("Failed to write {}", file.path.display()))?;
+            }
+            has_skipped |= file.has_data() && !self.wanted[slice.file_index];
+            position += length;
+        }
+
+        let part_path = self.part_path(index);
+        if has_skipped {
+            fs::create_dir_all(&self.parts_dir)
+                .context(format!("Failed to create {}", self.parts_dir.display()))?;
+            fs::write(&part_path, data).context(format!("Failed to write {}", part_path.display()))?;
+        } else if part_path.exists() {
+            fs::remove_file(&part_path)?;
+        }
+        Ok(())
+    }
+
+    /** Read a piece back, missing data reads as zeros */
+    pub fn read_piece(&self, index: usize) -> Result<Vec<u8>> {
+        let part = fs::read(self.part_path(index)).ok();
+        let mut data = vec![0u8; self.info.piece_size(index) as usize];
+        let mut position = 0;
+        for slice in self.info.piece_file_slices(index) {
+            let length = slice.length as usize;
+            let file = &self.files[slice.file_index];
+            let stored = part
+                .as_ref()
+                .filter(|part| !self.wanted[slice.file_index] && part.len() == data.len());
+            if let (true, Some(part)) = (file.has_data(), stored) {
+                data[position..position + length].copy_from_slice(&part[position..position + length]);
+            } else if file.has_data() && file.path.exists() {
+                let mut handle = fs::File::open(&file.path)
+                    .context(format!("Failed to open {}", file.path.display()))?;
+                handle.seek(SeekFrom::Start(slice.offset))?;
+                let mut read = 0;
+                while read < length {
+                    let count = handle.read(&mut data[position + read..position + length])?;
+                    if count == 0 {
+                        break;
+                    }
+                    read += count;
+                }
+            }
+            position += length;
+        }
+        Ok(data)
+    }
+
+    /** Whether any file of the torrent already exists on disk */
+    pub fn has_files(&self) -> bool {
+        self.files.iter().any(|file| file.has_data() && file.path.exists()) || self.parts_dir.exists()
+    }
+
+    /**
+     * Cheap sanity check of resume data: every piece it claims must land in files at least long enough to hold it.
+     * Anything else means the files changed behind our back and the pieces have to be hashed again.
+     */
+    pub fn has_pieces_on_disk(&self, pieces: &BitField) -> bool {
+        (0..self.info.pieces_count())
+            .filter(|&index| pieces.get(index))
+            .all(|index| {
+                self.info.piece_file_slices(index).iter().all(|slice| {
+                    let file = &self.files[slice.file_index];
+                    if !file.has_data() {
+                        return true;
+                    }
+                    if !self.wanted[slice.file_index] && self.part_path(index).exists() {
+                        return true;
+                    }
+                    fs::metadata(&file.path).is_ok_and(|metadata| metadata.len() >= slice.offset + slice.length)
+                })
+            })
+    }
+
+    /** Hash every piece found on disk, v1 piece hashes are required */
+    pub fn check_pieces(&self) -> Result<BitField> {
+        let mut bitfield = BitField::new(self.info.pieces_count());
+        if !self.info.is_v1() {
+            return Ok(bitfield);
+        }
+        for index in 0..self.info.pieces_count() {
+            if self.info.verify_piece(index, &self.read_piece(index)?) {
+                bitfield.set(index);
+            }
+        }
+        Ok(bitfield)
+    }
+
+    /** Delete the downloaded files and the partial-file store, along with the directories left empty */
+    pub fn delete_files(&self) -> Result<()> {
+        for file in &self.files {
+            if fs::symlink_metadata(&file.path).is_ok() {
+                fs::remove_file(&file.path).context(format!("Failed to delete {}", file.path.display()))?;
+            }
+            let mut directory = file.path.parent();
+            while let Some(path) = directory.filter(|path| *path != self.download_dir) {
+                // Only succeeds for empty directories
+                if fs::remove_dir(path).is_err() {
+                    break;
+                }
+                directory = path.parent();
+            }
+        }
+        if self.parts_dir.exists() {
+            fs::remove_dir_all(&self.parts_dir)
+                .context(format!("Failed to delete {}", self.parts_dir.display()))?;
+        }
+        Ok(())
+    }
+
+    /** Apply the file attributes once the download is complete: executable bits and symlinks */
+    pub fn finalize(&self) -> Result<()> {
+        for (file_index, file) in self.files.iter().enumerate() {
+            if !self.wanted[file_index] {
+                continue;
+            }
+            if let Some(target) = &file.symlink_target {
+                if let Some(parent) = file.path.parent() {
+                    fs::create_dir_all(parent)?;
+                }
+                if fs::symlink_metadata(&file.path).is_ok() {
+                    fs::remove_file(&file.path)?;
+                }
+                create_symlink(target, &file.path)?;
+                continue;
+            }
+            if let Some(sha1) = &file.sha1 {
+                if file.has_data() && file_sha1(&file.path)? != *sha1 {
+                    return Err(Error::msg(format!("{} doesn't match its SHA-1", file.path.display())));
+                }
+            }
+            if file.attributes.executable && file.has_data() {
+                set_executable(&file.path)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+fn file_sha1(path: &Path) -> Result<Vec<u8>> {
+    let mut file = fs::File::open(path).context(format!("Failed to open {}", path.display()))?;
+    let mut hasher = Sha1::new();
+    std::io::copy(&mut file, &mut hasher).context(format!("Failed to read {}", path.display()))?;
+    Ok(hasher.finalize().to_vec())
+}
+
+#[cfg(unix)]
+fn create_symlink(target: &Path, link: &Path) -> Result<()> {
+    // Relative links keep working if the download directory is moved
+    let target = match link.parent() {
+        Some(parent) => relative_path(parent, target),
+        None => target.to_path_buf(),
+    };
+    std::os::unix::fs::symlink(&target, link)
+        .context(format!("Failed to create symlink {}", link.display()))
+}
+
+#[cfg(not(unix))]
+fn create_symlink(target: &Path, link: &Path) -> Result<()> {
+    fs::copy(target, link).context(format!("Failed to copy {} to {}", target.display(), link.display()))?;
+    Ok(())
+}
+
+#[cfg(unix)]
+fn relative_path(from: &Path, to: &Path) -> PathBuf {
+    let from = from.components().collect::<Vec<_>>();
+    let to = to.components().collect::<Vec<_>>();
+    let common = from.iter().zip(&to).take_while(|(a, b)| a == b).count();
+    let mut path = PathBuf::new();
+    for _ in common..from.len() {
+        path.push("..");
+    }
+    for component in &to[common..] {
+        path.push(component);
+    }
+    path
+}
+
+#[cfg(unix)]
+fn set_executable(path: &Path) -> Result<()> {
+    use std::os::unix::fs::PermissionsExt;
+    let mut permissions = fs::metadata(path)?.permissions();
+    // Executable for whoever can read it
+    permissions.set_mode(permissions.mode() | ((permissions.mode() & 0o444) >> 2));
+    fs::set_permissions(path, permissions).context(format!("Failed to make {} executable", path.display()))
+}
+
+#[cfg(not(unix))]
+fn set_executable(_path: &Path) -> Result<()> {
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_bytes::ByteBuf;
+
+    use super::*;
+    use crate::meta_info::File;
+
+    fn file(path: &[&str], length: i64, attr: Option<&str>) -> File {
+        File {
+            path: path.iter().map(|component| ByteBuf::from(*component)).collect(),
+            length,
+            attr: attr.map(ByteBuf::from),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_padding_and_attributes() {
+        let piece_length = 16;
+        // 10 bytes of data padded to the piece boundary, then 20 bytes of data
+        let first = (0..10u8).collect::<Vec<_>>();
+        let second = (100..120u8).collect::<Vec<_>>();
+        let data = [first.clone(), vec![0; 6], second.clone()].concat();
+
+        let mut link = file(&["link"], 0, Some("l"));
+        link.symlink_path = Some(vec![ByteBuf::from("bin"), ByteBuf::from("run")]);
+        let mut info = Info::default();
+        info.name = ByteBuf::from("data");
+        info.pieces = ByteBuf::from(
+            data.chunks(piece_length)
+                .flat_map(|chunk| Sha1::digest(chunk).to_vec())
+                .collect::<Vec<_>>(),
+        );
+        info.piece_length = piece_length as i64;
+        let mut text = file(&["a.txt"], 10, None);
+        text.sha1 = Some(ByteBuf::from(Sha1::digest(&first).to_vec()));
+        info.files = Some(vec![
+            text,
+            file(&[".pad", "6"], 6, Some("p")),
+            file(&["bin", "run"], 20, Some("x")),
+            link,
+        ]);
+        let mut buffer = b"d4:info".to_vec();
+        buffer.extend(info.to_buffer().unwrap());
+        buffer.push(b'e');
+        let meta_info = MetaInfo::from_buffer(&buffer).unwrap();
+        assert!(meta_info.info.file_attributes(1).padding);
+
+        let download_dir = std::env::temp_dir().join(format!("riffle-storage-{}", std::process::id()));
+        let storage = Storage::new(&download_dir, &meta_info);
+        for (index, piece) in data.chunks(piece_length).enumerate() {
+            storage.write_piece(index, piece).unwrap();
+        }
+        for index in 0..meta_info.info.pieces_count() {
+            assert!(meta_info.info.verify_piece(index, &storage.read_piece(index).unwrap()));
+        }
+        storage.finalize().unwrap();
+
+        let root = download_dir.join("data");
+        assert_eq!(fs::read(root.join("a.txt")).unwrap(), first);
+        assert_eq!(fs::read(root.join("bin").join("run")).unwrap(), second);
+        assert!(!root.join(".pad").exists());
+        fs::write(root.join("a.txt"), b"corrupted!").unwrap();
+        assert!(storage.finalize().is_err());
+
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            let mode = fs::metadata(root.join("bin").join("run")).unwrap().permissions().mode();
+            assert_ne!(mode & 0o111, 0);
+            assert_eq!(fs::read_link(root.join("link")).unwrap(), PathBuf::from("bin/run"));
+            assert_eq!(fs::read(root.join("link")).unwrap(), second);
+        }
+
+        fs::remove_dir_all(download_dir).unwrap();
+    }
+
+    #[test]
+    fn test_single_file_symlink() {
+        let mut info = Info::default();
+        info.name = ByteBuf::from("link");
+        info.piece_length = 16;
+        info.length = Some(0);
+        info.attr = Some(ByteBuf::from("l"));
+        info.symlink_path = Some(vec![ByteBuf::from("target")]);
+        let mut buffer = b"d4:info".to_vec();
+        buffer.extend(info.to_buffer().unwrap());
+        buffer.push(b'e');
+        let meta_info = MetaInfo::from_buffer(&buffer).unwrap();
+
+        let download_dir = std::env::temp_dir().join(format!("riffle-link-{}", std::process::id()));
+        fs::create_dir_all(&download_dir).unwrap();
+        fs::write(download_dir.join("target"), b"data").unwrap();
+        let storage = Storage::new(&download_dir, &meta_info);
+        assert_eq!(storage.files[0].symlink_target, Some(download_dir.join("target")));
+        storage.finalize().unwrap();
+        assert_eq!(fs::read(download_dir.join("link")).unwrap(), b"data");
+
+        fs::remove_dir_all(download_dir).unwrap();
+    }
+
+    #[test]
+    fn test_partial_file_store() {
+        let piece_length = 16;
+        let data = (0..40u8).collect::<Vec<_>>();
+        let mut info = Info::default();
+        info.name = ByteBuf::from("parts");
+        info.pieces = ByteBuf::from(
+            data.chunks(piece_length)
+                .flat_map(|chunk| Sha1::digest(chunk).to_vec())
+                .collect::<Vec<_>>(),
+        );
+        info.piece_length = piece_length as i64;
+        info.files = Some(vec![file(&["wanted"], 20, None), file(&["skipped"], 20, None)]);
+        let mut buffer = b"d4:info".to_vec();
+        buffer.extend(info.to_buffer().unwrap());
+        buffer.push(b'e');
+        let meta_info = MetaInfo::from_buffer(&buffer).unwrap();
+
+        let download_dir = std::env::temp_dir().join(format!("riffle-parts-{}", std::process::id()));
+        let mut storage = Storage::new(&download_dir, &meta_info);
+        storage.set_wanted(1, false).unwrap();
+        // Piece 1 is shared by both files
+        for index in 0..2 {
+            storage
+                .write_piece(index, &data[index * piece_length..(index + 1) * piece_length])
+                .unwrap();
+        }
+        let root = download_dir.join("parts");
+        assert_eq!(fs::read(root.join("wanted")).unwrap(), data[..20]);
+        assert!(!root.join("skipped").exists());
+        assert!(meta_info.info.verify_piece(1, &storage.read_piece(1).unwrap()));
+
+        storage.set_wanted(1, true).unwrap();
+        assert_eq!(fs::read(root.join("skipped")).unwrap(), data[20..32]);
+        assert!(!storage.part_path(1).exists());
+        assert!(meta_info.info.verify_piece(1, &storage.read_piece(1).unwrap()));
+
+        fs::remove_dir_all(download_dir).unwrap();
+    }
+}
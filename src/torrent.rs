@@ -1,37 +1,259 @@
-use std::time::Instant;
+use std::fmt;
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::time::{Duration, Instant};
+
+use anyhow::{Context, Error, Result};
+use serde_bytes::ByteBuf;
+use thiserror::Error;
 
 use crate::bitfield::BitField;
+use crate::client::PeerSource;
+use crate::connection::{ConnectionManager, PeerCommand};
 use crate::meta_info::{Info, MetaInfo};
-use crate::peer::PeerWire;
-use crate::piece_picker::PiecePicker;
+use crate::peer::{Handshake, PeerWire};
+use crate::piece::{Block, PieceDownload};
+use crate::piece_picker::{PiecePicker, Priority};
+use crate::rate_limit::{Bandwidth, Limiters};
+use crate::resume::ResumeData;
+use crate::smart_ban::SmartBan;
+use crate::storage::Storage;
+use crate::tracker::{AnnounceEvent, AnnounceParams};
+use crate::web_seed::WebSeed;
+
+/**
+ * Lifecycle of a torrent.
+ * Every torrent starts by checking its resume data, then either transfers data, waits in the queue or is paused.
+ * Any state may fall into the error state, which is only left by pausing or checking the data again.
+ */
+#[derive(Debug, Clone, PartialEq)]
+pub enum TorrentState {
+    CheckingResumeData,
+    FetchingMetadata,
+    Downloading,
+    Seeding,
+    Paused,
+    Queued,
+    Error(String),
+}
+
+impl TorrentState {
+    pub fn can_transition_to(&self, next: &TorrentState) -> bool {
+        use TorrentState::*;
+        matches!(
+            (self, next),
+            (_, Error(_))
+                | (Error(_), CheckingResumeData | Paused)
+                | (CheckingResumeData, Downloading | Seeding | Paused | Queued)
+                | (FetchingMetadata, CheckingResumeData | Paused)
+                | (Downloading, Seeding | Paused | Queued | CheckingResumeData)
+                | (Seeding, Downloading | Paused | Queued | CheckingResumeData)
+                | (Paused, CheckingResumeData | FetchingMetadata | Downloading | Seeding | Queued)
+                | (Queued, Downloading | Seeding | Paused | CheckingResumeData)
+        )
+    }
+
+    /** Downloading or seeding, the states exchanging data with peers */
+    pub fn is_active(&self) -> bool {
+        matches!(self, TorrentState::Downloading | TorrentState::Seeding)
+    }
+}
+
+impl fmt::Display for TorrentState {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TorrentState::CheckingResumeData => write!(f, "Checking"),
+            TorrentState::FetchingMetadata => write!(f, "Fetching metadata"),
+            TorrentState::Downloading => write!(f, "Downloading"),
+            TorrentState::Seeding => write!(f, "Seeding"),
+            TorrentState::Paused => write!(f, "Paused"),
+            TorrentState::Queued => write!(f, "Queued"),
+            TorrentState::Error(cause) => write!(f, "Error: {}", cause),
+        }
+    }
+}
+
+/** What happens to a torrent once it reached one of its seeding goals */
+#[derive(Debug, Clone, Copy, PartialEq, Default, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum GoalAction {
+    #[default]
+    Pause,
+    Remove,
+    RemoveWithData,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SeedingGoal {
+    Ratio,
+    SeedingTime,
+}
+
+/** Seed until either goal is reached, a torrent without any goal seeds forever */
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct SeedingGoals {
+    /** Uploaded bytes over downloaded bytes */
+    pub ratio: Option<f64>,
+    pub seeding_time: Option<Duration>,
+    pub action: GoalAction,
+}
+
+impl Default for SeedingGoals {
+    fn default() -> Self {
+        Self {
+            ratio: None,
+            seeding_time: None,
+            action: GoalAction::Pause,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Error)]
+#[error("a torrent can't go from {from} to {to}")]
+pub struct InvalidTransition {
+    pub from: TorrentState,
+    pub to: TorrentState,
+}
 
 #[derive(Debug, Clone)]
-pub struct Torrent<'a> {
+pub struct Torrent {
     pub info_hash: String,
+    /** Hashed once, serializing and hashing the info for every lookup or announce adds up */
+    swarm_hashes: Vec<Vec<u8>>,
     pub meta_info: MetaInfo,
     pub inserted_at: Option<Instant>,
     pub pieces_bitfield: BitField,
+    /** Peers we are connected to */
     pub peers: Vec<PeerWire>,
-    pub picker: PiecePicker<'a>,
+    /** Every known peer address and the state of the connection to it */
+    pub connections: ConnectionManager,
+    /** Peers rejected by the IP filter, whether learned about or connecting to us */
+    pub filtered_peers: u64,
+    pub smart_ban: SmartBan,
+    pub web_seeds: Vec<WebSeed>,
+    pub picker: PiecePicker,
+    /** Indexed like `Info::files` */
+    pub file_priorities: Vec<Priority>,
+    pub storage: Storage,
+    pub uploaded: u64,
+    pub downloaded: u64,
+    pub state: TorrentState,
+    /** Position in the session queue, lower runs first */
+    pub queue_position: usize,
+    /** Bytes per second, measured over the last session tick */
+    pub download_rate: u64,
+    pub upload_rate: u64,
+    rate_snapshot: (u64, u64),
+    /** When the torrent last became active, used to give new torrents time to pick up speed */
+    pub active_since: Option<Instant>,
+    pub limiters: Limiters,
+    /** Time spent in the seeding state, across sessions */
+    pub seeding_time: Duration,
+    /** Overrides the session seeding goals when set */
+    pub seeding_goals: Option<SeedingGoals>,
+    /** When the trackers are due for the next announce, right away when not set */
+    pub announce_at: Option<Instant>,
+    /** Sent with the next announce, started until a tracker answered since the torrent became active */
+    pub announce_event: Option<AnnounceEvent>,
+    /** Announces to the trackers are in flight */
+    pub announcing: bool,
+}
+
+/** Snapshot of a torrent handed out by the session */
+#[derive(Debug, Clone)]
+pub struct TorrentStatus {
+    pub info_hash: String,
+    pub name: String,
+    pub total_length: u64,
+    pub piece_length: u64,
+    pub pieces: BitField,
+    pub file_priorities: Vec<Priority>,
+    pub peers_count: usize,
+    pub filtered_peers: u64,
+    pub banned_peers: Vec<SocketAddr>,
+    pub uploaded: u64,
+    pub downloaded: u64,
+    pub left: u64,
+    pub state: TorrentState,
+    pub queue_position: usize,
+    pub download_rate: u64,
+    pub upload_rate: u64,
+    pub upload_limit: Option<u64>,
+    pub download_limit: Option<u64>,
+    pub ratio: f64,
+    pub seeding_time: Duration,
+}
+
+impl TorrentStatus {
+    pub fn pieces_count(&self) -> usize {
+        self.pieces.len()
+    }
+
+    pub fn downloaded_pieces(&self) -> usize {
+        self.pieces.iter().filter(|has| *has).count()
+    }
 }
 
-impl Torrent<'_> {
-    pub fn new(meta_info: MetaInfo) -> Self {
+impl Torrent {
+    pub fn new(meta_info: MetaInfo, download_dir: impl Into<PathBuf>) -> Self {
         let info_hash = meta_info.to_info_hash();
-        let pieces_bitfield = BitField::new(meta_info.info.pieces.len() / 20);
-        let peers = vec![];
+        let swarm_hashes = meta_info.info.swarm_hashes().unwrap_or_default();
+        let pieces_bitfield = BitField::new(meta_info.info.pieces_count());
+        let web_seeds = WebSeed::from_meta_info(&meta_info);
+        let mut picker = PiecePicker::new(meta_info.info.pieces_count());
+        for _ in &web_seeds {
+            picker.add_seed();
+        }
+        let file_priorities = vec![Priority::Normal; meta_info.info.file_lengths().len()];
+        let storage = Storage::new(download_dir, &meta_info);
         Self {
             info_hash,
+            swarm_hashes,
             meta_info,
             inserted_at: Some(Instant::now()),
             pieces_bitfield,
-            picker: PiecePicker::new(&peers),
-            peers
+            peers: vec![],
+            connections: ConnectionManager::new(),
+            filtered_peers: 0,
+            smart_ban: SmartBan::new(),
+            web_seeds,
+            picker,
+            file_priorities,
+            storage,
+            uploaded: 0,
+            downloaded: 0,
+            state: TorrentState::CheckingResumeData,
+            queue_position: 0,
+            download_rate: 0,
+            upload_rate: 0,
+            rate_snapshot: (0, 0),
+            active_since: None,
+            limiters: Limiters::unlimited(),
+            seeding_time: Duration::ZERO,
+            seeding_goals: None,
+            announce_at: None,
+            announce_event: None,
+            announcing: false,
         }
     }
 
+    /** Limits applying to the transfers of this torrent, below the session limits */
+    pub fn bandwidth(&self, session: &Bandwidth) -> Bandwidth {
+        session.child(&self.limiters)
+    }
+
     pub fn info_hash(&self) -> String {
-        self.meta_info.to_info_hash()
+        self.info_hash.clone()
+    }
+
+    /** 20 byte info hashes of every swarm the torrent takes part in, v1 first */
+    pub fn swarm_hashes(&self) -> &[Vec<u8>] {
+        &self.swarm_hashes
+    }
+
+    /** Hex info hashes of every swarm the torrent takes part in, both v1 and v2 for hybrid torrents */
+    pub fn info_hashes(&self) -> Vec<String> {
+        self.swarm_hashes.iter().map(hex::encode).collect()
     }
 
     pub fn meta_info(&self) -> MetaInfo {
@@ -43,18 +265,459 @@ impl Torrent<'_> {
     }
 
     pub fn set_meta_info(&mut self, meta_info: MetaInfo) {
+        self.info_hash = meta_info.to_info_hash();
+        self.swarm_hashes = meta_info.info.swarm_hashes().unwrap_or_default();
         self.meta_info = meta_info;
     }
 
     pub fn inserted_at(&self) -> Option<Instant> {
-        self.inserted_at.clone()
+        self.inserted_at
     }
 
     pub fn set_inserted_at(&mut self, inserted_at: Instant) {
         self.inserted_at = Some(inserted_at);
     }
 
+    pub fn is_private(&self) -> bool {
+        self.meta_info.info.is_private()
+    }
+
+    /** Add a newly discovered peer to connect to, returns false if it was already known */
+    pub fn add_peer(&mut self, addr: SocketAddr, source: PeerSource) -> bool {
+        self.connections.add_candidate(addr, source)
+    }
+
+    /** Handshake for outgoing connections, on the v1 swarm of hybrid torrents */
+    pub fn handshake(&self, peer_id: [u8; 20]) -> Result<Handshake> {
+        let swarm_hash = self.swarm_hashes.first().context("Torrent has no info hash")?;
+        let handshake = Handshake::new(swarm_hash.as_slice().try_into()?, peer_id);
+        Ok(if self.meta_info.info.is_v2() {
+            handshake.with_v2()
+        } else {
+            handshake
+        })
+    }
+
+    pub fn peer_connected(&mut self, addr: SocketAddr, handshake: &Handshake) {
+        let pieces_count = self.meta_info.info.pieces_count();
+        self.peers.push(PeerWire::from_handshake(addr, handshake, pieces_count));
+    }
+
+    /** The pieces of a disconnected peer are no longer available from it */
+    pub fn peer_disconnected(&mut self, addr: &SocketAddr) -> bool {
+        let Some(position) = self.peers.iter().position(|peer| peer.is_addr(addr)) else {
+            return false;
+        };
+        let peer = self.peers.remove(position);
+        self.picker.remove_bitfield(peer.bitfield());
+        if let Some(download) = peer.download() {
+            self.picker.set_downloading(download.index, false);
+        }
+        true
+    }
+
+    pub fn set_peer_bitfield(&mut self, addr: &SocketAddr, bytes: &[u8]) {
+        let pieces_count = self.meta_info.info.pieces_count();
+        let Some(peer) = self.peers.iter_mut().find(|peer| peer.is_addr(addr)) else {
+            return;
+        };
+        let bitfield = BitField::from_bytes(bytes, pieces_count);
+        self.picker.remove_bitfield(peer.bitfield());
+        self.picker.add_bitfield(&bitfield);
+        peer.set_bitfield(bitfield);
+    }
+
+    pub fn peer_has(&mut self, addr: &SocketAddr, index: usize) {
+        let Some(peer) = self.peers.iter_mut().find(|peer| peer.is_addr(addr)) else {
+            return;
+        };
+        if index < peer.bitfield().len() && !peer.bitfield().get(index) {
+            peer.bitfield_mut().set(index);
+            self.picker.add_have(index);
+        }
+    }
+
+    /**
+     * What to send a peer next: interested once it has a piece we want, then the requests for one such piece once it unchoked us.
+     * The piece isn't picked again until cleared in the picker.
+     */
+    pub fn next_requests(&mut self, addr: &SocketAddr) -> Vec<PeerCommand> {
+        let Some(peer) = self.peers.iter_mut().find(|peer| peer.is_addr(addr)) else {
+            return vec![];
+        };
+        if peer.download().is_some() {
+            return vec![];
+        }
+        let Some(index) = self.picker.pick(&self.pieces_bitfield, |index| peer.bitfield().get(index)) else {
+            return vec![];
+        };
+        let mut commands = vec![];
+        if !peer.is_interested() {
+            peer.set_interested(true);
+            commands.push(PeerCommand::Interested);
+        }
+        if peer.is_choking() {
+            return commands;
+        }
+        self.picker.set_downloading(index, true);
+        let download = PieceDownload::new(index, self.meta_info.info.piece_size(index));
+        commands.extend(download.pending.iter().map(|&(begin, length)| PeerCommand::Request {
+            index: index as u32,
+            begin,
+            length,
+        }));
+        peer.set_download(Some(download));
+        commands
+    }
+
+    /** Keep a block received from a peer, returns its piece and blocks once they are all in */
+    pub fn block_received(&mut self, addr: &SocketAddr, block: Block) -> Option<(usize, Vec<Block>)> {
+        let peer = self.peers.iter_mut().find(|peer| peer.is_addr(addr))?;
+        if !peer.download_mut()?.add_block(block) {
+            return None;
+        }
+        let download = peer.take_download()?;
+        Some((download.index, download.blocks))
+    }
+
+    /** A peer choking us discards our requests, the piece they were for is up for download again */
+    pub fn peer_choked(&mut self, addr: &SocketAddr, choked: bool) {
+        let Some(peer) = self.peers.iter_mut().find(|peer| peer.is_addr(addr)) else {
+            return;
+        };
+        peer.set_choking(choked);
+        if !choked {
+            return;
+        }
+        if let Some(download) = peer.take_download() {
+            self.picker.set_downloading(download.index, false);
+        }
+    }
+
+    /** Next piece to request from a web seed, they have every piece. It isn't picked again until cleared in the picker */
+    pub fn pick_web_seed_piece(&mut self) -> Option<usize> {
+        let index = self.picker.pick(&self.pieces_bitfield, |_| true)?;
+        self.picker.set_downloading(index, true);
+        Some(index)
+    }
+
+    pub fn file_priority(&self, file_index: usize) -> Priority {
+        self.file_priorities[file_index]
+    }
+
+    /** Skipped files are never written to, their share of edge pieces goes to the partial-file store */
+    pub fn set_file_priority(&mut self, file_index: usize, priority: Priority) -> Result<()> {
+        let priority_slot = self
+            .file_priorities
+            .get_mut(file_index)
+            .context(format!("No file at index {}", file_index))?;
+        *priority_slot = priority;
+        self.storage.set_wanted(file_index, priority != Priority::Skip)?;
+        self.update_piece_priorities();
+        // Selecting more files turns a seed back into a download and the other way around
+        if self.state.is_active() {
+            self.state = self.active_state();
+        }
+        Ok(())
+    }
+
+    pub fn set_state(&mut self, state: TorrentState) -> Result<(), InvalidTransition> {
+        if self.state == state {
+            return Ok(());
+        }
+        if !self.state.can_transition_to(&state) {
+            return Err(InvalidTransition {
+                from: self.state.clone(),
+                to: state,
+            });
+        }
+        if state.is_active() && !self.state.is_active() {
+            self.active_since = Some(Instant::now());
+            self.announce_at = None;
+            self.announce_event = Some(AnnounceEvent::Started);
+        } else if !state.is_active() {
+            self.active_since = None;
+            self.announce_at = None;
+            self.announce_event = None;
+        }
+        self.state = state;
+        Ok(())
+    }
+
+    /** Count the data of piece messages as it arrives from peers, or pieces from web seeds and callers once written */
+    pub fn record_download(&mut self, bytes: u64) {
+        self.downloaded += bytes;
+    }
+
+    /** Count block data sent to peers */
+    pub fn record_upload(&mut self, bytes: u64) {
+        self.uploaded += bytes;
+    }
+
+    /** Torrents added complete never downloaded anything, their ratio is computed on the selected size instead */
+    pub fn ratio(&self) -> f64 {
+        let selected_length = self.meta_info.info.total_length() - self.left();
+        let downloaded = if self.downloaded > 0 {
+            self.downloaded
+        } else {
+            selected_length
+        };
+        if downloaded == 0 {
+            return 0.0;
+        }
+        self.uploaded as f64 / downloaded as f64
+    }
+
+    /** The first goal reached while seeding, if any */
+    pub fn seeding_goal_reached(&self, goals: &SeedingGoals) -> Option<SeedingGoal> {
+        if self.state != TorrentState::Seeding {
+            return None;
+        }
+        if goals.ratio.is_some_and(|ratio| self.ratio() >= ratio) {
+            return Some(SeedingGoal::Ratio);
+        }
+        if goals
+            .seeding_time
+            .is_some_and(|seeding_time| self.seeding_time >= seeding_time)
+        {
+            return Some(SeedingGoal::SeedingTime);
+        }
+        None
+    }
+
+    /** Update the transfer rates from the counters, called once per session tick */
+    pub fn update_rates(&mut self, elapsed: Duration) {
+        let millis = elapsed.as_millis().max(1) as u64;
+        let (downloaded, uploaded) = self.rate_snapshot;
+        self.download_rate = self.downloaded.saturating_sub(downloaded) * 1000 / millis;
+        self.upload_rate = self.uploaded.saturating_sub(uploaded) * 1000 / millis;
+        self.rate_snapshot = (self.downloaded, self.uploaded);
+    }
+
+    /** Active for longer than the grace period without transferring much in either direction */
+    pub fn is_slow(&self, rate_threshold: u64, grace_period: Duration) -> bool {
+        self.active_since
+            .is_some_and(|active_since| active_since.elapsed() >= grace_period)
+            && self.download_rate < rate_threshold
+            && self.upload_rate < rate_threshold
+    }
+
+    /** The state a running torrent should be in given the pieces it has */
+    pub fn active_state(&self) -> TorrentState {
+        if self.left() == 0 {
+            TorrentState::Seeding
+        } else {
+            TorrentState::Downloading
+        }
+    }
+
+    pub fn resume_data(&self) -> ResumeData {
+        ResumeData {
+            info_hash: self.info_hash.clone(),
+            pieces: ByteBuf::from(self.pieces_bitfield.as_bytes()),
+            file_priorities: self.file_priorities.iter().map(|priority| *priority as u8).collect(),
+            uploaded: self.uploaded,
+            downloaded: self.downloaded,
+            seeding_time: self.seeding_time.as_secs(),
+            paused: u8::from(self.state == TorrentState::Paused),
+            error: match &self.state {
+                TorrentState::Error(cause) => Some(cause.clone()),
+                _ => None,
+            },
+            queue_position: self.queue_position,
+        }
+    }
+
+    /** Restore the pieces, file priorities, counters and queue position, the state is left to the session */
+    pub fn apply_resume_data(&mut self, resume_data: &ResumeData) -> Result<()> {
+        let pieces_count = self.meta_info.info.pieces_count();
+        if resume_data.info_hash != self.info_hash
+            || resume_data.pieces.len() != pieces_count.div_ceil(8)
+            || resume_data.file_priorities.len() != self.file_priorities.len()
+        {
+            return Err(Error::msg(format!("Resume data doesn't match torrent {}", self.info_hash)));
+        }
+        for (file_index, priority) in resume_data.file_priorities.iter().enumerate() {
+            let priority = Priority::from_u8(*priority).context("Invalid file priority in resume data")?;
+            self.set_file_priority(file_index, priority)?;
+        }
+        self.pieces_bitfield = BitField::from_bytes(&resume_data.pieces, pieces_count);
+        self.uploaded = resume_data.uploaded;
+        self.downloaded = resume_data.downloaded;
+        self.seeding_time = Duration::from_secs(resume_data.seeding_time);
+        self.queue_position = resume_data.queue_position;
+        Ok(())
+    }
+
+    /** Pieces take the highest priority of the files they span, padding files don't count */
+    fn update_piece_priorities(&mut self) {
+        let info = &self.meta_info.info;
+        for index in 0..info.pieces_count() {
+            let priority = info
+                .piece_file_slices(index)
+                .iter()
+                .filter(|slice| !info.file_attributes(slice.file_index).padding)
+                .map(|slice| self.file_priorities[slice.file_index])
+                .max()
+                .unwrap_or(Priority::Skip);
+            self.picker.set_priority(index, priority);
+        }
+    }
+
+    /** Bytes still missing from the selected files */
+    pub fn left(&self) -> u64 {
+        let info = &self.meta_info.info;
+        (0..info.pieces_count())
+            .filter(|&index| !self.pieces_bitfield.get(index))
+            .flat_map(|index| info.piece_file_slices(index))
+            .filter(|slice| {
+                self.file_priorities[slice.file_index] != Priority::Skip
+                    && !info.file_attributes(slice.file_index).padding
+            })
+            .map(|slice| slice.length)
+            .sum()
+    }
+
+    pub fn announce_params(&self, peer_id: [u8; 20], port: u16, event: Option<AnnounceEvent>) -> Result<AnnounceParams> {
+        Ok(AnnounceParams {
+            info_hash: self.swarm_hashes.first().context("Torrent has no info hash")?.clone(),
+            peer_id,
+            port,
+            uploaded: self.uploaded,
+            downloaded: self.downloaded,
+            left: self.left(),
+            event,
+        })
+    }
+
+    pub fn status(&self) -> TorrentStatus {
+        TorrentStatus {
+            info_hash: self.info_hash.clone(),
+            name: self.meta_info.name(),
+            total_length: self.meta_info.info.total_length(),
+            piece_length: self.meta_info.info.piece_length as u64,
+            pieces: self.pieces_bitfield.clone(),
+            file_priorities: self.file_priorities.clone(),
+            peers_count: self.peers.len(),
+            filtered_peers: self.filtered_peers,
+            banned_peers: self.connections.banned(),
+            uploaded: self.uploaded,
+            downloaded: self.downloaded,
+            left: self.left(),
+            state: self.state.clone(),
+            queue_position: self.queue_position,
+            download_rate: self.download_rate,
+            upload_rate: self.upload_rate,
+            upload_limit: self.limiters.upload.rate(),
+            download_limit: self.limiters.download.rate(),
+            ratio: self.ratio(),
+            seeding_time: self.seeding_time,
+        }
+    }
+
     pub fn downloaded_pieces(&self) -> u64 {
         self.pieces_bitfield.iter().fold(0, |x, y| if y { x + 1 } else { x })
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use serde_bytes::ByteBuf;
+
+    use super::*;
+    use crate::meta_info::File;
+
+    #[test]
+    fn test_file_priorities() {
+        let file = |name: &str, length| File {
+            path: vec![ByteBuf::from(name)],
+            length,
+            ..Default::default()
+        };
+        let mut info = Info::default();
+        info.name = ByteBuf::from("priorities");
+        info.pieces = ByteBuf::from(vec![0u8; 4 * 20]);
+        info.piece_length = 16;
+        info.files = Some(vec![file("a", 20), file("b", 20), file("c", 24)]);
+        let mut buffer = b"d4:info".to_vec();
+        buffer.extend(info.to_buffer().unwrap());
+        buffer.push(b'e');
+        let meta_info = MetaInfo::from_buffer(&buffer).unwrap();
+
+        let download_dir = std::env::temp_dir().join(format!("riffle-priorities-{}", std::process::id()));
+        let mut torrent = Torrent::new(meta_info, &download_dir);
+        assert_eq!(torrent.left(), 64);
+
+        torrent.set_file_priority(1, Priority::Skip).unwrap();
+        torrent.set_file_priority(2, Priority::High).unwrap();
+        assert!(torrent.set_file_priority(3, Priority::Low).is_err());
+        // Piece 1 covers a and b, piece 2 covers b and c
+        assert_eq!(torrent.picker.priority(1), Priority::Normal);
+        assert_eq!(torrent.picker.priority(2), Priority::High);
+        assert_eq!(torrent.left(), 44);
+
+        torrent.set_file_priority(0, Priority::Skip).unwrap();
+        assert_eq!(torrent.picker.priority(0), Priority::Skip);
+        assert_eq!(torrent.picker.priority(1), Priority::Skip);
+        assert_eq!(torrent.pick_web_seed_piece(), Some(2));
+        assert_eq!(torrent.pick_web_seed_piece(), Some(3));
+        assert_eq!(torrent.pick_web_seed_piece(), None);
+        torrent.picker.set_downloading(3, false);
+        assert_eq!(torrent.pick_web_seed_piece(), Some(3));
+        torrent.pieces_bitfield.set(2);
+        torrent.pieces_bitfield.set(3);
+        torrent.picker.set_downloading(3, false);
+        assert_eq!(torrent.pick_web_seed_piece(), None);
+        assert_eq!(torrent.left(), 0);
+
+        let params = torrent.announce_params([b'-'; 20], 6881, Some(AnnounceEvent::Started)).unwrap();
+        let url = params.to_url("http://tracker/announce");
+        assert!(url.starts_with("http://tracker/announce?info_hash="));
+        assert!(url.ends_with("&port=6881&uploaded=0&downloaded=0&left=0&compact=1&event=started"));
+    }
+
+    #[test]
+    fn test_seeding_goals() {
+        let mut buffer = b"d4:infod6:lengthi100e4:name5:goals12:piece lengthi16384e6:pieces20:".to_vec();
+        buffer.extend_from_slice(&[0; 20]);
+        buffer.extend_from_slice(b"ee");
+        let mut torrent = Torrent::new(MetaInfo::from_buffer(&buffer).unwrap(), std::env::temp_dir());
+        torrent.pieces_bitfield.set(0);
+        torrent.state = TorrentState::Seeding;
+
+        let goals = SeedingGoals {
+            ratio: Some(1.5),
+            seeding_time: Some(Duration::from_secs(60)),
+            ..Default::default()
+        };
+        // Added complete, the ratio is based on the size of the torrent
+        torrent.record_upload(100);
+        assert_eq!(torrent.ratio(), 1.0);
+        assert_eq!(torrent.seeding_goal_reached(&goals), None);
+        torrent.seeding_time = Duration::from_secs(60);
+        assert_eq!(torrent.seeding_goal_reached(&goals), Some(SeedingGoal::SeedingTime));
+
+        torrent.record_download(50);
+        assert_eq!(torrent.ratio(), 2.0);
+        assert_eq!(torrent.seeding_goal_reached(&goals), Some(SeedingGoal::Ratio));
+        assert_eq!(torrent.seeding_goal_reached(&SeedingGoals::default()), None);
+        torrent.state = TorrentState::Paused;
+        assert_eq!(torrent.seeding_goal_reached(&goals), None);
+    }
+
+    #[test]
+    fn test_state_transitions() {
+        use TorrentState::*;
+        let error = Error("disk full".to_string());
+        assert!(CheckingResumeData.can_transition_to(&Downloading));
+        assert!(Downloading.can_transition_to(&Seeding));
+        assert!(Seeding.can_transition_to(&error));
+        assert!(Queued.can_transition_to(&error));
+        assert!(error.can_transition_to(&CheckingResumeData));
+        assert!(!error.can_transition_to(&Downloading));
+        assert!(!Paused.can_transition_to(&Paused));
+        assert!(!Downloading.can_transition_to(&FetchingMetadata));
+        assert!(!Queued.can_transition_to(&FetchingMetadata));
+        assert_eq!(error.to_string(), "Error: disk full");
+    }
+}
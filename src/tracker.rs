@@ -1,4 +1,8 @@
-use std::{collections::BTreeMap, net::Ipv4Addr, time::Instant};
+use std::{
+    collections::BTreeMap,
+    net::{Ipv4Addr, SocketAddr},
+    time::Instant,
+};
 
 use anyhow::{Context, Result};
 use futures::future::join_all;
@@ -8,6 +12,8 @@ use serde_bytes::ByteBuf;
 use urlencoding::encode_binary;
 
 use crate::meta_info::MetaInfo;
+use crate::network::Interface;
+use crate::proxy::ProxySettings;
 use crate::utils::fetch_buffer;
 use crate::utils::IpAddr;
 
@@ -20,6 +26,27 @@ pub struct TrackerPeer {
     pub port: u16,
 }
 
+impl From<SocketAddr> for TrackerPeer {
+    fn from(addr: SocketAddr) -> Self {
+        TrackerPeer {
+            peer_id: None,
+            ip: addr.ip().into(),
+            port: addr.port(),
+        }
+    }
+}
+
+impl TrackerPeer {
+    /** Peers given by host name aren't resolved */
+    pub fn socket_addr(&self) -> Option<SocketAddr> {
+        match &self.ip {
+            IpAddr::V4(ip) => Some(SocketAddr::new((*ip).into(), self.port)),
+            IpAddr::V6(ip) => Some(SocketAddr::new((*ip).into(), self.port)),
+            IpAddr::DNS(_) => None,
+        }
+    }
+}
+
 #[derive(Debug, Clone, Deserialize, Serialize)]
 #[serde(untagged)]
 pub enum Peers {
@@ -50,6 +77,58 @@ pub struct TrackerAnnounceResponse {
     pub warning_message: Option<String>,
 }
 
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum AnnounceEvent {
+    Started,
+    Stopped,
+    Completed,
+}
+
+impl AnnounceEvent {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            AnnounceEvent::Started => "started",
+            AnnounceEvent::Stopped => "stopped",
+            AnnounceEvent::Completed => "completed",
+        }
+    }
+}
+
+/** Query parameters of an announce request */
+#[derive(Debug, Clone, PartialEq)]
+pub struct AnnounceParams {
+    pub info_hash: Vec<u8>,
+    pub peer_id: [u8; 20],
+    pub port: u16,
+    pub uploaded: u64,
+    pub downloaded: u64,
+    /** Bytes still needed to complete the selected files */
+    pub left: u64,
+    pub event: Option<AnnounceEvent>,
+}
+
+impl AnnounceParams {
+    pub fn to_url(&self, tracker_url: &str) -> String {
+        let separator = if tracker_url.contains('?') { '&' } else { '?' };
+        let mut url = format!(
+            "{}{}info_hash={}&peer_id={}&port={}&uploaded={}&downloaded={}&left={}&compact=1",
+            tracker_url,
+            separator,
+            encode_binary(&self.info_hash),
+            encode_binary(&self.peer_id),
+            self.port,
+            self.uploaded,
+            self.downloaded,
+            self.left
+        );
+        if let Some(event) = self.event {
+            url.push_str("&event=");
+            url.push_str(event.as_str());
+        }
+        url
+    }
+}
+
 #[derive(Debug)]
 pub struct Announce {
     pub url: String,
@@ -99,8 +178,8 @@ impl Announce {
         Ok(normalized_response)
     }
 
-    pub async fn from_url(url: String) -> Self {
-        let response = fetch_buffer(url.as_str())
+    pub async fn from_url(url: String, proxy: &ProxySettings, interface: Option<&Interface>) -> Self {
+        let response = fetch_buffer(url.as_str(), proxy, interface)
             .await
             .context(format!("Failed to fetch announce from {}", url))
             .and_then(Announce::parse_buffer)
@@ -112,6 +191,15 @@ impl Announce {
             response,
         }
     }
+
+    pub async fn from_params(
+        tracker_url: &str,
+        params: &AnnounceParams,
+        proxy: &ProxySettings,
+        interface: Option<&Interface>,
+    ) -> Self {
+        Announce::from_url(params.to_url(tracker_url), proxy, interface).await
+    }
 }
 
 #[derive(Debug, Clone, Deserialize, Serialize)]
@@ -161,8 +249,8 @@ impl Scrape {
         ))
     }
 
-    pub async fn from_url(url: String) -> Self {
-        let response = fetch_buffer(url.as_str())
+    pub async fn from_url(url: String, proxy: &ProxySettings) -> Self {
+        let response = fetch_buffer(url.as_str(), proxy, None)
             .await
             .context(format!("Failed to fetch scrape from {}", url))
             .and_then(Scrape::parse_buffer)
@@ -175,12 +263,12 @@ impl Scrape {
         }
     }
 
-    pub async fn from_meta_info(meta_info: &MetaInfo) -> Result<Vec<Scrape>> {
+    pub async fn from_meta_info(meta_info: &MetaInfo, proxy: &ProxySettings) -> Result<Vec<Scrape>> {
         let scrape_urls = meta_info.scrape_urls()?;
 
         let responses = scrape_urls
             .iter()
-            .map(|url| Scrape::from_url(url.clone()))
+            .map(|url| Scrape::from_url(url.clone(), proxy))
             .collect::<Vec<_>>();
 
         let response = join_all(responses).await;
@@ -189,12 +277,16 @@ impl Scrape {
 
     pub fn stream_from_meta_info(
         meta_info: &MetaInfo,
+        proxy: &ProxySettings,
     ) -> Result<FuturesUnordered<impl Future<Output = Scrape>>> {
         let scrape_urls = meta_info.scrape_urls()?;
 
         let futures: FuturesUnordered<_> = scrape_urls
             .iter()
-            .map(|url| Scrape::from_url(url.clone()))
+            .map(|url| {
+                let (url, proxy) = (url.clone(), proxy.clone());
+                async move { Scrape::from_url(url, &proxy).await }
+            })
             .collect();
 
         Ok(futures)
@@ -204,20 +296,28 @@ impl Scrape {
 #[derive(Debug, Clone)]
 pub struct Tracker {
     pub url: String,
+    pub proxy: ProxySettings,
 }
 
 impl Tracker {
     pub fn new(url: String) -> Self {
-        Self { url }
+        Self {
+            url,
+            proxy: ProxySettings::default(),
+        }
+    }
+
+    pub fn with_proxy(url: String, proxy: ProxySettings) -> Self {
+        Self { url, proxy }
     }
 
     pub async fn fetch_announce(&mut self) -> Result<Announce> {
-        let response = Announce::from_url(self.url.clone()).await;
+        let response = Announce::from_url(self.url.clone(), &self.proxy, None).await;
         Ok(response)
     }
 
     pub async fn fetch_scrape(&mut self) -> Result<Scrape> {
-        let response = Scrape::from_url(self.url.clone()).await;
+        let response = Scrape::from_url(self.url.clone(), &self.proxy).await;
         Ok(response)
     }
 }
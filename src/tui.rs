@@ -1,11 +1,16 @@
-use std::cmp;
+use std::collections::VecDeque;
 
 use anyhow::Result;
 use humansize::{format_size, DECIMAL};
 use ratatui::{prelude::*, widgets::*};
 use tokio::sync::mpsc;
 
-use crate::{client::TorrentClient, meta_info::MetaInfo};
+use crate::{
+    client::{ClientEvent, ClientHandle},
+    config::Config,
+    lsd::run_lsd,
+    torrent::TorrentStatus,
+};
 
 pub fn initialize_panic_handler() {
     let original_hook = std::panic::take_hook();
@@ -27,10 +32,27 @@ pub fn shutdown() -> Result<()> {
     Ok(())
 }
 
+/** Older messages are dropped, so the pane never pushes the torrents off the screen */
+const MAX_MESSAGES: usize = 5;
+
 pub struct App {
     action_tx: mpsc::UnboundedSender<Action>,
     should_quit: bool,
-    torrent_client: TorrentClient,
+    client: ClientHandle,
+    /** Latest status of every torrent in queue order, refreshed from the session after each action */
+    torrents: Vec<TorrentStatus>,
+    selected: usize,
+    /** Rejected torrents, validation warnings and failed actions shown to the user */
+    messages: VecDeque<String>,
+}
+
+impl App {
+    fn push_message(&mut self, message: String) {
+        if self.messages.len() == MAX_MESSAGES {
+            self.messages.pop_front();
+        }
+        self.messages.push_back(message);
+    }
 }
 
 pub fn render_root_box(f: &mut Frame, area: Rect) -> Rect {
@@ -46,42 +68,24 @@ pub fn render_root_box(f: &mut Frame, area: Rect) -> Rect {
 }
 
 pub fn render_torrent_selection(f: &mut Frame, app: &App, area: Rect) {
-    let mut state = ListState::default().with_selected(Some(0));
+    let mut state = ListState::default().with_selected(Some(app.selected));
 
     let header_widget = List::new(
         app
-            .torrent_client
             .torrents
-            .values()
+            .iter()
             .map(|torrent| {
-                let current_torrent_file_sizes = torrent
-                    .meta_info
-                    .info
-                    .files
-                    .clone()
-                    .unwrap()
-                    .iter()
-                    .map(|file| file.length)
-                    .reduce(|x, y| x + y)
-                    .unwrap_or(0);
-              
-                let current_torrent_total_size = torrent
-                    .meta_info
-                    .info
-                    .length
-                    .unwrap_or(current_torrent_file_sizes);
-              
-                let current_torrent_total_size_u64 = u64::try_from(current_torrent_total_size).unwrap();
-              
                 let current_torrent_size = format_size(
-                  current_torrent_total_size_u64,
+                    torrent.total_length,
                     DECIMAL,
                 );
 
                 ListItem::new(Span::raw(format!(
-                    "{} ({})",
-                    torrent.meta_info.info.name,
-                    current_torrent_size
+                    "#{} {} ({}) [{}]",
+                    torrent.queue_position + 1,
+                    torrent.name,
+                    current_torrent_size,
+                    torrent.state
                 )))
               })
               .collect::<Vec<_>>()
@@ -98,20 +102,9 @@ pub fn render_torrent_selection(f: &mut Frame, app: &App, area: Rect) {
 }
 
 pub fn render_pieces(f: &mut Frame, app: &App, area: Rect) {
-    let torrent_hashes = app
-        .torrent_client
-        .torrents
-        .clone()
-        .into_keys()
-        .collect::<Vec<_>>();
+    let selected_torrent = &app.torrents[app.selected];
 
-    let selected_torrent = app
-        .torrent_client
-        .torrents
-        .get(torrent_hashes[0].as_str())
-        .unwrap();
-
-    let chunks_lines = u16::try_from(selected_torrent.info().pieces.len() / area.width as usize).unwrap();
+    let chunks_lines = u16::try_from(selected_torrent.pieces_count() / area.width as usize).unwrap();
 
     let chunks = Layout::default()
       .direction(Direction::Vertical)
@@ -122,16 +115,16 @@ pub fn render_pieces(f: &mut Frame, app: &App, area: Rect) {
       .split(area);
 
     let piece_length_formatted = format_size(
-        u64::try_from(selected_torrent.info().piece_length).unwrap(),
+        selected_torrent.piece_length,
         DECIMAL,
     );
 
     let info =
-      Paragraph::new( format!("Pieces: {}/{} * {}", selected_torrent.downloaded_pieces(), selected_torrent.info().pieces_count(), piece_length_formatted))
+      Paragraph::new( format!("Pieces: {}/{} * {}", selected_torrent.downloaded_pieces(), selected_torrent.pieces_count(), piece_length_formatted))
           .wrap(Wrap { trim: false });
 
     let pieces =
-        Paragraph::new( selected_torrent.pieces_bitfield.iter().map(|x| if x { "█" } else { "░" }).collect::<String>())
+        Paragraph::new( selected_torrent.pieces.iter().map(|x| if x { "█" } else { "░" }).collect::<String>())
             .wrap(Wrap { trim: false });
 
     f.render_widget(info, chunks[0]);
@@ -139,47 +132,21 @@ pub fn render_pieces(f: &mut Frame, app: &App, area: Rect) {
 }
 
 pub fn render_torrent_info(f: &mut Frame, app: &App, area: Rect) {
-    let torrent_hashes = app
-        .torrent_client
-        .torrents
-        .clone()
-        .into_keys()
-        .collect::<Vec<_>>();
-
-    let selected_torrent = app
-        .torrent_client
-        .torrents
-        .get(torrent_hashes[0].as_str())
-        .unwrap();
-
-    let current_torrent_file_sizes = selected_torrent
-        .meta_info
-        .info
-        .files
-        .clone()
-        .unwrap()
-        .iter()
-        .map(|file| file.length)
-        .reduce(|x, y| x + y)
-        .unwrap_or(0);
-
-    let current_torrent_total_size = selected_torrent
-        .meta_info
-        .info
-        .length
-        .unwrap_or(current_torrent_file_sizes);
-
-    let current_torrent_total_size_u64 = u64::try_from(current_torrent_total_size).unwrap();
-
-    let current_torrent_size = format_size(
-      current_torrent_total_size_u64,
-        DECIMAL,
-    );
-
-    let selected_torrent_widget = Paragraph::new(selected_torrent.meta_info.info.name.clone())
+    let selected_torrent = &app.torrents[app.selected];
+
+    let mut header = selected_torrent.name.clone();
+    if !selected_torrent.banned_peers.is_empty() {
+        let banned = selected_torrent
+            .banned_peers
+            .iter()
+            .map(|addr| addr.to_string())
+            .collect::<Vec<_>>();
+        header.push_str(&format!("\nBanned peers: {}", banned.join(", ")));
+    }
+    let selected_torrent_widget = Paragraph::new(header)
         .alignment(Alignment::Center);
 
-    let chunks_lines = u16::try_from(selected_torrent.info().pieces.len() / area.width as usize).unwrap();
+    let chunks_lines = u16::try_from(selected_torrent.pieces_count() / area.width as usize).unwrap();
 
     let chunks = Layout::default()
       .direction(Direction::Vertical)
@@ -195,26 +162,42 @@ pub fn render_torrent_info(f: &mut Frame, app: &App, area: Rect) {
 
 }
 
+pub fn render_messages(f: &mut Frame, app: &App, area: Rect) {
+    let messages = List::new(
+        app.messages
+            .iter()
+            .map(|message| ListItem::new(Span::raw(message.clone())))
+            .collect::<Vec<_>>(),
+    )
+    .block(Block::default().borders(Borders::TOP))
+    .style(Style::default().fg(Color::Yellow));
+
+    f.render_widget(messages, area);
+}
+
 pub fn draw(f: &mut Frame, app: &App) {
     let size = render_root_box(f, f.size());
-    
-    let torrent_hashes = app
-        .torrent_client
-        .torrents
-        .clone()
-        .into_keys()
-        .collect::<Vec<_>>();
+
+    let messages_lines = if app.messages.is_empty() {
+        0
+    } else {
+        u16::try_from(app.messages.len().min(MAX_MESSAGES) + 1).unwrap_or(u16::MAX)
+    };
 
     let chunks = Layout::default()
         .direction(Direction::Vertical)
         .constraints(vec![
             Constraint::Min(3),
             Constraint::Percentage(100),
+            Constraint::Length(messages_lines),
         ])
         .split(size);
 
     render_torrent_selection(f, app, chunks[0]);
-    render_torrent_info(f, app, chunks[1]);
+    if !app.torrents.is_empty() {
+        render_torrent_info(f, app, chunks[1]);
+    }
+    render_messages(f, app, chunks[2]);
 }
 
 pub fn ui(f: &mut Frame, app: &mut App) {
@@ -224,18 +207,57 @@ pub fn ui(f: &mut Frame, app: &mut App) {
 #[derive(PartialEq)]
 pub enum Action {
     Quit,
+    SelectPrevious,
+    SelectNext,
+    QueueUp,
+    QueueDown,
+    Event(ClientEvent),
     None,
 }
 
-pub fn update(app: &mut App, msg: Action) -> Action {
+pub async fn update(app: &mut App, msg: Action) -> Result<Action> {
     match msg {
         Action::Quit => app.should_quit = true,
-        _ => {}
+        Action::SelectPrevious => app.selected = app.selected.saturating_sub(1),
+        Action::SelectNext => app.selected += 1,
+        Action::QueueUp | Action::QueueDown => {
+            if let Some(torrent) = app.torrents.get(app.selected) {
+                let position = if msg == Action::QueueUp {
+                    torrent.queue_position.saturating_sub(1)
+                } else {
+                    torrent.queue_position + 1
+                };
+                match app.client.set_queue_position(&torrent.info_hash, position).await {
+                    // Keep the moved torrent selected
+                    Ok(()) => app.selected = position,
+                    Err(error) => app.push_message(format!("Failed to move {}: {}", torrent.name, error)),
+                }
+            }
+        }
+        Action::Event(event) => {
+            let message = match event {
+                ClientEvent::TorrentCompleted { info_hash } => Some(format!("Completed: {}", info_hash)),
+                ClientEvent::SeedingGoalReached { info_hash, goal, action } => {
+                    Some(format!("Seeding goal reached: {}: {:?}, {:?}", info_hash, goal, action))
+                }
+                ClientEvent::TrackerError { url, message, .. } => Some(format!("Tracker error: {}: {}", url, message)),
+                ClientEvent::TrackerWarning { url, message, .. } => Some(format!("Tracker warning: {}: {}", url, message)),
+                ClientEvent::StorageError { info_hash, message } => Some(format!("Storage error: {}: {}", info_hash, message)),
+                ClientEvent::PeerBanned { addr, .. } => Some(format!("Banned peer {} for sending bad data", addr)),
+                _ => None,
+            };
+            if let Some(message) = message {
+                app.push_message(message);
+            }
+        }
+        Action::None => {}
     };
-    Action::None
+    app.torrents = app.client.list().await?;
+    app.selected = app.selected.min(app.torrents.len().saturating_sub(1));
+    Ok(Action::None)
 }
 
-pub fn handle_event(app: &App, tx: mpsc::UnboundedSender<Action>) -> tokio::task::JoinHandle<()> {
+pub fn handle_event(tx: mpsc::UnboundedSender<Action>) -> tokio::task::JoinHandle<()> {
     let tick_rate = std::time::Duration::from_millis(250);
     tokio::spawn(async move {
         loop {
@@ -244,6 +266,10 @@ pub fn handle_event(app: &App, tx: mpsc::UnboundedSender<Action>) -> tokio::task
                     if key.kind == crossterm::event::KeyEventKind::Press {
                         match key.code {
                             crossterm::event::KeyCode::Char('q') => Action::Quit,
+                            crossterm::event::KeyCode::Up => Action::SelectPrevious,
+                            crossterm::event::KeyCode::Down => Action::SelectNext,
+                            crossterm::event::KeyCode::Char('u') => Action::QueueUp,
+                            crossterm::event::KeyCode::Char('d') => Action::QueueDown,
                             _ => Action::None,
                         }
                     } else {
@@ -255,34 +281,61 @@ pub fn handle_event(app: &App, tx: mpsc::UnboundedSender<Action>) -> tokio::task
             } else {
                 Action::None
             };
-            if let Err(_) = tx.send(action) {
+            if tx.send(action).is_err() {
                 break;
             }
         }
     })
 }
 
-pub async fn run() -> Result<()> {
-    let mut torrent_client = TorrentClient::new();
+pub fn handle_client_events(client: &ClientHandle, tx: mpsc::UnboundedSender<Action>) -> tokio::task::JoinHandle<()> {
+    let mut events = client.subscribe();
+    tokio::spawn(async move {
+        loop {
+            match events.recv().await {
+                Ok(event) => {
+                    if tx.send(Action::Event(event)).is_err() {
+                        break;
+                    }
+                }
+                // Missed events are fine, the status is refreshed after every action anyway
+                Err(tokio::sync::broadcast::error::RecvError::Lagged(_)) => {}
+                Err(tokio::sync::broadcast::error::RecvError::Closed) => break,
+            }
+        }
+    })
+}
 
-    let meta_info = MetaInfo::from_file("./torrent_test.torrent")?;
-    torrent_client.add_torrent(meta_info);
+pub async fn run(config: Config) -> Result<()> {
+    let client = config.client()?;
+    let listen_port = client.listen_port();
+    let client = client.spawn();
+    let (action_tx, mut action_rx) = mpsc::unbounded_channel();
+    let events_task = handle_client_events(&client, action_tx.clone());
+    let messages = config.add_torrents(&client).await;
 
     let mut t = Terminal::new(CrosstermBackend::new(std::io::stderr()))?;
-    let (action_tx, mut action_rx) = mpsc::unbounded_channel();
     let mut app = App {
         should_quit: false,
         action_tx,
-        torrent_client,
+        torrents: client.list().await?,
+        selected: 0,
+        client,
+        messages: VecDeque::new(),
     };
-    let task = handle_event(&app, app.action_tx.clone());
+    for message in messages {
+        app.push_message(message);
+    }
+    let task = handle_event(app.action_tx.clone());
+    // Without the multicast group there are just no LAN peers, the TUI has nowhere to report it
+    let lsd_task = tokio::spawn(run_lsd(app.client.clone(), listen_port));
     loop {
         t.draw(|f| {
             ui(f, &mut app);
         })?;
 
         if let Some(action) = action_rx.recv().await {
-            update(&mut app, action);
+            update(&mut app, action).await?;
         }
 
         if app.should_quit {
@@ -291,6 +344,9 @@ pub async fn run() -> Result<()> {
     }
 
     task.abort();
+    lsd_task.abort();
+    events_task.abort();
+    app.client.shutdown_gracefully().await?;
 
     Ok(())
 }
@@ -6,13 +6,26 @@ use std::{
 
 use anyhow::{Error, Result};
 
-#[derive(Debug, Clone, Deserialize, Serialize)]
+use crate::network::Interface;
+use crate::proxy::ProxySettings;
+
+#[allow(clippy::upper_case_acronyms)]
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
 pub enum IpAddr {
     V4(Ipv4Addr),
     V6(Ipv6Addr),
     DNS(String),
 }
 
+impl From<std::net::IpAddr> for IpAddr {
+    fn from(ip: std::net::IpAddr) -> Self {
+        match ip {
+            std::net::IpAddr::V4(ip) => IpAddr::V4(ip),
+            std::net::IpAddr::V6(ip) => IpAddr::V6(ip),
+        }
+    }
+}
+
 pub fn read_file(file_path: &str) -> Result<Vec<u8>> {
     let mut file = File::open(file_path)?;
     let mut buffer = Vec::new();
@@ -20,8 +33,8 @@ pub fn read_file(file_path: &str) -> Result<Vec<u8>> {
     Ok(buffer)
 }
 
-pub async fn fetch_buffer(url: &str) -> Result<Vec<u8>> {
-    let resp = reqwest::get(url).await?;
+pub async fn fetch_buffer(url: &str, proxy: &ProxySettings, interface: Option<&Interface>) -> Result<Vec<u8>> {
+    let resp = proxy.http_client(interface)?.get(url).send().await?;
 
     if (resp.status().as_u16() / 100) != 2 {
         return Err(Error::msg(format!("Bad status code: {}", resp.status())));
@@ -0,0 +1,1022 @@
+use std::collections::{HashMap, VecDeque};
+use std::io;
+use std::net::SocketAddr;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex, OnceLock};
+use std::task::{Context as TaskContext, Poll, Waker};
+use std::time::{Duration, Instant};
+
+use rand::Rng;
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio::net::UdpSocket;
+use tokio::sync::{mpsc, Notify};
+use tokio::task::AbortHandle;
+
+/*
+ * BEP 29, uTorrent transport protocol
+ * Reliable ordered streams over UDP. Congestion is controlled with LEDBAT: the window grows while the one way delay
+ * measured by the peer stays under a target above the lowest delay seen, and shrinks as soon as queues build up,
+ * so uTP traffic gives way to everything else on the link.
+ *
+ * 0       4       8               16              24              32
+ * +-------+-------+---------------+---------------+---------------+
+ * | type  | ver   | extension     | connection_id                 |
+ * +-------+-------+---------------+---------------+---------------+
+ * | timestamp_microseconds                                        |
+ * +---------------+---------------+---------------+---------------+
+ * | timestamp_difference_microseconds                             |
+ * +---------------+---------------+---------------+---------------+
+ * | wnd_size                                                      |
+ * +---------------+---------------+---------------+---------------+
+ * | seq_nr                        | ack_nr                        |
+ * +---------------+---------------+---------------+---------------+
+ */
+
+const VERSION: u8 = 1;
+const HEADER_LENGTH: usize = 20;
+/** Payload per packet, keeping datagrams under common MTUs */
+const MAX_PAYLOAD: usize = 1380;
+/** Queuing delay LEDBAT aims for, in microseconds */
+const TARGET_DELAY: f64 = 100_000.0;
+/** Most the window grows in one round trip, in bytes */
+const MAX_WINDOW_INCREASE: f64 = 3000.0;
+const MIN_WINDOW: f64 = MAX_PAYLOAD as f64;
+const MAX_WINDOW: f64 = (4 << 20) as f64;
+const RECEIVE_WINDOW: usize = 1 << 20;
+/** Data accepted from the writer and not sent yet */
+const SEND_BUFFER: usize = 1 << 20;
+const INITIAL_TIMEOUT: Duration = Duration::from_secs(1);
+const MIN_TIMEOUT: Duration = Duration::from_millis(500);
+const MAX_TIMEOUT: Duration = Duration::from_secs(30);
+/** Timeouts in a row before the connection is given up */
+const MAX_SYN_TIMEOUTS: u32 = 2;
+const MAX_TIMEOUTS: u32 = 6;
+/** Duplicate acknowledgements triggering a retransmission without waiting for the timeout */
+const DUPLICATE_ACKS: u32 = 3;
+/** The base delay is the lowest delay of the last two minutes, tracked per minute */
+const BASE_DELAY_INTERVAL: Duration = Duration::from_secs(60);
+const BASE_DELAY_HISTORY: usize = 2;
+/** Packets further ahead than this are dropped rather than buffered */
+const MAX_REORDER: u16 = 1024;
+/** Extension carrying a bitmask of the packets received past the first missing one */
+const SELECTIVE_ACK: u8 = 1;
+/** Bits of the selective ack bitmask we send, the packets after `ack_nr + 1` */
+const SELECTIVE_ACK_BITS: u16 = 32;
+/** Datagrams not belonging to uTP waiting for whoever shares the socket, dropped past this */
+const OTHER_DATAGRAMS: usize = 64;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum PacketType {
+    Data = 0,
+    Fin = 1,
+    State = 2,
+    Reset = 3,
+    Syn = 4,
+}
+
+impl PacketType {
+    fn from_u8(value: u8) -> Option<Self> {
+        match value {
+            0 => Some(PacketType::Data),
+            1 => Some(PacketType::Fin),
+            2 => Some(PacketType::State),
+            3 => Some(PacketType::Reset),
+            4 => Some(PacketType::Syn),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+struct Packet {
+    kind: PacketType,
+    connection_id: u16,
+    timestamp: u32,
+    timestamp_difference: u32,
+    window: u32,
+    seq_nr: u16,
+    ack_nr: u16,
+    /** Bit `i` is set when packet `ack_nr + 2 + i` was received */
+    selective_ack: Option<Vec<u8>>,
+    payload: Vec<u8>,
+}
+
+impl Packet {
+    fn new(kind: PacketType, connection_id: u16, seq_nr: u16, payload: Vec<u8>) -> Self {
+        Self {
+            kind,
+            connection_id,
+            timestamp: 0,
+            timestamp_difference: 0,
+            window: 0,
+            seq_nr,
+            ack_nr: 0,
+            selective_ack: None,
+            payload,
+        }
+    }
+
+    /** Unknown extensions are skipped */
+    fn from_buffer(buffer: &[u8]) -> Option<Packet> {
+        if buffer.len() < HEADER_LENGTH || buffer[0] & 0x0f != VERSION {
+            return None;
+        }
+        let kind = PacketType::from_u8(buffer[0] >> 4)?;
+        let u32_at = |offset: usize| u32::from_be_bytes(buffer[offset..offset + 4].try_into().unwrap());
+        let u16_at = |offset: usize| u16::from_be_bytes(buffer[offset..offset + 2].try_into().unwrap());
+        let mut offset = HEADER_LENGTH;
+        let mut extension = buffer[1];
+        let mut selective_ack = None;
+        while extension != 0 {
+            let header = buffer.get(offset..offset + 2)?;
+            let data = buffer.get(offset + 2..offset + 2 + header[1] as usize)?;
+            if extension == SELECTIVE_ACK {
+                selective_ack = Some(data.to_vec());
+            }
+            extension = header[0];
+            offset += 2 + data.len();
+        }
+        Some(Packet {
+            kind,
+            connection_id: u16_at(2),
+            timestamp: u32_at(4),
+            timestamp_difference: u32_at(8),
+            window: u32_at(12),
+            seq_nr: u16_at(16),
+            ack_nr: u16_at(18),
+            selective_ack,
+            payload: buffer.get(offset..)?.to_vec(),
+        })
+    }
+
+    fn to_buffer(&self) -> Vec<u8> {
+        let mut buffer = Vec::with_capacity(HEADER_LENGTH + self.payload.len());
+        buffer.push((self.kind as u8) << 4 | VERSION);
+        buffer.push(if self.selective_ack.is_some() { SELECTIVE_ACK } else { 0 });
+        buffer.extend_from_slice(&self.connection_id.to_be_bytes());
+        buffer.extend_from_slice(&self.timestamp.to_be_bytes());
+        buffer.extend_from_slice(&self.timestamp_difference.to_be_bytes());
+        buffer.extend_from_slice(&self.window.to_be_bytes());
+        buffer.extend_from_slice(&self.seq_nr.to_be_bytes());
+        buffer.extend_from_slice(&self.ack_nr.to_be_bytes());
+        if let Some(selective_ack) = &self.selective_ack {
+            buffer.push(0);
+            buffer.push(selective_ack.len() as u8);
+            buffer.extend_from_slice(selective_ack);
+        }
+        buffer.extend_from_slice(&self.payload);
+        buffer
+    }
+}
+
+/** Microseconds on a clock of our own, only differences between two timestamps of the same side matter */
+fn timestamp_micros() -> u32 {
+    static EPOCH: OnceLock<Instant> = OnceLock::new();
+    EPOCH.get_or_init(Instant::now).elapsed().as_micros() as u32
+}
+
+/** Sequence numbers wrap around */
+fn seq_before(a: u16, b: u16) -> bool {
+    (b.wrapping_sub(a) as i16) > 0
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum State {
+    SynSent,
+    /** Incoming connections wait for the initiator to acknowledge our answer before sending data */
+    SynReceived,
+    Connected,
+    Failed(io::ErrorKind),
+}
+
+#[derive(Debug)]
+struct SentPacket {
+    packet: Packet,
+    sent_at: Instant,
+    transmissions: u32,
+    /** Received by the peer past a missing packet */
+    selectively_acked: bool,
+}
+
+/** State of one connection, driven by its task and read and written by its `UtpStream` */
+#[derive(Debug)]
+struct Connection {
+    state: State,
+    send_id: u16,
+    recv_id: u16,
+    /** Sequence number of the next packet sent */
+    seq_nr: u16,
+    /** Last packet received in order */
+    ack_nr: u16,
+    in_flight: VecDeque<SentPacket>,
+    send_buffer: VecDeque<u8>,
+    /** The writer shut down, a FIN follows the data */
+    write_closed: bool,
+    fin_sent: bool,
+    fin_acked: bool,
+    receive_buffer: VecDeque<u8>,
+    out_of_order: HashMap<u16, Packet>,
+    /** Sequence number of the FIN from the peer, the stream ends once everything before it arrived */
+    fin_seq: Option<u16>,
+    eof: bool,
+    stream_dropped: bool,
+    /** Congestion window in bytes */
+    max_window: f64,
+    peer_window: u32,
+    rtt: Option<Duration>,
+    rtt_var: Duration,
+    timeout: Duration,
+    timeout_at: Option<Instant>,
+    timeouts: u32,
+    duplicate_acks: u32,
+    /** Packets sent before this may be acknowledged late because of a loss, their round trip times are off */
+    last_retransmit: Option<Instant>,
+    base_delays: VecDeque<(Instant, u32)>,
+    /** Delay of the last packet from the peer, echoed back so it can measure its own */
+    reply_micro: u32,
+    ack_pending: bool,
+    advertised_window: u32,
+    outgoing: Vec<Packet>,
+    read_waker: Option<Waker>,
+    write_waker: Option<Waker>,
+}
+
+impl Connection {
+    fn new(state: State, send_id: u16, recv_id: u16, seq_nr: u16, ack_nr: u16) -> Self {
+        Self {
+            state,
+            send_id,
+            recv_id,
+            seq_nr,
+            ack_nr,
+            in_flight: VecDeque::new(),
+            send_buffer: VecDeque::new(),
+            write_closed: false,
+            fin_sent: false,
+            fin_acked: false,
+            receive_buffer: VecDeque::new(),
+            out_of_order: HashMap::new(),
+            fin_seq: None,
+            eof: false,
+            stream_dropped: false,
+            max_window: MIN_WINDOW,
+            peer_window: RECEIVE_WINDOW as u32,
+            rtt: None,
+            rtt_var: Duration::ZERO,
+            timeout: INITIAL_TIMEOUT,
+            timeout_at: None,
+            timeouts: 0,
+            duplicate_acks: 0,
+            last_retransmit: None,
+            base_delays: VecDeque::new(),
+            reply_micro: 0,
+            ack_pending: false,
+            advertised_window: RECEIVE_WINDOW as u32,
+            outgoing: vec![],
+            read_waker: None,
+            write_waker: None,
+        }
+    }
+
+    /** The SYN is the only packet sent with our own connection id, the peer answers on `recv_id` */
+    fn outgoing(recv_id: u16, now: Instant) -> Self {
+        let mut connection = Self::new(State::SynSent, recv_id.wrapping_add(1), recv_id, 1, 0);
+        let syn = Packet::new(PacketType::Syn, recv_id, connection.seq_nr, vec![]);
+        connection.seq_nr = connection.seq_nr.wrapping_add(1);
+        connection.send(syn, now);
+        connection
+    }
+
+    /** Our first data packet reuses the sequence number of the STATE answering the SYN */
+    fn incoming(syn: &Packet) -> Self {
+        let seq_nr = rand::thread_rng().gen();
+        let mut connection = Self::new(
+            State::SynReceived,
+            syn.connection_id,
+            syn.connection_id.wrapping_add(1),
+            seq_nr,
+            syn.seq_nr,
+        );
+        connection.ack_pending = true;
+        connection
+    }
+
+    fn wake(&mut self) {
+        if let Some(waker) = self.read_waker.take() {
+            waker.wake();
+        }
+        if let Some(waker) = self.write_waker.take() {
+            waker.wake();
+        }
+    }
+
+    fn fail(&mut self, kind: io::ErrorKind) {
+        self.state = State::Failed(kind);
+        self.in_flight.clear();
+        self.timeout_at = None;
+        self.wake();
+    }
+
+    fn receive_window(&self) -> u32 {
+        let buffered = self.receive_buffer.len() + self.out_of_order.len() * MAX_PAYLOAD;
+        RECEIVE_WINDOW.saturating_sub(buffered) as u32
+    }
+
+    fn bytes_in_flight(&self) -> usize {
+        self.in_flight
+            .iter()
+            .filter(|sent| !sent.selectively_acked)
+            .map(|sent| sent.packet.payload.len())
+            .sum()
+    }
+
+    /** Packets carrying a sequence number are kept until acknowledged */
+    fn send(&mut self, packet: Packet, now: Instant) {
+        self.in_flight.push_back(SentPacket {
+            packet: packet.clone(),
+            sent_at: now,
+            transmissions: 1,
+            selectively_acked: false,
+        });
+        if self.timeout_at.is_none() {
+            self.timeout_at = Some(now + self.timeout);
+        }
+        self.outgoing.push(packet);
+    }
+
+    fn handle_packet(&mut self, packet: Packet, now: Instant) {
+        if packet.kind == PacketType::Reset {
+            self.fail(io::ErrorKind::ConnectionReset);
+            return;
+        }
+        if let State::Failed(_) = self.state {
+            return;
+        }
+        self.reply_micro = timestamp_micros().wrapping_sub(packet.timestamp);
+        self.peer_window = packet.window;
+        if packet.kind == PacketType::Syn {
+            // Our answer got lost
+            self.ack_pending = true;
+            return;
+        }
+        match self.state {
+            State::SynSent if packet.kind != PacketType::State => return,
+            State::SynSent => {
+                self.state = State::Connected;
+                self.ack_nr = packet.seq_nr.wrapping_sub(1);
+                // Lets the peer send even if we have nothing to say
+                self.ack_pending = true;
+                self.wake();
+            }
+            State::SynReceived => {
+                self.state = State::Connected;
+                self.wake();
+            }
+            _ => {}
+        }
+        self.process_ack(&packet, now);
+
+        if !matches!(packet.kind, PacketType::Data | PacketType::Fin) {
+            return;
+        }
+        self.ack_pending = true;
+        if packet.kind == PacketType::Fin {
+            self.fin_seq = Some(packet.seq_nr);
+        }
+        let ahead = packet.seq_nr.wrapping_sub(self.ack_nr);
+        if ahead == 0 || ahead > MAX_REORDER {
+            return;
+        }
+        self.out_of_order.insert(packet.seq_nr, packet);
+        let mut received = false;
+        while let Some(packet) = self.out_of_order.remove(&self.ack_nr.wrapping_add(1)) {
+            self.ack_nr = packet.seq_nr;
+            self.receive_buffer.extend(packet.payload);
+            received = true;
+        }
+        if self.fin_seq == Some(self.ack_nr) {
+            self.eof = true;
+            self.out_of_order.clear();
+        }
+        if received {
+            if let Some(waker) = self.read_waker.take() {
+                waker.wake();
+            }
+        }
+    }
+
+    fn process_ack(&mut self, packet: &Packet, now: Instant) {
+        let acked = self
+            .in_flight
+            .iter()
+            .take_while(|sent| !seq_before(packet.ack_nr, sent.packet.seq_nr))
+            .count();
+        let mut bytes_acked = 0;
+        for sent in self.in_flight.drain(..acked) {
+            if !sent.selectively_acked {
+                bytes_acked += sent.packet.payload.len();
+            }
+            if sent.packet.kind == PacketType::Fin {
+                self.fin_acked = true;
+            }
+            // Retransmitted packets don't tell which transmission was acknowledged
+            let late = self.last_retransmit.is_some_and(|retransmit| sent.sent_at <= retransmit);
+            if sent.transmissions == 1 && !late {
+                let sample = now.duration_since(sent.sent_at);
+                match self.rtt {
+                    None => {
+                        self.rtt = Some(sample);
+                        self.rtt_var = sample / 2;
+                    }
+                    Some(rtt) => {
+                        let deviation = rtt.abs_diff(sample);
+                        self.rtt_var = (self.rtt_var * 3 + deviation) / 4;
+                        self.rtt = Some((rtt * 7 + sample) / 8);
+                    }
+                }
+            }
+        }
+        if acked > 0 {
+            // Progress ends the back off of the timeout
+            if let Some(rtt) = self.rtt {
+                self.timeout = (rtt + self.rtt_var * 4).clamp(MIN_TIMEOUT, MAX_TIMEOUT);
+            }
+            self.duplicate_acks = 0;
+            self.timeouts = 0;
+            self.timeout_at = (!self.in_flight.is_empty()).then(|| now + self.timeout);
+        } else if packet.kind == PacketType::State
+            && self
+                .in_flight
+                .front()
+                .is_some_and(|sent| sent.packet.seq_nr == packet.ack_nr.wrapping_add(1))
+        {
+            self.duplicate_acks += 1;
+        }
+
+        if let Some(selective_ack) = &packet.selective_ack {
+            for sent in &mut self.in_flight {
+                let bit = sent.packet.seq_nr.wrapping_sub(packet.ack_nr).wrapping_sub(2) as usize;
+                let received = selective_ack
+                    .get(bit / 8)
+                    .is_some_and(|byte| byte & (1 << (bit % 8)) != 0);
+                if received && !sent.selectively_acked {
+                    sent.selectively_acked = true;
+                    bytes_acked += sent.packet.payload.len();
+                }
+            }
+        }
+        self.fast_retransmit(now);
+        if bytes_acked > 0 && packet.timestamp_difference != 0 {
+            self.congestion_control(packet.timestamp_difference, bytes_acked, now);
+        }
+    }
+
+    /**
+     * Resend, once, the packets which enough packets sent after them overtook, or the first one after duplicate acks.
+     * The window is halved once per loss event.
+     */
+    fn fast_retransmit(&mut self, now: Instant) {
+        let mut overtaken = 0;
+        let mut lost = vec![];
+        for (index, sent) in self.in_flight.iter().enumerate().rev() {
+            if sent.selectively_acked {
+                overtaken += 1;
+            } else if overtaken >= DUPLICATE_ACKS && sent.transmissions == 1 {
+                lost.push(index);
+            }
+        }
+        if self.duplicate_acks == DUPLICATE_ACKS && self.in_flight.front().is_some_and(|sent| sent.transmissions == 1) {
+            lost.push(0);
+        }
+        if lost.is_empty() {
+            return;
+        }
+        self.max_window = (self.max_window / 2.0).max(MIN_WINDOW);
+        lost.sort_unstable();
+        lost.dedup();
+        for index in lost {
+            self.retransmit(index, now);
+        }
+    }
+
+    /** LEDBAT: grow the window in proportion to how far under the target the queuing delay is, shrink it when over */
+    fn congestion_control(&mut self, delay: u32, bytes_acked: usize, now: Instant) {
+        match self.base_delays.back_mut() {
+            Some((start, lowest)) if now.duration_since(*start) < BASE_DELAY_INTERVAL => *lowest = (*lowest).min(delay),
+            _ => {
+                self.base_delays.push_back((now, delay));
+                if self.base_delays.len() > BASE_DELAY_HISTORY {
+                    self.base_delays.pop_front();
+                }
+            }
+        }
+        let base_delay = self.base_delays.iter().map(|&(_, lowest)| lowest).min().unwrap_or(delay);
+        let queuing_delay = delay.wrapping_sub(base_delay) as f64;
+        let off_target = (TARGET_DELAY - queuing_delay) / TARGET_DELAY;
+        let window_factor = bytes_acked as f64 / self.max_window;
+        self.max_window = (self.max_window + MAX_WINDOW_INCREASE * off_target * window_factor).clamp(MIN_WINDOW, MAX_WINDOW);
+    }
+
+    fn retransmit(&mut self, index: usize, now: Instant) {
+        if let Some(sent) = self.in_flight.get_mut(index) {
+            sent.sent_at = now;
+            sent.transmissions += 1;
+            let packet = sent.packet.clone();
+            self.outgoing.push(packet);
+            self.last_retransmit = Some(now);
+        }
+    }
+
+    fn handle_timeout(&mut self, now: Instant) {
+        if self.in_flight.is_empty() {
+            self.timeout_at = None;
+            return;
+        }
+        self.timeouts += 1;
+        let max_timeouts = if self.state == State::SynSent {
+            MAX_SYN_TIMEOUTS
+        } else {
+            MAX_TIMEOUTS
+        };
+        if self.timeouts > max_timeouts {
+            self.fail(io::ErrorKind::TimedOut);
+            return;
+        }
+        self.max_window = MIN_WINDOW;
+        self.duplicate_acks = 0;
+        self.timeout = (self.timeout * 2).min(MAX_TIMEOUT);
+        self.timeout_at = Some(now + self.timeout);
+        if let Some(index) = self.in_flight.iter().position(|sent| !sent.selectively_acked) {
+            self.retransmit(index, now);
+        }
+    }
+
+    /** Packetize what the window allows and return every packet to send, stamped with our latest state */
+    fn flush(&mut self, now: Instant) -> Vec<Packet> {
+        if self.state == State::Connected {
+            let mut in_flight = self.bytes_in_flight();
+            let window = self.max_window.min(self.peer_window as f64) as usize;
+            while !self.send_buffer.is_empty() {
+                let length = self.send_buffer.len().min(MAX_PAYLOAD);
+                // One packet may always be in flight, so a closed window gets probed
+                if !self.in_flight.is_empty() && in_flight + length > window {
+                    break;
+                }
+                let payload = self.send_buffer.drain(..length).collect();
+                let packet = Packet::new(PacketType::Data, self.send_id, self.seq_nr, payload);
+                self.seq_nr = self.seq_nr.wrapping_add(1);
+                self.send(packet, now);
+                in_flight += length;
+                if let Some(waker) = self.write_waker.take() {
+                    waker.wake();
+                }
+            }
+            if self.write_closed && self.send_buffer.is_empty() && !self.fin_sent {
+                let fin = Packet::new(PacketType::Fin, self.send_id, self.seq_nr, vec![]);
+                self.seq_nr = self.seq_nr.wrapping_add(1);
+                self.send(fin, now);
+                self.fin_sent = true;
+            }
+        }
+
+        let window = self.receive_window();
+        // Tell the peer once a window it saw closed opens again
+        let window_opened = self.advertised_window < MAX_PAYLOAD as u32 && window >= MAX_PAYLOAD as u32;
+        let answering = matches!(self.state, State::Connected | State::SynReceived);
+        if self.outgoing.is_empty() && (self.ack_pending || window_opened) && answering {
+            self.outgoing.push(Packet::new(PacketType::State, self.send_id, self.seq_nr, vec![]));
+        }
+        let mut outgoing = std::mem::take(&mut self.outgoing);
+        if !outgoing.is_empty() {
+            self.ack_pending = false;
+            self.advertised_window = window;
+        }
+        let selective_ack = (!self.out_of_order.is_empty()).then(|| {
+            let mut bitmask = vec![0u8; SELECTIVE_ACK_BITS as usize / 8];
+            for bit in 0..SELECTIVE_ACK_BITS {
+                if self.out_of_order.contains_key(&self.ack_nr.wrapping_add(2 + bit)) {
+                    bitmask[bit as usize / 8] |= 1 << (bit % 8);
+                }
+            }
+            bitmask
+        });
+        for packet in &mut outgoing {
+            packet.selective_ack = selective_ack.clone();
+            packet.timestamp = timestamp_micros();
+            packet.timestamp_difference = self.reply_micro;
+            packet.window = window;
+            packet.ack_nr = self.ack_nr;
+        }
+        outgoing
+    }
+
+    fn is_finished(&self) -> bool {
+        match self.state {
+            State::Failed(_) => true,
+            State::SynSent | State::SynReceived => self.stream_dropped,
+            State::Connected => self.fin_sent && self.fin_acked && (self.eof || self.stream_dropped),
+        }
+    }
+}
+
+#[derive(Debug)]
+struct Shared {
+    connection: Mutex<Connection>,
+    /** Wakes the connection task when the stream changed something */
+    notify: Notify,
+}
+
+type Datagram = (Vec<u8>, SocketAddr);
+
+#[derive(Debug)]
+struct SocketInner {
+    udp: UdpSocket,
+    connections: Mutex<HashMap<(SocketAddr, u16), mpsc::UnboundedSender<Packet>>>,
+    other: mpsc::Sender<Datagram>,
+}
+
+impl SocketInner {
+    fn spawn_connection(self: &Arc<Self>, connection: Connection, addr: SocketAddr) -> UtpStream {
+        let (packets_tx, packets) = mpsc::unbounded_channel();
+        let key = (addr, connection.recv_id);
+        self.connections.lock().unwrap().insert(key, packets_tx);
+        let shared = Arc::new(Shared {
+            connection: Mutex::new(connection),
+            notify: Notify::new(),
+        });
+        tokio::spawn(drive(shared.clone(), self.clone(), addr, packets));
+        UtpStream { shared, addr }
+    }
+
+    /** Answering a SYN from a new peer creates the connection */
+    fn dispatch(self: &Arc<Self>, packet: Packet, addr: SocketAddr, incoming: &mpsc::UnboundedSender<UtpStream>) {
+        let recv_id = if packet.kind == PacketType::Syn {
+            packet.connection_id.wrapping_add(1)
+        } else {
+            packet.connection_id
+        };
+        if let Some(connection) = self.connections.lock().unwrap().get(&(addr, recv_id)) {
+            let _ = connection.send(packet);
+            return;
+        }
+        if packet.kind == PacketType::Syn {
+            let stream = self.spawn_connection(Connection::incoming(&packet), addr);
+            let _ = incoming.send(stream);
+        }
+    }
+}
+
+/** Run a connection until both sides are done with it, or it fails */
+async fn drive(
+    shared: Arc<Shared>,
+    socket: Arc<SocketInner>,
+    addr: SocketAddr,
+    mut packets: mpsc::UnboundedReceiver<Packet>,
+) {
+    let recv_id = shared.connection.lock().unwrap().recv_id;
+    loop {
+        let (outgoing, timeout_at, finished) = {
+            let mut connection = shared.connection.lock().unwrap();
+            let outgoing = connection.flush(Instant::now());
+            (outgoing, connection.timeout_at, connection.is_finished())
+        };
+        for packet in outgoing {
+            let _ = socket.udp.send_to(&packet.to_buffer(), addr).await;
+        }
+        if finished {
+            break;
+        }
+        let timeout = async {
+            match timeout_at {
+                Some(timeout_at) => tokio::time::sleep_until(timeout_at.into()).await,
+                None => std::future::pending().await,
+            }
+        };
+        tokio::select! {
+            packet = packets.recv() => {
+                let mut connection = shared.connection.lock().unwrap();
+                let Some(packet) = packet else {
+                    connection.fail(io::ErrorKind::ConnectionAborted);
+                    break;
+                };
+                connection.handle_packet(packet, Instant::now());
+                while let Ok(packet) = packets.try_recv() {
+                    connection.handle_packet(packet, Instant::now());
+                }
+            }
+            _ = shared.notify.notified() => {}
+            _ = timeout => shared.connection.lock().unwrap().handle_timeout(Instant::now()),
+        }
+    }
+    socket.connections.lock().unwrap().remove(&(addr, recv_id));
+}
+
+async fn receive(socket: Arc<SocketInner>, incoming: mpsc::UnboundedSender<UtpStream>) {
+    let mut buffer = vec![0u8; 1 << 16];
+    loop {
+        let Ok((length, addr)) = socket.udp.recv_from(&mut buffer).await else {
+            continue;
+        };
+        match Packet::from_buffer(&buffer[..length]) {
+            Some(packet) => socket.dispatch(packet, addr, &incoming),
+            None => {
+                let _ = socket.other.try_send((buffer[..length].to_vec(), addr));
+            }
+        }
+    }
+}
+
+#[derive(Debug)]
+struct ReceiveTask(AbortHandle);
+
+impl Drop for ReceiveTask {
+    fn drop(&mut self) {
+        self.0.abort();
+    }
+}
+
+/**
+ * UDP socket carrying uTP connections in both directions.
+ * Datagrams which aren't uTP packets, DHT messages for one, are kept for whoever else uses the port.
+ */
+#[derive(Debug, Clone)]
+pub struct UtpSocket {
+    inner: Arc<SocketInner>,
+    incoming: Arc<tokio::sync::Mutex<mpsc::UnboundedReceiver<UtpStream>>>,
+    other: Arc<tokio::sync::Mutex<mpsc::Receiver<Datagram>>>,
+    _receive_task: Arc<ReceiveTask>,
+}
+
+impl UtpSocket {
+    pub async fn bind(addr: SocketAddr) -> io::Result<UtpSocket> {
+        Self::from_udp(UdpSocket::bind(addr).await?)
+    }
+
+    /** Must be called within a runtime */
+    pub fn from_std(socket: std::net::UdpSocket) -> io::Result<UtpSocket> {
+        socket.set_nonblocking(true)?;
+        Self::from_udp(UdpSocket::from_std(socket)?)
+    }
+
+    fn from_udp(udp: UdpSocket) -> io::Result<UtpSocket> {
+        let (other_tx, other) = mpsc::channel(OTHER_DATAGRAMS);
+        let (incoming_tx, incoming) = mpsc::unbounded_channel();
+        let inner = Arc::new(SocketInner {
+            udp,
+            connections: Mutex::new(HashMap::new()),
+            other: other_tx,
+        });
+        let receive_task = tokio::spawn(receive(inner.clone(), incoming_tx)).abort_handle();
+        Ok(UtpSocket {
+            inner,
+            incoming: Arc::new(tokio::sync::Mutex::new(incoming)),
+            other: Arc::new(tokio::sync::Mutex::new(other)),
+            _receive_task: Arc::new(ReceiveTask(receive_task)),
+        })
+    }
+
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.inner.udp.local_addr()
+    }
+
+    pub async fn connect(&self, addr: SocketAddr) -> io::Result<UtpStream> {
+        let recv_id = {
+            let connections = self.inner.connections.lock().unwrap();
+            let mut rng = rand::thread_rng();
+            loop {
+                let recv_id: u16 = rng.gen();
+                if !connections.contains_key(&(addr, recv_id)) {
+                    break recv_id;
+                }
+            }
+        };
+        let stream = self
+            .inner
+            .spawn_connection(Connection::outgoing(recv_id, Instant::now()), addr);
+        std::future::poll_fn(|cx| stream.poll_connected(cx)).await?;
+        Ok(stream)
+    }
+
+    pub async fn accept(&self) -> io::Result<(UtpStream, SocketAddr)> {
+        let stream = self
+            .incoming
+            .lock()
+            .await
+            .recv()
+            .await
+            .ok_or_else(|| io::Error::from(io::ErrorKind::ConnectionAborted))?;
+        let addr = stream.peer_addr();
+        Ok((stream, addr))
+    }
+
+    /** Next datagram which wasn't a uTP packet */
+    pub async fn recv_other(&self) -> Option<Datagram> {
+        self.other.lock().await.recv().await
+    }
+
+    pub async fn send_to(&self, buffer: &[u8], addr: SocketAddr) -> io::Result<usize> {
+        self.inner.udp.send_to(buffer, addr).await
+    }
+}
+
+/** A uTP connection, usable wherever a `TcpStream` is */
+#[derive(Debug)]
+pub struct UtpStream {
+    shared: Arc<Shared>,
+    addr: SocketAddr,
+}
+
+impl UtpStream {
+    pub fn peer_addr(&self) -> SocketAddr {
+        self.addr
+    }
+
+    fn poll_connected(&self, cx: &mut TaskContext<'_>) -> Poll<io::Result<()>> {
+        let mut connection = self.shared.connection.lock().unwrap();
+        match connection.state {
+            State::Connected | State::SynReceived => Poll::Ready(Ok(())),
+            State::Failed(kind) => Poll::Ready(Err(kind.into())),
+            State::SynSent => {
+                connection.write_waker = Some(cx.waker().clone());
+                Poll::Pending
+            }
+        }
+    }
+}
+
+impl AsyncRead for UtpStream {
+    fn poll_read(self: Pin<&mut Self>, cx: &mut TaskContext<'_>, buf: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
+        let mut connection = self.shared.connection.lock().unwrap();
+        if !connection.receive_buffer.is_empty() {
+            let length = connection.receive_buffer.len().min(buf.remaining());
+            let (front, back) = connection.receive_buffer.as_slices();
+            let from_front = length.min(front.len());
+            buf.put_slice(&front[..from_front]);
+            buf.put_slice(&back[..length - from_front]);
+            connection.receive_buffer.drain(..length);
+            drop(connection);
+            // The receive window may have opened
+            self.shared.notify.notify_one();
+            return Poll::Ready(Ok(()));
+        }
+        match connection.state {
+            State::Failed(kind) => Poll::Ready(Err(kind.into())),
+            _ if connection.eof => Poll::Ready(Ok(())),
+            _ => {
+                connection.read_waker = Some(cx.waker().clone());
+                Poll::Pending
+            }
+        }
+    }
+}
+
+impl AsyncWrite for UtpStream {
+    fn poll_write(self: Pin<&mut Self>, cx: &mut TaskContext<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+        let mut connection = self.shared.connection.lock().unwrap();
+        if let State::Failed(kind) = connection.state {
+            return Poll::Ready(Err(kind.into()));
+        }
+        if connection.write_closed {
+            return Poll::Ready(Err(io::ErrorKind::BrokenPipe.into()));
+        }
+        let space = SEND_BUFFER.saturating_sub(connection.send_buffer.len());
+        if space == 0 {
+            connection.write_waker = Some(cx.waker().clone());
+            return Poll::Pending;
+        }
+        let length = space.min(buf.len());
+        connection.send_buffer.extend(&buf[..length]);
+        drop(connection);
+        self.shared.notify.notify_one();
+        Poll::Ready(Ok(length))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, _: &mut TaskContext<'_>) -> Poll<io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+
+    /** The FIN goes out once everything written before was sent */
+    fn poll_shutdown(self: Pin<&mut Self>, _: &mut TaskContext<'_>) -> Poll<io::Result<()>> {
+        self.shared.connection.lock().unwrap().write_closed = true;
+        self.shared.notify.notify_one();
+        Poll::Ready(Ok(()))
+    }
+}
+
+/** The connection task finishes sending what was written, then closes the connection */
+impl Drop for UtpStream {
+    fn drop(&mut self) {
+        let mut connection = self.shared.connection.lock().unwrap();
+        connection.write_closed = true;
+        connection.stream_dropped = true;
+        drop(connection);
+        self.shared.notify.notify_one();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rand::rngs::StdRng;
+    use rand::SeedableRng;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    use super::*;
+
+    #[test]
+    fn test_packet() {
+        let mut packet = Packet::new(PacketType::Data, 0x1234, 65535, b"payload".to_vec());
+        packet.timestamp = 1;
+        packet.timestamp_difference = 2;
+        packet.window = 3;
+        packet.ack_nr = 4;
+        let mut buffer = packet.to_buffer();
+        assert_eq!(buffer[0], 0x01);
+        assert_eq!(Packet::from_buffer(&buffer), Some(packet.clone()));
+
+        // Unknown extensions are skipped
+        buffer[1] = 2;
+        buffer.splice(HEADER_LENGTH..HEADER_LENGTH, [0, 4, 0xff, 0, 0, 0]);
+        assert_eq!(Packet::from_buffer(&buffer), Some(packet.clone()));
+        packet.selective_ack = Some(vec![0b101, 0, 0, 0x80]);
+        assert_eq!(Packet::from_buffer(&packet.to_buffer()), Some(packet));
+
+        assert_eq!(Packet::from_buffer(b"d1:ad2:id20:"), None);
+        assert!(seq_before(65535, 0) && !seq_before(0, 65535));
+    }
+
+    /** Relay between one client and `server`, dropping a share of the datagrams both ways */
+    async fn lossy_relay(server: SocketAddr, loss: f64) -> SocketAddr {
+        let relay = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let relay_addr = relay.local_addr().unwrap();
+        tokio::spawn(async move {
+            let mut rng = StdRng::seed_from_u64(29);
+            let mut client = None;
+            let mut buffer = vec![0u8; 1 << 16];
+            loop {
+                let (length, from) = relay.recv_from(&mut buffer).await.unwrap();
+                let to = if from == server {
+                    client
+                } else {
+                    client = Some(from);
+                    Some(server)
+                };
+                if let Some(to) = to.filter(|_| !rng.gen_bool(loss)) {
+                    relay.send_to(&buffer[..length], to).await.unwrap();
+                }
+            }
+        });
+        relay_addr
+    }
+
+    /** Write everything then shut down, while reading until the peer does */
+    async fn exchange(stream: &mut UtpStream, data: &[u8]) -> Vec<u8> {
+        let (mut reader, mut writer) = tokio::io::split(stream);
+        let writing = async {
+            writer.write_all(data).await.unwrap();
+            writer.shutdown().await.unwrap();
+        };
+        let reading = async {
+            let mut received = vec![];
+            reader.read_to_end(&mut received).await.unwrap();
+            received
+        };
+        tokio::join!(writing, reading).1
+    }
+
+    async fn transfer(loss: f64) {
+        let server = UtpSocket::bind("127.0.0.1:0".parse().unwrap()).await.unwrap();
+        let client = UtpSocket::bind("127.0.0.1:0".parse().unwrap()).await.unwrap();
+        let relay = lossy_relay(server.local_addr().unwrap(), loss).await;
+        let upload = (0..200_000u32).map(|i| (i % 251) as u8).collect::<Vec<_>>();
+        let download = (0..100_000u32).map(|i| (i % 241) as u8).collect::<Vec<_>>();
+
+        let (accepted, connected) = tokio::join!(server.accept(), client.connect(relay));
+        let (mut accepted, mut connected) = (accepted.unwrap().0, connected.unwrap());
+        let exchanged = tokio::time::timeout(Duration::from_secs(20), async {
+            tokio::join!(exchange(&mut connected, &upload), exchange(&mut accepted, &download))
+        });
+        let (from_server, from_client) = exchanged.await.unwrap();
+        assert_eq!(from_server, download);
+        assert_eq!(from_client, upload);
+    }
+
+    #[tokio::test]
+    async fn test_utp_transfer() {
+        transfer(0.0).await;
+    }
+
+    #[tokio::test]
+    async fn test_utp_packet_loss() {
+        transfer(0.1).await;
+    }
+
+    #[tokio::test]
+    async fn test_utp_shared_socket() {
+        let socket = UtpSocket::bind("127.0.0.1:0".parse().unwrap()).await.unwrap();
+        let other = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        other.send_to(b"d1:ad2:id20:", socket.local_addr().unwrap()).await.unwrap();
+        let (datagram, from) = socket.recv_other().await.unwrap();
+        assert_eq!(datagram, b"d1:ad2:id20:");
+        assert_eq!(from, other.local_addr().unwrap());
+    }
+}
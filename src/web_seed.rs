@@ -0,0 +1,367 @@
+use std::time::{Duration, Instant};
+
+use anyhow::{Context, Error, Result};
+use reqwest::{header, Client, Response, StatusCode};
+use urlencoding::encode_binary;
+
+use crate::meta_info::{Info, MetaInfo};
+use crate::network::Interface;
+use crate::proxy::ProxySettings;
+use crate::rate_limit::{Bandwidth, Direction};
+
+/** A web seed failing a request is left alone for this long */
+pub const WEB_SEED_RETRY_INTERVAL: Duration = Duration::from_secs(30);
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum WebSeedKind {
+    /** BEP 19, a plain HTTP server hosting the files, pieces are fetched with range requests */
+    GetRight,
+    /** BEP 17, a script serving pieces by index */
+    HttpSeed,
+}
+
+/**
+ * Web seeds are treated like peers having every piece.
+ * Data received from them goes through the same hash check as data received from the swarm.
+ * The session fetches one piece at a time from each of them.
+ */
+#[derive(Debug, Clone)]
+pub struct WebSeed {
+    pub url: String,
+    pub kind: WebSeedKind,
+    /** Missing when no client could be built for the proxy and network settings, requests fail until one can */
+    client: Option<Client>,
+    bandwidth: Bandwidth,
+    /** A piece is being fetched */
+    pub busy: bool,
+    /** Set after a failed request */
+    pub retry_at: Option<Instant>,
+}
+
+impl WebSeed {
+    pub fn new(url: String, kind: WebSeedKind) -> Self {
+        Self {
+            url,
+            kind,
+            client: Some(Client::new()),
+            bandwidth: Bandwidth::unlimited(),
+            busy: false,
+            retry_at: None,
+        }
+    }
+
+    pub fn is_ready(&self, now: Instant) -> bool {
+        !self.busy && self.retry_at.is_none_or(|retry_at| retry_at <= now)
+    }
+
+    /** Web seed traffic counts against the same limits as the peers of the torrent */
+    pub fn set_bandwidth(&mut self, bandwidth: Bandwidth) {
+        self.bandwidth = bandwidth;
+    }
+
+    /** Requests leave from `interface` when given, through the proxy if there is one */
+    pub fn set_proxy(&mut self, proxy: &ProxySettings, interface: Option<&Interface>) -> Result<()> {
+        // Falling back to the previous client could send requests around the proxy or the interface
+        self.client = None;
+        self.client = Some(proxy.http_client(interface)?);
+        Ok(())
+    }
+
+    fn client(&self) -> Result<&Client> {
+        self.client
+            .as_ref()
+            .context(format!("No HTTP client to reach web seed {} with", self.url))
+    }
+
+    /**
+     * Read a response body chunk by chunk, waiting on the rate limits, headers are overhead.
+     * The first `skip` bytes are dropped and reading stops after `limit` bytes, the rest is never downloaded.
+     */
+    async fn read_body(&self, mut response: Response, skip: u64, limit: u64) -> Result<Vec<u8>> {
+        let headers_length = response
+            .headers()
+            .iter()
+            .map(|(name, value)| name.as_str().len() + value.len() + 4)
+            .sum::<usize>();
+        self.bandwidth
+            .acquire(Direction::Download, 0, headers_length as u64)
+            .await;
+        let mut body = Vec::new();
+        let mut position = 0;
+        while let Some(chunk) = response.chunk().await? {
+            self.bandwidth
+                .acquire(Direction::Download, chunk.len() as u64, 0)
+                .await;
+            let start = skip.saturating_sub(position).min(chunk.len() as u64) as usize;
+            position += chunk.len() as u64;
+            body.extend_from_slice(&chunk[start..]);
+            if body.len() as u64 >= limit {
+                body.truncate(limit as usize);
+                break;
+            }
+        }
+        Ok(body)
+    }
+
+    pub fn from_meta_info(meta_info: &MetaInfo) -> Vec<WebSeed> {
+        let get_right = meta_info
+            .url_list()
+            .into_iter()
+            .map(|url| WebSeed::new(url, WebSeedKind::GetRight));
+        let http_seeds = meta_info
+            .http_seeds()
+            .into_iter()
+            .map(|url| WebSeed::new(url, WebSeedKind::HttpSeed));
+        get_right.chain(http_seeds).collect()
+    }
+
+    /**
+     * If the url ends in a slash, the client must add the name of the torrent for single file torrents.
+     * For multi-file torrents, the name and path of each file is always added to the url.
+     */
+    pub fn file_url(&self, info: &Info, file_index: usize) -> String {
+        let mut url = self.url.clone();
+        match &info.files {
+            None => {
+                if url.ends_with('/') {
+                    url.push_str(&encode_binary(info.name_bytes()));
+                }
+            }
+            Some(files) => {
+                if !url.ends_with('/') {
+                    url.push('/');
+                }
+                url.push_str(&encode_binary(info.name_bytes()));
+                for component in files[file_index].path_components() {
+                    url.push('/');
+                    url.push_str(&encode_binary(component));
+                }
+            }
+        }
+        url
+    }
+
+    async fn fetch_range(&self, url: &str, offset: u64, length: u64) -> Result<Vec<u8>> {
+        let response = self
+            .client()?
+            .get(url)
+            .header(header::RANGE, format!("bytes={}-{}", offset, offset + length - 1))
+            .send()
+            .await
+            .context(format!("Failed to fetch web seed range from {}", url))?;
+
+        let status = response.status();
+        if status != StatusCode::PARTIAL_CONTENT && status != StatusCode::OK {
+            return Err(Error::msg(format!("Bad status code: {}", status)));
+        }
+
+        // Servers not supporting ranges answer with the whole file, only the range is read from it
+        let skip = if status == StatusCode::OK { offset } else { 0 };
+        let data = self.read_body(response, skip, length).await?;
+
+        if data.len() as u64 != length {
+            return Err(Error::msg(format!(
+                "Web seed returned {} bytes instead of {} from {}",
+                data.len(),
+                length,
+                url
+            )));
+        }
+        Ok(data)
+    }
+
+    /**
+     * The client calls the URL given, in the following format:
+     * <url>?info_hash=[hash]&piece=[piece]{&ranges=[start]-[end]{,[start]-[end]}...}
+     * If the server is busy it answers with a 503 whose body is the number of seconds to wait before retrying.
+     */
+    async fn fetch_http_seed_piece(&self, info: &Info, index: usize) -> Result<Vec<u8>> {
+        let info_hash = info.to_hash_buffer()?;
+        let separator = if self.url.contains('?') { '&' } else { '?' };
+        let url = format!(
+            "{}{}info_hash={}&piece={}",
+            self.url,
+            separator,
+            encode_binary(&info_hash),
+            index
+        );
+        let response = self
+            .client()?
+            .get(&url)
+            .send()
+            .await
+            .context(format!("Failed to fetch web seed piece from {}", url))?;
+
+        match response.status() {
+            StatusCode::OK => self.read_body(response, 0, info.piece_size(index)).await,
+            StatusCode::SERVICE_UNAVAILABLE => {
+                let retry_in = response.text().await.unwrap_or_default();
+                Err(Error::msg(format!(
+                    "Web seed {} is busy, retry in {} seconds",
+                    self.url,
+                    retry_in.trim()
+                )))
+            }
+            status => Err(Error::msg(format!("Bad status code: {}", status))),
+        }
+    }
+
+    /** Download a whole piece and check it against its hash */
+    pub async fn fetch_piece(&self, info: &Info, index: usize) -> Result<Vec<u8>> {
+        let data = match self.kind {
+            WebSeedKind::GetRight => {
+                let mut data = Vec::with_capacity(info.piece_size(index) as usize);
+                for slice in info.piece_file_slices(index) {
+                    // Padding files aren't hosted by web seeds
+                    if info.file_attributes(slice.file_index).padding {
+                        data.resize(data.len() + slice.length as usize, 0);
+                        continue;
+                    }
+                    let url = self.file_url(info, slice.file_index);
+                    data.extend(self.fetch_range(&url, slice.offset, slice.length).await?);
+                }
+                data
+            }
+            WebSeedKind::HttpSeed => self.fetch_http_seed_piece(info, index).await?,
+        };
+
+        if data.len() as u64 != info.piece_size(index) || !info.verify_piece(index, &data) {
+            return Err(Error::msg(format!(
+                "Piece {} from web seed {} failed the hash check",
+                index, self.url
+            )));
+        }
+        Ok(data)
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use std::collections::HashMap;
+    use std::sync::Arc;
+
+    use serde_bytes::ByteBuf;
+    use sha1::{Digest, Sha1};
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpListener;
+
+    use super::*;
+    use crate::meta_info::File;
+
+    /** Minimal HTTP/1.1 server answering GET requests, with single range support unless `ranges` is false */
+    pub(crate) async fn serve(files: HashMap<String, Vec<u8>>, ranges: bool) -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let files = Arc::new(files);
+        tokio::spawn(async move {
+            loop {
+                let (mut stream, _) = listener.accept().await.unwrap();
+                let files = files.clone();
+                tokio::spawn(async move {
+                    let mut request = Vec::new();
+                    let mut buffer = [0u8; 1024];
+                    while !request.ends_with(b"\r\n\r\n") {
+                        let read = stream.read(&mut buffer).await.unwrap();
+                        if read == 0 {
+                            return;
+                        }
+                        request.extend_from_slice(&buffer[..read]);
+                    }
+                    let request = String::from_utf8(request).unwrap();
+                    let path = request.split(' ').nth(1).unwrap().to_string();
+                    let range = request
+                        .lines()
+                        .find_map(|line| line.to_ascii_lowercase().strip_prefix("range: bytes=").map(str::to_string))
+                        .filter(|_| ranges);
+
+                    let (status, body) = match (files.get(&path), range) {
+                        (None, _) => ("404 Not Found", vec![]),
+                        (Some(file), None) => ("200 OK", file.clone()),
+                        (Some(file), Some(range)) => {
+                            let (start, end) = range.split_once('-').unwrap();
+                            let (start, end): (usize, usize) = (start.parse().unwrap(), end.parse().unwrap());
+                            ("206 Partial Content", file[start..=end].to_vec())
+                        }
+                    };
+                    let head = format!(
+                        "HTTP/1.1 {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+                        status,
+                        body.len()
+                    );
+                    // Clients stop reading once they have the range they wanted
+                    let _ = stream.write_all(head.as_bytes()).await;
+                    let _ = stream.write_all(&body).await;
+                });
+            }
+        });
+        format!("http://{}/", addr)
+    }
+
+    fn multi_file_info(data: &[u8], piece_length: usize) -> Info {
+        let pieces = data
+            .chunks(piece_length)
+            .flat_map(|chunk| Sha1::digest(chunk).to_vec())
+            .collect::<Vec<_>>();
+        let mut info = Info::default();
+        info.name = ByteBuf::from("data set");
+        info.pieces = ByteBuf::from(pieces);
+        info.piece_length = piece_length as i64;
+        info.files = Some(vec![
+            File {
+                path: vec![ByteBuf::from("a.bin")],
+                length: 10,
+                ..Default::default()
+            },
+            File {
+                path: vec![ByteBuf::from("sub"), ByteBuf::from("b.bin")],
+                length: data.len() as i64 - 10,
+                ..Default::default()
+            },
+        ]);
+        info
+    }
+
+    #[tokio::test]
+    async fn test_get_right_web_seed() {
+        let data = (0..40u8).collect::<Vec<_>>();
+        let info = multi_file_info(&data, 16);
+        let files = HashMap::from([
+            ("/data%20set/a.bin".to_string(), data[..10].to_vec()),
+            ("/data%20set/sub/b.bin".to_string(), data[10..].to_vec()),
+        ]);
+        let url = serve(files, true).await;
+
+        let seed = WebSeed::new(url, WebSeedKind::GetRight);
+        assert_eq!(seed.fetch_piece(&info, 0).await.unwrap(), data[..16]);
+        assert_eq!(seed.fetch_piece(&info, 1).await.unwrap(), data[16..32]);
+        assert_eq!(seed.fetch_piece(&info, 2).await.unwrap(), data[32..]);
+
+        let mut corrupted = info.clone();
+        corrupted.pieces[0] ^= 0xff;
+        assert!(seed.fetch_piece(&corrupted, 0).await.is_err());
+
+        // The whole file comes back without range support, the piece is cut out of it
+        let data = (0..200_000u32).map(|x| (x * 13) as u8).collect::<Vec<_>>();
+        let info = multi_file_info(&data, 16384);
+        let files = HashMap::from([
+            ("/data%20set/a.bin".to_string(), data[..10].to_vec()),
+            ("/data%20set/sub/b.bin".to_string(), data[10..].to_vec()),
+        ]);
+        let url = serve(files, false).await;
+        let seed = WebSeed::new(url, WebSeedKind::GetRight);
+        assert_eq!(seed.fetch_piece(&info, 3).await.unwrap(), data[3 * 16384..4 * 16384]);
+    }
+
+    #[tokio::test]
+    async fn test_http_seed() {
+        let data = (0..40u8).collect::<Vec<_>>();
+        let info = multi_file_info(&data, 16);
+        let info_hash = encode_binary(&info.to_hash_buffer().unwrap()).to_string();
+        let files = HashMap::from([(format!("/seed?info_hash={}&piece=1", info_hash), data[16..32].to_vec())]);
+        let url = serve(files, true).await;
+
+        let seed = WebSeed::new(format!("{}seed", url), WebSeedKind::HttpSeed);
+        assert_eq!(seed.fetch_piece(&info, 1).await.unwrap(), data[16..32]);
+        assert!(seed.fetch_piece(&info, 0).await.is_err());
+    }
+}